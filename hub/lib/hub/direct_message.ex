@@ -123,10 +123,9 @@ defmodule Hub.DirectMessage do
   def list_queued(fleet_id, agent_id) do
     prefix = "dmq:#{fleet_id}:#{agent_id}:"
 
-    case StorePort.list_documents() do
+    case StorePort.list_documents(prefix) do
       {:ok, ids} ->
         ids
-        |> Enum.filter(&String.starts_with?(&1, prefix))
         |> Enum.reduce([], fn queue_key, acc ->
           case fetch_queued_message(queue_key) do
             {:ok, envelope} ->
@@ -156,10 +155,8 @@ defmodule Hub.DirectMessage do
   def deliver_queued(fleet_id, agent_id) do
     prefix = "dmq:#{fleet_id}:#{agent_id}:"
 
-    case StorePort.list_documents() do
-      {:ok, ids} ->
-        queue_ids = Enum.filter(ids, &String.starts_with?(&1, prefix))
-
+    case StorePort.list_documents(prefix) do
+      {:ok, queue_ids} ->
         Enum.reduce(queue_ids, [], fn queue_key, delivered ->
           case fetch_queued_message(queue_key) do
             {:ok, envelope} ->
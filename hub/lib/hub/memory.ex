@@ -226,10 +226,9 @@ defmodule Hub.Memory do
   end
 
   defp load_fleet_entries(prefix) do
-    case StorePort.list_documents() do
+    case StorePort.list_documents(prefix) do
       {:ok, ids} ->
         ids
-        |> Enum.filter(&String.starts_with?(&1, prefix))
         |> Enum.reduce([], fn id, acc ->
           case fetch_raw(id) do
             {:ok, entry} -> [entry | acc]
@@ -99,11 +99,10 @@ defmodule Hub.Messaging.Announcements do
     limit = Keyword.get(opts, :limit, 20)
     prefix = "ann:#{fleet_id}:"
 
-    case StorePort.list_documents() do
+    case StorePort.list_documents(prefix) do
       {:ok, ids} ->
         announcements =
           ids
-          |> Enum.filter(&String.starts_with?(&1, prefix))
           |> Enum.sort()
           |> Enum.take(-limit)
           |> Enum.map(&fetch_announcement/1)
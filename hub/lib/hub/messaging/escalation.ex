@@ -417,12 +417,9 @@ defmodule Hub.Messaging.Escalation do
     # We need to search across all fleets — extract fleet from the ID if possible
     # Or search through all known escalations
     # The escalation_id embeds no fleet info, so we scan the index
-    case StorePort.list_documents() do
+    case StorePort.list_documents("esc:") do
       {:ok, ids} ->
-        key =
-          Enum.find(ids, fn id ->
-            String.starts_with?(id, "esc:") and String.ends_with?(id, ":#{escalation_id}")
-          end)
+        key = Enum.find(ids, &String.ends_with?(&1, ":#{escalation_id}"))
 
         if key do
           load_escalation(key)
@@ -205,11 +205,10 @@ defmodule Hub.Messaging.Threads do
     before = Keyword.get(opts, :before)
     prefix = "thr_msg:#{thread_id}:"
 
-    case StorePort.list_documents() do
+    case StorePort.list_documents(prefix) do
       {:ok, ids} ->
         messages =
           ids
-          |> Enum.filter(&String.starts_with?(&1, prefix))
           |> Enum.sort()
           |> maybe_filter_before(before, prefix)
           |> Enum.take(-limit)
@@ -32,10 +32,28 @@ defmodule Hub.StorePort do
     GenServer.call(__MODULE__, {:has_blob, hash}, 30_000)
   end
 
-  @doc "Store/update a document. meta and crdt_state are raw binaries."
-  def put_document(id, meta \\ <<>>, crdt_state \\ <<>>)
-      when is_binary(id) and is_binary(meta) and is_binary(crdt_state) do
-    GenServer.call(__MODULE__, {:put_document, id, meta, crdt_state}, 30_000)
+  @doc """
+  Store/update a document. meta and crdt_state are raw binaries.
+  Returns `:ok` or `{:error, reason}`.
+
+  `index` is a list of `{key, value}` strings the document can be found by
+  with `query_documents/2`; `nil` keeps its existing entries.
+  """
+  def put_document(id, meta \\ <<>>, crdt_state \\ <<>>, index \\ nil)
+      when is_binary(id) and is_binary(meta) and is_binary(crdt_state) and
+             (is_nil(index) or is_list(index)) do
+    GenServer.call(__MODULE__, {:put_document, id, meta, crdt_state, index}, 30_000)
+  end
+
+  @doc """
+  Store a document only if no live document has the id, deciding that in
+  the store's write transaction, so two racing creates can't both win.
+  Returns `:ok`, `{:error, :already_exists}` or `{:error, reason}`.
+  """
+  def create_document(id, meta \\ <<>>, crdt_state \\ <<>>, index \\ nil)
+      when is_binary(id) and is_binary(meta) and is_binary(crdt_state) and
+             (is_nil(index) or is_list(index)) do
+    GenServer.call(__MODULE__, {:create_document, id, meta, crdt_state, index}, 30_000)
   end
 
   @doc "Retrieve a document by id."
@@ -48,9 +66,37 @@ defmodule Hub.StorePort do
     GenServer.call(__MODULE__, {:delete_document, id}, 30_000)
   end
 
-  @doc "List all document ids."
-  def list_documents do
-    GenServer.call(__MODULE__, :list_documents, 30_000)
+  @doc "Ids of the documents indexed with `key` = `value`."
+  def query_documents(key, value) when is_binary(key) and is_binary(value) do
+    GenServer.call(__MODULE__, {:query_documents, key, value}, 30_000)
+  end
+
+  @doc "Count documents whose id starts with `prefix`, without listing them."
+  def count_documents(prefix \\ "") when is_binary(prefix) do
+    GenServer.call(__MODULE__, {:count, :documents, prefix}, 30_000)
+  end
+
+  @doc "List document ids, optionally only those starting with `prefix`."
+  def list_documents(prefix \\ "") when is_binary(prefix) do
+    GenServer.call(__MODULE__, {:list_documents, prefix}, 30_000)
+  end
+
+  @doc """
+  One 32-byte Merkle root over the versions of `doc_ids` (every document
+  if empty).  Two stores with equal roots are in sync, so comparing roots
+  is a cheap check before any finer-grained reconciliation.
+  """
+  def combined_root(doc_ids \\ []) when is_list(doc_ids) do
+    GenServer.call(__MODULE__, {:get_combined_root, doc_ids}, 30_000)
+  end
+
+  @doc """
+  The combined root of every document as the store reported it with the
+  last write through this port, without asking the store; `nil` before the
+  first.  Writes the store takes from elsewhere (peer sync) aren't seen.
+  """
+  def last_root do
+    GenServer.call(__MODULE__, :last_root)
   end
 
   # ── GenServer callbacks ──────────────────────────────────────────────
@@ -69,7 +115,7 @@ defmodule Hub.StorePort do
         [
           :binary,
           :exit_status,
-          {:args, ["--data-dir", data_dir]},
+          {:args, ["--data-dir", data_dir, "--root-in-replies"]},
           {:packet, 4}
         ]
       )
@@ -81,11 +127,16 @@ defmodule Hub.StorePort do
        port: port,
        ref_counter: 0,
        pending: %{},
-       buffer: <<>>
+       buffer: <<>>,
+       root: nil
      }}
   end
 
   @impl true
+  def handle_call(:last_root, _from, state) do
+    {:reply, state.root, state}
+  end
+
   def handle_call(request, from, state) do
     ref_id = state.ref_counter + 1
 
@@ -101,17 +152,52 @@ defmodule Hub.StorePort do
 
   @impl true
   def handle_info({port, {:data, data}}, %{port: port} = state) do
-    {ref_id, response} = StoreProtocol.decode_response(data)
+    case StoreProtocol.decode_response(data) do
+      {0, {:integrity_alert, database, namespace, drifted_buckets, message}} ->
+        Logger.error(
+          "Store integrity alert for #{database} namespace=#{inspect(namespace)}: #{message}"
+        )
+
+        Hub.Telemetry.execute(
+          [:hub, :store, :integrity_alert],
+          %{drifted_buckets: length(drifted_buckets)},
+          %{database: database, namespace: namespace}
+        )
 
-    case Map.pop(state.pending, ref_id) do
-      {nil, _pending} ->
-        Logger.warning("StorePort received response for unknown ref_id=#{ref_id}")
         {:noreply, state}
 
-      {from, pending} ->
-        reply = translate_response(response)
-        GenServer.reply(from, reply)
-        {:noreply, %{state | pending: pending}}
+      {0, {:peer_sync, peer, namespace, %{error: nil} = progress}} ->
+        Logger.debug(
+          "Store peer sync from #{peer} namespace=#{inspect(namespace)}: " <>
+            "#{progress.applied}/#{progress.total}"
+        )
+
+        Hub.Telemetry.execute(
+          [:hub, :store, :peer_sync],
+          %{applied: progress.applied, total: progress.total},
+          %{peer: peer, namespace: namespace, done: progress.done}
+        )
+
+        {:noreply, state}
+
+      {0, {:peer_sync, peer, namespace, %{error: error}}} ->
+        Logger.warning(
+          "Store peer sync from #{peer} namespace=#{inspect(namespace)} failed: #{error}"
+        )
+
+        Hub.Telemetry.execute(
+          [:hub, :store, :peer_sync_failed],
+          %{count: 1},
+          %{peer: peer, namespace: namespace, error: error}
+        )
+
+        {:noreply, state}
+
+      {ref_id, {:with_root, root, response}} ->
+        handle_response(ref_id, response, %{state | root: root})
+
+      {ref_id, response} ->
+        handle_response(ref_id, response, state)
     end
   end
 
@@ -130,6 +216,19 @@ defmodule Hub.StorePort do
     {:noreply, state}
   end
 
+  defp handle_response(ref_id, response, state) do
+    case Map.pop(state.pending, ref_id) do
+      {nil, _pending} ->
+        Logger.warning("StorePort received response for unknown ref_id=#{ref_id}")
+        {:noreply, state}
+
+      {from, pending} ->
+        reply = translate_response(response)
+        GenServer.reply(from, reply)
+        {:noreply, %{state | pending: pending}}
+    end
+  end
+
   @impl true
   def terminate(_reason, %{port: port}) do
     Port.close(port)
@@ -147,10 +246,14 @@ defmodule Hub.StorePort do
         {:put_blob, data} -> {:put_blob, data}
         {:get_blob, hash} -> {:get_blob, hash}
         {:has_blob, hash} -> {:has_blob, hash}
-        {:put_document, id, meta, crdt_state} -> {:put_document, id, meta, crdt_state}
+        {:put_document, id, meta, crdt_state, index} -> {:put_document, id, meta, crdt_state, index}
+        {:create_document, id, meta, crdt_state, index} -> {:create_document, id, meta, crdt_state, index}
+        {:query_documents, key, value} -> {:query_documents, key, value}
         {:get_document, id} -> {:get_document, id}
         {:delete_document, id} -> {:delete_document, id}
-        :list_documents -> :list_documents
+        {:list_documents, prefix} -> {:list_documents, prefix}
+        {:count, what, prefix} -> {:count, what, prefix}
+        {:get_combined_root, doc_ids} -> {:get_combined_root, doc_ids}
       end
 
     frame = StoreProtocol.encode_request(ref_id, req_body)
@@ -168,6 +271,15 @@ defmodule Hub.StorePort do
   defp translate_response({:document, id, meta, crdt_state}),
     do: {:ok, %{id: id, meta: meta, crdt_state: crdt_state}}
 
+  # The new version isn't surfaced; callers only check that the put landed.
+  defp translate_response({:document_stored, _version}), do: :ok
+  defp translate_response(:already_exists), do: {:error, :already_exists}
   defp translate_response({:document_list, ids}), do: {:ok, ids}
-  defp translate_response({:error, message}), do: {:error, message}
+  defp translate_response({:count, count}), do: {:ok, count}
+  defp translate_response({:combined_root, root}), do: {:ok, root}
+  defp translate_response({:error, :unknown_response, idx}),
+    do: {:error, {:unknown_response, idx}}
+
+  defp translate_response({:error, _code, message}), do: {:error, message}
+  defp translate_response({:busy, retry_after_ms}), do: {:error, {:busy, retry_after_ms}}
 end
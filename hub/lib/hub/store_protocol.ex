@@ -3,13 +3,17 @@ defmodule Hub.StoreProtocol do
   Encodes/decodes the bincode wire protocol for the Rust storage engine.
 
   Wire format: 4-byte big-endian length prefix + bincode payload.
-  Each payload is a tuple: (ref_id: u64-LE, Request|Response).
+  Request payloads are
+  (ref_id: u64-LE, trace_id: Option<String>, namespace: String,
+   tenant: Option<String>, durability: Option<Durability>, Request);
+  response payloads are (ref_id: u64-LE, Response).
 
   Bincode 1 conventions:
     - Integers are little-endian, fixed-width
     - Enum variant index: u32 LE
     - Vec<u8>/String: u64 LE length prefix, then raw bytes
     - bool: single byte (0 or 1)
+    - Option<T>: 0 for None, 1 followed by T for Some
     - Tuples/structs: fields concatenated in order
   """
 
@@ -25,6 +29,14 @@ defmodule Hub.StoreProtocol do
   # @get_roots 7
   # @get_changes 8
   # @apply_changes 9
+  # 10..13: batch, listing and GC requests
+  @open_tenant 14
+  @close_tenant 15
+  # 16..17: document history
+  @query_documents 18
+  @count 19
+  # 20..56: stats, maintenance, transaction and sync requests
+  @get_combined_root 57
 
   # ── Response variant indices ─────────────────────────────────────────
 
@@ -39,17 +51,57 @@ defmodule Hub.StoreProtocol do
   # @resp_changes 8
   # @resp_sync_diff 9
   @resp_error 10
+  # @resp_changes_part 11
+  @resp_busy 12
+  # 13..18: batch, listing, GC and history responses
+  @resp_count 19
+  # 20..25: stats and maintenance responses
+  @resp_document_stored 26
+  @resp_already_exists 27
+  # 28..42: integrity, sync and maintenance responses
+  @resp_integrity_alert 43
+  # 44..45: IBLT responses
+  @resp_peer_sync 46
+  # 47..49: conflict and change-listing responses
+  @resp_combined_root 50
+  # 51: blob sync response
+  @resp_with_root 52
 
   # ── Encoding ─────────────────────────────────────────────────────────
 
-  @doc "Encode a request into a length-prefixed bincode frame."
-  def encode_request(ref_id, request) do
-    payload = encode_u64(ref_id) <> encode_request_body(request)
+  @doc """
+  Encode a request into a length-prefixed bincode frame.
+
+  Options:
+    * `:trace_id` - logged by the store alongside the request, so slow
+      requests in its stderr can be correlated with the Telemetry event that
+      issued them.
+    * `:namespace` - documents and blobs namespace the request operates on;
+      defaults to `""`, the default namespace.
+    * `:tenant` - tenant database to route the request to; it must have been
+      opened with `{:open_tenant, name}`.  Defaults to the root database.
+    * `:durability` - `:none`, `:eventual` or `:immediate`, overriding the
+      store's `--durability` for this request's writes (e.g. `:none` for
+      bulk imports).  Defaults to the store's setting.
+  """
+  def encode_request(ref_id, request, opts \\ []) do
+    payload =
+      encode_u64(ref_id) <>
+        encode_option_string(Keyword.get(opts, :trace_id)) <>
+        encode_string(Keyword.get(opts, :namespace, "")) <>
+        encode_option_string(Keyword.get(opts, :tenant)) <>
+        encode_option_durability(Keyword.get(opts, :durability)) <>
+        encode_request_body(request)
+
     <<byte_size(payload)::big-unsigned-32>> <> payload
   end
 
   defp encode_request_body({:put_blob, data}) do
-    encode_variant(@put_blob) <> encode_bytes(data)
+    encode_request_body({:put_blob, data, nil})
+  end
+
+  defp encode_request_body({:put_blob, data, ttl_secs}) do
+    encode_variant(@put_blob) <> encode_bytes(data) <> encode_option_u64(ttl_secs)
   end
 
   defp encode_request_body({:get_blob, hash}) do
@@ -61,22 +113,67 @@ defmodule Hub.StoreProtocol do
   end
 
   defp encode_request_body({:put_document, id, meta, crdt_state}) do
-    encode_variant(@put_document) <>
-      encode_string(id) <>
-      encode_bytes(meta) <>
-      encode_bytes(crdt_state)
+    encode_request_body({:put_document, id, meta, crdt_state, nil})
+  end
+
+  # `index` is nil (keep the document's index entries) or a list of
+  # {key, value} strings replacing them.  Puts overwrite; creates
+  # (`create_only` true) leave a live document alone and get
+  # `AlreadyExists`.  Gets, deletes and listings send the store's boolean
+  # options as false.
+  defp encode_request_body({:put_document, id, meta, crdt_state, index}) do
+    encode_put_document(id, meta, crdt_state, index, false)
+  end
+
+  defp encode_request_body({:create_document, id, meta, crdt_state, index}) do
+    encode_put_document(id, meta, crdt_state, index, true)
   end
 
   defp encode_request_body({:get_document, id}) do
-    encode_variant(@get_document) <> encode_string(id)
+    encode_variant(@get_document) <> encode_string(id) <> encode_bool(false)
   end
 
   defp encode_request_body({:delete_document, id}) do
-    encode_variant(@delete_document) <> encode_string(id)
+    encode_variant(@delete_document) <> encode_string(id) <> encode_bool(false)
   end
 
   defp encode_request_body(:list_documents) do
-    encode_variant(@list_documents)
+    encode_request_body({:list_documents, ""})
+  end
+
+  defp encode_request_body({:list_documents, prefix}) do
+    encode_variant(@list_documents) <> encode_string(prefix) <> encode_bool(false)
+  end
+
+  defp encode_request_body({:query_documents, key, value}) do
+    encode_variant(@query_documents) <> encode_string(key) <> encode_string(value)
+  end
+
+  defp encode_request_body({:count, what, prefix}) when what in [:documents, :blobs] do
+    target = if what == :documents, do: 0, else: 1
+    encode_variant(@count) <> encode_variant(target) <> encode_bytes(prefix)
+  end
+
+  # An empty list covers every document.
+  defp encode_request_body({:get_combined_root, doc_ids}) do
+    encode_variant(@get_combined_root) <> encode_string_list(doc_ids)
+  end
+
+  defp encode_request_body({:open_tenant, name}) do
+    encode_variant(@open_tenant) <> encode_string(name)
+  end
+
+  defp encode_request_body({:close_tenant, name}) do
+    encode_variant(@close_tenant) <> encode_string(name)
+  end
+
+  defp encode_put_document(id, meta, crdt_state, index, create_only) do
+    encode_variant(@put_document) <>
+      encode_string(id) <>
+      encode_bytes(meta) <>
+      encode_bytes(crdt_state) <>
+      encode_option_index(index) <>
+      encode_bool(create_only)
   end
 
   # ── Decoding ─────────────────────────────────────────────────────────
@@ -111,10 +208,23 @@ defmodule Hub.StoreProtocol do
   defp decode_response_body(<<@resp_document::little-unsigned-32, rest::binary>>) do
     {id, rest1} = decode_string(rest)
     {meta, rest2} = decode_bytes(rest1)
+    # The version and timestamps that follow are not surfaced.
     {crdt_state, _} = decode_bytes(rest2)
     {:document, id, meta, crdt_state}
   end
 
+  # Reply to a put, with the document's new version.
+  defp decode_response_body(
+         <<@resp_document_stored::little-unsigned-32, version::little-unsigned-64, _rest::binary>>
+       ) do
+    {:document_stored, version}
+  end
+
+  # Reply to a create-only put of a live document.
+  defp decode_response_body(<<@resp_already_exists::little-unsigned-32, _rest::binary>>) do
+    :already_exists
+  end
+
   defp decode_response_body(<<@resp_document_list::little-unsigned-32, rest::binary>>) do
     {ids, _} = decode_string_list(rest)
     {:document_list, ids}
@@ -128,17 +238,76 @@ defmodule Hub.StoreProtocol do
     :not_found
   end
 
-  defp decode_response_body(<<@resp_error::little-unsigned-32, rest::binary>>) do
+  defp decode_response_body(<<@resp_count::little-unsigned-32, count::little-unsigned-64, _rest::binary>>) do
+    {:count, count}
+  end
+
+  defp decode_response_body(<<@resp_busy::little-unsigned-32, retry_after_ms::little-unsigned-64, _rest::binary>>) do
+    {:busy, retry_after_ms}
+  end
+
+  defp decode_response_body(<<@resp_combined_root::little-unsigned-32, rest::binary>>) do
+    {root, _} = decode_bytes(rest)
+    {:combined_root, root}
+  end
+
+  # Sent with `--root-in-replies` in place of the reply to a write.
+  defp decode_response_body(<<@resp_with_root::little-unsigned-32, rest::binary>>) do
+    {root, rest1} = decode_bytes(rest)
+    {:with_root, root, decode_response_body(rest1)}
+  end
+
+  # Pushed with ref_id 0 by the store's anti-entropy checks.
+  defp decode_response_body(<<@resp_integrity_alert::little-unsigned-32, rest::binary>>) do
+    {database, rest1} = decode_string(rest)
+    {namespace, rest2} = decode_string(rest1)
+    {drifted_buckets, rest3} = decode_string_list(rest2)
+    {message, _} = decode_string(rest3)
+    {:integrity_alert, database, namespace, drifted_buckets, message}
+  end
+
+  # Pushed with ref_id 0 while the store pulls from a `--peer`.
+  defp decode_response_body(<<@resp_peer_sync::little-unsigned-32, rest::binary>>) do
+    {peer, rest1} = decode_string(rest)
+    {namespace, rest2} = decode_string(rest1)
+    <<applied::little-unsigned-64, total::little-unsigned-64, done::8, rest3::binary>> = rest2
+    {error, _} = decode_option_string(rest3)
+    progress = %{applied: applied, total: total, done: done == 1, error: error}
+    {:peer_sync, peer, namespace, progress}
+  end
+
+  defp decode_response_body(
+         <<@resp_error::little-unsigned-32, code::little-unsigned-32, rest::binary>>
+       ) do
     {message, _} = decode_string(rest)
-    {:error, message}
+    {:error, error_code(code), message}
+  end
+
+  # A response this module doesn't know yet, e.g. from a newer store; the
+  # caller gets an error instead of the port process crashing.
+  defp decode_response_body(<<idx::little-unsigned-32, _rest::binary>>) do
+    {:error, :unknown_response, idx}
   end
 
+  # ErrorCode variant indices (match Rust enum order)
+  defp error_code(0), do: :storage
+  defp error_code(1), do: :decode
+  defp error_code(2), do: :bad_request
+  defp error_code(3), do: :internal
+  defp error_code(4), do: :too_large
+  defp error_code(5), do: :invalid_id
+  defp error_code(6), do: :unauthorized
+  defp error_code(_), do: :unknown
+
   # ── Primitives ───────────────────────────────────────────────────────
 
   defp encode_variant(idx), do: <<idx::little-unsigned-32>>
 
   defp encode_u64(n), do: <<n::little-unsigned-64>>
 
+  defp encode_bool(true), do: <<1>>
+  defp encode_bool(false), do: <<0>>
+
   defp encode_bytes(bin) when is_binary(bin) do
     <<byte_size(bin)::little-unsigned-64>> <> bin
   end
@@ -146,6 +315,28 @@ defmodule Hub.StoreProtocol do
   # Bincode 1 encodes String identically to Vec<u8>
   defp encode_string(str) when is_binary(str), do: encode_bytes(str)
 
+  defp encode_string_list(strs) when is_list(strs) do
+    encode_u64(length(strs)) <> Enum.map_join(strs, &encode_string/1)
+  end
+
+  defp encode_option_index(nil), do: <<0>>
+
+  defp encode_option_index(pairs) when is_list(pairs) do
+    encoded = for {key, value} <- pairs, into: <<>>, do: encode_string(key) <> encode_string(value)
+    <<1>> <> encode_u64(length(pairs)) <> encoded
+  end
+
+  defp encode_option_durability(nil), do: <<0>>
+  defp encode_option_durability(:none), do: <<1>> <> encode_variant(0)
+  defp encode_option_durability(:eventual), do: <<1>> <> encode_variant(1)
+  defp encode_option_durability(:immediate), do: <<1>> <> encode_variant(2)
+
+  defp encode_option_u64(nil), do: <<0>>
+  defp encode_option_u64(n) when is_integer(n), do: <<1>> <> encode_u64(n)
+
+  defp encode_option_string(nil), do: <<0>>
+  defp encode_option_string(str) when is_binary(str), do: <<1>> <> encode_string(str)
+
   defp decode_bytes(<<len::little-unsigned-64, rest::binary>>) do
     <<data::binary-size(len), remaining::binary>> = rest
     {data, remaining}
@@ -153,6 +344,9 @@ defmodule Hub.StoreProtocol do
 
   defp decode_string(bin), do: decode_bytes(bin)
 
+  defp decode_option_string(<<0, rest::binary>>), do: {nil, rest}
+  defp decode_option_string(<<1, rest::binary>>), do: decode_string(rest)
+
   defp decode_string_list(<<count::little-unsigned-64, rest::binary>>) do
     decode_n_strings(rest, count, [])
   end
@@ -0,0 +1,30 @@
+defmodule Hub.StoreProtocolTest do
+  use ExUnit.Case, async: true
+
+  alias Hub.StoreProtocol
+
+  # Response payloads as ringforge-store writes them (without the length
+  # prefix the port driver strips).
+
+  test "decodes DocumentStored with its version" do
+    frame = Base.decode16!("05000000000000001A0000000300000000000000")
+    assert StoreProtocol.decode_response(frame) == {5, {:document_stored, 3}}
+  end
+
+  test "decodes AlreadyExists" do
+    frame = Base.decode16!("06000000000000001B000000")
+    assert StoreProtocol.decode_response(frame) == {6, :already_exists}
+  end
+
+  test "encodes a create-only put" do
+    <<_len::32, payload::binary>> =
+      StoreProtocol.encode_request(1, {:create_document, "d", "", "", nil})
+
+    assert binary_part(payload, byte_size(payload) - 2, 2) == <<0, 1>>
+  end
+
+  test "decodes a response it doesn't know as an error" do
+    frame = Base.decode16!("070000000000000063000000")
+    assert StoreProtocol.decode_response(frame) == {7, {:error, :unknown_response, 99}}
+  end
+end
@@ -0,0 +1,78 @@
+//! Background anti-entropy checks of the doc hash buckets.
+//!
+//! With `--anti-entropy-interval-secs`, a thread recomputes the buckets of
+//! every namespace of every open database from doc_hashes at that interval
+//! and compares them with the stored trie that `GetBucket` serves.  Drift
+//! means peers would reconcile against wrong digests, so each namespace
+//! that has drifted, or can't be read, is reported with an
+//! `IntegrityAlert` frame pushed with ref_id 0 rather than waiting for
+//! someone to notice missing data.  `Repair` rebuilds the buckets.
+
+use crate::protocol::Response;
+use crate::server::Notifications;
+use crate::store::Store;
+use crate::tenants::Tenants;
+use anyhow::Result;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+/// Granularity at which the thread notices shutdown.
+const TICK: Duration = Duration::from_millis(200);
+
+/// Check every namespace of every open database each `interval` until
+/// `shutdown` is set, queueing an alert in `notifications` for each
+/// problem.
+pub fn spawn(
+    tenants: Tenants,
+    interval: Duration,
+    notifications: Arc<Notifications>,
+    shutdown: Arc<AtomicBool>,
+) -> JoinHandle<()> {
+    thread::Builder::new()
+        .name("anti-entropy".into())
+        .spawn(move || {
+            info!(interval_secs = interval.as_secs(), "anti-entropy checks started");
+            let mut next = Instant::now() + interval;
+            while !shutdown.load(Ordering::SeqCst) {
+                if Instant::now() < next {
+                    thread::sleep(TICK);
+                    continue;
+                }
+                for store in tenants.stores() {
+                    if let Err(e) = check_all(&store, &notifications) {
+                        let dir = store.dir().display();
+                        warn!(dir = %dir, error = %e, "anti-entropy check failed");
+                    }
+                }
+                next = Instant::now() + interval;
+            }
+        })
+        .expect("spawning anti-entropy thread")
+}
+
+fn check_all(store: &Store, notifications: &Notifications) -> Result<()> {
+    let mut namespaces = vec![String::new()];
+    namespaces.extend(store.namespaces()?);
+    for namespace in namespaces {
+        let (drifted_buckets, message) = match store.namespace(&namespace)?.check_buckets() {
+            Ok(drifted) if drifted.is_empty() => continue,
+            Ok(drifted) => {
+                let message = format!("{} doc hash buckets drifted", drifted.len());
+                (drifted, message)
+            }
+            Err(e) => (Vec::new(), format!("checking the doc hash buckets failed: {e:#}")),
+        };
+        let database = store.dir().display().to_string();
+        warn!(database, namespace, %message, "anti-entropy check found a problem");
+        notifications.push(Response::IntegrityAlert {
+            database,
+            namespace,
+            drifted_buckets,
+            message,
+        });
+    }
+    Ok(())
+}
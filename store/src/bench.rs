@@ -0,0 +1,244 @@
+//! Built-in benchmark.
+//!
+//! Drives request frames through a `Server` from several threads, the way
+//! the port's reader would, so the numbers include decoding, dispatch and
+//! encoding as well as the store.  Values are generated from a fixed seed,
+//! so two runs with the same flags write the same bytes.
+
+use crate::protocol::{Envelope, RefId, Request, Response};
+use crate::server::Server;
+use anyhow::{bail, Context, Result};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// Id prefix of the documents the benchmark writes.
+const DOC_PREFIX: &str = "bench/";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Workload {
+    /// One `PutBlob` of a new blob per operation.
+    PutBlob,
+    /// One `GetDocument` of a seeded document per operation.
+    GetDocument,
+    /// One `GetChanges` over every seeded document per operation.
+    GetChanges,
+}
+
+impl FromStr for Workload {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "put-blob" => Ok(Workload::PutBlob),
+            "get-document" => Ok(Workload::GetDocument),
+            "get-changes" => Ok(Workload::GetChanges),
+            other => bail!("unknown workload {other:?} (put-blob, get-document, get-changes)"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub workload: Workload,
+    /// Operations in all, shared out between the threads.
+    pub ops: u64,
+    pub concurrency: usize,
+    /// Size of each blob, or of each document's CRDT state.
+    pub value_bytes: usize,
+    /// Documents written before a read workload starts.
+    pub documents: u64,
+    /// Fill values with repeated text instead of incompressible bytes.
+    pub compressible: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct Report {
+    pub ops: u64,
+    /// Response payload bytes.
+    pub bytes: u64,
+    pub elapsed: Duration,
+    /// Latency of every operation, sorted.
+    pub latencies: Vec<Duration>,
+}
+
+impl Report {
+    /// Latency below which `p` percent of the operations finished.
+    pub fn percentile(&self, p: f64) -> Duration {
+        percentile(&self.latencies, p)
+    }
+
+    pub fn ops_per_sec(&self) -> f64 {
+        self.ops as f64 / self.elapsed.as_secs_f64()
+    }
+}
+
+/// Seed the documents a read workload needs, then run it.
+pub fn run(server: &Server, config: &Config) -> Result<Report> {
+    if config.concurrency == 0 {
+        bail!("concurrency must be at least 1");
+    }
+    if config.workload != Workload::PutBlob {
+        if config.documents == 0 {
+            bail!("{:?} needs at least one document", config.workload);
+        }
+        for n in 0..config.documents {
+            call(
+                server,
+                n,
+                Request::PutDocument {
+                    id: doc_id(n),
+                    meta: Vec::new(),
+                    crdt_state: value(n, config.value_bytes, config.compressible),
+                    index: None,
+                    create_only: false,
+                },
+            )
+            .context("seeding documents")?;
+        }
+    }
+
+    let next = AtomicU64::new(0);
+    let started = Instant::now();
+    let results: Vec<Result<(Vec<Duration>, u64)>> = std::thread::scope(|scope| {
+        let workers: Vec<_> = (0..config.concurrency)
+            .map(|_| scope.spawn(|| worker(server, config, &next)))
+            .collect();
+        workers
+            .into_iter()
+            .map(|w| w.join().unwrap_or_else(|_| bail!("benchmark thread panicked")))
+            .collect()
+    });
+    let elapsed = started.elapsed();
+
+    let mut report = Report {
+        ops: config.ops,
+        bytes: 0,
+        elapsed,
+        latencies: Vec::with_capacity(config.ops as usize),
+    };
+    for result in results {
+        let (latencies, bytes) = result?;
+        report.latencies.extend(latencies);
+        report.bytes += bytes;
+    }
+    report.latencies.sort_unstable();
+    Ok(report)
+}
+
+/// Take operations off the shared counter until there are none left.
+fn worker(server: &Server, config: &Config, next: &AtomicU64) -> Result<(Vec<Duration>, u64)> {
+    let mut latencies = Vec::new();
+    let mut bytes = 0;
+    loop {
+        let op = next.fetch_add(1, Ordering::Relaxed);
+        if op >= config.ops {
+            return Ok((latencies, bytes));
+        }
+        let request = match config.workload {
+            // Offset past the seeded documents' values, so every blob is new.
+            Workload::PutBlob => Request::PutBlob {
+                data: value(config.documents + op, config.value_bytes, config.compressible),
+                ttl_secs: None,
+            },
+            Workload::GetDocument => Request::GetDocument {
+                id: doc_id(op % config.documents),
+                with_timestamps: false,
+            },
+            Workload::GetChanges => Request::GetChanges {
+                known_roots: Vec::new(),
+                doc_ids: Vec::new(),
+                prefix: Some(DOC_PREFIX.to_string()),
+                namespace: None,
+            },
+        };
+        let started = Instant::now();
+        bytes += call(server, op, request)?;
+        latencies.push(started.elapsed());
+    }
+}
+
+/// Send one request and check its replies for errors.  Returns the bytes
+/// of the reply frames.
+fn call(server: &Server, ref_id: RefId, request: Request) -> Result<u64> {
+    let envelope = Envelope {
+        ref_id,
+        trace_id: None,
+        namespace: String::new(),
+        tenant: None,
+        durability: None,
+        request,
+    };
+    let mut frames: Vec<Vec<u8>> = Vec::new();
+    server.handle_frame(&bincode::serialize(&envelope)?, &mut frames)?;
+    let mut bytes = 0;
+    for frame in &frames {
+        let (_, response): (RefId, Response) = bincode::deserialize(frame)?;
+        if let Response::Error { code, message } = response {
+            bail!("ref_id {ref_id}: {code:?}: {message}");
+        }
+        bytes += frame.len() as u64;
+    }
+    Ok(bytes)
+}
+
+fn doc_id(n: u64) -> String {
+    format!("{DOC_PREFIX}{n}")
+}
+
+/// The `n`th value: blake3's output stream seeded with `n`, or with
+/// `compressible` a line of text repeated.
+fn value(n: u64, len: usize, compressible: bool) -> Vec<u8> {
+    let mut out = vec![0u8; len];
+    if compressible {
+        let line = format!("benchmark value {n}\n");
+        for (byte, c) in out.iter_mut().zip(line.bytes().cycle()) {
+            *byte = c;
+        }
+    } else {
+        blake3::Hasher::new()
+            .update(&n.to_le_bytes())
+            .finalize_xof()
+            .fill(&mut out);
+    }
+    out
+}
+
+/// Nearest-rank percentile of sorted latencies.
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    let rank = (p / 100.0 * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_percentile() {
+        let sorted: Vec<Duration> = (1..=100).map(Duration::from_millis).collect();
+        assert_eq!(percentile(&sorted, 50.0), Duration::from_millis(50));
+        assert_eq!(percentile(&sorted, 99.0), Duration::from_millis(99));
+        assert_eq!(percentile(&sorted, 100.0), Duration::from_millis(100));
+        assert_eq!(percentile(&sorted, 0.0), Duration::from_millis(1));
+        assert_eq!(percentile(&[], 50.0), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_values() {
+        assert_eq!(value(1, 64, false), value(1, 64, false));
+        assert_ne!(value(1, 64, false), value(2, 64, false));
+        assert_eq!(&value(3, 40, true)[..18], b"benchmark value 3\n");
+        assert_eq!(value(0, 0, false), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn test_workload_from_str() {
+        assert_eq!("put-blob".parse::<Workload>().unwrap(), Workload::PutBlob);
+        assert_eq!("get-changes".parse::<Workload>().unwrap(), Workload::GetChanges);
+        assert!("scan".parse::<Workload>().is_err());
+    }
+}
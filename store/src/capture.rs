@@ -0,0 +1,235 @@
+//! Frame capture files for reproducing protocol bugs.
+//!
+//! A capture starts with an 8-byte magic, followed by records:
+//!   [1-byte direction][8-byte BE unix micros][8-byte BE ref_id]
+//!   [4-byte BE length][frame payload]
+//!
+//! Payloads are the raw bincode frames as seen on the wire (without the
+//! length prefix), so a capture can be replayed against any store.
+
+use crate::frame::peek_ref_id;
+use crate::protocol::RefId;
+use crate::server::{FrameSink, Server};
+use anyhow::{bail, Context, Result};
+use std::collections::{HashMap, VecDeque};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::{debug, warn};
+
+const MAGIC: &[u8; 8] = b"KSCAPv1\n";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Inbound,
+    Outbound,
+}
+
+impl Direction {
+    fn to_byte(self) -> u8 {
+        match self {
+            Direction::Inbound => 0,
+            Direction::Outbound => 1,
+        }
+    }
+
+    fn from_byte(b: u8) -> Result<Self> {
+        match b {
+            0 => Ok(Direction::Inbound),
+            1 => Ok(Direction::Outbound),
+            other => bail!("invalid capture direction byte {other}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Record {
+    pub direction: Direction,
+    pub timestamp_us: u64,
+    pub ref_id: RefId,
+    pub payload: Vec<u8>,
+}
+
+// ── Writing ───────────────────────────────────────────────────────────
+
+/// Appends frames to a capture file.  Shared by every connection of a
+/// socket transport, whose frames interleave in the capture.
+pub struct Recorder {
+    out: Mutex<BufWriter<File>>,
+}
+
+impl Recorder {
+    /// Open `path` for appending, writing the magic if the file is new.
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("opening capture file {}", path.display()))?;
+        let fresh = file.metadata()?.len() == 0;
+        let mut out = BufWriter::new(file);
+        if fresh {
+            out.write_all(MAGIC)?;
+            out.flush()?;
+        }
+        Ok(Self {
+            out: Mutex::new(out),
+        })
+    }
+
+    /// Append one frame.  Flushed immediately so a crash loses nothing.
+    pub fn record(&self, direction: Direction, payload: &[u8]) -> Result<()> {
+        let ref_id = peek_ref_id(payload).unwrap_or(0);
+        let mut out = self.out.lock().unwrap_or_else(|e| e.into_inner());
+        write_record(&mut *out, direction, now_us(), ref_id, payload)?;
+        out.flush()?;
+        Ok(())
+    }
+}
+
+fn write_record(
+    w: &mut impl Write,
+    direction: Direction,
+    timestamp_us: u64,
+    ref_id: RefId,
+    payload: &[u8],
+) -> Result<()> {
+    w.write_all(&[direction.to_byte()])?;
+    w.write_all(&timestamp_us.to_be_bytes())?;
+    w.write_all(&ref_id.to_be_bytes())?;
+    w.write_all(&(payload.len() as u32).to_be_bytes())?;
+    w.write_all(payload)?;
+    Ok(())
+}
+
+fn now_us() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_micros() as u64)
+        .unwrap_or(0)
+}
+
+// ── Reading ───────────────────────────────────────────────────────────
+
+/// Read every record from a capture file.
+pub fn read_capture(path: &Path) -> Result<Vec<Record>> {
+    let file = File::open(path)
+        .with_context(|| format!("opening capture file {}", path.display()))?;
+    read_records(&mut BufReader::new(file))
+}
+
+fn read_records(r: &mut impl Read) -> Result<Vec<Record>> {
+    let mut magic = [0u8; 8];
+    r.read_exact(&mut magic).context("reading capture header")?;
+    if &magic != MAGIC {
+        bail!("not a keyring-store capture file");
+    }
+
+    let mut records = Vec::new();
+    loop {
+        let mut dir = [0u8; 1];
+        match r.read_exact(&mut dir) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e.into()),
+        }
+        let mut head = [0u8; 20];
+        r.read_exact(&mut head).context("truncated capture record")?;
+        let timestamp_us = u64::from_be_bytes(head[0..8].try_into().unwrap());
+        let ref_id = u64::from_be_bytes(head[8..16].try_into().unwrap());
+        let len = u32::from_be_bytes(head[16..20].try_into().unwrap()) as usize;
+        let mut payload = vec![0u8; len];
+        r.read_exact(&mut payload).context("truncated capture record")?;
+        records.push(Record {
+            direction: Direction::from_byte(dir[0])?,
+            timestamp_us,
+            ref_id,
+            payload,
+        });
+    }
+    Ok(records)
+}
+
+// ── Replay ────────────────────────────────────────────────────────────
+
+#[derive(Debug, Default)]
+pub struct ReplaySummary {
+    /// Inbound frames fed to the store.
+    pub requests: usize,
+    /// Requests whose recorded responses matched the replayed ones.
+    pub matched: usize,
+    /// Requests whose responses differed from the recording.
+    pub mismatched: Vec<RefId>,
+    /// Requests with no recorded response to compare against.
+    pub unrecorded: usize,
+}
+
+/// Feed every inbound frame in a capture to `server`, comparing the
+/// produced frames against the recorded outbound ones.
+pub fn replay(server: &Server, records: &[Record]) -> Result<ReplaySummary> {
+    let mut recorded: HashMap<RefId, VecDeque<&[u8]>> = HashMap::new();
+    for rec in records.iter().filter(|r| r.direction == Direction::Outbound) {
+        recorded.entry(rec.ref_id).or_default().push_back(&rec.payload);
+    }
+
+    let mut summary = ReplaySummary::default();
+    for rec in records.iter().filter(|r| r.direction == Direction::Inbound) {
+        summary.requests += 1;
+        debug!(ref_id = rec.ref_id, recorded_at_us = rec.timestamp_us, "replaying request");
+
+        let mut produced: Vec<Vec<u8>> = Vec::new();
+        server.handle_frame(&rec.payload, &mut produced)
+            .with_context(|| format!("replaying ref_id {}", rec.ref_id))?;
+
+        let Some(queue) = recorded.get_mut(&rec.ref_id) else {
+            summary.unrecorded += 1;
+            continue;
+        };
+        let expected: Vec<&[u8]> = queue.drain(..produced.len().min(queue.len())).collect();
+        if expected.len() == produced.len()
+            && expected.iter().zip(&produced).all(|(a, b)| *a == b.as_slice())
+        {
+            summary.matched += 1;
+        } else {
+            warn!(ref_id = rec.ref_id, "replayed response differs from capture");
+            summary.mismatched.push(rec.ref_id);
+        }
+    }
+    Ok(summary)
+}
+
+impl FrameSink for Vec<Vec<u8>> {
+    fn send_frame(&mut self, payload: &[u8]) -> Result<()> {
+        self.push(payload.to_vec());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_roundtrip() {
+        let mut buf = MAGIC.to_vec();
+        write_record(&mut buf, Direction::Inbound, 10, 7, b"req").unwrap();
+        write_record(&mut buf, Direction::Outbound, 11, 7, b"resp").unwrap();
+
+        let records = read_records(&mut buf.as_slice()).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].direction, Direction::Inbound);
+        assert_eq!(records[0].ref_id, 7);
+        assert_eq!(records[0].payload, b"req");
+        assert_eq!(records[1].direction, Direction::Outbound);
+        assert_eq!(records[1].timestamp_us, 11);
+        assert_eq!(records[1].payload, b"resp");
+    }
+
+    #[test]
+    fn test_rejects_bad_magic() {
+        let buf = b"notacapture".to_vec();
+        assert!(read_records(&mut buf.as_slice()).is_err());
+    }
+}
@@ -0,0 +1,220 @@
+//! Decoding frames for people.
+//!
+//! Takes a frame payload as hex or base64 text, with or without its length
+//! prefix, and turns it back into the `Envelope` or `(RefId, Response)` it
+//! encodes, shown as JSON.  Byte fields (hashes, CRDT states, blob data)
+//! become hex strings, cut short unless asked for in full.
+
+use crate::frame::peek_ref_id;
+use crate::protocol::{Envelope, RefId, Response};
+use crate::store::{from_hex, to_hex};
+use anyhow::{anyhow, bail, Result};
+use bincode::Options;
+use serde_json::Value;
+use std::str::FromStr;
+
+/// Bytes of a byte field shown before it is cut short.
+const PREVIEW_BYTES: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Request,
+    Response,
+}
+
+impl FromStr for Kind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "request" => Ok(Kind::Request),
+            "response" => Ok(Kind::Response),
+            other => bail!("unknown frame kind {other:?} (request, response)"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Decoded {
+    Request(Envelope),
+    Response(RefId, Response),
+}
+
+impl Decoded {
+    pub fn ref_id(&self) -> RefId {
+        match self {
+            Decoded::Request(envelope) => envelope.ref_id,
+            Decoded::Response(ref_id, _) => *ref_id,
+        }
+    }
+
+    /// Pretty JSON, with byte fields as hex: in full, or cut short after
+    /// `PREVIEW_BYTES`.
+    pub fn to_json(&self, full: bool) -> Result<String> {
+        let mut value = match self {
+            Decoded::Request(envelope) => serde_json::to_value(envelope)?,
+            Decoded::Response(_, response) => serde_json::to_value(response)?,
+        };
+        bytes_to_hex(&mut value, full);
+        Ok(serde_json::to_string_pretty(&value)?)
+    }
+}
+
+/// Frame bytes from hex (optionally `0x`-prefixed) or base64 text.
+/// Whitespace is ignored, so hex dumps can be pasted as they are.
+pub fn parse_text(text: &str) -> Result<Vec<u8>> {
+    let text: String = text.chars().filter(|c| !c.is_whitespace()).collect();
+    let text = text.strip_prefix("0x").unwrap_or(&text);
+    if text.is_empty() {
+        bail!("no frame given");
+    }
+    if text.len().is_multiple_of(2) && text.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return from_hex(text);
+    }
+    base64(text)
+}
+
+/// The payload, without the 4-byte big-endian length prefix if `frame`
+/// starts with one that matches its length.
+pub fn strip_length_prefix(frame: &[u8]) -> &[u8] {
+    match frame.get(..4) {
+        Some(len) if u32::from_be_bytes(len.try_into().unwrap()) as usize == frame.len() - 4 => {
+            &frame[4..]
+        }
+        _ => frame,
+    }
+}
+
+/// Decode a payload as `kind`, or, if `None`, as whichever of the two it
+/// decodes as exactly, trying a request first.
+pub fn decode(payload: &[u8], kind: Option<Kind>) -> Result<Decoded> {
+    // As `bincode::deserialize`, but a payload with bytes left over is
+    // not taken for a match.
+    let options = bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .reject_trailing_bytes();
+    let request = || options.deserialize::<Envelope>(payload).map(Decoded::Request);
+    let response = || {
+        options
+            .deserialize::<(RefId, Response)>(payload)
+            .map(|(ref_id, response)| Decoded::Response(ref_id, response))
+    };
+    let ref_id = || match peek_ref_id(payload) {
+        Some(ref_id) => format!("ref_id {ref_id}"),
+        None => "too short for a ref_id".to_string(),
+    };
+    match kind {
+        Some(Kind::Request) => {
+            request().map_err(|e| anyhow!("not a request ({}): {e}", ref_id()))
+        }
+        Some(Kind::Response) => {
+            response().map_err(|e| anyhow!("not a response ({}): {e}", ref_id()))
+        }
+        None => request().or_else(|request_err| {
+            response().map_err(|response_err| {
+                anyhow!(
+                    "neither a request nor a response ({}): {request_err}; {response_err}",
+                    ref_id()
+                )
+            })
+        }),
+    }
+}
+
+/// Replace every non-empty array of bytes with a hex string.  The protocol
+/// has no other integer arrays, so nothing else is caught.
+fn bytes_to_hex(value: &mut Value, full: bool) {
+    match value {
+        Value::Array(items) => {
+            let bytes: Option<Vec<u8>> = items
+                .iter()
+                .map(|item| item.as_u64().and_then(|n| u8::try_from(n).ok()))
+                .collect();
+            match bytes {
+                Some(bytes) if !bytes.is_empty() => *value = Value::String(hex(&bytes, full)),
+                _ => items.iter_mut().for_each(|item| bytes_to_hex(item, full)),
+            }
+        }
+        Value::Object(fields) => fields.values_mut().for_each(|field| bytes_to_hex(field, full)),
+        _ => {}
+    }
+}
+
+fn hex(bytes: &[u8], full: bool) -> String {
+    if full || bytes.len() <= PREVIEW_BYTES {
+        return format!("0x{}", to_hex(bytes));
+    }
+    format!("0x{}… ({} bytes)", to_hex(&bytes[..PREVIEW_BYTES]), bytes.len())
+}
+
+/// Standard or URL-safe base64, padding optional.
+fn base64(text: &str) -> Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(text.len() * 3 / 4);
+    let (mut acc, mut bits) = (0u32, 0);
+    for (i, c) in text.trim_end_matches('=').bytes().enumerate() {
+        let sextet = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' | b'-' => 62,
+            b'/' | b'_' => 63,
+            _ => bail!("frame is neither hex nor base64 (byte {:?} at {i})", c as char),
+        };
+        acc = (acc << 6) | u32::from(sextet);
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+        }
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::Request;
+
+    #[test]
+    fn test_parse_text() {
+        assert_eq!(parse_text("0x00ff 10").unwrap(), vec![0x00, 0xff, 0x10]);
+        assert_eq!(parse_text("aGVsbG8=").unwrap(), b"hello");
+        assert_eq!(parse_text("aGVsbG8").unwrap(), b"hello");
+        assert_eq!(parse_text("-_8").unwrap(), vec![0xfb, 0xff]);
+        assert!(parse_text("zz!").is_err());
+        assert!(parse_text("  ").is_err());
+    }
+
+    #[test]
+    fn test_strip_length_prefix() {
+        assert_eq!(strip_length_prefix(&[0, 0, 0, 2, 7, 8]), &[7, 8]);
+        assert_eq!(strip_length_prefix(&[0, 0, 0, 9, 7, 8]), &[0, 0, 0, 9, 7, 8]);
+        assert_eq!(strip_length_prefix(&[1, 2]), &[1, 2]);
+    }
+
+    #[test]
+    fn test_decode() {
+        let envelope = Envelope {
+            ref_id: 7,
+            trace_id: None,
+            namespace: "team".to_string(),
+            tenant: None,
+            durability: None,
+            request: Request::HasBlob { hash: vec![0xab; 32] },
+        };
+        let payload = bincode::serialize(&envelope).unwrap();
+        let decoded = decode(&payload, None).unwrap();
+        assert!(matches!(decoded, Decoded::Request(_)));
+        assert_eq!(decoded.ref_id(), 7);
+        assert!(decoded.to_json(false).unwrap().contains(&format!("\"0x{}\"", "ab".repeat(32))));
+        assert!(decode(&payload, Some(Kind::Response)).is_err());
+
+        let payload = bincode::serialize(&(9u64, Response::Blob { data: vec![1; 100] })).unwrap();
+        let decoded = decode(&payload, None).unwrap();
+        assert!(matches!(decoded, Decoded::Response(9, _)));
+        assert!(decoded.to_json(false).unwrap().contains("… (100 bytes)"));
+        assert!(!decoded.to_json(true).unwrap().contains('…'));
+
+        assert!(decode(&[1, 2, 3], None).unwrap_err().to_string().contains("too short"));
+    }
+}
@@ -0,0 +1,168 @@
+//! Binary deltas between CRDT states, for sync.
+//!
+//! A delta rebuilds a target from a base the receiver already has: the
+//! target's length as an 8-byte LE integer, then a run of operations,
+//! each `0 [8-byte LE offset][8-byte LE len]` to copy bytes of the base or
+//! `1 [8-byte LE len][bytes]` to insert new ones.  `encode` finds copies
+//! the rsync way: it indexes the base's aligned `BLOCK`-byte blocks, looks
+//! up every block-sized window of the target, and grows each hit in both
+//! directions.  It knows nothing about the CRDT format, so it suits any
+//! state that mostly keeps its bytes from one version to the next.
+
+use anyhow::{bail, Context, Result};
+use std::collections::HashMap;
+
+/// Bytes of the base indexed per entry; matches shorter than this are
+/// only found by growing a longer one.
+const BLOCK: usize = 32;
+
+const COPY: u8 = 0;
+const INSERT: u8 = 1;
+
+/// A delta from `base` to `target`.
+pub fn encode(base: &[u8], target: &[u8]) -> Vec<u8> {
+    let mut blocks: HashMap<&[u8], usize> = HashMap::new();
+    for offset in (0..base.len().saturating_sub(BLOCK - 1)).step_by(BLOCK) {
+        blocks.entry(&base[offset..offset + BLOCK]).or_insert(offset);
+    }
+
+    let mut out = Vec::new();
+    out.extend_from_slice(&(target.len() as u64).to_le_bytes());
+    let mut pending = 0; // start of the bytes not yet covered
+    let mut i = 0;
+    while i + BLOCK <= target.len() {
+        let Some(&offset) = blocks.get(&target[i..i + BLOCK]) else {
+            i += 1;
+            continue;
+        };
+        let (mut start, mut base_start) = (i, offset);
+        while start > pending && base_start > 0 && target[start - 1] == base[base_start - 1] {
+            start -= 1;
+            base_start -= 1;
+        }
+        let (mut end, mut base_end) = (i + BLOCK, offset + BLOCK);
+        while end < target.len() && base_end < base.len() && target[end] == base[base_end] {
+            end += 1;
+            base_end += 1;
+        }
+        push_insert(&mut out, &target[pending..start]);
+        out.push(COPY);
+        out.extend_from_slice(&(base_start as u64).to_le_bytes());
+        out.extend_from_slice(&((end - start) as u64).to_le_bytes());
+        pending = end;
+        i = end;
+    }
+    push_insert(&mut out, &target[pending..]);
+    out
+}
+
+fn push_insert(out: &mut Vec<u8>, bytes: &[u8]) {
+    if bytes.is_empty() {
+        return;
+    }
+    out.push(INSERT);
+    out.extend_from_slice(&(bytes.len() as u64).to_le_bytes());
+    out.extend_from_slice(bytes);
+}
+
+/// Rebuild the target of `delta` from `base`.
+pub fn apply(base: &[u8], delta: &[u8]) -> Result<Vec<u8>> {
+    let mut rest = delta;
+    let len = take_u64(&mut rest)?;
+    let mut out = Vec::new();
+    while let Some((&op, tail)) = rest.split_first() {
+        rest = tail;
+        match op {
+            COPY => {
+                let offset = take_u64(&mut rest)?;
+                let count = take_u64(&mut rest)?;
+                let bytes = offset
+                    .checked_add(count)
+                    .and_then(|end| base.get(offset as usize..end as usize))
+                    .context("delta copies past the end of its base")?;
+                out.extend_from_slice(bytes);
+            }
+            INSERT => {
+                let count = take_u64(&mut rest)? as usize;
+                if rest.len() < count {
+                    bail!("truncated delta");
+                }
+                let (bytes, tail) = rest.split_at(count);
+                out.extend_from_slice(bytes);
+                rest = tail;
+            }
+            _ => bail!("unknown delta operation {op}"),
+        }
+        if out.len() as u64 > len {
+            bail!("delta is longer than its stated {len} bytes");
+        }
+    }
+    if out.len() as u64 != len {
+        bail!("delta produced {} bytes instead of {len}", out.len());
+    }
+    Ok(out)
+}
+
+fn take_u64(rest: &mut &[u8]) -> Result<u64> {
+    if rest.len() < 8 {
+        bail!("truncated delta");
+    }
+    let (bytes, tail) = rest.split_at(8);
+    *rest = tail;
+    Ok(u64::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(seed: u64, len: usize) -> Vec<u8> {
+        let mut x = seed;
+        (0..len)
+            .map(|_| {
+                x = x.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+                (x >> 56) as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let base = text(1, 10_000);
+        let mut edited = base.clone();
+        edited.splice(5_000..5_010, b"replacement".iter().copied());
+        edited.extend_from_slice(b"appended");
+        edited.drain(..100);
+
+        for (base, target) in [
+            (&base, &edited),
+            (&edited, &base),
+            (&base, &Vec::new()),
+            (&Vec::new(), &base),
+            (&base, &text(2, 300)),
+        ] {
+            let delta = encode(base, target);
+            assert_eq!(&apply(base, &delta).unwrap(), target);
+        }
+    }
+
+    #[test]
+    fn test_small_edit_gives_small_delta() {
+        let base = text(3, 100_000);
+        let mut edited = base.clone();
+        edited[50_000] ^= 0xff;
+        assert!(encode(&base, &edited).len() < 200);
+    }
+
+    #[test]
+    fn test_malformed_delta() {
+        let base = text(4, 64);
+        let delta = encode(&base, &base);
+        assert!(apply(&base[..10], &delta).is_err());
+        assert!(apply(&base, &delta[..delta.len() - 1]).is_err());
+        assert!(apply(&base, &[0; 4]).is_err());
+        let mut wrong_len = delta.clone();
+        wrong_len[0] += 1;
+        assert!(apply(&base, &wrong_len).is_err());
+    }
+}
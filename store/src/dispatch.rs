@@ -0,0 +1,1772 @@
+//! Request dispatch: maps each protocol request onto store operations.
+
+use crate::delta;
+use crate::merkle;
+use crate::protocol::{
+    AccessEntry, AttachmentInfo, BlobInfo, BucketSummary, Change, ChangeHead, ChangelogEntry,
+    ConflictInfo, CountTarget, DocumentData, DocumentEntry, ErrorCode, HashBloom, HashedBlob,
+    IntegrityProblem, PeerInfo, Request, Response, Root, SiblingInfo, SignedRootInfo, SubRoot,
+    TableStats, VersionInfo, MAX_PAGE_LIMIT,
+};
+use crate::server::Reply;
+use crate::store::{
+    deletion_hash, valid_bucket_prefix, valid_iblt, valid_iblt_size, validate_namespace,
+    ApplyBatch, ArchiveReport, ImportPolicy, Incoming, Key, Problem, Resolution, RootRejected,
+    SignedRoot, Store, SyncProgress, WriteOp, WriteOutcome, ANCESTRY_DEPTH, BUCKET_DEPTH,
+    BUCKET_FANOUT, MAX_IBLT_CELLS,
+};
+use crate::throttle::Throttles;
+use anyhow::{bail, Context, Result};
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::time::Instant;
+use tracing::debug;
+
+pub fn handle_request(store: &Store, req: Request) -> Response {
+    match req {
+        Request::PutBlob { data, ttl_secs } => match store.put_blob(&data, ttl_secs) {
+            Ok(hash) => Response::BlobStored { hash },
+            Err(e) => e.into(),
+        },
+
+        Request::PutBlobs { blobs } => match store.put_blobs(&blobs) {
+            Ok(hashes) => Response::BlobsStored { hashes },
+            Err(e) => e.into(),
+        },
+
+        Request::GetBlob { hash } => match store.get_blob(&hash) {
+            Ok(Some(data)) => Response::Blob { data },
+            Ok(None) => Response::NotFound,
+            Err(e) => e.into(),
+        },
+
+        Request::GetBlobs { hashes } => match store.get_blobs(&hashes) {
+            Ok(results) => {
+                let mut found = Vec::new();
+                let mut missing = Vec::new();
+                for (hash, data) in hashes.into_iter().zip(results) {
+                    match data {
+                        Some(data) => found.push(HashedBlob { hash, data }),
+                        None => missing.push(hash),
+                    }
+                }
+                Response::Blobs { found, missing }
+            }
+            Err(e) => e.into(),
+        },
+
+        Request::GetChangelog { from_seq, limit } => {
+            let limit = limit.clamp(1, MAX_PAGE_LIMIT) as usize;
+            match store.changelog(from_seq, limit) {
+                Ok(page) => Response::Changelog {
+                    next_seq: page.last().map_or(from_seq, |e| e.seq + 1),
+                    entries: page
+                        .into_iter()
+                        .map(|e| ChangelogEntry {
+                            seq: e.seq,
+                            doc_id: e.id,
+                            hash: e.hash,
+                            deleted: e.deleted,
+                            at: e.at,
+                        })
+                        .collect(),
+                },
+                Err(e) => e.into(),
+            }
+        }
+
+        Request::ListBlobs {
+            cursor,
+            limit,
+            with_timestamps,
+        } => {
+            let limit = limit.clamp(1, MAX_PAGE_LIMIT) as usize;
+            match store.list_blobs(cursor.as_deref(), limit) {
+                Ok(page) => {
+                    let next_cursor = match page.last() {
+                        Some(last) if page.len() == limit => Some(last.hash.clone()),
+                        _ => None,
+                    };
+                    let blobs = page
+                        .into_iter()
+                        .map(|b| BlobInfo {
+                            hash: b.hash,
+                            size: b.size,
+                            times: b.times.filter(|_| with_timestamps),
+                        })
+                        .collect();
+                    Response::BlobList { blobs, next_cursor }
+                }
+                Err(e) => e.into(),
+            }
+        }
+
+        Request::Gc { dry_run } => match store.gc(dry_run) {
+            Ok(report) => Response::GcReport {
+                blobs_scanned: report.blobs_scanned,
+                unreferenced: report.unreferenced,
+                reclaimable_bytes: report.reclaimable_bytes,
+                swept: report.swept,
+            },
+            Err(e) => e.into(),
+        },
+
+        Request::HasBlob { hash } => match store.has_blob(&hash) {
+            Ok(exists) => Response::BlobExists { exists },
+            Err(e) => e.into(),
+        },
+
+        Request::PutDocument {
+            id,
+            meta,
+            crdt_state,
+            index,
+            create_only,
+        } => {
+            match store.put_document(&id, &meta, &crdt_state, index.as_deref(), create_only) {
+                Ok(Some(version)) => Response::DocumentStored { version },
+                Ok(None) => Response::AlreadyExists,
+                Err(e) => e.into(),
+            }
+        }
+
+        Request::PutDocumentMeta { id, meta } => match store.put_document_meta(&id, &meta) {
+            Ok(Some(version)) => Response::DocumentStored { version },
+            Ok(None) => Response::NotFound,
+            Err(e) => e.into(),
+        },
+
+        Request::GetDocument {
+            id,
+            with_timestamps,
+        } => match store.get_document(&id) {
+            Ok(Some(doc)) => {
+                store.record_read(&id);
+                Response::Document {
+                    id,
+                    meta: doc.meta,
+                    crdt_state: doc.crdt_state,
+                    version: doc.version,
+                    times: doc.times.filter(|_| with_timestamps),
+                }
+            }
+            Ok(None) => Response::NotFound,
+            Err(e) => e.into(),
+        },
+
+        Request::GetDocuments {
+            ids,
+            with_timestamps,
+        } => match store.get_documents(&ids) {
+            Ok(results) => {
+                let mut found = Vec::new();
+                let mut missing = Vec::new();
+                for (id, doc) in ids.into_iter().zip(results) {
+                    match doc {
+                        Some(doc) => {
+                            store.record_read(&id);
+                            found.push(DocumentData {
+                                id,
+                                meta: doc.meta,
+                                crdt_state: doc.crdt_state,
+                                version: doc.version,
+                                times: doc.times.filter(|_| with_timestamps),
+                            });
+                        }
+                        None => missing.push(id),
+                    }
+                }
+                Response::Documents { found, missing }
+            }
+            Err(e) => e.into(),
+        },
+
+        Request::DeleteDocument { id, cascade } => match store.delete_document(&id, cascade) {
+            Ok(true) => Response::Ok,
+            Ok(false) => Response::NotFound,
+            Err(e) => e.into(),
+        },
+
+        Request::AttachBlob { doc_id, hash, name } => {
+            match store.attach_blob(&doc_id, &hash, &name) {
+                Ok(true) => Response::Ok,
+                Ok(false) => Response::NotFound,
+                Err(e) => e.into(),
+            }
+        }
+
+        Request::DetachBlob { doc_id, name } => match store.detach_blob(&doc_id, &name) {
+            Ok(true) => Response::Ok,
+            Ok(false) => Response::NotFound,
+            Err(e) => e.into(),
+        },
+
+        Request::WhoReferences { hash } => match store.who_references(&hash) {
+            Ok(ids) => Response::DocumentList { ids },
+            Err(e) => e.into(),
+        },
+
+        Request::ListAttachments { doc_id } => match store.list_attachments(&doc_id) {
+            Ok(attachments) => Response::Attachments {
+                attachments: attachments
+                    .into_iter()
+                    .map(|a| AttachmentInfo {
+                        name: a.name,
+                        hash: a.hash,
+                        size: a.size,
+                    })
+                    .collect(),
+            },
+            Err(e) => e.into(),
+        },
+
+        Request::ListDocumentsRange { start, end, limit } => {
+            let limit = limit.clamp(1, MAX_PAGE_LIMIT) as usize;
+            match store.list_documents_range(&start, end.as_deref(), limit) {
+                Ok((ids, next_start)) => Response::DocumentRange { ids, next_start },
+                Err(e) => e.into(),
+            }
+        }
+
+        Request::GetDocumentHistory { id } => match store.document_history(&id) {
+            Ok(versions) => Response::DocumentHistory {
+                versions: versions
+                    .into_iter()
+                    .map(|v| VersionInfo {
+                        hash: v.hash,
+                        saved_at: v.saved_at,
+                        size: v.size,
+                    })
+                    .collect(),
+            },
+            Err(e) => e.into(),
+        },
+
+        Request::GetCombinedRoot { doc_ids } => {
+            let root = if doc_ids.is_empty() {
+                store.combined_root()
+            } else {
+                store.get_doc_hashes(&doc_ids).map(|pairs| merkle::compute_root(&pairs))
+            };
+            match root {
+                Ok(root) => Response::CombinedRoot { root },
+                Err(e) => e.into(),
+            }
+        }
+
+        Request::GetSubRoots { depth } => {
+            let depth = depth as usize;
+            if depth > merkle::MAX_SUB_ROOT_DEPTH {
+                return Response::error(
+                    ErrorCode::BadRequest,
+                    format!("depth must be at most {}", merkle::MAX_SUB_ROOT_DEPTH),
+                );
+            }
+            match store.all_doc_hashes() {
+                Ok(pairs) => Response::SubRoots {
+                    roots: merkle::sub_roots(&pairs, depth)
+                        .into_iter()
+                        .map(|(prefix, count, root)| SubRoot {
+                            prefix,
+                            count,
+                            root,
+                        })
+                        .collect(),
+                },
+                Err(e) => e.into(),
+            }
+        }
+
+        Request::DiffRoots { remote_roots } => match store.all_doc_hashes() {
+            Ok(local) => {
+                let remote: Vec<(String, Vec<u8>)> =
+                    remote_roots.into_iter().map(|root| (root.doc_id, root.hash)).collect();
+                let (to_send, to_request) = merkle::diff_roots(&local, &remote);
+                Response::SyncDiff {
+                    to_send: to_send.into_iter().map(String::into_bytes).collect(),
+                    to_request: to_request.into_iter().map(String::into_bytes).collect(),
+                }
+            }
+            Err(e) => e.into(),
+        },
+
+        Request::GetMissingBlobs { hashes } => match store.missing_blobs(&hashes) {
+            Ok(hashes) => Response::MissingBlobs { hashes },
+            Err(e) => e.into(),
+        },
+
+        Request::GetSignedRoot => match store.signed_root() {
+            Ok(Some(signed)) => Response::SignedRoot {
+                signed: signed_root_info(signed),
+            },
+            Ok(None) => Response::error(ErrorCode::BadRequest, "the store has no signing key"),
+            Err(e) => e.into(),
+        },
+
+        Request::AddPeer {
+            peer_id,
+            public_key,
+            namespaces,
+        } => {
+            if peer_id.is_empty() {
+                return Response::error(ErrorCode::BadRequest, "peer id is empty");
+            }
+            if !public_key.is_empty() && public_key.len() != 32 {
+                return Response::error(
+                    ErrorCode::BadRequest,
+                    "public key must be empty or 32 bytes of Ed25519",
+                );
+            }
+            if let Some(e) = namespaces.iter().find_map(|n| validate_namespace(n).err()) {
+                return Response::error(ErrorCode::BadRequest, format!("{e:#}"));
+            }
+            match store.add_peer(&peer_id, &public_key, &namespaces) {
+                Ok(()) => Response::Ok,
+                Err(e) => e.into(),
+            }
+        }
+
+        Request::RemovePeer { peer_id } => match store.remove_peer(&peer_id) {
+            Ok(true) => Response::Ok,
+            Ok(false) => Response::NotFound,
+            Err(e) => e.into(),
+        },
+
+        Request::ListPeers => match store.peers() {
+            Ok(peers) => Response::Peers {
+                peers: peers
+                    .into_iter()
+                    .map(|peer| PeerInfo {
+                        peer_id: peer.peer_id,
+                        public_key: peer.public_key,
+                        namespaces: peer.namespaces,
+                        last_seen: peer.last_seen,
+                    })
+                    .collect(),
+            },
+            Err(e) => e.into(),
+        },
+
+        Request::PushBlobs { blobs, peer_id } => {
+            if let Err(e) = store.check_peer(peer_id.as_deref()) {
+                return e.into();
+            }
+            if let Some(bad) = blobs
+                .iter()
+                .position(|blob| blake3::hash(&blob.data).as_bytes().as_slice() != blob.hash)
+            {
+                return Response::error(
+                    ErrorCode::BadRequest,
+                    format!("blob {bad} does not match its hash"),
+                );
+            }
+            let data: Vec<Vec<u8>> = blobs.into_iter().map(|blob| blob.data).collect();
+            match store.put_blobs(&data) {
+                Ok(hashes) => Response::BlobsStored { hashes },
+                Err(e) => e.into(),
+            }
+        }
+
+        Request::GetChangeHeads {
+            known_roots,
+            doc_ids,
+            prefix,
+        } => match change_heads(store, known_roots, doc_ids, prefix) {
+            Ok(heads) => Response::ChangeHeads { heads },
+            Err(e) => e.into(),
+        },
+
+        Request::GetSiblings { id } => match store.document_siblings(&id) {
+            Ok(siblings) => Response::Siblings {
+                siblings: siblings
+                    .into_iter()
+                    .map(|s| SiblingInfo {
+                        hash: s.hash,
+                        saved_at: s.saved_at,
+                        crdt_state: s.crdt_state,
+                    })
+                    .collect(),
+            },
+            Err(e) => e.into(),
+        },
+
+        Request::GetDocumentVersion { id, hash } => match store.document_version(&id, &hash) {
+            Ok(Some(crdt_state)) => Response::DocumentVersion { crdt_state },
+            Ok(None) => Response::NotFound,
+            Err(e) => e.into(),
+        },
+
+        Request::QueryDocuments { key, value } => match store.query_documents(&key, &value) {
+            Ok(ids) => Response::DocumentList { ids },
+            Err(e) => e.into(),
+        },
+
+        Request::FilterDocuments { prefix, filter } => {
+            match store.filter_documents(&prefix, &filter) {
+                Ok(ids) => Response::DocumentList { ids },
+                Err(e) => e.into(),
+            }
+        }
+
+        Request::Search { query, limit } => {
+            let limit = limit.clamp(1, MAX_PAGE_LIMIT) as usize;
+            match store.search(&query, limit) {
+                Ok(ids) => Response::DocumentList { ids },
+                Err(e) => e.into(),
+            }
+        }
+
+        Request::HotDocuments {
+            limit,
+            coldest_first,
+        } => {
+            if !store.tracks_access() {
+                return Response::error(
+                    ErrorCode::BadRequest,
+                    "access tracking is off (start with --track-access)",
+                );
+            }
+            let limit = limit.clamp(1, MAX_PAGE_LIMIT) as usize;
+            match store.hot_documents(limit, coldest_first) {
+                Ok(stats) => Response::AccessStats {
+                    entries: stats
+                        .into_iter()
+                        .map(|s| AccessEntry {
+                            id: s.id,
+                            reads: s.reads,
+                            last_read: s.last_read,
+                        })
+                        .collect(),
+                },
+                Err(e) => e.into(),
+            }
+        }
+
+        Request::Count { what, prefix } => {
+            let count = match what {
+                CountTarget::Blobs => store.count_blobs(&prefix),
+                CountTarget::Documents => match std::str::from_utf8(&prefix) {
+                    Ok(prefix) => store.count_documents(prefix),
+                    Err(_) => {
+                        return Response::error(
+                            ErrorCode::BadRequest,
+                            "document id prefix is not valid UTF-8",
+                        )
+                    }
+                },
+            };
+            match count {
+                Ok(count) => Response::Count { count },
+                Err(e) => e.into(),
+            }
+        }
+
+        Request::Stats => match store.stats() {
+            Ok(stats) => Response::Stats {
+                file_bytes: stats.file_bytes,
+                page_size: stats.page_size,
+                allocated_pages: stats.allocated_pages,
+                free_pages: stats.free_pages,
+                fragmented_bytes: stats.fragmented_bytes,
+                stored_bytes: stats.stored_bytes,
+                metadata_bytes: stats.metadata_bytes,
+                tables: stats
+                    .tables
+                    .into_iter()
+                    .map(|t| TableStats {
+                        name: t.name,
+                        entries: t.entries,
+                        stored_bytes: t.stored_bytes,
+                        tree_height: t.tree_height,
+                    })
+                    .collect(),
+                blob_bytes: stats.blob_bytes,
+                last_compaction: stats.last_compaction,
+                db_cache_bytes: stats.db_cache_bytes,
+                // Filled in by the server, which holds the budgets.
+                throttles: Vec::new(),
+            },
+            Err(e) => e.into(),
+        },
+
+        Request::DedupStats => match store.dedup_stats() {
+            Ok(stats) => Response::DedupStats {
+                puts: stats.puts,
+                hits: stats.hits,
+                logical_bytes: stats.logical_bytes,
+                deduplicated_bytes: stats.deduplicated_bytes,
+                stored_bytes: stats.logical_bytes.saturating_sub(stats.deduplicated_bytes),
+            },
+            Err(e) => e.into(),
+        },
+
+        Request::Compact => match store.compact() {
+            Ok((bytes_before, bytes_after)) => Response::Compacted {
+                bytes_before,
+                bytes_after,
+            },
+            Err(e) => e.into(),
+        },
+
+        Request::Backup { dest_path } => match store.backup(Path::new(&dest_path)) {
+            Ok(report) => Response::BackedUp {
+                file_bytes: report.file_bytes,
+                spilled_blobs: report.spilled_blobs,
+            },
+            Err(e) => e.into(),
+        },
+
+        Request::Import {
+            archive_path,
+            policy,
+        } => match import_archive(store, Path::new(&archive_path), policy) {
+            Ok(report) => Response::Imported {
+                documents: report.documents,
+                tombstones: report.tombstones,
+                blobs: report.blobs,
+                skipped: report.skipped,
+            },
+            Err(e) => e.into(),
+        },
+
+        Request::Verify { deep } => match store.verify(deep) {
+            Ok(report) => Response::Verified {
+                documents: report.documents,
+                blobs: report.blobs,
+                problems: integrity_problems(report.problems.listed),
+                problem_count: report.problems.count,
+            },
+            Err(e) => e.into(),
+        },
+
+        Request::Repair {
+            drop_documents_without_data,
+        } => match store.repair(drop_documents_without_data) {
+            Ok(fixes) => Response::Repaired {
+                fixes: integrity_problems(fixes.listed),
+                fix_count: fixes.count,
+            },
+            Err(e) => e.into(),
+        },
+
+        Request::ProvideKey { key } => {
+            match Key::from_bytes(&key.0).and_then(|key| store.provide_key(key)) {
+                Ok(()) => Response::Ok,
+                Err(e) => Response::error(ErrorCode::BadRequest, format!("{e:#}")),
+            }
+        }
+
+        Request::RotateKey { new_key } => {
+            match Key::from_bytes(&new_key.0).and_then(|key| store.rotate_key(key)) {
+                Ok(()) => Response::Ok,
+                Err(e) => Response::error(ErrorCode::BadRequest, format!("{e:#}")),
+            }
+        }
+
+        Request::ListDocuments {
+            prefix,
+            with_timestamps: false,
+        } => match store.list_documents(&prefix) {
+            Ok(ids) => Response::DocumentList { ids },
+            Err(e) => e.into(),
+        },
+
+        Request::ListDocuments {
+            prefix,
+            with_timestamps: true,
+        } => match store.list_documents_with_times(&prefix) {
+            Ok(entries) => Response::DocumentEntries {
+                entries: entries
+                    .into_iter()
+                    .map(|(id, times)| DocumentEntry { id, times })
+                    .collect(),
+            },
+            Err(e) => e.into(),
+        },
+
+        Request::GetRoots { doc_ids } => {
+            let hashes = if doc_ids.is_empty() {
+                store.all_doc_hashes()
+            } else {
+                store.get_doc_hashes(&doc_ids)
+            };
+            match hashes {
+                Ok(pairs) => {
+                    let roots = pairs
+                        .into_iter()
+                        .map(|(doc_id, hash)| Root { doc_id, hash })
+                        .collect();
+                    Response::Roots { roots }
+                }
+                Err(e) => e.into(),
+            }
+        }
+
+        Request::GetBucket { prefix } => {
+            if !valid_bucket_prefix(&prefix) {
+                return Response::error(
+                    ErrorCode::BadRequest,
+                    format!(
+                        "bucket prefix takes at most {BUCKET_DEPTH} nibbles below \
+                         {BUCKET_FANOUT}, one per byte"
+                    ),
+                );
+            }
+            match store.get_bucket(&prefix) {
+                Ok(bucket) => Response::Bucket {
+                    count: bucket.count,
+                    digest: bucket.digest,
+                    children: bucket
+                        .children
+                        .into_iter()
+                        .map(|(count, digest)| BucketSummary { count, digest })
+                        .collect(),
+                    roots: bucket
+                        .roots
+                        .into_iter()
+                        .map(|(doc_id, hash)| Root { doc_id, hash })
+                        .collect(),
+                },
+                Err(e) => e.into(),
+            }
+        }
+
+        Request::GetIblt { cells } => {
+            if !valid_iblt_size(cells) {
+                return Response::error(
+                    ErrorCode::BadRequest,
+                    format!("an IBLT has between 3 and {MAX_IBLT_CELLS} cells"),
+                );
+            }
+            match store.iblt(cells) {
+                Ok(cells) => Response::Iblt { cells },
+                Err(e) => e.into(),
+            }
+        }
+
+        Request::ReconcileIblt { cells } => {
+            if !valid_iblt(&cells) {
+                return Response::error(
+                    ErrorCode::BadRequest,
+                    format!(
+                        "an IBLT has between 3 and {MAX_IBLT_CELLS} cells with 32-byte key sums"
+                    ),
+                );
+            }
+            match store.reconcile_iblt(&cells) {
+                Ok(diff) => Response::IbltDiff {
+                    local_only: diff
+                        .local_only
+                        .into_iter()
+                        .map(|(doc_id, hash)| Root { doc_id, hash })
+                        .collect(),
+                    remote_only: diff.remote_only,
+                    complete: diff.complete,
+                },
+                Err(e) => e.into(),
+            }
+        }
+
+        Request::GetSyncCheckpoint { peer_id } => match store.sync_checkpoint(&peer_id) {
+            Ok(Some(checkpoint)) => Response::SyncCheckpoint {
+                seq: checkpoint.seq,
+                root: checkpoint.root,
+                at: checkpoint.at,
+            },
+            Ok(None) => Response::NotFound,
+            Err(e) => e.into(),
+        },
+
+        Request::AckChangesSince { peer_id, head } => {
+            match store.ack_sync_checkpoint(&peer_id, head) {
+                Ok(true) => Response::Ok,
+                Ok(false) => Response::NotFound,
+                Err(e) => e.into(),
+            }
+        }
+
+        Request::ResetSyncCheckpoint { peer_id } => match store.reset_sync_checkpoint(&peer_id) {
+            Ok(true) => Response::Ok,
+            Ok(false) => Response::NotFound,
+            Err(e) => e.into(),
+        },
+
+        Request::GetDocHash { id } => match store.get_doc_hash(&id) {
+            Ok(Some(hash)) => Response::DocHash { hash },
+            Ok(None) => Response::NotFound,
+            Err(e) => e.into(),
+        },
+
+        // Streamed by the server via `stream_changes`; a single-frame
+        // reply would have to hold every missing CRDT state in memory.
+        Request::GetChanges { .. }
+        | Request::GetChangesBloom { .. }
+        | Request::GetChangesSince { .. } => {
+            Response::error(ErrorCode::BadRequest, "GetChanges must be streamed")
+        }
+
+        // Tenant lifecycle belongs to the server, which owns the registry.
+        Request::OpenTenant { .. } | Request::CloseTenant { .. } => Response::error(
+            ErrorCode::BadRequest,
+            "tenant requests must be handled by the server",
+        ),
+
+        // Watches live in the server, which pushes their events.
+        Request::Watch { .. } | Request::Unwatch { .. } => Response::error(
+            ErrorCode::BadRequest,
+            "watch requests must be handled by the server",
+        ),
+
+        // Open transactions are held by the server.
+        Request::BeginTxn
+        | Request::TxnWrite { .. }
+        | Request::Commit { .. }
+        | Request::Abort { .. } => Response::error(
+            ErrorCode::BadRequest,
+            "transaction requests must be handled by the server",
+        ),
+
+        Request::StartSync {
+            known_roots,
+            doc_ids,
+            prefix,
+        } => {
+            let known: HashSet<Vec<u8>> = known_roots.into_iter().collect();
+            let pending: Vec<String> = match scoped_doc_hashes(store, doc_ids, prefix) {
+                Ok(pairs) => pairs
+                    .into_iter()
+                    .filter(|(_, hash)| !known.contains(hash))
+                    .map(|(doc_id, _)| doc_id)
+                    .collect(),
+                Err(e) => return e.into(),
+            };
+            let total = pending.len() as u64;
+            match store.start_sync_session(pending) {
+                Ok(session_id) => Response::SyncStarted { session_id, total },
+                Err(e) => e.into(),
+            }
+        }
+
+        Request::ContinueSync { .. } => Response::error(
+            ErrorCode::BadRequest,
+            "ContinueSync must be handled by the server",
+        ),
+
+        Request::FinishSync { session_id } => match store.finish_sync_session(session_id) {
+            Ok(true) => Response::Ok,
+            Ok(false) => Response::NotFound,
+            Err(e) => e.into(),
+        },
+
+        Request::MaintenanceStatus => Response::error(
+            ErrorCode::BadRequest,
+            "maintenance status must be handled by the server",
+        ),
+
+        Request::ApplyChanges {
+            changes,
+            signed_root,
+            peer_id,
+        } => {
+            let signed_root = signed_root.map(|info| SignedRoot {
+                root: info.root,
+                epoch: info.epoch,
+                public_key: info.public_key,
+                signature: info.signature,
+                changes: info.changes,
+            });
+            if let Err(e) = store.check_peer_changes(peer_id.as_deref(), signed_root.as_ref()) {
+                return e.into();
+            }
+            // Reject a malformed batch before applying any of it.
+            if let Some(bad) = changes
+                .iter()
+                .find(|c| {
+                    c.deleted
+                        && (!c.data.is_empty() || c.hash.len() != 32 || c.base_hash.is_some())
+                })
+            {
+                return Response::error(
+                    ErrorCode::BadRequest,
+                    format!(
+                        "deletion of {:?} must carry a 32-byte hash and no data or base",
+                        bad.doc_id
+                    ),
+                );
+            }
+            if let Some(bad) = changes.iter().find(|c| {
+                c.ancestors.len() > ANCESTRY_DEPTH || c.ancestors.iter().any(|h| h.len() != 32)
+            }) {
+                return Response::error(
+                    ErrorCode::BadRequest,
+                    format!(
+                        "change to {:?} may carry at most {ANCESTRY_DEPTH} 32-byte ancestors",
+                        bad.doc_id
+                    ),
+                );
+            }
+            if let Some(e) = changes.iter().find_map(|c| store.validate_id(&c.doc_id).err()) {
+                return e.into();
+            }
+            let digest = changes_digest(&changes);
+            let mut conflicts = Vec::new();
+            let applied = store.apply_changes(signed_root.as_ref(), &digest, |batch| {
+                let mut applied = 0;
+                for change in changes {
+                    if apply_change(batch, change, &mut conflicts)? {
+                        applied += 1;
+                    }
+                }
+                Ok(applied)
+            });
+            match applied {
+                Ok(applied) => Response::Applied { applied, conflicts },
+                Err(e) if e.is::<RootRejected>() => {
+                    Response::error(ErrorCode::BadRequest, format!("{e:#}"))
+                }
+                Err(e) => e.into(),
+            }
+        }
+    }
+}
+
+/// Apply `change` unless we hold its version or it is stale; returns
+/// whether it altered the document.  A change that diverges from the local
+/// version is noted in `conflicts`.
+fn apply_change(
+    batch: &mut ApplyBatch<'_>,
+    change: Change,
+    conflicts: &mut Vec<ConflictInfo>,
+) -> Result<bool> {
+    // Only apply if we don't already have this exact version.
+    let current = batch.doc_hash(&change.doc_id)?;
+    if current.as_deref() == Some(change.hash.as_slice()) {
+        return Ok(false);
+    }
+    let tombstone = batch.tombstone(&change.doc_id)?;
+    if let (false, Some(tombstone)) = (change.deleted, &tombstone) {
+        // A peer that missed our deletion still offers the version we
+        // deleted; don't let it resurrect the document.
+        if tombstone.deleted_state == change.hash {
+            debug!(
+                doc_id = change.doc_id,
+                deleted_at = tombstone.deleted_at,
+                "ignoring stale change to deleted document"
+            );
+            return Ok(false);
+        }
+    }
+    let mut resolution = Resolution::Merge;
+    let mut conflicted = false;
+    if let Some(local_hash) = current.clone() {
+        let builds_on_local = change.ancestors.contains(&local_hash)
+            || if change.deleted {
+                // Both sides deleting agree, whichever versions they deleted.
+                tombstone.is_some() || deletion_hash(&local_hash) == change.hash
+            } else {
+                change.base_hash.as_deref() == Some(local_hash.as_slice())
+            };
+        if !builds_on_local {
+            // The peer is behind: the local version descends from its own.
+            if batch.ancestry(&change.doc_id)?.contains(&change.hash) {
+                debug!(doc_id = change.doc_id, "ignoring change to an older version");
+                return Ok(false);
+            }
+            conflicts.push(ConflictInfo {
+                doc_id: change.doc_id.clone(),
+                local_hash,
+                local_deleted: tombstone.is_some(),
+                remote_hash: change.hash.clone(),
+                remote_deleted: change.deleted,
+            });
+            let incoming = Incoming {
+                hash: &change.hash,
+                deleted: change.deleted,
+                updated_at: change.updated_at,
+            };
+            resolution = batch.resolve_conflict(&change.doc_id, incoming)?;
+            conflicted = true;
+        }
+    }
+    if resolution == Resolution::KeepLocal {
+        debug!(doc_id = change.doc_id, "conflict policy kept the local version");
+        return Ok(false);
+    }
+    if change.deleted {
+        batch.apply_tombstone(&change.doc_id, &change.hash)?;
+        return Ok(true);
+    }
+    let state = match &change.base_hash {
+        Some(base_hash) => {
+            let base = if current.as_deref() == Some(base_hash.as_slice()) {
+                batch.document(&change.doc_id)?.map(|doc| doc.crdt_state)
+            } else {
+                batch.document_version(&change.doc_id, base_hash)?
+            };
+            let base = base.with_context(|| {
+                format!("no version of {:?} to apply its delta to", change.doc_id)
+            })?;
+            let state = delta::apply(&base, &change.data)
+                .with_context(|| format!("applying the delta to {:?}", change.doc_id))?;
+            if blake3::hash(&state).as_bytes().as_slice() != change.hash.as_slice() {
+                bail!("the delta to {:?} does not produce its hash", change.doc_id);
+            }
+            state
+        }
+        None => {
+            if blake3::hash(&change.data).as_bytes().as_slice() != change.hash.as_slice() {
+                bail!("the state of {:?} does not match its hash", change.doc_id);
+            }
+            change.data
+        }
+    };
+    // The state descends from the change's version too, even if merged.
+    let mut parents = vec![change.hash];
+    parents.extend(change.ancestors);
+    let (meta, state) = match resolution {
+        Resolution::Merge if conflicted => match batch.merge_remote_state(&change.doc_id, &state)? {
+            Some(merged) => merged,
+            None => {
+                batch.add_sibling(&change.doc_id, &state)?;
+                return Ok(false);
+            }
+        },
+        Resolution::KeepBoth => {
+            batch.add_sibling(&change.doc_id, &state)?;
+            return Ok(false);
+        }
+        // A fast-forward, or the policy took the remote version.
+        _ => {
+            let meta = batch.document(&change.doc_id)?.map(|doc| doc.meta);
+            (meta.unwrap_or_default(), state)
+        }
+    };
+    batch.put_applied_document(&change.doc_id, &meta, &state, &parents)?;
+    Ok(true)
+}
+
+/// The digest a batch of changes is signed by: blake3 of its encoding.
+fn changes_digest(changes: &[Change]) -> Vec<u8> {
+    let encoded = bincode::serialize(changes).expect("changes always serialize");
+    blake3::hash(&encoded).as_bytes().to_vec()
+}
+
+/// `changes` signed with the store's key, to send along with them; `None`
+/// without a key.
+fn sign_changes(store: &Store, changes: &[Change]) -> Result<Option<Box<SignedRootInfo>>> {
+    let signed = store.signed_changes(&changes_digest(changes))?;
+    Ok(signed.map(|signed| Box::new(signed_root_info(signed))))
+}
+
+fn signed_root_info(signed: SignedRoot) -> SignedRootInfo {
+    SignedRootInfo {
+        root: signed.root,
+        epoch: signed.epoch,
+        public_key: signed.public_key,
+        signature: signed.signature,
+        changes: signed.changes,
+    }
+}
+
+fn import_archive(store: &Store, path: &Path, policy: ImportPolicy) -> Result<ArchiveReport> {
+    let file = File::open(path).with_context(|| format!("opening {}", path.display()))?;
+    store.import(BufReader::new(file), policy, &[])
+}
+
+fn integrity_problems(problems: Vec<Problem>) -> Vec<IntegrityProblem> {
+    problems
+        .into_iter()
+        .map(|p| IntegrityProblem {
+            table: p.table,
+            key: p.key,
+            message: p.message,
+        })
+        .collect()
+}
+
+/// Whether `request` is a single-item write that can share a transaction
+/// with others (see `Store::write_batch`).  A cascading delete removes spill
+/// files after its commit, so it runs on its own.
+pub fn is_groupable(request: &Request) -> bool {
+    matches!(
+        request,
+        Request::PutBlob { .. }
+            | Request::PutDocument { .. }
+            | Request::DeleteDocument { cascade: false, .. }
+            | Request::AttachBlob { .. }
+            | Request::DetachBlob { .. }
+    )
+}
+
+/// The batchable form of a groupable request; other requests come back
+/// unchanged.
+pub fn write_op(request: Request) -> Result<WriteOp, Request> {
+    Ok(match request {
+        Request::PutBlob { data, ttl_secs } => WriteOp::PutBlob { data, ttl_secs },
+        Request::PutDocument {
+            id,
+            meta,
+            crdt_state,
+            index,
+            create_only,
+        } => WriteOp::PutDocument {
+            id,
+            meta,
+            crdt_state,
+            index,
+            create_only,
+        },
+        Request::DeleteDocument { id, cascade: false } => WriteOp::DeleteDocument { id },
+        Request::AttachBlob { doc_id, hash, name } => WriteOp::AttachBlob { doc_id, hash, name },
+        Request::DetachBlob { doc_id, name } => WriteOp::DetachBlob { doc_id, name },
+        other => return Err(other),
+    })
+}
+
+impl From<WriteOp> for Request {
+    fn from(op: WriteOp) -> Self {
+        match op {
+            WriteOp::PutBlob { data, ttl_secs } => Request::PutBlob { data, ttl_secs },
+            WriteOp::PutDocument {
+                id,
+                meta,
+                crdt_state,
+                index,
+                create_only,
+            } => Request::PutDocument {
+                id,
+                meta,
+                crdt_state,
+                index,
+                create_only,
+            },
+            WriteOp::DeleteDocument { id } => Request::DeleteDocument { id, cascade: false },
+            WriteOp::AttachBlob { doc_id, hash, name } => {
+                Request::AttachBlob { doc_id, hash, name }
+            }
+            WriteOp::DetachBlob { doc_id, name } => Request::DetachBlob { doc_id, name },
+        }
+    }
+}
+
+/// The reply `handle_request` would have given for a batched write.
+pub fn write_response(outcome: WriteOutcome) -> Response {
+    match outcome {
+        WriteOutcome::BlobStored(hash) => Response::BlobStored { hash },
+        WriteOutcome::DocumentStored(version) => Response::DocumentStored { version },
+        WriteOutcome::DocumentExists => Response::AlreadyExists,
+        WriteOutcome::DocumentDeleted(true) => Response::Ok,
+        WriteOutcome::DocumentDeleted(false) => Response::NotFound,
+        WriteOutcome::BlobAttached(true) | WriteOutcome::BlobDetached(true) => Response::Ok,
+        WriteOutcome::BlobAttached(false) | WriteOutcome::BlobDetached(false) => {
+            Response::NotFound
+        }
+    }
+}
+
+/// The change carrying `doc_id` as stored: its data, or its tombstone if
+/// it was deleted.  `None` if it is gone altogether.
+///
+/// With `known`, the hashes the receiver holds, the data is a delta from
+/// the newest retained version among them when that is smaller.
+fn change_for(
+    store: &Store,
+    doc_id: String,
+    hash: Vec<u8>,
+    known: Option<&HashSet<Vec<u8>>>,
+) -> Result<Option<Change>> {
+    let mut ancestors = store.document_ancestry(&doc_id)?;
+    ancestors.retain(|ancestor| *ancestor != hash);
+    if let Some(doc) = store.get_document(&doc_id)? {
+        let blobs = store.document_blobs(&doc_id)?;
+        let base = match known {
+            Some(known) if !known.is_empty() => delta_base(store, &doc_id, known)?,
+            _ => None,
+        };
+        let (data, base_hash) = match base {
+            Some((base_hash, base)) => {
+                let delta = delta::encode(&base, &doc.crdt_state);
+                if delta.len() < doc.crdt_state.len() {
+                    (delta, Some(base_hash))
+                } else {
+                    (doc.crdt_state, None)
+                }
+            }
+            None => (doc.crdt_state, None),
+        };
+        return Ok(Some(Change {
+            doc_id,
+            data,
+            hash,
+            deleted: false,
+            base_hash,
+            updated_at: doc.times.map(|times| times.updated_at),
+            ancestors,
+            blobs,
+        }));
+    }
+    Ok(store.get_tombstone(&doc_id)?.map(|tombstone| Change {
+        doc_id,
+        data: Vec::new(),
+        hash: tombstone.hash,
+        deleted: true,
+        base_hash: None,
+        updated_at: Some(tombstone.deleted_at),
+        ancestors,
+        blobs: Vec::new(),
+    }))
+}
+
+/// The newest retained version of `doc_id` whose hash is in `known`, with
+/// its state.
+fn delta_base(
+    store: &Store,
+    doc_id: &str,
+    known: &HashSet<Vec<u8>>,
+) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+    let Some(version) = store
+        .document_history(doc_id)?
+        .into_iter()
+        .find(|version| known.contains(&version.hash))
+    else {
+        return Ok(None);
+    };
+    let state = store.document_version(doc_id, &version.hash)?;
+    Ok(state.map(|state| (version.hash, state)))
+}
+
+/// Reply to `ContinueSync`: acknowledge the first `ack` ids of the session
+/// and send the changes after them, up to about `streaming.chunk_bytes`.
+/// Also returns when the bandwidth budget lets the reply go, if not at
+/// once.
+pub fn continue_sync(
+    store: &Store,
+    session_id: u64,
+    ack: u64,
+    streaming: Streaming,
+) -> (Response, Option<Instant>) {
+    let (response, bytes) = sync_batch(store, session_id, ack, streaming.chunk_bytes);
+    (response, streaming.throttles.reserve("get_changes", bytes))
+}
+
+/// The `SyncBatch` for `continue_sync`, with the bytes of its changes.
+fn sync_batch(store: &Store, session_id: u64, ack: u64, chunk_bytes: usize) -> (Response, usize) {
+    let pending = match store.ack_sync_session(session_id, ack) {
+        Ok(SyncProgress::Pending(pending)) => pending,
+        Ok(SyncProgress::Unknown) => return (Response::NotFound, 0),
+        Ok(SyncProgress::BadAck { acked, sent }) => {
+            let message =
+                format!("ack {ack} must lie between the last ack, {acked}, and {sent}, the changes sent");
+            return (Response::error(ErrorCode::BadRequest, message), 0);
+        }
+        Err(e) => return (e.into(), 0),
+    };
+
+    let mut changes = Vec::new();
+    let mut batch_bytes = 0usize;
+    let mut taken = 0u64;
+    for doc_id in pending.iter() {
+        if batch_bytes >= chunk_bytes {
+            break;
+        }
+        taken += 1;
+        let hash = match store.get_doc_hash(doc_id) {
+            Ok(Some(hash)) => hash,
+            Ok(None) => continue, // gone since the session started
+            Err(e) => return (e.into(), 0),
+        };
+        match change_for(store, doc_id.clone(), hash, None) {
+            Ok(Some(change)) => {
+                batch_bytes += change.doc_id.len() + change.hash.len() + change.data.len();
+                changes.push(change);
+            }
+            Ok(None) => {}
+            Err(e) => return (e.into(), 0),
+        }
+    }
+    let next_ack = ack + taken;
+    if let Err(e) = store.sent_sync_session(session_id, next_ack) {
+        return (e.into(), 0);
+    }
+    let signed = match sign_changes(store, &changes) {
+        Ok(signed) => signed,
+        Err(e) => return (e.into(), 0),
+    };
+    let response = Response::SyncBatch {
+        changes,
+        next_ack,
+        remaining: pending.len() as u64 - taken,
+        signed,
+    };
+    (response, batch_bytes)
+}
+
+/// The doc hashes `GetChanges` considers: those of `doc_ids` and of the
+/// ids starting with `prefix`, in id order, or every one if neither is set.
+fn scoped_doc_hashes(
+    store: &Store,
+    doc_ids: Vec<String>,
+    prefix: Option<String>,
+) -> Result<Vec<(String, Vec<u8>)>> {
+    if doc_ids.is_empty() && prefix.is_none() {
+        return store.all_doc_hashes();
+    }
+    let mut pairs = store.get_doc_hashes(&doc_ids)?;
+    if let Some(prefix) = prefix {
+        pairs.extend(store.doc_hashes_with_prefix(&prefix)?);
+    }
+    pairs.sort();
+    pairs.dedup_by(|a, b| a.0 == b.0);
+    Ok(pairs)
+}
+
+/// The changes `GetChanges` would send, without their data.
+fn change_heads(
+    store: &Store,
+    known_roots: Vec<Vec<u8>>,
+    doc_ids: Vec<String>,
+    prefix: Option<String>,
+) -> Result<Vec<ChangeHead>> {
+    let known: HashSet<Vec<u8>> = known_roots.into_iter().collect();
+    let mut pairs = scoped_doc_hashes(store, doc_ids, prefix)?;
+    pairs.retain(|(_, hash)| !known.contains(hash));
+    let ids: Vec<String> = pairs.iter().map(|(id, _)| id.clone()).collect();
+    let sizes = store.state_sizes(&ids)?;
+    // doc_hashes holds deletion hashes too; those documents have no state.
+    Ok(pairs
+        .into_iter()
+        .zip(sizes)
+        .map(|((doc_id, hash), size)| ChangeHead {
+            doc_id,
+            hash,
+            deleted: size.is_none(),
+            size: size.unwrap_or(0),
+        })
+        .collect())
+}
+
+/// How changes are streamed: the size of each chunk, and the bandwidth
+/// budgets chunks are paced by.
+#[derive(Debug, Clone, Copy)]
+pub struct Streaming<'a> {
+    pub chunk_bytes: usize,
+    pub throttles: &'a Throttles,
+}
+
+/// Stream the documents whose hash is not in `known_roots` as a sequence of
+/// `ChangesPart` frames, each carrying roughly `streaming.chunk_bytes` of
+/// changes.
+///
+/// The final frame has `last: true` (and may be empty).  A storage error
+/// mid-stream is reported as a trailing `Error` frame with the same ref_id.
+/// Returns the rest of the stream if the bandwidth budget holds a part back.
+pub fn stream_changes(
+    store: &Store,
+    known_roots: Vec<Vec<u8>>,
+    doc_ids: Vec<String>,
+    prefix: Option<String>,
+    namespace: Option<String>,
+    streaming: Streaming,
+    reply: &mut Reply,
+) -> Result<Option<Paced>> {
+    let store = match namespace {
+        Some(namespace) => match store.namespace(&namespace) {
+            Ok(handle) => handle,
+            Err(e) => {
+                let response = Response::error(ErrorCode::BadRequest, format!("{e:#}"));
+                return reply.send(&response).map(|()| None);
+            }
+        },
+        None => store.clone(),
+    };
+    let local_pairs = match scoped_doc_hashes(&store, doc_ids, prefix) {
+        Ok(pairs) => pairs,
+        Err(e) => return reply.send(&e.into()).map(|()| None),
+    };
+    let known = Known::Hashes(known_roots.into_iter().collect());
+    ChangeStream::new(store, local_pairs, known).pump(streaming, reply)
+}
+
+/// Reply to `GetChangesBloom`: stream every change whose hash `bloom`
+/// doesn't contain.
+pub fn stream_changes_bloom(
+    store: &Store,
+    bloom: HashBloom,
+    streaming: Streaming,
+    reply: &mut Reply,
+) -> Result<Option<Paced>> {
+    if let Err(e) = bloom.validate() {
+        let response = Response::error(ErrorCode::BadRequest, format!("{e:#}"));
+        return reply.send(&response).map(|()| None);
+    }
+    let local_pairs = match store.all_doc_hashes() {
+        Ok(pairs) => pairs,
+        Err(e) => return reply.send(&e.into()).map(|()| None),
+    };
+    ChangeStream::new(store.clone(), local_pairs, Known::Bloom(bloom)).pump(streaming, reply)
+}
+
+/// Reply to `GetChangesSince`: stream the changes since `peer_id`'s
+/// checkpoint, each part carrying the head the peer acknowledges once it
+/// has applied them all.
+pub fn stream_changes_since(
+    store: &Store,
+    peer_id: &str,
+    streaming: Streaming,
+    reply: &mut Reply,
+) -> Result<Option<Paced>> {
+    let since = match store.changed_since_checkpoint(peer_id) {
+        Ok(since) => since,
+        Err(e) => return reply.send(&e.into()).map(|()| None),
+    };
+    let mut stream = ChangeStream::new(store.clone(), since.pairs, Known::Nothing);
+    stream.head = Some(since.head);
+    stream.pump(streaming, reply)
+}
+
+/// What the receiver of a change stream holds.
+enum Known {
+    /// These hashes, which changes may also be deltas against.
+    Hashes(HashSet<Vec<u8>>),
+    /// The hashes this filter may contain.  A false positive could name a
+    /// version the receiver lacks, so no deltas.
+    Bloom(HashBloom),
+    /// Nothing we know of, so no deltas either.
+    Nothing,
+}
+
+impl Known {
+    fn contains(&self, hash: &[u8]) -> bool {
+        match self {
+            Known::Hashes(hashes) => hashes.contains(hash),
+            Known::Bloom(bloom) => bloom.may_contain(hash),
+            Known::Nothing => false,
+        }
+    }
+
+    fn delta_bases(&self) -> Option<&HashSet<Vec<u8>>> {
+        match self {
+            Known::Hashes(hashes) => Some(hashes),
+            _ => None,
+        }
+    }
+}
+
+/// A `ChangesPart` stream under way: the changes of the pairs whose hash
+/// the receiver doesn't hold, a part of about `Streaming::chunk_bytes` at
+/// a time.
+pub struct ChangeStream {
+    store: Store,
+    pairs: std::vec::IntoIter<(String, Vec<u8>)>,
+    known: Known,
+    seq: u32,
+    /// Changelog seq a `GetChangesSince` stream brings the receiver up to.
+    head: Option<u64>,
+}
+
+/// A part of a `ChangeStream`, with the bytes of its changes.
+struct Part {
+    response: Response,
+    bytes: usize,
+    last: bool,
+}
+
+/// A part the bandwidth budget held back, to be sent at `deadline` by
+/// `resume`, with the stream it belongs to.
+pub struct Paced {
+    pub deadline: Instant,
+    part: Part,
+    stream: ChangeStream,
+}
+
+impl ChangeStream {
+    fn new(store: Store, pairs: Vec<(String, Vec<u8>)>, known: Known) -> Self {
+        Self {
+            store,
+            pairs: pairs.into_iter(),
+            known,
+            seq: 0,
+            head: None,
+        }
+    }
+
+    /// Send parts until the stream ends or the bandwidth budget holds one
+    /// back, which is returned.
+    fn pump(mut self, streaming: Streaming, reply: &mut Reply) -> Result<Option<Paced>> {
+        loop {
+            let part = self.next_part(streaming.chunk_bytes);
+            if let Some(deadline) = streaming.throttles.reserve("get_changes", part.bytes) {
+                return Ok(Some(Paced {
+                    deadline,
+                    part,
+                    stream: self,
+                }));
+            }
+            if self.send(part, reply)? {
+                return Ok(None);
+            }
+        }
+    }
+
+    /// The changes up to the next part boundary.  A failure ends the
+    /// stream with an error reply instead.
+    fn next_part(&mut self, chunk_bytes: usize) -> Part {
+        let mut changes = Vec::new();
+        let mut bytes = 0usize;
+        let mut last = true;
+        for (doc_id, hash) in self.pairs.by_ref() {
+            if self.known.contains(&hash) {
+                continue;
+            }
+            // Remote doesn't have this version.
+            let change = match change_for(&self.store, doc_id, hash, self.known.delta_bases()) {
+                Ok(Some(change)) => change,
+                Ok(None) => continue, // deleted between reads, skip
+                Err(e) => return Self::failed(e),
+            };
+            bytes += change.doc_id.len() + change.hash.len() + change.data.len();
+            changes.push(change);
+            if bytes >= chunk_bytes {
+                last = false;
+                break;
+            }
+        }
+        let signed = match sign_changes(&self.store, &changes) {
+            Ok(signed) => signed,
+            Err(e) => return Self::failed(e),
+        };
+        self.seq += 1;
+        Part {
+            response: Response::ChangesPart {
+                seq: self.seq - 1,
+                last,
+                changes,
+                signed,
+                head: self.head,
+            },
+            bytes,
+            last,
+        }
+    }
+
+    /// A last part replying with `e`.
+    fn failed(e: anyhow::Error) -> Part {
+        Part {
+            response: e.into(),
+            bytes: 0,
+            last: true,
+        }
+    }
+
+    /// Send `part`, true if it ended the stream.
+    fn send(&mut self, part: Part, reply: &mut Reply) -> Result<bool> {
+        reply.send(&part.response)?;
+        Ok(part.last)
+    }
+}
+
+impl Paced {
+    /// Send the part held back and stream on, returning the rest of the
+    /// stream if the budget holds another part back.
+    pub fn resume(self, streaming: Streaming, reply: &mut Reply) -> Result<Option<Paced>> {
+        let Paced {
+            part, mut stream, ..
+        } = self;
+        if stream.send(part, reply)? {
+            return Ok(None);
+        }
+        stream.pump(streaming, reply)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::StoreOptions;
+
+    fn change(doc_id: &str, data: &[u8], base_hash: Option<Vec<u8>>) -> Change {
+        Change {
+            doc_id: doc_id.into(),
+            data: data.to_vec(),
+            hash: blake3::hash(data).as_bytes().to_vec(),
+            deleted: false,
+            base_hash,
+            updated_at: None,
+            ancestors: Vec::new(),
+            blobs: Vec::new(),
+        }
+    }
+
+    /// The changes `GetChanges` streams from `store` to a receiver holding
+    /// `known_roots`.
+    fn changes(store: &Store, known_roots: Vec<Vec<u8>>) -> Vec<Change> {
+        let throttles = Throttles::new(&[]);
+        let streaming = Streaming {
+            chunk_bytes: 1024,
+            throttles: &throttles,
+        };
+        let mut frames: Vec<Vec<u8>> = Vec::new();
+        let mut reply = Reply::new(1, &mut frames);
+        let paced =
+            stream_changes(store, known_roots, Vec::new(), None, None, streaming, &mut reply);
+        assert!(paced.unwrap().is_none());
+        let mut changes = Vec::new();
+        for frame in frames {
+            match bincode::deserialize::<(u64, Response)>(&frame).unwrap().1 {
+                Response::ChangesPart { changes: part, .. } => changes.extend(part),
+                other => panic!("unexpected reply {other:?}"),
+            }
+        }
+        changes
+    }
+
+    fn apply(store: &Store, changes: Vec<Change>) -> Response {
+        let request = Request::ApplyChanges {
+            changes,
+            signed_root: None,
+            peer_id: None,
+        };
+        handle_request(store, request)
+    }
+
+    #[test]
+    fn test_deletion_sync() {
+        let dir = tempfile::tempdir().unwrap();
+        let a = Store::open(&dir.path().join("a"), StoreOptions::default()).unwrap();
+        let b = Store::open(&dir.path().join("b"), StoreOptions::default()).unwrap();
+        a.put_document("doc", b"meta", b"state", None, false).unwrap();
+        a.put_document("kept", b"meta", b"kept", None, false).unwrap();
+        let response = apply(&b, changes(&a, Vec::new()));
+        assert!(matches!(response, Response::Applied { applied: 2, .. }), "{response:?}");
+        let stale = changes(&b, Vec::new());
+
+        a.delete_document("doc", false).unwrap();
+        let known = b.get_doc_hashes(&["doc".into(), "kept".into()]).unwrap();
+        let deletions = changes(&a, known.into_iter().map(|(_, hash)| hash).collect());
+        assert_eq!(deletions.len(), 1);
+        assert!(deletions[0].deleted && deletions[0].data.is_empty());
+        let response = apply(&b, deletions);
+        assert!(matches!(response, Response::Applied { applied: 1, .. }), "{response:?}");
+        assert!(b.get_document("doc").unwrap().is_none());
+        let tombstone = b.get_tombstone("doc").unwrap().unwrap();
+        assert_eq!(Some(tombstone.hash), a.get_doc_hash("doc").unwrap());
+        assert_eq!(a.combined_root().unwrap(), b.combined_root().unwrap());
+
+        // A peer that missed the deletion can't bring the document back.
+        let response = apply(&a, stale);
+        assert!(matches!(response, Response::Applied { applied: 0, .. }), "{response:?}");
+        assert!(a.get_document("doc").unwrap().is_none());
+
+        let mut malformed = changes(&a, Vec::new());
+        malformed.retain(|c| c.deleted);
+        malformed[0].data = b"data".to_vec();
+        let response = apply(&b, malformed);
+        let bad_request = matches!(response, Response::Error { code: ErrorCode::BadRequest, .. });
+        assert!(bad_request, "{response:?}");
+    }
+
+    #[test]
+    fn test_sync_session() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a");
+        let a = Store::open(&path, StoreOptions::default()).unwrap();
+        let b = Store::open(&dir.path().join("b"), StoreOptions::default()).unwrap();
+        for i in 0..3u8 {
+            a.put_document(&format!("doc-{i}"), b"meta", &[i; 100], None, false).unwrap();
+        }
+        let throttles = Throttles::new(&[]);
+        let streaming = Streaming {
+            chunk_bytes: 150,
+            throttles: &throttles,
+        };
+        let start = Request::StartSync {
+            known_roots: Vec::new(),
+            doc_ids: Vec::new(),
+            prefix: None,
+        };
+        let Response::SyncStarted { session_id, total } = handle_request(&a, start) else {
+            panic!("expected SyncStarted");
+        };
+        assert_eq!(total, 3);
+        let batch = |store: &Store, ack| match continue_sync(store, session_id, ack, streaming) {
+            (Response::SyncBatch { changes, next_ack, remaining, .. }, None) => {
+                (changes, next_ack, remaining)
+            }
+            other => panic!("expected SyncBatch, got {other:?}"),
+        };
+
+        let (first, next_ack, _) = batch(&a, 0);
+        assert!(!first.is_empty() && first.len() < 3);
+        // A lost batch is sent again, even by a port that restarted since.
+        drop(a);
+        let a = Store::open(&path, StoreOptions::default()).unwrap();
+        let (again, _, _) = batch(&a, 0);
+        let ids =
+            |changes: &[Change]| changes.iter().map(|c| c.doc_id.clone()).collect::<Vec<_>>();
+        assert_eq!(ids(&again), ids(&first));
+        let (bad, _) = continue_sync(&a, session_id, next_ack + 1, streaming);
+        assert!(matches!(bad, Response::Error { .. }), "{bad:?}");
+
+        let mut received = first;
+        let mut ack = next_ack;
+        loop {
+            let (changes, next_ack, remaining) = batch(&a, ack);
+            received.extend(changes);
+            ack = next_ack;
+            if remaining == 0 {
+                break;
+            }
+        }
+        assert_eq!(ids(&received), ["doc-0", "doc-1", "doc-2"]);
+        let response = apply(&b, received);
+        assert!(matches!(response, Response::Applied { applied: 3, .. }), "{response:?}");
+        assert_eq!(a.combined_root().unwrap(), b.combined_root().unwrap());
+
+        let finish = || Request::FinishSync { session_id };
+        assert!(matches!(handle_request(&a, finish()), Response::Ok));
+        assert!(matches!(handle_request(&a, finish()), Response::NotFound));
+        let (gone, _) = continue_sync(&a, session_id, ack, streaming);
+        assert!(matches!(gone, Response::NotFound), "{gone:?}");
+    }
+
+    #[test]
+    fn test_conflict_policies() {
+        use crate::store::ConflictPolicy;
+
+        let dir = tempfile::tempdir().unwrap();
+        let drafts = ("drafts".to_string(), ConflictPolicy::PreferRemote);
+        let open = |name: &str, policy| {
+            let options = StoreOptions {
+                conflict_policy: policy,
+                namespace_conflict_policies: [drafts.clone()].into(),
+                ..Default::default()
+            };
+            Store::open(&dir.path().join(name), options).unwrap()
+        };
+        let conflict = |store: &Store, incoming: Change| {
+            store.put_document("d", b"meta", b"local", None, false).unwrap();
+            match apply(store, vec![incoming]) {
+                Response::Applied { conflicts, .. } => {
+                    assert_eq!(conflicts.len(), 1);
+                    assert_eq!(conflicts[0].doc_id, "d");
+                    assert_eq!(conflicts[0].remote_hash, blake3::hash(b"remote").as_bytes());
+                }
+                other => panic!("expected Applied, got {other:?}"),
+            }
+            store.get_document("d").unwrap().unwrap().crdt_state
+        };
+        // Neither state is Automerge, so `Merge` keeps both.
+        for (name, policy, kept) in [
+            ("merge", ConflictPolicy::Merge, &b"local"[..]),
+            ("local", ConflictPolicy::PreferLocal, b"local"),
+            ("remote", ConflictPolicy::PreferRemote, b"remote"),
+            ("both", ConflictPolicy::KeepBoth, b"local"),
+        ] {
+            let store = open(name, policy);
+            assert_eq!(conflict(&store, change("d", b"remote", None)), kept, "{policy}");
+            let siblings = store.document_siblings("d").unwrap();
+            if matches!(policy, ConflictPolicy::Merge | ConflictPolicy::KeepBoth) {
+                assert_eq!(siblings.len(), 1, "{policy}");
+                assert_eq!(siblings[0].crdt_state, b"remote");
+            } else {
+                assert!(siblings.is_empty(), "{policy}");
+            }
+            // A namespace can settle its conflicts its own way.
+            let drafts = store.namespace("drafts").unwrap();
+            assert_eq!(conflict(&drafts, change("d", b"remote", None)), b"remote", "{policy}");
+        }
+
+        let lww = open("lww", ConflictPolicy::LastWriterWins);
+        let at = |updated_at| Change {
+            updated_at: Some(updated_at),
+            ..change("d", b"remote", None)
+        };
+        assert_eq!(conflict(&lww, at(0)), b"local");
+        assert_eq!(conflict(&lww, at(u64::MAX)), b"remote");
+    }
+
+    #[test]
+    fn test_changes_since_checkpoint() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Store::open(dir.path(), StoreOptions::default()).unwrap();
+        for i in 0..3u8 {
+            store.put_document(&format!("doc-{i}"), b"meta", &[i; 100], None, false).unwrap();
+        }
+        let throttles = Throttles::new(&[]);
+        let streaming = Streaming {
+            chunk_bytes: 150,
+            throttles: &throttles,
+        };
+        let stream = |store: &Store| {
+            let mut frames: Vec<Vec<u8>> = Vec::new();
+            let mut reply = Reply::new(1, &mut frames);
+            let paced = stream_changes_since(store, "peer", streaming, &mut reply);
+            assert!(paced.unwrap().is_none());
+            let mut heads = Vec::new();
+            let mut count = 0;
+            for frame in frames {
+                match bincode::deserialize::<(u64, Response)>(&frame).unwrap().1 {
+                    Response::ChangesPart { changes, head, .. } => {
+                        count += changes.len();
+                        heads.push(head.unwrap());
+                    }
+                    other => panic!("unexpected reply {other:?}"),
+                }
+            }
+            heads.dedup();
+            assert_eq!(heads.len(), 1, "every part carries the same head");
+            (count, heads[0])
+        };
+        let ack = |head| Request::AckChangesSince {
+            peer_id: "peer".into(),
+            head,
+        };
+
+        let (count, head) = stream(&store);
+        assert_eq!(count, 3);
+        // Until the peer acknowledges them, the same changes are sent.
+        assert_eq!(stream(&store), (3, head));
+        assert!(matches!(handle_request(&store, ack(head)), Response::Ok));
+        let checkpoint = Request::GetSyncCheckpoint {
+            peer_id: "peer".into(),
+        };
+        let response = handle_request(&store, checkpoint);
+        assert!(matches!(response, Response::SyncCheckpoint { seq, .. } if seq == head));
+        assert!(matches!(handle_request(&store, ack(head + 1)), Response::NotFound));
+        assert_eq!(stream(&store), (0, head));
+    }
+
+    #[test]
+    fn test_state_must_match_its_hash() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Store::open(dir.path(), StoreOptions::default()).unwrap();
+        let forged = Change {
+            hash: blake3::hash(b"other").as_bytes().to_vec(),
+            ..change("a", b"state-a", None)
+        };
+        let response = apply(&store, vec![forged]);
+        let bad = matches!(&response, Response::Error { message, .. } if message.contains("hash"));
+        assert!(bad, "{response:?}");
+        assert!(store.get_document("a").unwrap().is_none());
+        assert!(store.document_ancestry("a").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_apply_changes_is_all_or_nothing() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Store::open(dir.path(), StoreOptions::default()).unwrap();
+
+        // The second change is a delta from a version we don't hold.
+        let delta = change("b", b"delta", Some(vec![7; 32]));
+        let response = apply(&store, vec![change("a", b"state-a", None), delta]);
+        assert!(matches!(response, Response::Error { .. }), "{response:?}");
+        assert!(store.get_document("a").unwrap().is_none());
+
+        let changes = vec![change("a", b"state-a", None), change("b", b"state-b", None)];
+        let response = apply(&store, changes);
+        assert!(matches!(response, Response::Applied { applied: 2, .. }), "{response:?}");
+        assert_eq!(store.get_document("a").unwrap().unwrap().crdt_state, b"state-a");
+    }
+}
@@ -0,0 +1,39 @@
+//! Length-prefixed frame I/O.
+//!
+//! Every frame is `[4-byte big-endian length][payload]`.
+
+use anyhow::{Context, Result};
+use std::io::{self, Read, Write};
+
+/// Read one frame.  Returns `None` on a clean EOF before the length prefix.
+pub fn read_frame(r: &mut impl Read) -> Result<Option<Vec<u8>>> {
+    let mut len_buf = [0u8; 4];
+    match r.read_exact(&mut len_buf) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let len = u32::from_be_bytes(len_buf) as usize;
+    let mut buf = vec![0u8; len];
+    r.read_exact(&mut buf)
+        .context("reading frame body")?;
+    Ok(Some(buf))
+}
+
+/// Write one frame and flush.
+pub fn write_frame(w: &mut impl Write, data: &[u8]) -> Result<()> {
+    let len = (data.len() as u32).to_be_bytes();
+    w.write_all(&len)?;
+    w.write_all(data)?;
+    w.flush()?;
+    Ok(())
+}
+
+/// Best-effort extraction of the ref_id from an encoded payload.
+///
+/// bincode encodes the leading `u64` as 8 little-endian bytes, so this works
+/// even when the rest of the payload fails to decode.
+pub fn peek_ref_id(payload: &[u8]) -> Option<u64> {
+    let bytes: [u8; 8] = payload.get(..8)?.try_into().ok()?;
+    Some(u64::from_le_bytes(bytes))
+}
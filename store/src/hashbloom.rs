@@ -0,0 +1,117 @@
+//! Bloom filters of state hashes, sent by peers in `GetChangesBloom`.
+//!
+//! A peer holding many documents can describe the hashes it has in about
+//! 10 bits each instead of the 32 bytes `known_roots` takes.  Bit `i` of
+//! the filter is bit `i % 8` of byte `i / 8` of `bits`.  A hash sets the
+//! `probes` bits `(h1 + k * h2) % (8 * bits.len())` for `k` in
+//! `0..probes`, where `h1` is its first 8 bytes and `h2` its next 8 with
+//! the lowest bit set, both read as little-endian integers.  Hashes are
+//! blake3 output, uniform already, so no further hashing is needed.
+//!
+//! A hit may be false, so a change the peer lacks is occasionally left
+//! out; a bucket comparison or an exact `GetChanges` finds those.
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+
+/// Most probes a filter may ask for; 7 is optimal at 10 bits per hash.
+pub const MAX_PROBES: u8 = 32;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HashBloom {
+    pub bits: Vec<u8>,
+    pub probes: u8,
+}
+
+impl HashBloom {
+    /// Reject a filter that can't answer: no bits, or a probe count of 0
+    /// or above `MAX_PROBES`.
+    pub fn validate(&self) -> Result<()> {
+        if self.bits.is_empty() {
+            bail!("bloom filter has no bits");
+        }
+        if self.probes == 0 || self.probes > MAX_PROBES {
+            bail!("bloom filter probes must be between 1 and {MAX_PROBES}, not {}", self.probes);
+        }
+        Ok(())
+    }
+
+    /// False if `hash` was never inserted; true if it may have been.
+    pub fn may_contain(&self, hash: &[u8]) -> bool {
+        self.positions(hash).all(|i| self.bits[i / 8] & (1 << (i % 8)) != 0)
+    }
+
+    fn positions(&self, hash: &[u8]) -> impl Iterator<Item = usize> {
+        let len = self.bits.len() as u64 * 8;
+        let h1 = word(hash);
+        let h2 = word(hash.get(8..).unwrap_or_default()) | 1;
+        (0..self.probes as u64).map(move |k| (h1.wrapping_add(k.wrapping_mul(h2)) % len) as usize)
+    }
+}
+
+/// The first 8 bytes of `bytes`, zero-padded, as a little-endian u64.
+fn word(bytes: &[u8]) -> u64 {
+    let mut buf = [0u8; 8];
+    let n = bytes.len().min(8);
+    buf[..n].copy_from_slice(&bytes[..n]);
+    u64::from_le_bytes(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hash(n: u32) -> [u8; 32] {
+        *blake3::hash(&n.to_le_bytes()).as_bytes()
+    }
+
+    fn empty(bytes: usize, probes: u8) -> HashBloom {
+        HashBloom {
+            bits: vec![0; bytes],
+            probes,
+        }
+    }
+
+    fn insert(bloom: &mut HashBloom, hash: &[u8]) {
+        for i in bloom.positions(hash).collect::<Vec<_>>() {
+            bloom.bits[i / 8] |= 1 << (i % 8);
+        }
+    }
+
+    #[test]
+    fn test_no_false_negatives() {
+        let mut bloom = empty(1250, 7);
+        for n in 0..1000 {
+            insert(&mut bloom, &hash(n));
+        }
+        assert!((0..1000).all(|n| bloom.may_contain(&hash(n))));
+    }
+
+    #[test]
+    fn test_false_positive_rate() {
+        let mut bloom = empty(1250, 7);
+        for n in 0..1000 {
+            insert(&mut bloom, &hash(n));
+        }
+        let hits = (1000..11_000).filter(|&n| bloom.may_contain(&hash(n))).count();
+        assert!(hits < 200, "{hits} false positives");
+    }
+
+    #[test]
+    fn test_bit_layout() {
+        // h1 = 3, h2 = 1: bits 3 and 4 of 16.
+        let mut hash = [0u8; 32];
+        hash[0] = 3;
+        let mut bloom = empty(2, 2);
+        insert(&mut bloom, &hash);
+        assert_eq!(bloom.bits, [0b0001_1000, 0]);
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(empty(8, 7).validate().is_ok());
+        assert!(empty(0, 7).validate().is_err());
+        assert!(empty(8, 0).validate().is_err());
+        assert!(empty(8, MAX_PROBES + 1).validate().is_err());
+    }
+}
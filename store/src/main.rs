@@ -1,22 +1,67 @@
 //! keyring-store — Elixir ↔ Rust storage port.
 //!
 //! Communicates via stdin/stdout using length-prefixed bincode frames:
-//!   [4-byte big-endian length][bincode(ref_id: u64, Request)]
+//!   [4-byte big-endian length][bincode(ref_id: u64, trace_id: Option<String>,
+//!                                      namespace: String, tenant: Option<String>,
+//!                                      durability: Option<Durability>, Request)]
 //!   [4-byte big-endian length][bincode(ref_id: u64, Response)]
 //!
 //! Logs go to stderr so they don't corrupt the binary protocol.
 
+mod antientropy;
+mod bench;
+mod capture;
+mod decode;
+mod delta;
+mod dispatch;
+mod frame;
+mod hashbloom;
+mod maintenance;
 mod merkle;
+mod peer;
 mod protocol;
+mod quic;
+mod ratelimit;
+mod repl;
+mod server;
+mod snapshotter;
 mod store;
+mod sweeper;
+mod tar;
+mod tenants;
+mod throttle;
+mod transport;
+mod txns;
+mod watch;
 
-use anyhow::{Context, Result};
-use clap::Parser;
-use protocol::{Change, RefId, Request, Response, Root};
-use std::io::{self, Read, Write};
-use std::path::PathBuf;
-use store::Store;
-use tracing::{debug, info};
+use anyhow::{bail, Context, Result};
+use capture::Recorder;
+use clap::{Args, Parser, Subcommand};
+use server::Server;
+use signal_hook::consts::{SIGINT, SIGTERM};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, IsTerminal};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use store::{
+    BlobBackend, CharSet, ConflictPolicy, CreateMode, Durability, IdPolicy, ImportPolicy, Key,
+    NamespacePolicy, SigningKey, Store, StoreOptions,
+};
+use tracing::info;
+use transport::{AuthToken, Listener, Transport};
+
+/// Environment variable holding the encryption key as 64 hex digits.  An
+/// environment variable rather than a flag keeps it out of `ps`.
+const KEY_ENV: &str = "KEYRING_STORE_KEY";
+
+/// Environment variable holding the Ed25519 key combined roots are signed
+/// with, as 64 hex digits.
+const SIGNING_KEY_ENV: &str = "KEYRING_STORE_SIGNING_KEY";
+
+/// Environment variable holding the token TCP clients must present, see
+/// `transport`.  Without it, `--transport tcp` binds only loopback.
+const AUTH_TOKEN_ENV: &str = "KEYRING_STORE_AUTH_TOKEN";
 
 // ── CLI ───────────────────────────────────────────────────────────────
 
@@ -24,148 +69,583 @@ use tracing::{debug, info};
 #[command(name = "keyring-store", about = "Content-addressed storage port for Keyring")]
 struct Cli {
     /// Directory for the redb database.
-    #[arg(long, default_value = "./data")]
+    #[arg(long, default_value = "./data", global = true, env = "KEYRING_STORE_DATA_DIR")]
     data_dir: PathBuf,
-}
 
-// ── Frame I/O ─────────────────────────────────────────────────────────
+    /// zstd level for blob values (0 disables compression).
+    #[arg(long, default_value_t = 3, global = true, env = "KEYRING_STORE_BLOB_COMPRESSION_LEVEL")]
+    blob_compression_level: i32,
 
-fn read_frame(r: &mut impl Read) -> Result<Option<Vec<u8>>> {
-    let mut len_buf = [0u8; 4];
-    match r.read_exact(&mut len_buf) {
-        Ok(()) => {}
-        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
-        Err(e) => return Err(e.into()),
-    }
-    let len = u32::from_be_bytes(len_buf) as usize;
-    let mut buf = vec![0u8; len];
-    r.read_exact(&mut buf)
-        .context("reading frame body")?;
-    Ok(Some(buf))
+    /// Blobs at least this many bytes are stored as files under
+    /// `<data-dir>/blobs/` instead of inside redb (0 disables spilling).
+    #[arg(
+        long,
+        default_value_t = 1024 * 1024,
+        global = true,
+        env = "KEYRING_STORE_SPILL_THRESHOLD_BYTES"
+    )]
+    spill_threshold_bytes: usize,
+
+    /// Where new blobs go: `redb` (spilling only those over
+    /// --spill-threshold-bytes) or `files` (every blob in its own file under
+    /// `<data-dir>/blobs/`, uncompressed, with only an index entry in redb).
+    #[arg(
+        long,
+        value_name = "BACKEND",
+        default_value = "redb",
+        global = true,
+        env = "KEYRING_STORE_BLOB_BACKEND"
+    )]
+    blob_backend: BlobBackend,
+
+    /// Bytes of hot documents and blobs cached in memory (0 disables the
+    /// cache).
+    #[arg(
+        long,
+        default_value_t = StoreOptions::default().cache_bytes,
+        global = true,
+        env = "KEYRING_STORE_CACHE_BYTES"
+    )]
+    cache_bytes: usize,
+
+    /// Whether the database may be created: `always` (it must not exist
+    /// yet), `if-missing`, or `never` (it must exist, so a data volume that
+    /// failed to mount is not mistaken for an empty store).
+    #[arg(
+        long,
+        value_name = "MODE",
+        default_value = "if-missing",
+        global = true,
+        env = "KEYRING_STORE_CREATE"
+    )]
+    create: CreateMode,
+
+    /// Bytes of database pages redb caches in memory, per database file
+    /// (each tenant has its own).
+    #[arg(
+        long,
+        default_value_t = StoreOptions::default().db_cache_bytes,
+        global = true,
+        env = "KEYRING_STORE_DB_CACHE_BYTES"
+    )]
+    db_cache_bytes: usize,
+
+    /// Create new database files in redb's v3 file format; existing files
+    /// keep theirs.
+    #[arg(long, global = true, env = "KEYRING_STORE_DB_FILE_FORMAT_V3")]
+    db_file_format_v3: bool,
+
+    /// Give decoded (decompressed and decrypted) blobs an LRU cache of
+    /// their own of this many bytes, instead of sharing --cache-bytes with
+    /// documents (0 disables blob caching).
+    #[arg(long, value_name = "BYTES", global = true, env = "KEYRING_STORE_BLOB_CACHE_BYTES")]
+    blob_cache_bytes: Option<usize>,
+
+    /// Default durability of write transactions: `none` (no fsync),
+    /// `eventual` (background fsync) or `immediate` (fsync per commit).
+    #[arg(long, default_value = "immediate", global = true, env = "KEYRING_STORE_DURABILITY")]
+    durability: Durability,
+
+    /// Versions of each document's CRDT state kept for recovery (0 disables
+    /// history).
+    #[arg(
+        long,
+        default_value_t = StoreOptions::default().history_depth,
+        global = true,
+        env = "KEYRING_STORE_HISTORY_DEPTH"
+    )]
+    history_depth: usize,
+
+    /// How ApplyChanges settles a change that conflicts with a document
+    /// whose meta names no CRDT engine: `merge`, `lww`, `prefer-local`,
+    /// `prefer-remote` or `keep-both`.
+    #[arg(long, default_value = "merge", global = true, env = "KEYRING_STORE_CONFLICT_POLICY")]
+    conflict_policy: ConflictPolicy,
+
+    /// `--conflict-policy` for one namespace, as `namespace=policy`.  May
+    /// be repeated.
+    #[arg(
+        long = "namespace-conflict-policy",
+        value_name = "NS=POLICY",
+        global = true,
+        env = "KEYRING_STORE_NAMESPACE_CONFLICT_POLICY",
+        value_delimiter = ','
+    )]
+    namespace_conflict_policies: Vec<NamespacePolicy>,
+
+    /// Top-level text field of the (CBOR) document meta to index for
+    /// Search requests.  May be repeated; changing the set reindexes on
+    /// open.
+    #[arg(
+        long = "search-field",
+        value_name = "FIELD",
+        global = true,
+        env = "KEYRING_STORE_SEARCH_FIELD",
+        value_delimiter = ','
+    )]
+    search_fields: Vec<String>,
+
+    /// Count document reads (batched in memory, flushed periodically) for
+    /// HotDocuments requests.
+    #[arg(long, global = true, env = "KEYRING_STORE_TRACK_ACCESS")]
+    track_access: bool,
+
+    /// Reject blobs larger than this many bytes.
+    #[arg(long, value_name = "BYTES", global = true, env = "KEYRING_STORE_MAX_BLOB_BYTES")]
+    max_blob_bytes: Option<u64>,
+
+    /// Reject documents whose meta and CRDT state together are larger than
+    /// this many bytes.
+    #[arg(long, value_name = "BYTES", global = true, env = "KEYRING_STORE_MAX_DOC_BYTES")]
+    max_doc_bytes: Option<u64>,
+
+    /// Reject document ids longer than this many bytes.
+    #[arg(long, value_name = "BYTES", global = true, env = "KEYRING_STORE_MAX_ID_LEN")]
+    max_id_len: Option<usize>,
+
+    /// Characters document ids may consist of, as ranges and single
+    /// characters, e.g. `A-Za-z0-9_.:/-`.  Control characters are always
+    /// rejected.
+    #[arg(long, value_name = "CHARS", global = true, env = "KEYRING_STORE_ID_CHARS")]
+    id_chars: Option<CharSet>,
+
+    /// Reject document ids starting with this prefix.  May be repeated.
+    #[arg(
+        long = "reserved-id-prefix",
+        value_name = "PREFIX",
+        global = true,
+        env = "KEYRING_STORE_RESERVED_ID_PREFIX",
+        value_delimiter = ','
+    )]
+    reserved_id_prefixes: Vec<String>,
+
+    /// Refuse to open a database from an older version instead of
+    /// migrating it; run the `migrate` subcommand to upgrade it.
+    #[arg(long, global = true, env = "KEYRING_STORE_NO_MIGRATE_ON_OPEN")]
+    no_migrate_on_open: bool,
+
+    /// Ed25519 public key, as 64 hex digits, of a store whose signed roots
+    /// are trusted.  May be repeated.
+    #[arg(
+        long = "trusted-signer",
+        value_name = "HEX",
+        global = true,
+        env = "KEYRING_STORE_TRUSTED_SIGNER",
+        value_delimiter = ','
+    )]
+    trusted_signers: Vec<String>,
+
+    /// Refuse ApplyChanges batches, including peer pulls, that don't carry
+    /// a root signed by a `--trusted-signer`.
+    #[arg(long, global = true, env = "KEYRING_STORE_REQUIRE_SIGNED_ROOTS")]
+    require_signed_roots: bool,
+
+    /// Open a private copy of the snapshot that the process serving
+    /// --data-dir last published (see --snapshot-interval-secs) instead of
+    /// the database, refusing writes.
+    #[arg(long, global = true, env = "KEYRING_STORE_READ_ONLY")]
+    read_only: bool,
+
+    /// Serving flags, for when no subcommand is given.
+    #[command(flatten)]
+    serve: ServeArgs,
+
+    #[command(subcommand)]
+    command: Option<Command>,
 }
 
-fn write_frame(w: &mut impl Write, data: &[u8]) -> Result<()> {
-    let len = (data.len() as u32).to_be_bytes();
-    w.write_all(&len)?;
-    w.write_all(data)?;
-    w.flush()?;
-    Ok(())
+/// Flags of `serve`, which bare invocation also takes.
+#[derive(Args, Debug)]
+struct ServeArgs {
+    /// What to serve requests over: `stdio` (the Elixir port), `uds` (a
+    /// Unix socket at --listen) or `tcp` (--listen as `host:port`).
+    /// Socket transports serve many client connections at once.  `tcp`
+    /// binds only loopback unless KEYRING_STORE_AUTH_TOKEN is set, and then
+    /// requires clients to present it.
+    #[arg(long, default_value = "stdio", env = "KEYRING_STORE_TRANSPORT")]
+    transport: Transport,
+
+    /// Socket path or address for `--transport uds` or `tcp`.
+    #[arg(long, value_name = "ADDR", env = "KEYRING_STORE_LISTEN")]
+    listen: Option<String>,
+
+    /// Client connections served at once over `uds` or `tcp`; more are
+    /// refused.
+    #[arg(long, default_value_t = 64, env = "KEYRING_STORE_MAX_CLIENTS")]
+    max_clients: usize,
+
+    /// Append every inbound/outbound frame to this capture file.
+    #[arg(long, value_name = "FILE", env = "KEYRING_STORE_RECORD")]
+    record: Option<PathBuf>,
+
+    /// Byte budget for each streamed GetChanges chunk.
+    #[arg(
+        long,
+        default_value_t = server::Config::default().changes_chunk_bytes,
+        env = "KEYRING_STORE_CHANGES_CHUNK_BYTES"
+    )]
+    changes_chunk_bytes: usize,
+
+    /// Token-bucket limit for a request type, as `kind=rate[/burst]`
+    /// (e.g. `apply_changes=5/20`).  May be repeated.
+    #[arg(
+        long = "rate-limit",
+        value_name = "KIND=RATE[/BURST]",
+        env = "KEYRING_STORE_RATE_LIMIT",
+        value_delimiter = ','
+    )]
+    rate_limits: Vec<ratelimit::RateLimit>,
+
+    /// Bytes-per-second budget for sync streaming, as `kind=bytes[/burst]`
+    /// where kind is `get_changes` or `push_blobs` (e.g.
+    /// `get_changes=262144/1048576`).  May be repeated.
+    #[arg(
+        long = "bandwidth-limit",
+        value_name = "KIND=BYTES[/BURST]",
+        value_parser = throttle::parse_limit,
+        env = "KEYRING_STORE_BANDWIDTH_LIMIT",
+        value_delimiter = ','
+    )]
+    bandwidth_limits: Vec<ratelimit::RateLimit>,
+
+    /// Seconds between expired-blob sweeps (0 disables the sweeper).
+    #[arg(long, default_value_t = 60, env = "KEYRING_STORE_TTL_SWEEP_INTERVAL_SECS")]
+    ttl_sweep_interval_secs: u64,
+
+    /// Most queued single-item writes committed together in one
+    /// transaction (1 disables group commit).
+    #[arg(
+        long,
+        default_value_t = server::Config::default().group_commit_max_ops,
+        env = "KEYRING_STORE_GROUP_COMMIT_MAX_OPS"
+    )]
+    group_commit_max_ops: usize,
+
+    /// Milliseconds a write waits for others to share its commit (0 only
+    /// groups writes that are already queued).
+    #[arg(long, default_value_t = 0, env = "KEYRING_STORE_GROUP_COMMIT_WINDOW_MS")]
+    group_commit_window_ms: u64,
+
+    /// Run GC, a sampled integrity check and compaction once per window,
+    /// as `[DAYS ]HH:MM-HH:MM` in UTC (e.g. `sat,sun 01:00-05:00`).
+    #[arg(long, value_name = "WINDOW", env = "KEYRING_STORE_MAINTENANCE_WINDOW")]
+    maintenance_window: Option<maintenance::Window>,
+
+    /// Seconds between snapshots published for `--read-only` openers (0
+    /// publishes none).
+    #[arg(long, default_value_t = 0, env = "KEYRING_STORE_SNAPSHOT_INTERVAL_SECS")]
+    snapshot_interval_secs: u64,
+
+    /// Seconds between checks of the doc hash buckets against the doc
+    /// hashes, pushing an IntegrityAlert on drift (0 disables the checks).
+    #[arg(long, default_value_t = 0, env = "KEYRING_STORE_ANTI_ENTROPY_INTERVAL_SECS")]
+    anti_entropy_interval_secs: u64,
+
+    /// Address to accept direct sync connections from other stores on
+    /// (e.g. `0.0.0.0:7420`); peers can read the root database's changes.
+    #[arg(long, value_name = "ADDR", env = "KEYRING_STORE_PEER_LISTEN")]
+    peer_listen: Option<SocketAddr>,
+
+    /// Peer connections served at once; more are refused.
+    #[arg(long, default_value_t = 16, env = "KEYRING_STORE_PEER_MAX_CONNECTIONS")]
+    peer_max_connections: usize,
+
+    /// `--peer-listen` address of another store to pull changes from.  May
+    /// be repeated.
+    #[arg(long = "peer", value_name = "ADDR", env = "KEYRING_STORE_PEER", value_delimiter = ',')]
+    peers: Vec<String>,
+
+    /// Seconds between pulls from each `--peer`.
+    #[arg(long, default_value_t = 30, env = "KEYRING_STORE_PEER_SYNC_INTERVAL_SECS")]
+    peer_sync_interval_secs: u64,
+
+    /// Wrap the reply to every request that changed documents in WithRoot,
+    /// carrying the namespace's combined Merkle root after the change.
+    #[arg(long, env = "KEYRING_STORE_ROOT_IN_REPLIES")]
+    root_in_replies: bool,
 }
 
-// ── Request dispatch ──────────────────────────────────────────────────
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Serve requests against the database at --data-dir until the input
+    /// closes or SIGTERM/SIGINT.  Running with no subcommand does the same.
+    Serve(Box<ServeArgs>),
 
-fn handle_request(store: &Store, req: Request) -> Response {
-    match req {
-        Request::PutBlob { data } => match store.put_blob(&data) {
-            Ok(hash) => Response::BlobStored { hash },
-            Err(e) => Response::Error { message: e.to_string() },
-        },
+    /// Feed the requests from a capture file into the store at --data-dir
+    /// and compare the responses against the recorded ones.
+    Replay {
+        /// Capture file written by --record.
+        capture: PathBuf,
+    },
 
-        Request::GetBlob { hash } => match store.get_blob(&hash) {
-            Ok(Some(data)) => Response::Blob { data },
-            Ok(None) => Response::NotFound,
-            Err(e) => Response::Error { message: e.to_string() },
-        },
+    /// Decode frames and print their ref_id and request or response as
+    /// JSON: FRAME as hex or base64, with or without its length prefix, or
+    /// one per line on stdin, or every record of a capture file.
+    DecodeFrame {
+        /// Frame payload as hex or base64 (stdin, one per line, if omitted
+        /// or `-`).
+        #[arg(conflicts_with = "capture")]
+        frame: Option<String>,
 
-        Request::HasBlob { hash } => match store.has_blob(&hash) {
-            Ok(exists) => Response::BlobExists { exists },
-            Err(e) => Response::Error { message: e.to_string() },
-        },
+        /// Capture file written by --record to decode instead.
+        #[arg(long, value_name = "FILE")]
+        capture: Option<PathBuf>,
 
-        Request::PutDocument { id, meta, crdt_state } => {
-            match store.put_document(&id, &meta, &crdt_state) {
-                Ok(()) => Response::Ok,
-                Err(e) => Response::Error { message: e.to_string() },
-            }
-        }
+        /// Decode as `request` or `response` rather than whichever fits.
+        #[arg(long = "as", value_name = "KIND")]
+        kind: Option<decode::Kind>,
 
-        Request::GetDocument { id } => match store.get_document(&id) {
-            Ok(Some((meta, crdt_state))) => Response::Document { id, meta, crdt_state },
-            Ok(None) => Response::NotFound,
-            Err(e) => Response::Error { message: e.to_string() },
-        },
-
-        Request::DeleteDocument { id } => match store.delete_document(&id) {
-            Ok(true) => Response::Ok,
-            Ok(false) => Response::NotFound,
-            Err(e) => Response::Error { message: e.to_string() },
-        },
-
-        Request::ListDocuments => match store.list_documents() {
-            Ok(ids) => Response::DocumentList { ids },
-            Err(e) => Response::Error { message: e.to_string() },
-        },
-
-        Request::GetRoots { doc_ids } => {
-            let hashes = if doc_ids.is_empty() {
-                store.all_doc_hashes()
-            } else {
-                store.get_doc_hashes(&doc_ids)
-            };
-            match hashes {
-                Ok(pairs) => {
-                    let roots = pairs
-                        .into_iter()
-                        .map(|(doc_id, hash)| Root { doc_id, hash })
-                        .collect();
-                    Response::Roots { roots }
-                }
-                Err(e) => Response::Error { message: e.to_string() },
-            }
-        }
+        /// Print byte fields in full instead of cutting them short.
+        #[arg(long)]
+        full: bool,
+    },
 
-        Request::GetChanges { known_roots } => {
-            // Compare known roots against local state to find what to send.
-            match store.all_doc_hashes() {
-                Ok(local_pairs) => {
-                    // Build a set of known hashes for quick lookup.
-                    let known_set: std::collections::HashSet<Vec<u8>> =
-                        known_roots.into_iter().collect();
-                    let mut changes = Vec::new();
-                    for (doc_id, hash) in &local_pairs {
-                        if !known_set.contains(hash) {
-                            // Remote doesn't have this version — include the data.
-                            match store.get_document(doc_id) {
-                                Ok(Some((_meta, crdt_state))) => {
-                                    changes.push(Change {
-                                        doc_id: doc_id.clone(),
-                                        data: crdt_state,
-                                        hash: hash.clone(),
-                                    });
-                                }
-                                Ok(None) => {} // deleted between reads, skip
-                                Err(e) => {
-                                    return Response::Error { message: e.to_string() };
-                                }
-                            }
-                        }
-                    }
-                    Response::Changes { changes }
-                }
-                Err(e) => Response::Error { message: e.to_string() },
-            }
+    /// Compact the database at --data-dir, returning free pages to the
+    /// filesystem.  Run it while no port is serving the directory.
+    Compact,
+
+    /// Write a consistent copy of the database at --data-dir to the
+    /// directory DEST.  While a port is serving the directory, send it a
+    /// Backup request instead.
+    Backup {
+        /// Directory to create the backup in; must not hold a database.
+        dest: PathBuf,
+    },
+
+    /// Replace the database at --data-dir with a backup, after checking it
+    /// and rebuilding its document hashes.  Run it while no port is
+    /// serving the directory; the old file is kept as
+    /// keyring.redb.pre-restore.
+    Restore {
+        /// Directory written by `backup` or a Backup request.
+        backup: PathBuf,
+    },
+
+    /// Write the database at --data-dir to a portable tar archive of its
+    /// documents, tombstones and blobs, independent of the redb file
+    /// format.
+    Export {
+        /// Archive file to create.
+        archive: PathBuf,
+
+        /// Export only this namespace (`''` for the default one).  May be
+        /// repeated.
+        #[arg(long = "namespace", value_name = "NS")]
+        namespaces: Vec<String>,
+    },
+
+    /// Load an archive written by `export` into the database at
+    /// --data-dir, verifying every blob and document against its hash.
+    Import {
+        /// Archive file to read.
+        archive: PathBuf,
+
+        /// Keep local documents whose ids the archive also holds (default).
+        #[arg(long, conflicts_with = "replace")]
+        merge: bool,
+
+        /// Overwrite local documents whose ids the archive also holds.
+        #[arg(long)]
+        replace: bool,
+
+        /// Import only this namespace of the archive (`''` for the default
+        /// one).  May be repeated.
+        #[arg(long = "namespace", value_name = "NS")]
+        namespaces: Vec<String>,
+    },
+
+    /// Apply the schema migrations the database at --data-dir and its
+    /// tenants lack, copying each file to keyring.redb.v<N>.bak first.  Run
+    /// it while no port is serving the directory.
+    Migrate {
+        /// Only list the migrations that would run.
+        #[arg(long)]
+        dry_run: bool,
+    },
+
+    /// Report, per namespace of the database at --data-dir, the blobs no
+    /// document references, the expired blobs and the history versions
+    /// beyond --history-depth; with --apply, delete them.  Run it while no
+    /// port is serving the directory.
+    Gc {
+        /// Only report what would be deleted (default).
+        #[arg(long, conflicts_with = "apply")]
+        dry_run: bool,
+
+        /// Delete what the report lists.
+        #[arg(long)]
+        apply: bool,
+    },
+
+    /// Print the sizes, page usage and per-table counts a Stats request
+    /// reports, for the database at --data-dir.  Run it while no port is
+    /// serving the directory, e.g. on a backup.
+    Stats {
+        /// Print the statistics as JSON.
+        #[arg(long)]
+        json: bool,
+
+        /// Namespace whose blob bytes to count (`''` for the default one).
+        #[arg(long, value_name = "NS", default_value = "")]
+        namespace: String,
+    },
+
+    /// Re-hash the stored bytes of a blob, or of every blob, in every
+    /// namespace and compare them with the hash they are stored under.
+    /// Prints each mismatch with the documents referencing the blob and
+    /// exits non-zero if there are any.
+    VerifyBlob {
+        /// Hex blake3 hash of the blob.
+        #[arg(required_unless_present = "all", conflicts_with = "all")]
+        hash: Option<String>,
+
+        /// Check every blob.
+        #[arg(long)]
+        all: bool,
+    },
+
+    /// Deep-verify the database at --data-dir, printing each problem, and
+    /// exit non-zero if there are any.  Run it while no port is serving
+    /// the directory.
+    Fsck,
+
+    /// Run a benchmark against the store at --data-dir, which it writes
+    /// to, and print throughput and latency percentiles.  Point it at a
+    /// scratch directory.
+    Bench {
+        /// `put-blob`, `get-document` or `get-changes`.
+        #[arg(long, default_value = "put-blob")]
+        workload: bench::Workload,
+
+        /// Operations to run, shared out between the threads.
+        #[arg(long, default_value_t = 10_000)]
+        ops: u64,
+
+        /// Threads sending requests.
+        #[arg(long, default_value_t = 4)]
+        concurrency: usize,
+
+        /// Bytes in each blob or document state.
+        #[arg(long, default_value_t = 4096)]
+        value_bytes: usize,
+
+        /// Documents written before a `get-document` or `get-changes` run.
+        #[arg(long, default_value_t = 1000)]
+        documents: u64,
+
+        /// Fill values with repeated text rather than random bytes, to see
+        /// what compression gains.
+        #[arg(long)]
+        compressible: bool,
+    },
+
+    /// Read commands such as `put-doc notes/1 @file.bin` or `get-blob
+    /// HASH` from stdin and print the replies, against the store at
+    /// --data-dir or a running endpoint.  `help` lists the commands.
+    Repl {
+        /// Send the requests to this `host:port`, or Unix socket path (any
+        /// value with a `/`), instead of opening --data-dir: a port served
+        /// with `--transport tcp` or `uds`.  KEYRING_STORE_AUTH_TOKEN is
+        /// presented to a TCP endpoint if it is set.
+        #[arg(long, value_name = "ADDR")]
+        connect: Option<String>,
+    },
+
+    /// Fix the recoverable inconsistencies a Verify request reports in the
+    /// database at --data-dir, printing each change.
+    Repair {
+        /// Also remove documents whose data is gone, so that the next sync
+        /// fetches them again from a peer.
+        #[arg(long)]
+        drop_documents_without_data: bool,
+    },
+}
+
+impl Cli {
+    fn store_options(&self) -> Result<StoreOptions> {
+        let encryption_key = match std::env::var(KEY_ENV) {
+            Ok(hex) => Some(Key::from_hex(&hex).with_context(|| format!("reading {KEY_ENV}"))?),
+            Err(_) => None,
+        };
+        let signing_key = match std::env::var(SIGNING_KEY_ENV) {
+            Ok(hex) => Some(
+                SigningKey::from_hex(&hex).with_context(|| format!("reading {SIGNING_KEY_ENV}"))?,
+            ),
+            Err(_) => None,
+        };
+        let trusted_signers = self
+            .trusted_signers
+            .iter()
+            .map(|hex| {
+                store::parse_public_key(hex).with_context(|| format!("--trusted-signer {hex}"))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        if self.require_signed_roots && trusted_signers.is_empty() {
+            bail!("--require-signed-roots needs at least one --trusted-signer");
         }
+        Ok(StoreOptions {
+            compression_level: Some(self.blob_compression_level).filter(|&l| l != 0),
+            spill_threshold: Some(self.spill_threshold_bytes).filter(|&t| t != 0),
+            blob_backend: self.blob_backend,
+            cache_bytes: self.cache_bytes,
+            blob_cache_bytes: self.blob_cache_bytes,
+            durability: self.durability,
+            history_depth: self.history_depth,
+            encryption_key,
+            conflict_policy: self.conflict_policy,
+            namespace_conflict_policies: self
+                .namespace_conflict_policies
+                .iter()
+                .map(|p| (p.namespace.clone(), p.policy))
+                .collect(),
+            search_fields: self.search_fields.clone(),
+            track_access: self.track_access,
+            max_blob_bytes: self.max_blob_bytes,
+            max_doc_bytes: self.max_doc_bytes,
+            db_cache_bytes: self.db_cache_bytes,
+            db_file_format_v3: self.db_file_format_v3,
+            create: self.create,
+            migrate_on_open: !self.no_migrate_on_open,
+            id_policy: IdPolicy {
+                max_len: self.max_id_len,
+                allowed: self.id_chars.clone(),
+                reserved_prefixes: self.reserved_id_prefixes.clone(),
+            },
+            signing_key,
+            trusted_signers,
+            require_signed_roots: self.require_signed_roots,
+            read_only: self.read_only,
+            ..Default::default()
+        })
+    }
+}
 
-        Request::ApplyChanges { changes } => {
-            for change in changes {
-                // Only apply if we don't already have this exact version.
-                match store.get_doc_hash(&change.doc_id) {
-                    Ok(Some(existing)) if existing == change.hash => continue,
-                    Ok(_) => {}
-                    Err(e) => return Response::Error { message: e.to_string() },
-                }
-                // Store the CRDT state; meta is empty for remote changes
-                // (the real app would merge CRDTs here).
-                if let Err(e) = store.put_document(&change.doc_id, &[], &change.data) {
-                    return Response::Error { message: e.to_string() };
-                }
-            }
-            Response::Ok
+impl ServeArgs {
+    fn server_config(&self) -> server::Config {
+        server::Config {
+            changes_chunk_bytes: self.changes_chunk_bytes,
+            rate_limits: self.rate_limits.clone(),
+            bandwidth_limits: self.bandwidth_limits.clone(),
+            ttl_sweep_interval: Some(Duration::from_secs(self.ttl_sweep_interval_secs))
+                .filter(|d| !d.is_zero()),
+            group_commit_max_ops: self.group_commit_max_ops,
+            group_commit_window: Duration::from_millis(self.group_commit_window_ms),
+            maintenance_window: self.maintenance_window.clone(),
+            anti_entropy_interval: Some(Duration::from_secs(self.anti_entropy_interval_secs))
+                .filter(|d| !d.is_zero()),
+            peer_listen: self.peer_listen,
+            peer_max_connections: self.peer_max_connections,
+            max_clients: self.max_clients,
+            peers: self.peers.clone(),
+            peer_sync_interval: Duration::from_secs(self.peer_sync_interval_secs),
+            root_in_replies: self.root_in_replies,
+            snapshot_interval: Some(Duration::from_secs(self.snapshot_interval_secs))
+                .filter(|d| !d.is_zero()),
         }
     }
 }
 
-// ── Main loop ─────────────────────────────────────────────────────────
+// ── Main ──────────────────────────────────────────────────────────────
 
 fn main() -> Result<()> {
     // Logs to stderr so stdout stays clean for the binary protocol.
@@ -178,34 +658,499 @@ fn main() -> Result<()> {
         .init();
 
     let cli = Cli::parse();
-    info!(data_dir = %cli.data_dir.display(), "keyring-store starting");
+    let options = cli.store_options()?;
+    if let Some(key) = &options.signing_key {
+        info!(?key, "signing combined roots");
+    }
 
-    let store = Store::open(&cli.data_dir)?;
+    match cli.command {
+        None => serve(&cli.data_dir, options, cli.serve),
+        Some(Command::Serve(args)) => serve(&cli.data_dir, options, *args),
+        Some(Command::Replay { capture }) => replay(&cli.data_dir, options, &capture),
+        Some(Command::DecodeFrame {
+            frame,
+            capture,
+            kind,
+            full,
+        }) => decode_frames(frame.as_deref(), capture.as_deref(), kind, full),
+        Some(Command::Compact) => compact(&cli.data_dir, options),
+        Some(Command::Backup { dest }) => backup(&cli.data_dir, options, &dest),
+        Some(Command::Restore { backup }) => restore(&cli.data_dir, options, &backup),
+        Some(Command::Export {
+            archive,
+            namespaces,
+        }) => export(&cli.data_dir, options, &archive, &namespaces),
+        Some(Command::Import {
+            archive,
+            replace,
+            namespaces,
+            ..
+        }) => {
+            let policy = if replace {
+                ImportPolicy::Replace
+            } else {
+                ImportPolicy::Merge
+            };
+            import(&cli.data_dir, options, &archive, policy, &namespaces)
+        }
+        Some(Command::Migrate { dry_run }) => migrate(&cli.data_dir, options, dry_run),
+        Some(Command::Gc { apply, .. }) => gc(&cli.data_dir, options, apply),
+        Some(Command::Stats { json, namespace }) => stats(&cli.data_dir, options, &namespace, json),
+        Some(Command::VerifyBlob { hash, .. }) => {
+            verify_blob(&cli.data_dir, options, hash.as_deref())
+        }
+        Some(Command::Fsck) => fsck(&cli.data_dir, options),
+        Some(Command::Bench {
+            workload,
+            ops,
+            concurrency,
+            value_bytes,
+            documents,
+            compressible,
+        }) => {
+            let config = bench::Config {
+                workload,
+                ops,
+                concurrency,
+                value_bytes,
+                documents,
+                compressible,
+            };
+            bench(&cli.data_dir, options, &config)
+        }
+        Some(Command::Repl { connect }) => repl(&cli.data_dir, options, connect.as_deref()),
+        Some(Command::Repair {
+            drop_documents_without_data,
+        }) => repair(&cli.data_dir, options, drop_documents_without_data),
+    }
+}
 
-    let mut stdin = io::stdin().lock();
-    let mut stdout = io::stdout().lock();
+fn serve(data_dir: &Path, options: StoreOptions, args: ServeArgs) -> Result<()> {
+    info!(data_dir = %data_dir.display(), transport = %args.transport, "keyring-store starting");
 
-    loop {
-        let frame = match read_frame(&mut stdin)? {
-            Some(f) => f,
-            None => {
-                info!("stdin closed, shutting down");
-                break;
-            }
+    let store = Store::open(data_dir, options)?;
+    let listener = match args.transport {
+        Transport::Stdio => None,
+        transport => {
+            let addr = args
+                .listen
+                .as_deref()
+                .with_context(|| format!("--transport {transport} needs --listen"))?;
+            let listener = Listener::bind(transport, addr, auth_token()?)?;
+            info!(%addr, "accepting clients");
+            Some(listener)
+        }
+    };
+    let recorder = match &args.record {
+        Some(path) => {
+            info!(capture = %path.display(), "recording frames");
+            Some(Recorder::open(path)?)
+        }
+        None => None,
+    };
+
+    let server = Server::new(store, args.server_config());
+    for signal in [SIGTERM, SIGINT] {
+        signal_hook::flag::register(signal, server.shutdown_flag())?;
+    }
+
+    match listener {
+        Some(listener) => server.run_listener(listener, recorder),
+        None => server.run(io::stdin(), io::stdout().lock(), recorder),
+    }
+}
+
+/// The token in `AUTH_TOKEN_ENV`, if it is set.
+fn auth_token() -> Result<Option<AuthToken>> {
+    match std::env::var(AUTH_TOKEN_ENV) {
+        Ok(token) => Ok(Some(
+            AuthToken::new(token).with_context(|| format!("reading {AUTH_TOKEN_ENV}"))?,
+        )),
+        Err(_) => Ok(None),
+    }
+}
+
+fn replay(data_dir: &Path, options: StoreOptions, capture: &Path) -> Result<()> {
+    let records = capture::read_capture(capture)?;
+    let server = Server::new(Store::open(data_dir, options)?, server::Config::default());
+    let summary = capture::replay(&server, &records)?;
+
+    println!(
+        "replayed {} requests: {} matched, {} mismatched, {} without recorded response",
+        summary.requests,
+        summary.matched,
+        summary.mismatched.len(),
+        summary.unrecorded
+    );
+    for ref_id in &summary.mismatched {
+        println!("  mismatch: ref_id {ref_id}");
+    }
+    Ok(())
+}
+
+fn decode_frames(
+    frame: Option<&str>,
+    capture: Option<&Path>,
+    kind: Option<decode::Kind>,
+    full: bool,
+) -> Result<()> {
+    let show = |decoded: &decode::Decoded, bytes: usize| -> Result<()> {
+        let what = match decoded {
+            decode::Decoded::Request(_) => "request",
+            decode::Decoded::Response(..) => "response",
         };
+        println!("{what} ref_id {} ({bytes} bytes)", decoded.ref_id());
+        println!("{}", decoded.to_json(full)?);
+        Ok(())
+    };
+
+    if let Some(capture) = capture {
+        let mut failed = 0;
+        for (n, record) in capture::read_capture(capture)?.iter().enumerate() {
+            let (direction, record_kind) = match record.direction {
+                capture::Direction::Inbound => ("inbound", decode::Kind::Request),
+                capture::Direction::Outbound => ("outbound", decode::Kind::Response),
+            };
+            println!("#{n} {direction} at {} (unix micros)", record.timestamp_us);
+            match decode::decode(&record.payload, Some(kind.unwrap_or(record_kind))) {
+                Ok(decoded) => show(&decoded, record.payload.len())?,
+                Err(e) => {
+                    println!("error: {e:#}");
+                    failed += 1;
+                }
+            }
+        }
+        if failed > 0 {
+            bail!("{failed} records did not decode");
+        }
+        return Ok(());
+    }
+
+    let frames = match frame {
+        Some(frame) if frame != "-" => vec![frame.to_string()],
+        _ => io::stdin()
+            .lines()
+            .filter(|line| line.as_ref().map_or(true, |l| !l.trim().is_empty()))
+            .collect::<io::Result<_>>()?,
+    };
+    for text in &frames {
+        let bytes = decode::parse_text(text)?;
+        let payload = decode::strip_length_prefix(&bytes);
+        show(&decode::decode(payload, kind)?, payload.len())?;
+    }
+    Ok(())
+}
 
-        let (ref_id, request): (RefId, Request) =
-            bincode::deserialize(&frame).context("decoding request frame")?;
+fn compact(data_dir: &Path, options: StoreOptions) -> Result<()> {
+    let (before, after) = Store::open(data_dir, options)?.compact()?;
+    println!("compacted {}: {before} → {after} bytes", data_dir.display());
+    Ok(())
+}
 
-        debug!(ref_id, ?request, "received request");
+fn backup(data_dir: &Path, options: StoreOptions, dest: &Path) -> Result<()> {
+    let report = Store::open(data_dir, options)?.backup(dest)?;
+    println!(
+        "backed up {} to {}: {} bytes, {} spilled blobs",
+        data_dir.display(),
+        dest.display(),
+        report.file_bytes,
+        report.spilled_blobs
+    );
+    Ok(())
+}
 
-        let response = handle_request(&store, request);
+fn restore(data_dir: &Path, options: StoreOptions, backup: &Path) -> Result<()> {
+    let report = store::restore(backup, data_dir, options.encryption_key.as_ref())?;
+    println!(
+        "restored {} from {}: {} documents, {} document hashes rebuilt, {} spilled blobs",
+        data_dir.display(),
+        backup.display(),
+        report.documents,
+        report.rebuilt_hashes,
+        report.spilled_blobs
+    );
+    Ok(())
+}
 
-        debug!(ref_id, ?response, "sending response");
+fn export(
+    data_dir: &Path,
+    options: StoreOptions,
+    archive: &Path,
+    namespaces: &[String],
+) -> Result<()> {
+    let store = Store::open(data_dir, options)?;
+    // Written under a temporary name so a failed export leaves nothing
+    // that looks like an archive.
+    let partial = archive.with_extension("partial");
+    let file = File::create(&partial)
+        .with_context(|| format!("creating {}", partial.display()))?;
+    let report = match store.export(BufWriter::new(file), namespaces) {
+        Ok(report) => report,
+        Err(e) => {
+            let _ = fs::remove_file(&partial);
+            return Err(e);
+        }
+    };
+    fs::rename(&partial, archive)
+        .with_context(|| format!("renaming into {}", archive.display()))?;
+    println!(
+        "exported {} to {}: {} documents, {} tombstones, {} blobs",
+        data_dir.display(),
+        archive.display(),
+        report.documents,
+        report.tombstones,
+        report.blobs
+    );
+    Ok(())
+}
 
-        let resp_bytes = bincode::serialize(&(ref_id, &response))?;
-        write_frame(&mut stdout, &resp_bytes)?;
+fn import(
+    data_dir: &Path,
+    options: StoreOptions,
+    archive: &Path,
+    policy: ImportPolicy,
+    namespaces: &[String],
+) -> Result<()> {
+    let store = Store::open(data_dir, options)?;
+    let file = File::open(archive).with_context(|| format!("opening {}", archive.display()))?;
+    let report = store.import(BufReader::new(file), policy, namespaces)?;
+    println!(
+        "imported {} into {}: {} documents, {} tombstones, {} blobs, {} skipped",
+        archive.display(),
+        data_dir.display(),
+        report.documents,
+        report.tombstones,
+        report.blobs,
+        report.skipped
+    );
+    Ok(())
+}
+
+fn migrate(data_dir: &Path, options: StoreOptions, dry_run: bool) -> Result<()> {
+    let options = StoreOptions {
+        create: CreateMode::Never,
+        migrate_on_open: true,
+        ..options
+    };
+    let mut dirs = vec![data_dir.to_path_buf()];
+    dirs.extend(tenants::tenant_dirs(data_dir)?);
+    for dir in &dirs {
+        let plan = store::plan_migrations(dir, &options)?;
+        if plan.pending.is_empty() {
+            println!("{}: schema version {}, up to date", dir.display(), plan.version);
+            continue;
+        }
+        println!(
+            "{}: schema version {} → {}, backup at {}",
+            dir.display(),
+            plan.version,
+            plan.target,
+            plan.backup.display()
+        );
+        for (version, description) in &plan.pending {
+            println!("  v{version}: {description}");
+        }
+        if !dry_run {
+            Store::open(dir, options.clone())?;
+            println!("  migrated");
+        }
+    }
+    Ok(())
+}
+
+fn gc(data_dir: &Path, options: StoreOptions, apply: bool) -> Result<()> {
+    let store = Store::open(data_dir, options)?;
+    let now = store::unix_now();
+    let mut namespaces = vec![String::new()];
+    namespaces.extend(store.namespaces()?);
+    for namespace in &namespaces {
+        let handle = store.namespace(namespace)?;
+        // Expired blobs go first, so the GC pass doesn't count them too.
+        let expired = if apply {
+            handle.sweep_expired(now)?
+        } else {
+            handle.count_expired(now)?
+        };
+        let history = handle.trim_history(!apply)?;
+        let blobs = handle.gc(!apply)?;
+        println!(
+            "{}: {} unreferenced blobs ({} bytes), {} expired blobs, \
+             {} history versions of {} documents over retention ({} bytes)",
+            if namespace.is_empty() { "default" } else { namespace },
+            blobs.unreferenced,
+            blobs.reclaimable_bytes,
+            expired,
+            history.versions,
+            history.documents,
+            history.bytes
+        );
+    }
+    if apply {
+        println!("deleted; run `compact` to return the space to the filesystem");
+    } else {
+        println!("dry run: nothing deleted; an expired blob may be unreferenced too");
+    }
+    Ok(())
+}
+
+fn stats(data_dir: &Path, options: StoreOptions, namespace: &str, json: bool) -> Result<()> {
+    // Statistics of an empty database made up on the spot would mislead.
+    let options = StoreOptions {
+        create: CreateMode::Never,
+        ..options
+    };
+    let store = Store::open(data_dir, options)?;
+    if !namespace.is_empty() && !store.namespaces()?.iter().any(|ns| ns == namespace) {
+        bail!("no namespace {namespace:?}");
+    }
+    let stats = store.namespace(namespace)?.stats()?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&stats)?);
+        return Ok(());
+    }
+
+    println!("file:            {} bytes", stats.file_bytes);
+    println!(
+        "pages:           {} allocated, {} free ({} bytes compaction would reclaim), {} bytes each",
+        stats.allocated_pages,
+        stats.free_pages,
+        stats.free_pages * stats.page_size,
+        stats.page_size
+    );
+    println!("stored:          {} bytes", stats.stored_bytes);
+    println!("metadata:        {} bytes", stats.metadata_bytes);
+    println!("fragmented:      {} bytes", stats.fragmented_bytes);
+    println!("blobs:           {} bytes uncompressed", stats.blob_bytes);
+    match stats.last_compaction {
+        Some(at) => println!("last compaction: {at} (unix seconds)"),
+        None => println!("last compaction: never"),
+    }
+    println!("{:<32} {:>12} {:>14} {:>6}", "table", "entries", "stored bytes", "height");
+    for table in &stats.tables {
+        println!(
+            "{:<32} {:>12} {:>14} {:>6}",
+            table.name, table.entries, table.stored_bytes, table.tree_height
+        );
+    }
+    Ok(())
+}
+
+fn fsck(data_dir: &Path, options: StoreOptions) -> Result<()> {
+    // A missing database would otherwise be created, and pass as empty.
+    let options = StoreOptions {
+        create: CreateMode::Never,
+        ..options
+    };
+    let report = Store::open(data_dir, options)?.verify(true)?;
+    let problems = &report.problems;
+    for problem in &problems.listed {
+        println!("{} {:?}: {}", problem.table, problem.key, problem.message);
+    }
+    if problems.count > problems.listed.len() as u64 {
+        println!("… and {} more", problems.count - problems.listed.len() as u64);
+    }
+    println!(
+        "checked {}: {} documents, {} blobs, {} problems",
+        data_dir.display(),
+        report.documents,
+        report.blobs,
+        problems.count
+    );
+    if problems.count > 0 {
+        bail!("{} failed verification", data_dir.display());
+    }
+    Ok(())
+}
+
+fn verify_blob(data_dir: &Path, options: StoreOptions, hash: Option<&str>) -> Result<()> {
+    let options = StoreOptions {
+        create: CreateMode::Never,
+        ..options
+    };
+    let hash = hash.map(store::from_hex).transpose()?;
+    let store = Store::open(data_dir, options)?;
+    let mut namespaces = vec![String::new()];
+    namespaces.extend(store.namespaces()?);
+    let (mut checked, mut bad) = (0, 0);
+    for namespace in &namespaces {
+        let handle = store.namespace(namespace)?;
+        let report = handle.verify_blob_contents(hash.as_deref())?;
+        let problems = &report.problems;
+        for problem in &problems.listed {
+            println!("{} {}: {}", problem.table, problem.key, problem.message);
+            let referrers = handle.who_references(&store::from_hex(&problem.key)?)?;
+            if referrers.is_empty() {
+                println!("  referenced by no documents");
+            }
+            for id in referrers {
+                println!("  referenced by {id:?}");
+            }
+        }
+        if problems.count > problems.listed.len() as u64 {
+            println!("… and {} more", problems.count - problems.listed.len() as u64);
+        }
+        checked += report.blobs;
+        bad += problems.count;
     }
+    if let (Some(hash), 0) = (&hash, checked) {
+        bail!("no blob {} in any namespace", store::to_hex(hash));
+    }
+    println!("checked {checked} blobs, {bad} do not match their hash");
+    if bad > 0 {
+        bail!("{} failed blob verification", data_dir.display());
+    }
+    Ok(())
+}
 
+fn bench(data_dir: &Path, options: StoreOptions, config: &bench::Config) -> Result<()> {
+    let server = Server::new(Store::open(data_dir, options)?, server::Config::default());
+    let report = bench::run(&server, config)?;
+    let ms = |p| report.percentile(p).as_secs_f64() * 1000.0;
+    println!(
+        "{:?}: {} ops on {} threads in {:.2}s: {:.0} ops/s, {:.1} MiB/s of replies",
+        config.workload,
+        report.ops,
+        config.concurrency,
+        report.elapsed.as_secs_f64(),
+        report.ops_per_sec(),
+        report.bytes as f64 / report.elapsed.as_secs_f64() / (1024.0 * 1024.0)
+    );
+    println!(
+        "latency ms: p50 {:.3}, p90 {:.3}, p99 {:.3}, p99.9 {:.3}, max {:.3}",
+        ms(50.0),
+        ms(90.0),
+        ms(99.0),
+        ms(99.9),
+        ms(100.0)
+    );
+    Ok(())
+}
+
+fn repl(data_dir: &Path, options: StoreOptions, connect: Option<&str>) -> Result<()> {
+    let mut endpoint = match connect {
+        Some(addr) => repl::Endpoint::connect(addr, auth_token()?.as_ref())?,
+        None => repl::Endpoint::Local(Box::new(Server::new(
+            Store::open(data_dir, options)?,
+            server::Config::default(),
+        ))),
+    };
+    let stdin = io::stdin();
+    let prompt = stdin.is_terminal();
+    repl::run(&mut endpoint, stdin.lock(), prompt)
+}
+
+fn repair(
+    data_dir: &Path,
+    options: StoreOptions,
+    drop_documents_without_data: bool,
+) -> Result<()> {
+    let fixes = Store::open(data_dir, options)?.repair(drop_documents_without_data)?;
+    for fix in &fixes.listed {
+        println!("{} {:?}: {}", fix.table, fix.key, fix.message);
+    }
+    if fixes.count > fixes.listed.len() as u64 {
+        println!("… and {} more", fixes.count - fixes.listed.len() as u64);
+    }
+    println!("repaired {}: {} changes", data_dir.display(), fixes.count);
     Ok(())
 }
@@ -0,0 +1,366 @@
+//! Scheduled maintenance during quiet hours.
+//!
+//! `--maintenance-window` names a daily UTC time range, optionally limited
+//! to some weekdays: `02:00-04:30`, `sat,sun 01:00-06:00`,
+//! `mon-fri 23:30-01:00`.  A window may cross midnight; it belongs to the
+//! day it starts on.  Once per window a background thread takes every open
+//! database in turn: it collects garbage in each namespace, deep-checks a
+//! sample of documents and blobs, and compacts the file.  Steps still
+//! pending when the window closes wait for the next one.  Progress goes to
+//! the log; `MaintenanceStatus` reports the window and the latest run.
+
+use crate::protocol::{MaintenanceRun, Response};
+use crate::store::{unix_now, Store};
+use crate::tenants::Tenants;
+use anyhow::{bail, Context, Result};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// How often the thread checks whether a window has opened, and notices
+/// shutdown.
+const TICK: Duration = Duration::from_secs(1);
+
+/// Documents and blobs deep-checked per namespace and run.
+const SAMPLE: u64 = 1000;
+
+const DAY: u64 = 24 * 60 * 60;
+
+const WEEKDAYS: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+
+/// When maintenance may run.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Window {
+    spec: String,
+    /// Bit `d` set if the window opens on weekday `d`, Monday being 0.
+    days: u8,
+    /// Opening time, in seconds after midnight UTC.
+    start: u64,
+    /// Length in seconds, less than a day.
+    len: u64,
+}
+
+impl FromStr for Window {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let spec = s.trim();
+        let (days, times) = match spec.rsplit_once(' ') {
+            Some((days, times)) => (parse_days(days.trim())?, times),
+            None => (0x7f, spec),
+        };
+        let (start, end) = times.split_once('-').context("expected [DAYS ]HH:MM-HH:MM")?;
+        let (start, end) = (parse_time(start)?, parse_time(end)?);
+        if start == end {
+            bail!("window opens and closes at the same time");
+        }
+        Ok(Self {
+            spec: spec.to_string(),
+            days,
+            start,
+            len: (end + DAY - start) % DAY,
+        })
+    }
+}
+
+impl fmt::Display for Window {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.spec)
+    }
+}
+
+impl Window {
+    /// When the occurrence of the window containing `now` opened, or `None`
+    /// outside the window.
+    pub fn current(&self, now: u64) -> Option<u64> {
+        let today = now / DAY;
+        // Only yesterday's occurrence can still be open, and only if it
+        // crosses midnight.
+        [today, today.saturating_sub(1)].into_iter().find_map(|day| {
+            let opens = day * DAY + self.start;
+            (self.opens_on(day) && opens <= now && now < opens + self.len).then_some(opens)
+        })
+    }
+
+    /// When the window next opens after `now`.
+    pub fn next_start(&self, now: u64) -> u64 {
+        let today = now / DAY;
+        (today..=today + 7)
+            .filter(|&day| self.opens_on(day))
+            .map(|day| day * DAY + self.start)
+            .find(|&opens| opens > now)
+            .expect("parsing guarantees at least one weekday")
+    }
+
+    fn opens_on(&self, day: u64) -> bool {
+        // 1970-01-01 was a Thursday.
+        self.days & (1 << ((day + 3) % 7)) != 0
+    }
+}
+
+/// `mon`, `sat,sun`, `mon-fri`, ... as a weekday bit set.
+fn parse_days(s: &str) -> Result<u8> {
+    let weekday = |name: &str| {
+        WEEKDAYS
+            .iter()
+            .position(|&d| d.eq_ignore_ascii_case(name))
+            .with_context(|| format!("unknown weekday {name:?}"))
+    };
+    let mut days = 0u8;
+    for part in s.split(',') {
+        let (first, last) = match part.split_once('-') {
+            Some((first, last)) => (weekday(first)?, weekday(last)?),
+            None => (weekday(part)?, weekday(part)?),
+        };
+        // A range may wrap past Sunday, e.g. `fri-mon`.
+        let mut day = first;
+        loop {
+            days |= 1 << day;
+            if day == last {
+                break;
+            }
+            day = (day + 1) % 7;
+        }
+    }
+    Ok(days)
+}
+
+/// `HH:MM` as seconds after midnight.
+fn parse_time(s: &str) -> Result<u64> {
+    let (hours, minutes) = s.split_once(':').with_context(|| format!("expected HH:MM, got {s:?}"))?;
+    let hours: u64 = hours.parse().context("invalid hour")?;
+    let minutes: u64 = minutes.parse().context("invalid minute")?;
+    if hours > 23 || minutes > 59 {
+        bail!("{s:?} is not a time of day");
+    }
+    Ok(hours * 3600 + minutes * 60)
+}
+
+#[derive(Default)]
+struct State {
+    /// Step in progress, e.g. `gc /data/tenants/a`.
+    running: Option<String>,
+    latest: Option<MaintenanceRun>,
+}
+
+/// The configured window, and what maintenance is doing or last did.
+#[derive(Default)]
+pub struct Maintenance {
+    window: Option<Window>,
+    state: Mutex<State>,
+}
+
+#[derive(Clone, Copy)]
+enum Step {
+    Gc,
+    Sample,
+    Compact,
+}
+
+impl Step {
+    fn name(self) -> &'static str {
+        match self {
+            Step::Gc => "gc",
+            Step::Sample => "verify sample",
+            Step::Compact => "compact",
+        }
+    }
+}
+
+impl Maintenance {
+    pub fn new(window: Option<Window>) -> Self {
+        Self {
+            window,
+            state: Mutex::default(),
+        }
+    }
+
+    /// Reply to `MaintenanceStatus`.
+    pub fn status(&self) -> Response {
+        let state = self.lock();
+        Response::MaintenanceStatus {
+            window: self.window.as_ref().map(Window::to_string),
+            next_window: self.window.as_ref().map(|w| w.next_start(unix_now())),
+            running: state.running.clone(),
+            latest: state.latest.clone(),
+        }
+    }
+
+    /// Run every step over every open database, stopping early if the
+    /// window that opened at `opened` closes or shutdown is requested.
+    fn run(&self, tenants: &Tenants, window: &Window, opened: u64, shutdown: &AtomicBool) {
+        info!(window = %window, "maintenance window opened");
+        self.lock().latest = Some(MaintenanceRun {
+            started_at: unix_now(),
+            ..MaintenanceRun::default()
+        });
+
+        for store in tenants.stores() {
+            for step in [Step::Gc, Step::Sample, Step::Compact] {
+                if shutdown.load(Ordering::SeqCst) || window.current(unix_now()) != Some(opened) {
+                    info!("maintenance window closed; remaining steps wait for the next one");
+                    self.finish(false);
+                    return;
+                }
+                let dir = store.dir().display().to_string();
+                info!(step = step.name(), dir = %dir, "maintenance step started");
+                self.lock().running = Some(format!("{} {dir}", step.name()));
+                if let Err(e) = self.step(&store, step) {
+                    warn!(step = step.name(), dir = %dir, error = %e, "maintenance step failed");
+                    self.record(|run| run.errors += 1);
+                }
+            }
+        }
+        self.finish(true);
+    }
+
+    fn step(&self, store: &Store, step: Step) -> Result<()> {
+        match step {
+            Step::Gc => {
+                let mut handles = vec![store.clone()];
+                for namespace in store.namespaces()? {
+                    handles.push(store.namespace(&namespace)?);
+                }
+                for handle in handles {
+                    let report = handle.gc(false)?;
+                    self.record(|run| {
+                        run.blobs_removed += report.unreferenced;
+                        run.blob_bytes_reclaimed += report.reclaimable_bytes;
+                    });
+                }
+            }
+            Step::Sample => {
+                let report = store.verify_sample(SAMPLE)?;
+                self.record(|run| {
+                    run.sampled += report.documents + report.blobs;
+                    run.problems += report.problems.count;
+                });
+            }
+            Step::Compact => {
+                let (before, after) = store.compact()?;
+                self.record(|run| run.file_bytes_reclaimed += before.saturating_sub(after));
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(&self, completed: bool) {
+        let mut state = self.lock();
+        state.running = None;
+        if let Some(run) = state.latest.as_mut() {
+            run.finished_at = Some(unix_now());
+            run.completed = completed;
+            info!(
+                completed,
+                blobs_removed = run.blobs_removed,
+                file_bytes_reclaimed = run.file_bytes_reclaimed,
+                sampled = run.sampled,
+                problems = run.problems,
+                errors = run.errors,
+                "maintenance finished"
+            );
+        }
+    }
+
+    fn record(&self, update: impl FnOnce(&mut MaintenanceRun)) {
+        if let Some(run) = self.lock().latest.as_mut() {
+            update(run);
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Run `maintenance` once per occurrence of its window until `shutdown` is
+/// set.  Does nothing without a window.
+pub fn spawn(
+    tenants: Tenants,
+    maintenance: Arc<Maintenance>,
+    shutdown: Arc<AtomicBool>,
+) -> Option<JoinHandle<()>> {
+    let window = maintenance.window.clone()?;
+    let handle = thread::Builder::new()
+        .name("maintenance".into())
+        .spawn(move || {
+            info!(window = %window, "maintenance scheduled");
+            // When the window last run in opened.
+            let mut done = None;
+            while !shutdown.load(Ordering::SeqCst) {
+                match window.current(unix_now()) {
+                    Some(opened) if done != Some(opened) => {
+                        maintenance.run(&tenants, &window, opened, &shutdown);
+                        done = Some(opened);
+                    }
+                    _ => thread::sleep(TICK),
+                }
+            }
+        })
+        .expect("spawning maintenance thread");
+    Some(handle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 2024-01-01, a Monday.
+    const MONDAY: u64 = 1_704_067_200;
+
+    fn at(day: u64, hours: u64, minutes: u64) -> u64 {
+        MONDAY + day * DAY + hours * 3600 + minutes * 60
+    }
+
+    #[test]
+    fn test_parse_window() {
+        let w: Window = "02:00-04:30".parse().unwrap();
+        assert_eq!((w.days, w.start, w.len), (0x7f, 7200, 9000));
+
+        let w: Window = "sat,sun 23:00-01:00".parse().unwrap();
+        assert_eq!((w.days, w.len), (0b110_0000, 7200));
+
+        let w: Window = "fri-mon 01:00-02:00".parse().unwrap();
+        assert_eq!(w.days, 0b111_0001);
+        assert_eq!(w.to_string(), "fri-mon 01:00-02:00");
+
+        assert!("02:00".parse::<Window>().is_err());
+        assert!("02:00-02:00".parse::<Window>().is_err());
+        assert!("24:00-01:00".parse::<Window>().is_err());
+        assert!("someday 01:00-02:00".parse::<Window>().is_err());
+    }
+
+    #[test]
+    fn test_current_window() {
+        let w: Window = "02:00-04:00".parse().unwrap();
+        assert_eq!(w.current(at(0, 1, 59)), None);
+        assert_eq!(w.current(at(0, 2, 0)), Some(at(0, 2, 0)));
+        assert_eq!(w.current(at(0, 3, 59)), Some(at(0, 2, 0)));
+        assert_eq!(w.current(at(0, 4, 0)), None);
+    }
+
+    #[test]
+    fn test_window_crossing_midnight() {
+        // Opens Sunday night only; Monday morning is still Sunday's window.
+        let w: Window = "sun 23:00-01:00".parse().unwrap();
+        assert_eq!(w.current(at(6, 23, 30)), Some(at(6, 23, 0)));
+        assert_eq!(w.current(at(7, 0, 30)), Some(at(6, 23, 0)));
+        assert_eq!(w.current(at(0, 23, 30)), None);
+        assert_eq!(w.current(at(1, 0, 30)), None);
+    }
+
+    #[test]
+    fn test_next_start() {
+        let w: Window = "sat 03:00-04:00".parse().unwrap();
+        assert_eq!(w.next_start(at(0, 12, 0)), at(5, 3, 0));
+        assert_eq!(w.next_start(at(5, 3, 0)), at(12, 3, 0));
+
+        let w: Window = "03:00-04:00".parse().unwrap();
+        assert_eq!(w.next_start(at(0, 2, 0)), at(0, 3, 0));
+        assert_eq!(w.next_start(at(0, 3, 30)), at(1, 3, 0));
+    }
+}
@@ -22,7 +22,7 @@ pub fn compute_root(pairs: &[(String, Vec<u8>)]) -> Vec<u8> {
     let mut layer: Vec<Vec<u8>> = sorted.into_iter().map(|(_, h)| h).collect();
 
     while layer.len() > 1 {
-        let mut next = Vec::with_capacity((layer.len() + 1) / 2);
+        let mut next = Vec::with_capacity(layer.len().div_ceil(2));
         let mut i = 0;
         while i + 1 < layer.len() {
             let mut hasher = blake3::Hasher::new();
@@ -41,6 +41,30 @@ pub fn compute_root(pairs: &[(String, Vec<u8>)]) -> Vec<u8> {
     layer.into_iter().next().unwrap()
 }
 
+/// Deepest `sub_roots` grouping `GetSubRoots` accepts.
+pub const MAX_SUB_ROOT_DEPTH: usize = 8;
+
+/// One `compute_root` per group of `pairs` sharing the first `depth`
+/// characters of their doc_id (the whole id, if shorter), as
+/// `(prefix, count, root)` in prefix order.  Depth 0 gives one group, whose
+/// root is `compute_root` of them all.
+pub fn sub_roots(pairs: &[(String, Vec<u8>)], depth: usize) -> Vec<(String, u64, Vec<u8>)> {
+    let mut sorted = pairs.to_vec();
+    sorted.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let prefix_of = |id: &str| -> String { id.chars().take(depth).collect() };
+    let mut out = Vec::new();
+    let mut start = 0;
+    while start < sorted.len() {
+        let prefix = prefix_of(&sorted[start].0);
+        let len = sorted[start..].iter().take_while(|(id, _)| prefix_of(id) == prefix).count();
+        let group = &sorted[start..start + len];
+        out.push((prefix, len as u64, compute_root(group)));
+        start += len;
+    }
+    out
+}
+
 /// Given local and remote root-sets, return `(to_send, to_request)`.
 ///
 /// * `to_send`    – doc_ids the remote is missing or has a different hash for.
@@ -100,6 +124,26 @@ mod tests {
         assert_eq!(root, h);
     }
 
+    #[test]
+    fn test_sub_roots() {
+        let pairs: Vec<(String, Vec<u8>)> = ["b2", "a1", "a", "b1", "ä"]
+            .iter()
+            .map(|id| (id.to_string(), blake3::hash(id.as_bytes()).as_bytes().to_vec()))
+            .collect();
+        let whole = sub_roots(&pairs, 0);
+        assert_eq!(whole, vec![(String::new(), 5, compute_root(&pairs))]);
+
+        let groups = sub_roots(&pairs, 1);
+        let prefixes: Vec<(&str, u64)> = groups.iter().map(|(p, n, _)| (p.as_str(), *n)).collect();
+        assert_eq!(prefixes, [("a", 2), ("b", 2), ("ä", 1)]);
+        let b: Vec<_> = pairs.iter().filter(|(id, _)| id.starts_with('b')).cloned().collect();
+        assert_eq!(groups[1].2, compute_root(&b));
+
+        let prefixes: Vec<String> = sub_roots(&pairs, 2).into_iter().map(|(p, ..)| p).collect();
+        assert_eq!(prefixes, ["a", "a1", "b1", "b2", "ä"]);
+        assert!(sub_roots(&[], 1).is_empty());
+    }
+
     #[test]
     fn test_diff_roots() {
         let h1 = blake3::hash(b"a").as_bytes().to_vec();
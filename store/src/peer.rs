@@ -0,0 +1,691 @@
+//! Direct sync between stores, without relaying through the port.
+//!
+//! `--peer-listen` accepts connections from other stores and serves them
+//! the read-only sync requests (`GetBucket`, `GetChanges` and the like)
+//! against the root database, in the port's own framing.  Each `--peer`
+//! is pulled from every `--peer-sync-interval-secs`: for the default
+//! namespace and every namespace of the root database, the puller walks
+//! the peer's doc hash buckets down to those whose digests differ from
+//! ours, fetches the documents it lacks with `GetChanges` and applies them
+//! as `ApplyChanges` would.  Versions ours descend from (see `ancestry`)
+//! are skipped, so a peer that is behind can't roll us back.  Blobs the
+//! changes declare that we lack are fetched with `GetBlobs` before the
+//! changes are applied, and each batch is applied with the signature its
+//! `ChangesPart` carried (see `signing`).  Progress goes to the port as
+//! `PeerSync` frames pushed with ref_id 0.
+//!
+//! The link is QUIC with TLS (see `quic`), each end identified by its
+//! signing key, so both need `KEYRING_STORE_SIGNING_KEY`.  Each end must
+//! have registered the other with `AddPeer` and its key (see `trust`):
+//! the puller under the `--peer` address it dials, the listener under any
+//! id.  The listener serves a peer only the namespaces it is allowed, and
+//! at most `--peer-max-connections` peers at once.
+//!
+//! Sync is pull-only: to sync both ways, point each store at the other.
+
+use crate::dispatch::{handle_request, stream_changes, stream_changes_bloom, Paced, Streaming};
+use crate::frame::{peek_ref_id, read_frame, write_frame};
+use crate::protocol::{Envelope, ErrorCode, RefId, Request, Response};
+use crate::quic::{self, BlockingRecv, BlockingSend};
+use crate::server::{spawn_reader, FrameSink, Notifications, Reply};
+use crate::store::{Peer, SigningKey, Store};
+use crate::throttle::{Deferred, Throttles};
+use anyhow::{anyhow, bail, Context, Result};
+use quinn::{Connection, Endpoint};
+use std::collections::HashMap;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use tokio::runtime::Handle;
+use tokio::time::timeout;
+use tracing::{info, warn};
+
+/// Granularity at which the threads notice shutdown.
+const TICK: Duration = Duration::from_millis(200);
+
+/// How long to wait for a peer to accept a connection or answer.
+const PEER_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Most blobs fetched with one `GetBlobs`.
+const BLOB_BATCH: usize = 16;
+
+/// Writes frames to a peer's request stream.
+struct PeerSink(BlockingSend);
+
+impl FrameSink for PeerSink {
+    fn send_frame(&mut self, payload: &[u8]) -> Result<()> {
+        write_frame(&mut self.0, payload)
+    }
+}
+
+/// A connection counted against `--peer-max-connections`; dropping it
+/// frees its place.
+struct Slot(Arc<AtomicUsize>);
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Accept registered peers on `addr`, at most `max_connections` at once,
+/// and serve each on its own thread until `shutdown` is set.
+pub fn spawn_listener(
+    addr: SocketAddr,
+    store: Store,
+    chunk_bytes: usize,
+    max_connections: usize,
+    throttles: Arc<Throttles>,
+    shutdown: Arc<AtomicBool>,
+) -> Result<JoinHandle<()>> {
+    let key = store.signing_key().cloned().context(
+        "--peer-listen needs KEYRING_STORE_SIGNING_KEY, the store's identity to its peers",
+    )?;
+    let runtime = quic::runtime()?;
+    let endpoint = {
+        let _context = runtime.enter();
+        quic::server(addr, &key, store.clone(), PEER_TIMEOUT)?
+    };
+    info!(%addr, max_connections, "accepting peers");
+    let active = Arc::new(AtomicUsize::new(0));
+    thread::Builder::new()
+        .name("peer-listener".into())
+        .spawn(move || {
+            while !shutdown.load(Ordering::SeqCst) {
+                // `timeout` needs the runtime's timer, so build it inside.
+                let accept = async { timeout(TICK, endpoint.accept()).await };
+                let incoming = match runtime.block_on(accept) {
+                    Ok(Some(incoming)) => incoming,
+                    Ok(None) => break,
+                    Err(_) => continue,
+                };
+                let remote = incoming.remote_address();
+                if active.load(Ordering::SeqCst) >= max_connections {
+                    warn!(%remote, max_connections, "refusing a peer: too many connections");
+                    incoming.refuse();
+                    continue;
+                }
+                active.fetch_add(1, Ordering::SeqCst);
+                let slot = Slot(active.clone());
+                let handle = runtime.handle().clone();
+                let store = store.clone();
+                let throttles = throttles.clone();
+                let shutdown = shutdown.clone();
+                // A thread that fails to start drops `slot` with it.
+                let spawned = thread::Builder::new().name("peer".into()).spawn(move || {
+                    let _slot = slot;
+                    let connection = match handle.block_on(async { incoming.await }) {
+                        Ok(connection) => connection,
+                        Err(e) => {
+                            warn!(%remote, error = %e, "peer handshake failed");
+                            return;
+                        }
+                    };
+                    let streaming = Streaming {
+                        chunk_bytes,
+                        throttles: &throttles,
+                    };
+                    if let Err(e) = serve_connection(&connection, handle, &store, streaming, &shutdown)
+                    {
+                        warn!(%remote, error = %format!("{e:#}"), "serving a peer failed");
+                    }
+                    connection.close(0u32.into(), b"done");
+                });
+                if let Err(e) = spawned {
+                    warn!(%remote, error = %e, "spawning a peer thread failed");
+                }
+            }
+            // Closing the endpoint ends every connection, so the peer
+            // threads stop reading before the runtime goes.
+            endpoint.close(0u32.into(), b"shutting down");
+            let deadline = Instant::now() + PEER_TIMEOUT;
+            while active.load(Ordering::SeqCst) > 0 && Instant::now() < deadline {
+                thread::sleep(TICK);
+            }
+            runtime.shutdown_timeout(TICK);
+        })
+        .context("spawning peer listener thread")
+}
+
+/// Serve the peer at the other end of `connection`, which the handshake
+/// found registered, over the request stream it opens.
+fn serve_connection(
+    connection: &Connection,
+    handle: Handle,
+    store: &Store,
+    streaming: Streaming,
+    shutdown: &AtomicBool,
+) -> Result<()> {
+    let key = quic::peer_key(connection)?;
+    let peer = store
+        .peers()?
+        .into_iter()
+        .find(|peer| peer.public_key == key)
+        .context("the peer is no longer registered")?;
+    info!(peer = peer.peer_id, remote = %connection.remote_address(), "peer connected");
+    let (send, recv) = handle.block_on(connection.accept_bi())?;
+    let frames = spawn_reader(BlockingRecv {
+        handle: handle.clone(),
+        stream: recv,
+    });
+    let mut sink = PeerSink(BlockingSend {
+        handle,
+        stream: send,
+    });
+    serve_peer(&peer, frames, &mut sink, store, streaming, shutdown)
+}
+
+/// Serve one peer's requests until it disconnects or `shutdown` is set.
+/// Streams the bandwidth budget holds back wait in a queue of their own,
+/// so the peer's other requests are served meanwhile.
+fn serve_peer(
+    peer: &Peer,
+    frames: Receiver<Result<Vec<u8>>>,
+    sink: &mut PeerSink,
+    store: &Store,
+    streaming: Streaming,
+    shutdown: &AtomicBool,
+) -> Result<()> {
+    let mut held: Deferred<(RefId, Paced)> = Deferred::default();
+    loop {
+        if shutdown.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+        for (ref_id, paced) in held.due() {
+            if let Some(paced) = paced.resume(streaming, &mut Reply::new(ref_id, sink))? {
+                held.push(paced.deadline, (ref_id, paced));
+            }
+        }
+        let frame = match frames.recv_timeout(held.wait(TICK)) {
+            Ok(frame) => frame?,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        };
+        let envelope: Envelope = match bincode::deserialize(&frame) {
+            Ok(envelope) => envelope,
+            Err(e) => {
+                let ref_id = peek_ref_id(&frame).unwrap_or_default();
+                Reply::new(ref_id, sink).send(&Response::error(
+                    ErrorCode::Decode,
+                    format!("decoding request frame: {e}"),
+                ))?;
+                continue;
+            }
+        };
+        let mut reply = Reply::new(envelope.ref_id, sink);
+        if envelope.tenant.is_some() {
+            reply.send(&Response::error(
+                ErrorCode::BadRequest,
+                "peers sync the root database only",
+            ))?;
+            continue;
+        }
+        if !peer.allows(&envelope.namespace) {
+            reply.send(&Response::error(
+                ErrorCode::Unauthorized,
+                format!("{:?} may not sync namespace {:?}", peer.peer_id, envelope.namespace),
+            ))?;
+            continue;
+        }
+        let store = match store.namespace(&envelope.namespace) {
+            Ok(store) => store,
+            Err(e) => {
+                reply.send(&Response::error(ErrorCode::BadRequest, format!("{e:#}")))?;
+                continue;
+            }
+        };
+        let paced = match envelope.request {
+            Request::GetChanges {
+                known_roots,
+                doc_ids,
+                prefix,
+                namespace,
+            } => stream_changes(
+                &store,
+                known_roots,
+                doc_ids,
+                prefix,
+                namespace,
+                streaming,
+                &mut reply,
+            )?,
+            Request::GetChangesBloom { bloom } => {
+                stream_changes_bloom(&store, bloom, streaming, &mut reply)?
+            }
+            request @ (Request::GetRoots { .. }
+            | Request::GetBucket { .. }
+            | Request::GetChangeHeads { .. }
+            | Request::GetCombinedRoot { .. }
+            | Request::GetSubRoots { .. }
+            | Request::DiffRoots { .. }
+            | Request::GetBlobs { .. }
+            | Request::GetMissingBlobs { .. }
+            | Request::GetSignedRoot
+            | Request::GetIblt { .. }
+            | Request::ReconcileIblt { .. }) => {
+                reply.send(&handle_request(&store, request))?;
+                None
+            }
+            request => {
+                reply.send(&Response::error(
+                    ErrorCode::BadRequest,
+                    format!("{} isn't served to peers", request.kind()),
+                ))?;
+                None
+            }
+        };
+        if let Some(paced) = paced {
+            held.push(paced.deadline, (envelope.ref_id, paced));
+        }
+    }
+}
+
+/// Pull from each of `peers` into `store` every `interval` until
+/// `shutdown` is set, pushing progress to `notifications`.
+pub fn spawn_puller(
+    store: Store,
+    peers: Vec<String>,
+    interval: Duration,
+    throttles: Arc<Throttles>,
+    notifications: Arc<Notifications>,
+    shutdown: Arc<AtomicBool>,
+) -> Result<JoinHandle<()>> {
+    let key = store.signing_key().cloned().context(
+        "--peer needs KEYRING_STORE_SIGNING_KEY, the store's identity to its peers",
+    )?;
+    let runtime = quic::runtime()?;
+    thread::Builder::new()
+        .name("peer-puller".into())
+        .spawn(move || {
+            info!(?peers, interval_secs = interval.as_secs(), "peer sync started");
+            let mut next = Instant::now();
+            while !shutdown.load(Ordering::SeqCst) {
+                if Instant::now() < next {
+                    thread::sleep(TICK);
+                    continue;
+                }
+                for peer in &peers {
+                    let link = Link::connect(&store, &key, runtime.handle(), peer);
+                    pull_all(&store, link, peer, &throttles, &notifications);
+                }
+                next = Instant::now() + interval;
+            }
+            runtime.shutdown_timeout(TICK);
+        })
+        .context("spawning peer puller thread")
+}
+
+/// Pull every namespace from `peer`, reporting failures as a `PeerSync`
+/// with an error.
+fn pull_all(
+    store: &Store,
+    link: Result<Link>,
+    peer: &str,
+    throttles: &Throttles,
+    notifications: &Notifications,
+) {
+    let mut namespaces = vec![String::new()];
+    match store.namespaces() {
+        Ok(names) => namespaces.extend(names),
+        Err(e) => warn!(error = %format!("{e:#}"), "listing namespaces failed"),
+    }
+    let mut link = match link {
+        Ok(link) => link,
+        Err(e) => {
+            warn!(peer, error = %format!("{e:#}"), "connecting to peer failed");
+            notifications.push(progress(peer, "", 0, 0, true, Some(format!("{e:#}"))));
+            return;
+        }
+    };
+    for namespace in namespaces {
+        if let Err(e) = pull(store, &mut link, peer, &namespace, throttles, notifications) {
+            warn!(peer, namespace, error = %format!("{e:#}"), "peer sync failed");
+            notifications.push(progress(peer, &namespace, 0, 0, true, Some(format!("{e:#}"))));
+            return;
+        }
+    }
+}
+
+fn progress(
+    peer: &str,
+    namespace: &str,
+    applied: u64,
+    total: u64,
+    done: bool,
+    error: Option<String>,
+) -> Response {
+    Response::PeerSync {
+        peer: peer.to_string(),
+        namespace: namespace.to_string(),
+        applied,
+        total,
+        done,
+        error,
+    }
+}
+
+/// Fetch and apply the documents of `namespace` that `peer` has and we
+/// lack.
+fn pull(
+    store: &Store,
+    link: &mut Link,
+    peer: &str,
+    namespace: &str,
+    throttles: &Throttles,
+    notifications: &Notifications,
+) -> Result<()> {
+    let store = store.namespace(namespace)?;
+    let wanted = differing(&store, link, namespace)?;
+    if wanted.is_empty() {
+        return Ok(());
+    }
+    let total = wanted.len() as u64;
+    info!(peer, namespace, total, "pulling from peer");
+    // Our versions let the peer send deltas from them.
+    let known_roots = store.get_doc_hashes(&wanted)?.into_iter().map(|(_, hash)| hash).collect();
+    let ref_id = link.send(
+        namespace,
+        Request::GetChanges {
+            known_roots,
+            doc_ids: wanted,
+            prefix: None,
+            namespace: None,
+        },
+    )?;
+    let mut applied = 0;
+    loop {
+        let Response::ChangesPart {
+            last,
+            changes,
+            signed,
+            ..
+        } = link.recv(ref_id)?
+        else {
+            bail!("unexpected reply to GetChanges");
+        };
+        let count = changes.len() as u64;
+        let declared: Vec<Vec<u8>> =
+            changes.iter().flat_map(|change| change.blobs.iter().cloned()).collect();
+        fetch_blobs(&store, link, peer, namespace, throttles, &declared)?;
+        let request = Request::ApplyChanges {
+            changes,
+            signed_root: signed,
+            peer_id: Some(peer.to_string()),
+        };
+        match handle_request(&store, request) {
+            Response::Applied { conflicts, .. } => {
+                for conflict in conflicts {
+                    warn!(peer, namespace, doc_id = conflict.doc_id, "conflicting change from peer");
+                }
+            }
+            Response::Error { code, message } => bail!("applying changes: {code:?}: {message}"),
+            other => bail!("unexpected reply to ApplyChanges: {other:?}"),
+        }
+        applied += count;
+        // Documents deleted since the walk leave `applied` short of `total`.
+        notifications.push(progress(peer, namespace, applied, total, last, None));
+        if last {
+            return Ok(());
+        }
+    }
+}
+
+/// Fetch from the peer and store those of `hashes` we lack.  Blobs the
+/// peer lacks too are left out.
+fn fetch_blobs(
+    store: &Store,
+    link: &mut Link,
+    peer: &str,
+    namespace: &str,
+    throttles: &Throttles,
+    hashes: &[Vec<u8>],
+) -> Result<()> {
+    let mut missing = store.missing_blobs(hashes)?;
+    missing.sort();
+    missing.dedup();
+    for batch in missing.chunks(BLOB_BATCH) {
+        let ref_id = link.send(
+            namespace,
+            Request::GetBlobs {
+                hashes: batch.to_vec(),
+            },
+        )?;
+        let Response::Blobs { found, missing } = link.recv(ref_id)? else {
+            bail!("unexpected reply to GetBlobs");
+        };
+        if !missing.is_empty() {
+            warn!(namespace, count = missing.len(), "peer lacks blobs its documents reference");
+        }
+        throttles.consume("push_blobs", found.iter().map(|blob| blob.data.len()).sum());
+        let request = Request::PushBlobs {
+            blobs: found,
+            peer_id: Some(peer.to_string()),
+        };
+        match handle_request(store, request) {
+            Response::BlobsStored { .. } => {}
+            Response::Error { code, message } => bail!("storing blobs: {code:?}: {message}"),
+            other => bail!("unexpected reply to PushBlobs: {other:?}"),
+        }
+    }
+    Ok(())
+}
+
+/// Ids of the documents whose version at the peer isn't among our
+/// ancestors, found by descending only into buckets whose digests differ.
+fn differing(store: &Store, link: &mut Link, namespace: &str) -> Result<Vec<String>> {
+    let mut wanted = Vec::new();
+    let mut prefixes = vec![Vec::new()];
+    while let Some(prefix) = prefixes.pop() {
+        let ref_id = link.send(
+            namespace,
+            Request::GetBucket {
+                prefix: prefix.clone(),
+            },
+        )?;
+        let Response::Bucket {
+            digest,
+            children,
+            roots,
+            ..
+        } = link.recv(ref_id)?
+        else {
+            bail!("unexpected reply to GetBucket");
+        };
+        let local = store.get_bucket(&prefix)?;
+        if digest == local.digest {
+            continue;
+        }
+        if !children.is_empty() {
+            for (nibble, child) in children.into_iter().enumerate() {
+                let same = local
+                    .children
+                    .get(nibble)
+                    .is_some_and(|(count, digest)| *count == child.count && *digest == child.digest);
+                if child.count > 0 && !same {
+                    prefixes.push([&prefix[..], &[nibble as u8]].concat());
+                }
+            }
+            continue;
+        }
+        let ids: Vec<String> = roots.iter().map(|root| root.doc_id.clone()).collect();
+        let ours: HashMap<String, Vec<u8>> = store.get_doc_hashes(&ids)?.into_iter().collect();
+        for root in roots {
+            if ours.get(&root.doc_id) == Some(&root.hash) {
+                continue;
+            }
+            if store.document_ancestry(&root.doc_id)?.contains(&root.hash) {
+                continue;
+            }
+            wanted.push(root.doc_id);
+        }
+    }
+    Ok(wanted)
+}
+
+/// A connection to a peer's `--peer-listen` address.
+struct Link {
+    connection: Connection,
+    send: BlockingSend,
+    recv: BlockingRecv,
+    next_ref: RefId,
+    // Dropped after the connection.
+    _endpoint: Endpoint,
+}
+
+impl Link {
+    /// Connect to the peer registered as `addr`, which must prove it holds
+    /// the key it is registered with, identifying ourselves by `key`.
+    fn connect(store: &Store, key: &SigningKey, handle: &Handle, addr: &str) -> Result<Self> {
+        let registered = store
+            .peers()?
+            .into_iter()
+            .find(|peer| peer.peer_id == addr)
+            .map(|peer| peer.public_key)
+            .filter(|public_key| !public_key.is_empty())
+            .with_context(|| format!("register {addr} as a peer, with its key, to pull from it"))?;
+        let expected: [u8; 32] = registered
+            .try_into()
+            .map_err(|_| anyhow!("the key registered for {addr} isn't 32 bytes"))?;
+        let resolved = addr
+            .to_socket_addrs()
+            .with_context(|| format!("resolving {addr}"))?
+            .next()
+            .with_context(|| format!("{addr} resolves to no address"))?;
+        let endpoint = {
+            let _context = handle.enter();
+            quic::client(key, expected, PEER_TIMEOUT)?
+        };
+        let (connection, send, recv) = handle
+            .block_on(quic::connect(&endpoint, resolved))
+            .with_context(|| format!("connecting to {addr}"))?;
+        Ok(Self {
+            connection,
+            send: BlockingSend {
+                handle: handle.clone(),
+                stream: send,
+            },
+            recv: BlockingRecv {
+                handle: handle.clone(),
+                stream: recv,
+            },
+            next_ref: 0,
+            _endpoint: endpoint,
+        })
+    }
+
+    fn send(&mut self, namespace: &str, request: Request) -> Result<RefId> {
+        self.next_ref += 1;
+        let envelope = Envelope {
+            ref_id: self.next_ref,
+            trace_id: None,
+            namespace: namespace.to_string(),
+            tenant: None,
+            durability: None,
+            request,
+        };
+        write_frame(&mut self.send, &bincode::serialize(&envelope)?)?;
+        Ok(self.next_ref)
+    }
+
+    /// The next reply, which must be to `ref_id`; an `Error` reply fails.
+    fn recv(&mut self, ref_id: RefId) -> Result<Response> {
+        let response = self.recv_any(ref_id)?;
+        if let Response::Error { code, message } = response {
+            bail!("peer replied {code:?}: {message}");
+        }
+        Ok(response)
+    }
+
+    /// `recv`, passing an `Error` reply through.
+    fn recv_any(&mut self, ref_id: RefId) -> Result<Response> {
+        let frame = read_frame(&mut self.recv)?.context("peer closed the connection")?;
+        let (got, response): (RefId, Response) = bincode::deserialize(&frame)?;
+        if got != ref_id {
+            bail!("peer replied to request {got} instead of {ref_id}");
+        }
+        Ok(response)
+    }
+}
+
+impl Drop for Link {
+    fn drop(&mut self) {
+        self.connection.close(0u32.into(), b"done");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::StoreOptions;
+    use std::net::UdpSocket;
+
+    fn open(path: &std::path::Path, seed: u8) -> Store {
+        let options = StoreOptions {
+            signing_key: Some(SigningKey::from_bytes(&[seed; 32]).unwrap()),
+            ..Default::default()
+        };
+        Store::open(path, options).unwrap()
+    }
+
+    #[test]
+    fn test_pull_from_peer() {
+        let dir = tempfile::tempdir().unwrap();
+        let a = open(&dir.path().join("a"), 1);
+        let b = open(&dir.path().join("b"), 2);
+        let a_key = a.signing_key().unwrap().public();
+        let b_key = b.signing_key().unwrap().public();
+        a.put_document("doc", b"meta", b"state", None, false).unwrap();
+        let shared = a.namespace("shared").unwrap();
+        shared.put_document("doc", b"meta", b"shared", None, false).unwrap();
+
+        let addr = UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let peer = addr.to_string();
+        a.add_peer("b", &b_key, &[]).unwrap();
+        b.add_peer(&peer, &a_key, &[]).unwrap();
+        let throttles = Arc::new(Throttles::new(&[]));
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let listener =
+            spawn_listener(addr, a.clone(), 1024, 4, throttles.clone(), shutdown.clone()).unwrap();
+
+        let runtime = quic::runtime().unwrap();
+        let key = b.signing_key().unwrap().clone();
+        let notifications = Notifications::default();
+        let mut link = Link::connect(&b, &key, runtime.handle(), &peer).unwrap();
+        for namespace in ["", "shared"] {
+            pull(&b, &mut link, &peer, namespace, &throttles, &notifications).unwrap();
+            let (a, b) = (a.namespace(namespace).unwrap(), b.namespace(namespace).unwrap());
+            assert_eq!(a.combined_root().unwrap(), b.combined_root().unwrap(), "{namespace:?}");
+        }
+        assert_eq!(b.get_document("doc").unwrap().unwrap().crdt_state, b"state");
+
+        // A version we already hold isn't fetched again, nor an older one.
+        b.put_document("doc", b"meta", b"newer", None, false).unwrap();
+        assert!(differing(&b, &mut link, "").unwrap().is_empty());
+        pull(&b, &mut link, &peer, "", &throttles, &notifications).unwrap();
+        assert_eq!(b.get_document("doc").unwrap().unwrap().crdt_state, b"newer");
+
+        // The listener serves a peer only the namespaces it allows.
+        drop(link);
+        a.add_peer("b", &b_key, &["shared".to_string()]).unwrap();
+        let mut link = Link::connect(&b, &key, runtime.handle(), &peer).unwrap();
+        pull(&b, &mut link, &peer, "shared", &throttles, &notifications).unwrap();
+        let refused = pull(&b, &mut link, &peer, "", &throttles, &notifications).unwrap_err();
+        assert!(refused.to_string().contains("may not sync"), "{refused:#}");
+        drop(link);
+
+        // Nor does it complete a handshake with a key it doesn't know.
+        let stranger = open(&dir.path().join("c"), 3);
+        stranger.add_peer(&peer, &a_key, &[]).unwrap();
+        let key = stranger.signing_key().unwrap().clone();
+        let link = Link::connect(&stranger, &key, runtime.handle(), &peer)
+            .and_then(|mut link| differing(&stranger, &mut link, ""));
+        assert!(link.is_err());
+
+        shutdown.store(true, Ordering::SeqCst);
+        listener.join().unwrap();
+        runtime.shutdown_timeout(TICK);
+    }
+}
@@ -3,19 +3,57 @@
 //! Every frame on stdin/stdout is:
 //!   [4-byte big-endian length] [bincode payload]
 //!
-//! Payload is always (ref_id: u64, Request) or (ref_id: u64, Response).
+//! Request payloads are an `Envelope` (ref_id, optional trace_id, namespace,
+//! optional tenant, optional durability, Request);
+//! response payloads are (ref_id: u64, Response).
 
+pub use crate::hashbloom::HashBloom;
+pub use crate::store::{Durability, IbltCell, ImportPolicy, Predicate, Timestamps};
+use crate::store::{InvalidId, PeerRejected, TooLarge};
 use serde::{Deserialize, Serialize};
+use std::fmt;
 
 /// Unique per-request id so Elixir can match replies.
 pub type RefId = u64;
 
+/// ref_id used on response frames that cannot be tied to a request, e.g. an
+/// error reply to a frame too short to carry its own ref_id.
+pub const NO_REF_ID: RefId = 0;
+
+/// Upper bound on the page size of listing requests.
+pub const MAX_PAGE_LIMIT: u32 = 10_000;
+
 // ── Requests ──────────────────────────────────────────────────────────
 
+/// Inbound frame payload.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Envelope {
+    pub ref_id: RefId,
+    /// Caller correlation id (e.g. the Elixir Telemetry span), attached to
+    /// every log line emitted while serving this request.
+    pub trace_id: Option<String>,
+    /// Namespace whose documents and blobs the request operates on; empty
+    /// for the default namespace.
+    pub namespace: String,
+    /// Tenant database the request is routed to (see `OpenTenant`); `None`
+    /// for the root database.
+    pub tenant: Option<String>,
+    /// Overrides `--durability` for this request's writes, e.g. `None` for
+    /// the batches of a bulk import.
+    pub durability: Option<Durability>,
+    pub request: Request,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Request {
-    /// Store a blob; returns its blake3 hash.
-    PutBlob { data: Vec<u8> },
+    /// Store a blob; returns its blake3 hash.  With `ttl_secs`, the blob is
+    /// removed by the expiry sweeper once that many seconds have passed.
+    /// Blobs are addressed by their content, so putting one twice is
+    /// already harmless and needs no create-only form.
+    PutBlob {
+        data: Vec<u8>,
+        ttl_secs: Option<u64>,
+    },
 
     /// Retrieve a blob by hash.
     GetBlob { hash: Vec<u8> },
@@ -23,35 +61,454 @@ pub enum Request {
     /// Check if a blob exists.
     HasBlob { hash: Vec<u8> },
 
-    /// Store / update a document.
+    /// Store / update a document.  `index` replaces the (key, value) pairs
+    /// the document can be found by with `QueryDocuments`; `None` keeps the
+    /// existing ones.  With `create_only`, an existing document is left
+    /// alone and the reply is `AlreadyExists`, decided in the same write
+    /// transaction as the put.
     PutDocument {
         id: String,
         meta: Vec<u8>,
         crdt_state: Vec<u8>,
+        index: Option<Vec<(String, String)>>,
+        create_only: bool,
     },
 
-    /// Get a document by id.
-    GetDocument { id: String },
+    /// Get a document by id; `with_timestamps` fills in the reply's
+    /// `times`.
+    GetDocument { id: String, with_timestamps: bool },
 
-    /// Delete a document by id.
-    DeleteDocument { id: String },
+    /// Delete a document by id, with its attachments.  With `cascade`, the
+    /// blobs attached to it that no other document attaches or references
+    /// are deleted too.
+    DeleteDocument { id: String, cascade: bool },
 
-    /// List all document ids.
-    ListDocuments,
+    /// List the document ids starting with `prefix`, in id order; an empty
+    /// prefix lists every document.  With `with_timestamps` the reply is
+    /// `DocumentEntries` rather than `DocumentList`.
+    ListDocuments { prefix: String, with_timestamps: bool },
 
-    /// Return the Merkle roots for the given document ids.
+    /// Return the Merkle roots for the given document ids (every document's
+    /// if empty); ids without a root are left out.
     GetRoots { doc_ids: Vec<String> },
 
-    /// Return changes since a set of known roots.
-    GetChanges { known_roots: Vec<Vec<u8>> },
+    /// Return changes since a set of known roots, streamed as one or more
+    /// `ChangesPart` frames.  Only the documents whose id is in `doc_ids`
+    /// or starts with `prefix` are considered, or every document if both
+    /// are unset; `namespace` reads from that namespace of the envelope's
+    /// database instead of the envelope's.
+    GetChanges {
+        known_roots: Vec<Vec<u8>>,
+        doc_ids: Vec<String>,
+        prefix: Option<String>,
+        namespace: Option<String>,
+    },
+
+    /// Apply a batch of changes from a remote peer; replies `Applied`.  A
+    /// batch applies entirely or not at all: one that fails leaves nothing
+    /// of it written.  `signed_root` is the sender's signature over the
+    /// batch, as the `ChangesPart` or `SyncBatch` that carried it had it; a
+    /// store started with `--require-signed-roots` refuses a batch without
+    /// a valid one from a trusted signer (see `signing`).  `peer_id` names
+    /// the sender once peers are registered (see `AddPeer`).
+    ApplyChanges {
+        changes: Vec<Change>,
+        signed_root: Option<Box<SignedRootInfo>>,
+        peer_id: Option<String>,
+    },
+
+    /// Store many blobs in one transaction; returns their hashes in order.
+    PutBlobs { blobs: Vec<Vec<u8>> },
+
+    /// Retrieve many blobs in one round trip.
+    GetBlobs { hashes: Vec<Vec<u8>> },
+
+    /// Page through stored blobs in hash order.  `cursor` is the
+    /// `next_cursor` of the previous page (`None` for the first page).
+    /// `with_timestamps` fills in each entry's `times`.
+    ListBlobs {
+        cursor: Option<Vec<u8>>,
+        limit: u32,
+        with_timestamps: bool,
+    },
+
+    /// Sweep blobs no document references.  With `dry_run`, only report
+    /// what would be reclaimed.
+    Gc { dry_run: bool },
+
+    /// Open (creating if needed) a tenant's database so requests naming the
+    /// tenant can be routed to it.
+    OpenTenant { name: String },
+
+    /// Close a tenant's database.  `NotFound` if it was not open.
+    CloseTenant { name: String },
+
+    /// List the retained versions of a document, newest first.
+    GetDocumentHistory { id: String },
+
+    /// CRDT state of a retained version, identified by its state hash.
+    GetDocumentVersion { id: String, hash: Vec<u8> },
+
+    /// Ids of the documents indexed with `key` = `value`.
+    QueryDocuments { key: String, value: String },
+
+    /// Count documents (ids as UTF-8) or blobs (raw hashes) whose key
+    /// starts with `prefix`; an empty prefix counts everything.
+    Count { what: CountTarget, prefix: Vec<u8> },
+
+    /// Database file and page usage, per-table entry counts and blob bytes.
+    Stats,
+
+    /// Rewrite the database file to give its free pages back to the
+    /// filesystem.
+    Compact,
+
+    /// Write a consistent copy of the database, every namespace included,
+    /// to the directory `dest_path` on the store's host while serving
+    /// continues.  The directory must not already hold a database.
+    Backup { dest_path: String },
+
+    /// Load an archive written by `keyring-store export` from
+    /// `archive_path` on the store's host, every namespace in one write
+    /// transaction.  `policy` decides what happens to ids that already
+    /// exist.
+    Import {
+        archive_path: String,
+        policy: ImportPolicy,
+    },
+
+    /// Cross-check the tables of every namespace against each other; with
+    /// `deep`, also recompute blake3 over every CRDT state and blob.
+    Verify { deep: bool },
+
+    /// Fix what `Verify` finds where the data to do so is still there:
+    /// rebuild doc_hashes and the expiry index, drop rows that belong to
+    /// nothing.  With `drop_documents_without_data`, also remove documents
+    /// whose data is gone, leaving no tombstone so that sync can restore
+    /// them from a peer.  Covers every namespace, in one write transaction.
+    Repair { drop_documents_without_data: bool },
+
+    /// The 32-byte key for encryption at rest, for a port started without
+    /// `KEYRING_STORE_KEY`.  Applies to the database (root or tenant) the
+    /// envelope routes to; once that database is encrypted it refuses every
+    /// other request until it has its key.
+    ProvideKey { key: SecretBytes },
+
+    /// Make `new_key` the database's encryption key and reseal values
+    /// written under older keys in the background.  The old key stays
+    /// readable until that finishes; sending the same `new_key` again
+    /// resumes an interrupted pass.
+    RotateKey { new_key: SecretBytes },
+
+    /// Push a `DocumentChanged` frame, tagged with this request's ref_id,
+    /// for every committed change to a document of the envelope's database
+    /// and namespace whose id is in `ids` or starts with `prefix`
+    /// (`Some("")` for all of them).  Replies `Ok` first.
+    Watch {
+        ids: Vec<String>,
+        prefix: Option<String>,
+    },
+
+    /// Stop the watch registered by the `Watch` request `watch_ref`.
+    /// `NotFound` if there is none.
+    Unwatch { watch_ref: RefId },
+
+    /// Up to `limit` entries of the namespace's changelog, oldest first,
+    /// starting at sequence number `from_seq` (1 for the beginning).
+    GetChangelog { from_seq: u64, limit: u32 },
+
+    /// Attach blob `hash` to document `doc_id` under `name`, replacing an
+    /// attachment of the same name.  Attached blobs are kept by GC and lose
+    /// any TTL.  `NotFound` if the document or blob doesn't exist.
+    AttachBlob {
+        doc_id: String,
+        hash: Vec<u8>,
+        name: String,
+    },
+
+    /// Remove attachment `name` of `doc_id`; the blob stays.  `NotFound` if
+    /// there is none.
+    DetachBlob { doc_id: String, name: String },
+
+    /// The attachments of `doc_id`, in name order.
+    ListAttachments { doc_id: String },
+
+    /// Ids of the documents referencing blob `hash`, from their metadata or
+    /// CRDT state (as the store's ref extractor reads them) or as an
+    /// attachment, in id order.
+    WhoReferences { hash: Vec<u8> },
+
+    /// Page through the document ids in `[start, end)`, in id order; `end:
+    /// None` runs to the last id.  Continue from the reply's `next_start`.
+    ListDocumentsRange {
+        start: String,
+        end: Option<String>,
+        limit: u32,
+    },
+
+    /// Ids starting with `prefix` whose metadata, read as a CBOR map,
+    /// passes every predicate in `filter`, in id order.  Evaluated in the
+    /// store, so only the matching ids cross the port.
+    FilterDocuments {
+        prefix: String,
+        filter: Vec<Predicate>,
+    },
+
+    /// Up to `limit` ids of documents whose search fields (`--search-field`)
+    /// contain any word of `query`, best match first.
+    Search { query: String, limit: u32 },
+
+    /// Up to `limit` documents by read count, most read first; with
+    /// `coldest_first`, least recently read first, never-read documents
+    /// leading.  Needs `--track-access`.
+    HotDocuments { limit: u32, coldest_first: bool },
+
+    /// Blob puts in the namespace so far and how many of them found their
+    /// content already stored.
+    DedupStats,
+
+    /// Retrieve many documents in one read transaction.  `with_timestamps`
+    /// as in `GetDocument`.
+    GetDocuments {
+        ids: Vec<String>,
+        with_timestamps: bool,
+    },
+
+    /// The Merkle root of one document: the hash of its CRDT state, or its
+    /// deletion hash once deleted.  `GetRoots` looks up several at once.
+    GetDocHash { id: String },
+
+    /// Replace an existing document's metadata, leaving its CRDT state as
+    /// it is.  Replies `DocumentStored` with the new version, or `NotFound`.
+    PutDocumentMeta { id: String, meta: Vec<u8> },
+
+    /// Open a transaction on this request's database, namespace and
+    /// durability; replies `TxnStarted`.
+    BeginTxn,
+
+    /// Stage `request` in transaction `txn` instead of running it; replies
+    /// `Ok`, or `NotFound` for an unknown handle.  Only the single-item
+    /// writes group commit takes (PutBlob, PutDocument, non-cascading
+    /// DeleteDocument, AttachBlob, DetachBlob) can be staged.
+    TxnWrite { txn: u64, request: Box<Request> },
+
+    /// Apply the writes staged in `txn` in one transaction, all or nothing,
+    /// and close it; replies `Committed`, or `NotFound`.
+    Commit { txn: u64 },
+
+    /// Close `txn`, dropping its staged writes; replies `Ok`, or `NotFound`.
+    Abort { txn: u64 },
+
+    /// The `--maintenance-window` schedule and the latest maintenance run;
+    /// replies `MaintenanceStatus`.
+    MaintenanceStatus,
+
+    /// The doc hash bucket at `prefix`, one nibble (0-15) per byte and at
+    /// most four; the empty prefix is the root.  Replies `Bucket`.  Peers
+    /// compare digests and descend only into the children that differ.
+    GetBucket { prefix: Vec<u8> },
+
+    /// Open a resumable sync session over the changes `GetChanges` would
+    /// stream for the same arguments; replies `SyncStarted`.  The session
+    /// belongs to the envelope's database and namespace.
+    StartSync {
+        known_roots: Vec<Vec<u8>>,
+        doc_ids: Vec<String>,
+        prefix: Option<String>,
+    },
+
+    /// Acknowledge the first `ack` changes of the session (the `next_ack`
+    /// of the last batch received, 0 at first) and get the next batch of
+    /// about `--changes-chunk-bytes`; replies `SyncBatch`, or `NotFound`.
+    /// Resending an acknowledgement resends the batch after it.
+    ContinueSync { session_id: u64, ack: u64 },
+
+    /// Close a sync session; replies `Ok`, or `NotFound`.
+    FinishSync { session_id: u64 },
+
+    /// Like `GetChanges` over every document, with the hashes the caller
+    /// has sent as a bloom filter instead of a list; streams the changes
+    /// whose hash `bloom` doesn't contain as `ChangesPart` frames.  A false
+    /// positive leaves a change out, and changes carry whole states.
+    GetChangesBloom { bloom: HashBloom },
+
+    /// An invertible Bloom lookup table of `cells` cells (3 to 2^20) over
+    /// the namespace's doc hashes; replies `Iblt`.  A peer subtracts its
+    /// own table of the same size and decodes the documents the two sides
+    /// differ on, with no exchange of full root lists.
+    GetIblt { cells: u32 },
+
+    /// Subtract the caller's table `cells` from the store's table of the
+    /// same size and decode the difference; replies `IbltDiff`.
+    ReconcileIblt { cells: Vec<IbltCell> },
 
-    /// Apply a batch of changes from a remote peer.
-    ApplyChanges { changes: Vec<Change> },
+    /// The states the keep-both conflict policy kept alongside a document,
+    /// in hash order; putting the document again drops them.
+    GetSiblings { id: String },
+
+    /// Like `GetChanges`, but replies `ChangeHeads` listing only the id,
+    /// hash and state size of each change, without loading any state; the
+    /// caller fetches the bodies it wants with `GetDocuments`.
+    GetChangeHeads {
+        known_roots: Vec<Vec<u8>>,
+        doc_ids: Vec<String>,
+        prefix: Option<String>,
+    },
+
+    /// One Merkle root (see `merkle`) over the hashes `GetRoots` would
+    /// return for `doc_ids`, or for every document if empty; two stores
+    /// with equal roots hold the same versions.  Replies `CombinedRoot`.
+    GetCombinedRoot { doc_ids: Vec<String> },
+
+    /// Compare the caller's roots with every local one (see
+    /// `merkle::diff_roots`); replies `SyncDiff` with the ids to send to
+    /// the caller and to request from it.
+    DiffRoots { remote_roots: Vec<Root> },
+
+    /// Which of `hashes` the store lacks, in the order given; replies
+    /// `MissingBlobs`.  A peer asks before pushing the blobs its changes
+    /// declare.
+    GetMissingBlobs { hashes: Vec<Vec<u8>> },
+
+    /// Store blobs sent by a peer with their hashes; replies `BlobsStored`.
+    /// If any hash isn't the blake3 of its data, none are stored.
+    /// `peer_id` as in `ApplyChanges`.
+    PushBlobs {
+        blobs: Vec<HashedBlob>,
+        peer_id: Option<String>,
+    },
+
+    /// The combined root (as `GetCombinedRoot` with no ids) signed with
+    /// the store's `KEYRING_STORE_SIGNING_KEY`, at an epoch that rises with
+    /// every new root; replies `SignedRoot`.
+    GetSignedRoot,
+
+    /// Register a sync peer, or update a registered one; replies `Ok`.
+    /// Once any peer is registered, `ApplyChanges` and `PushBlobs` must
+    /// name one allowed the namespace (every one if `namespaces` is empty).
+    /// With a 32-byte Ed25519 `public_key`, its changes must also carry a
+    /// root signed by that key.
+    AddPeer {
+        peer_id: String,
+        public_key: Vec<u8>,
+        namespaces: Vec<String>,
+    },
+
+    /// Unregister a sync peer; replies `Ok` or `NotFound`.
+    RemovePeer { peer_id: String },
+
+    /// Every registered sync peer, in id order; replies `Peers`.
+    ListPeers,
+
+    /// Stream, as `GetChanges` does, the documents changed since
+    /// `peer_id`'s checkpoint (every document the first time).  Each part
+    /// carries the `head` to pass to `AckChangesSince` once all of them
+    /// are applied (see `checkpoints`).
+    GetChangesSince { peer_id: String },
+
+    /// How far `peer_id` has acknowledged `GetChangesSince`; replies
+    /// `SyncCheckpoint` or `NotFound`.
+    GetSyncCheckpoint { peer_id: String },
+
+    /// Forget `peer_id`'s checkpoint, so its next `GetChangesSince` sends
+    /// every document; replies `Ok` or `NotFound`.
+    ResetSyncCheckpoint { peer_id: String },
+
+    /// One Merkle root per group of documents sharing the first `depth`
+    /// characters of their id (see `merkle::sub_roots`), at most
+    /// `MAX_SUB_ROOT_DEPTH`; replies `SubRoots`.  A peer compares them with
+    /// its own and fetches only the groups that differ, e.g. with
+    /// `GetChangeHeads { prefix }`.
+    GetSubRoots { depth: u32 },
+
+    /// Move `peer_id`'s checkpoint to `head`, the one its last
+    /// `GetChangesSince` carried, once it has applied every part; replies
+    /// `Ok`, or `NotFound` if `head` isn't the one last offered.
+    AckChangesSince { peer_id: String, head: u64 },
+}
+
+impl Request {
+    /// Stable snake_case name of the request type, used for rate-limit
+    /// configuration and logging.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Request::PutBlob { .. } => "put_blob",
+            Request::GetBlob { .. } => "get_blob",
+            Request::HasBlob { .. } => "has_blob",
+            Request::PutDocument { .. } => "put_document",
+            Request::GetDocument { .. } => "get_document",
+            Request::DeleteDocument { .. } => "delete_document",
+            Request::ListDocuments { .. } => "list_documents",
+            Request::GetRoots { .. } => "get_roots",
+            Request::GetChanges { .. } => "get_changes",
+            Request::ApplyChanges { .. } => "apply_changes",
+            Request::PutBlobs { .. } => "put_blobs",
+            Request::GetBlobs { .. } => "get_blobs",
+            Request::ListBlobs { .. } => "list_blobs",
+            Request::Gc { .. } => "gc",
+            Request::OpenTenant { .. } => "open_tenant",
+            Request::CloseTenant { .. } => "close_tenant",
+            Request::GetDocumentHistory { .. } => "get_document_history",
+            Request::GetDocumentVersion { .. } => "get_document_version",
+            Request::QueryDocuments { .. } => "query_documents",
+            Request::Count { .. } => "count",
+            Request::Stats => "stats",
+            Request::Compact => "compact",
+            Request::Backup { .. } => "backup",
+            Request::Import { .. } => "import",
+            Request::Verify { .. } => "verify",
+            Request::Repair { .. } => "repair",
+            Request::ProvideKey { .. } => "provide_key",
+            Request::RotateKey { .. } => "rotate_key",
+            Request::Watch { .. } => "watch",
+            Request::Unwatch { .. } => "unwatch",
+            Request::GetChangelog { .. } => "get_changelog",
+            Request::AttachBlob { .. } => "attach_blob",
+            Request::DetachBlob { .. } => "detach_blob",
+            Request::ListAttachments { .. } => "list_attachments",
+            Request::WhoReferences { .. } => "who_references",
+            Request::ListDocumentsRange { .. } => "list_documents_range",
+            Request::FilterDocuments { .. } => "filter_documents",
+            Request::Search { .. } => "search",
+            Request::HotDocuments { .. } => "hot_documents",
+            Request::DedupStats => "dedup_stats",
+            Request::GetDocuments { .. } => "get_documents",
+            Request::GetDocHash { .. } => "get_doc_hash",
+            Request::PutDocumentMeta { .. } => "put_document_meta",
+            Request::BeginTxn => "begin_txn",
+            Request::TxnWrite { .. } => "txn_write",
+            Request::Commit { .. } => "commit",
+            Request::Abort { .. } => "abort",
+            Request::MaintenanceStatus => "maintenance_status",
+            Request::GetBucket { .. } => "get_bucket",
+            Request::StartSync { .. } => "start_sync",
+            Request::ContinueSync { .. } => "continue_sync",
+            Request::FinishSync { .. } => "finish_sync",
+            Request::GetChangesBloom { .. } => "get_changes_bloom",
+            Request::GetIblt { .. } => "get_iblt",
+            Request::ReconcileIblt { .. } => "reconcile_iblt",
+            Request::GetSiblings { .. } => "get_siblings",
+            Request::GetChangeHeads { .. } => "get_change_heads",
+            Request::GetCombinedRoot { .. } => "get_combined_root",
+            Request::DiffRoots { .. } => "diff_roots",
+            Request::GetMissingBlobs { .. } => "get_missing_blobs",
+            Request::PushBlobs { .. } => "push_blobs",
+            Request::GetSignedRoot => "get_signed_root",
+            Request::AddPeer { .. } => "add_peer",
+            Request::RemovePeer { .. } => "remove_peer",
+            Request::ListPeers => "list_peers",
+            Request::GetChangesSince { .. } => "get_changes_since",
+            Request::GetSyncCheckpoint { .. } => "get_sync_checkpoint",
+            Request::ResetSyncCheckpoint { .. } => "reset_sync_checkpoint",
+            Request::GetSubRoots { .. } => "get_sub_roots",
+            Request::AckChangesSince { .. } => "ack_changes_since",
+        }
+    }
 }
 
 // ── Responses ─────────────────────────────────────────────────────────
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Response {
     Ok,
 
@@ -67,10 +524,15 @@ pub enum Response {
         exists: bool,
     },
 
+    /// `version` counts the puts of the document, see `DocumentStored`.
+    /// `times` is `None` unless asked for, or if the document was last
+    /// stored before timestamps were recorded.
     Document {
         id: String,
         meta: Vec<u8>,
         crdt_state: Vec<u8>,
+        version: u64,
+        times: Option<Timestamps>,
     },
 
     DocumentList {
@@ -87,27 +549,631 @@ pub enum Response {
         changes: Vec<Change>,
     },
 
+    /// Reply to `DiffRoots`: document ids (UTF-8), sorted.  A document
+    /// both sides hold in different versions is in both lists.
     SyncDiff {
         to_send: Vec<Vec<u8>>,
         to_request: Vec<Vec<u8>>,
     },
 
     Error {
+        code: ErrorCode,
         message: String,
     },
+
+    /// One chunk of a streamed `GetChanges` reply.  Chunks share the
+    /// request's ref_id, are numbered from 0, and the final one has `last`.
+    /// `signed` signs `changes` if the store has a signing key.  `head` is
+    /// set on a `GetChangesSince` stream, for `AckChangesSince`.
+    ChangesPart {
+        seq: u32,
+        last: bool,
+        changes: Vec<Change>,
+        signed: Option<Box<SignedRootInfo>>,
+        head: Option<u64>,
+    },
+
+    /// The request type is over its rate limit; retry after the given delay.
+    Busy {
+        retry_after_ms: u64,
+    },
+
+    BlobsStored {
+        hashes: Vec<Vec<u8>>,
+    },
+
+    Blobs {
+        found: Vec<HashedBlob>,
+        missing: Vec<Vec<u8>>,
+    },
+
+    /// One page of `ListBlobs`; `next_cursor` is `None` on the last page.
+    BlobList {
+        blobs: Vec<BlobInfo>,
+        next_cursor: Option<Vec<u8>>,
+    },
+
+    GcReport {
+        blobs_scanned: u64,
+        unreferenced: u64,
+        reclaimable_bytes: u64,
+        swept: bool,
+    },
+
+    /// Retained versions of a document, newest first.
+    DocumentHistory {
+        versions: Vec<VersionInfo>,
+    },
+
+    DocumentVersion {
+        crdt_state: Vec<u8>,
+    },
+
+    Count {
+        count: u64,
+    },
+
+    /// Page counts come from redb; `free_pages` is what `Compact` could
+    /// give back.  `tables` covers every table in the file, each with its
+    /// entries, key and value bytes and b-tree height.  `blob_bytes`
+    /// covers the request's namespace only.  `db_cache_bytes` is redb's
+    /// page cache for the file, as set by `--db-cache-bytes`.  `throttles`
+    /// is every `--bandwidth-limit`, port-wide.
+    Stats {
+        file_bytes: u64,
+        page_size: u64,
+        allocated_pages: u64,
+        free_pages: u64,
+        fragmented_bytes: u64,
+        stored_bytes: u64,
+        metadata_bytes: u64,
+        tables: Vec<TableStats>,
+        blob_bytes: u64,
+        last_compaction: Option<u64>,
+        db_cache_bytes: u64,
+        throttles: Vec<ThrottleStats>,
+    },
+
+    Compacted {
+        bytes_before: u64,
+        bytes_after: u64,
+    },
+
+    /// `file_bytes` is the size of the backup's database file;
+    /// `spilled_blobs` counts the spill files copied next to it.
+    BackedUp {
+        file_bytes: u64,
+        spilled_blobs: u64,
+    },
+
+    /// `skipped` counts documents and tombstones left alone under
+    /// `ImportPolicy::Merge`.
+    Imported {
+        documents: u64,
+        tombstones: u64,
+        blobs: u64,
+        skipped: u64,
+    },
+
+    /// `problems` lists at most the first 1000 of `problem_count`; an empty
+    /// list means the database is consistent.
+    Verified {
+        documents: u64,
+        blobs: u64,
+        problems: Vec<IntegrityProblem>,
+        problem_count: u64,
+    },
+
+    /// What `Repair` changed, one entry per row (the first 1000 of
+    /// `fix_count`); `message` says what was done.
+    Repaired {
+        fixes: Vec<IntegrityProblem>,
+        fix_count: u64,
+    },
+
+    /// Reply to `PutDocument`.  `version` is 1 for a new document and goes
+    /// up by one with every put, so it orders copies of a document where
+    /// its state hash can't.
+    DocumentStored {
+        version: u64,
+    },
+
+    /// Reply to a `create_only` `PutDocument` naming a live document.
+    AlreadyExists,
+
+    /// Event of a `Watch`, carrying its ref_id.  `seq` numbers the watch's
+    /// events from 0; `hash` is the new state hash, or the deletion hash if
+    /// `deleted`.  Writes close together may be reported once, with the
+    /// later hash.
+    DocumentChanged {
+        seq: u64,
+        id: String,
+        hash: Vec<u8>,
+        deleted: bool,
+    },
+
+    /// One page of `GetChangelog`; `next_seq` is the `from_seq` of the
+    /// next page, and of the next poll once a page comes back short.
+    Changelog {
+        entries: Vec<ChangelogEntry>,
+        next_seq: u64,
+    },
+
+    Attachments {
+        attachments: Vec<AttachmentInfo>,
+    },
+
+    /// One page of `ListDocumentsRange`; `next_start` is `None` on the last
+    /// page.
+    DocumentRange {
+        ids: Vec<String>,
+        next_start: Option<String>,
+    },
+
+    /// `ListDocuments` with `with_timestamps`.
+    DocumentEntries {
+        entries: Vec<DocumentEntry>,
+    },
+
+    AccessStats {
+        entries: Vec<AccessEntry>,
+    },
+
+    /// `stored_bytes` is `logical_bytes - deduplicated_bytes`, the bytes
+    /// the puts actually added before compression.  Counts start with the
+    /// first put after upgrading and include blobs deleted since.
+    DedupStats {
+        puts: u64,
+        hits: u64,
+        logical_bytes: u64,
+        deduplicated_bytes: u64,
+        stored_bytes: u64,
+    },
+
+    /// Reply to `GetDocuments`: the documents found, in request order, and
+    /// the ids that don't exist.
+    Documents {
+        found: Vec<DocumentData>,
+        missing: Vec<String>,
+    },
+
+    DocHash {
+        hash: Vec<u8>,
+    },
+
+    TxnStarted {
+        txn: u64,
+    },
+
+    /// The reply each staged write would have had on its own, in staging
+    /// order.
+    Committed {
+        results: Vec<Response>,
+    },
+
+    /// `window` is `None` when no maintenance is scheduled; `next_window`
+    /// is when it next opens (unix seconds); `running` names the step in
+    /// progress, e.g. `compact /data`; `latest` is the run in progress or
+    /// the last one.
+    MaintenanceStatus {
+        window: Option<String>,
+        next_window: Option<u64>,
+        running: Option<String>,
+        latest: Option<MaintenanceRun>,
+    },
+
+    /// Reply to `GetBucket`: the documents (and tombstones) under the
+    /// bucket and the XOR of their digests.  A bucket of more than 64
+    /// documents above the leaves has its 16 `children`; any other lists
+    /// its `roots`.
+    Bucket {
+        count: u64,
+        digest: Vec<u8>,
+        children: Vec<BucketSummary>,
+        roots: Vec<Root>,
+    },
+
+    /// Reply to `StartSync`: `total` changes are waiting.
+    SyncStarted {
+        session_id: u64,
+        total: u64,
+    },
+
+    /// Reply to `ContinueSync`.  `next_ack` acknowledges this batch in the
+    /// next `ContinueSync`; `remaining` changes are still to come, and at 0
+    /// the session can be finished.  `signed` signs `changes` if the store
+    /// has a signing key.
+    SyncBatch {
+        changes: Vec<Change>,
+        next_ack: u64,
+        remaining: u64,
+        signed: Option<Box<SignedRootInfo>>,
+    },
+
+    /// Pushed with ref_id 0 when a background anti-entropy check finds the
+    /// doc hash buckets of `namespace` in the database at `database` out
+    /// of step with doc_hashes (`drifted_buckets` lists their prefixes),
+    /// or can't read them.
+    IntegrityAlert {
+        database: String,
+        namespace: String,
+        drifted_buckets: Vec<Vec<u8>>,
+        message: String,
+    },
+
+    /// Reply to `GetIblt`.
+    Iblt {
+        cells: Vec<IbltCell>,
+    },
+
+    /// Reply to `ReconcileIblt`: the roots of the documents the caller
+    /// lacks or holds in another version, and the element keys (blake3
+    /// over the id's 8-byte LE length, the id and the hash) only the
+    /// caller holds.  Unless `complete`, the table was too small to decode
+    /// everything and both lists are partial.
+    IbltDiff {
+        local_only: Vec<Root>,
+        remote_only: Vec<Vec<u8>>,
+        complete: bool,
+    },
+
+    /// Pushed with ref_id 0 while pulling from the `--peer` at `peer`:
+    /// `applied` of the `total` documents of `namespace` that differed have
+    /// been applied.  `done` ends the pull of the namespace; with `error`
+    /// it failed, and the pull of the remaining namespaces was abandoned.
+    PeerSync {
+        peer: String,
+        namespace: String,
+        applied: u64,
+        total: u64,
+        done: bool,
+        error: Option<String>,
+    },
+
+    /// Reply to `ApplyChanges`: `applied` changes altered a document, the
+    /// rest were already held, stale or lost a conflict.  `conflicts` lists
+    /// the changes that met a local version they don't build on; each was
+    /// settled by the document's CRDT engine or the conflict policy.
+    Applied {
+        applied: u64,
+        conflicts: Vec<ConflictInfo>,
+    },
+
+    /// Reply to `GetSiblings`.
+    Siblings {
+        siblings: Vec<SiblingInfo>,
+    },
+
+    /// Reply to `GetChangeHeads`, in id order.
+    ChangeHeads {
+        heads: Vec<ChangeHead>,
+    },
+
+    /// Reply to `GetCombinedRoot`: 32 bytes, all zero with no documents.
+    CombinedRoot {
+        root: Vec<u8>,
+    },
+
+    /// Reply to `GetMissingBlobs`.
+    MissingBlobs {
+        hashes: Vec<Vec<u8>>,
+    },
+
+    /// With `--root-in-replies`, the reply to a request that changed
+    /// documents: `response` and the namespace's combined root (as
+    /// `GetCombinedRoot` with no ids) as of a moment after the change.
+    WithRoot {
+        root: Vec<u8>,
+        response: Box<Response>,
+    },
+
+    /// Reply to `GetSignedRoot`.
+    SignedRoot {
+        signed: SignedRootInfo,
+    },
+
+    /// Reply to `ListPeers`.
+    Peers {
+        peers: Vec<PeerInfo>,
+    },
+
+    /// Reply to `GetSyncCheckpoint`: `peer_id` has every change up to
+    /// changelog `seq`, as of which the combined root was `root`, and
+    /// acknowledged them at unix seconds `at`.
+    SyncCheckpoint {
+        seq: u64,
+        root: Vec<u8>,
+        at: u64,
+    },
+
+    /// Reply to `GetSubRoots`: the non-empty groups, in prefix order.
+    SubRoots {
+        roots: Vec<SubRoot>,
+    },
+}
+
+impl Response {
+    pub fn error(code: ErrorCode, message: impl Into<String>) -> Self {
+        Response::Error {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl From<anyhow::Error> for Response {
+    fn from(e: anyhow::Error) -> Self {
+        let code = if e.is::<TooLarge>() {
+            ErrorCode::TooLarge
+        } else if e.is::<InvalidId>() {
+            ErrorCode::InvalidId
+        } else if e.is::<PeerRejected>() {
+            ErrorCode::Unauthorized
+        } else {
+            ErrorCode::Storage
+        };
+        Response::error(code, format!("{e:#}"))
+    }
 }
 
 // ── Auxiliary types ───────────────────────────────────────────────────
 
+/// Machine-readable category of an `Error` response.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ErrorCode {
+    /// The storage engine failed to complete the operation.
+    Storage,
+    /// The request frame could not be decoded.
+    Decode,
+    /// The request was well-formed but cannot be served as sent.
+    BadRequest,
+    /// The handler panicked; the port itself keeps serving.
+    Internal,
+    /// A blob or document exceeded `--max-blob-bytes` or `--max-doc-bytes`.
+    TooLarge,
+    /// A write named a document id `--max-id-len`, `--id-chars` or
+    /// `--reserved-id-prefix` rejects, or one with control characters.
+    InvalidId,
+    /// A sync write didn't name a registered peer allowed to make it.
+    Unauthorized,
+}
+
+/// One child of a `Bucket`; an empty one has count 0 and a zero digest.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BucketSummary {
+    pub count: u64,
+    pub digest: Vec<u8>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Root {
     pub doc_id: String,
     pub hash: Vec<u8>,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HashedBlob {
+    pub hash: Vec<u8>,
+    pub data: Vec<u8>,
+}
+
+/// `times` as in `Response::Document`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BlobInfo {
+    pub hash: Vec<u8>,
+    pub size: u64,
+    pub times: Option<Timestamps>,
+}
+
+/// Fields as in `Response::Document`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DocumentData {
+    pub id: String,
+    pub meta: Vec<u8>,
+    pub crdt_state: Vec<u8>,
+    pub version: u64,
+    pub times: Option<Timestamps>,
+}
+
+/// `times` as in `Response::Document`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DocumentEntry {
+    pub id: String,
+    pub times: Option<Timestamps>,
+}
+
+/// Reads counted since tracking began; `last_read` (unix seconds) is
+/// `None` for a document never read.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AccessEntry {
+    pub id: String,
+    pub reads: u64,
+    pub last_read: Option<u64>,
+}
+
+/// Totals of one maintenance run over every database.  Times are unix
+/// seconds; `finished_at` is `None` while it runs, and `completed` is false
+/// if the window closed before every step ran.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MaintenanceRun {
+    pub started_at: u64,
+    pub finished_at: Option<u64>,
+    pub completed: bool,
+    pub blobs_removed: u64,
+    pub blob_bytes_reclaimed: u64,
+    pub file_bytes_reclaimed: u64,
+    /// Documents and blobs deep-checked.
+    pub sampled: u64,
+    pub problems: u64,
+    /// Steps that failed, e.g. a compaction blocked by a long read.
+    pub errors: u64,
+}
+
+/// `size` is `None` if the attached blob has gone missing.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AttachmentInfo {
+    pub name: String,
+    pub hash: Vec<u8>,
+    pub size: Option<u64>,
+}
+
+/// A put or deletion of a document, as logged by the store.  `hash` is the
+/// new state hash, or the deletion hash if `deleted`; `at` is in unix
+/// seconds.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChangelogEntry {
+    pub seq: u64,
+    pub doc_id: String,
+    pub hash: Vec<u8>,
+    pub deleted: bool,
+    pub at: u64,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum CountTarget {
+    Documents,
+    Blobs,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TableStats {
+    pub name: String,
+    pub entries: u64,
+    /// Key and value bytes, after compression for blobs.
+    pub stored_bytes: u64,
+    pub tree_height: u32,
+}
+
+/// One bandwidth budget and what it has held back.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ThrottleStats {
+    /// `get_changes` or `push_blobs`.
+    pub kind: String,
+    pub bytes_per_sec: u64,
+    pub burst_bytes: u64,
+    /// Bytes that could go through now without waiting.
+    pub available_bytes: u64,
+    /// Bytes let through since the port started.
+    pub bytes: u64,
+    /// Time chunks have been held back for the budget since the port
+    /// started.
+    pub waited_ms: u64,
+    /// Chunks held back for the budget right now.
+    pub waiting: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IntegrityProblem {
+    /// Table holding the bad row, e.g. `doc_hashes` or `blobs@team`.
+    pub table: String,
+    /// Document id, or hex blob hash.
+    pub key: String,
+    pub message: String,
+}
+
+/// Bytes kept out of logs: `Debug` shows only their length.  Encoded as a
+/// plain byte vector.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct SecretBytes(pub Vec<u8>);
+
+impl fmt::Debug for SecretBytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "<{} secret bytes>", self.0.len())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VersionInfo {
+    pub hash: Vec<u8>,
+    /// Unix seconds at which the version was written.
+    pub saved_at: u64,
+    pub size: u64,
+}
+
+/// A change `GetChanges` would send, without its data.  A deletion has
+/// size 0.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChangeHead {
+    pub doc_id: String,
+    pub hash: Vec<u8>,
+    pub deleted: bool,
+    /// Length of the CRDT state.
+    pub size: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SiblingInfo {
+    pub hash: Vec<u8>,
+    /// Unix seconds at which the sibling was kept.
+    pub saved_at: u64,
+    pub crdt_state: Vec<u8>,
+}
+
+/// A combined root signed by the store that computed it.  The signature
+/// (Ed25519, 64 bytes) covers the namespace, `epoch` and `root`, and
+/// `changes`, the digest of a batch of changes, when signing one.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SignedRootInfo {
+    pub root: Vec<u8>,
+    pub epoch: u64,
+    pub public_key: Vec<u8>,
+    pub signature: Vec<u8>,
+    pub changes: Option<Vec<u8>>,
+}
+
+/// A change to `doc_id` that diverged from the local version: the local
+/// hash was neither the change's base, nor among its ancestors, nor a
+/// version it deletes, and the change's hash isn't among the local
+/// ancestors either.  Either side may be a deletion, whose hash is then the
+/// deletion hash.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConflictInfo {
+    pub doc_id: String,
+    pub local_hash: Vec<u8>,
+    pub local_deleted: bool,
+    pub remote_hash: Vec<u8>,
+    pub remote_deleted: bool,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Change {
     pub doc_id: String,
     pub data: Vec<u8>,
+    /// blake3 of the state, which a receiver checks before applying it.
     pub hash: Vec<u8>,
+    /// The document was deleted; `hash` is its deletion hash and `data` is
+    /// empty.
+    pub deleted: bool,
+    /// `data` is a delta (see `delta`) from the version with this state
+    /// hash, which the receiver holds, rather than the whole state.
+    pub base_hash: Option<Vec<u8>>,
+    /// Unix seconds at which the sender wrote this version (or deleted the
+    /// document), if it knows; the last-writer-wins conflict policy
+    /// compares it with the local version's.
+    pub updated_at: Option<u64>,
+    /// Hashes of the versions this one descends from on the sender, newest
+    /// first, at most `ANCESTRY_DEPTH` of 32 bytes (see `ancestry`).
+    pub ancestors: Vec<Vec<u8>>,
+    /// Hashes of the blobs the version references or has attached, sorted;
+    /// the receiver fetches those it lacks.  Empty for a deletion.
+    pub blobs: Vec<Vec<u8>>,
+}
+
+/// A registered sync peer, as `store::Peer`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PeerInfo {
+    pub peer_id: String,
+    pub public_key: Vec<u8>,
+    pub namespaces: Vec<String>,
+    /// Unix seconds of its last accepted request; 0 if it sent none.
+    pub last_seen: u64,
+}
+
+/// The documents whose ids start with `prefix`, and their Merkle root.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SubRoot {
+    pub prefix: String,
+    pub count: u64,
+    pub root: Vec<u8>,
 }
@@ -0,0 +1,291 @@
+//! The peer link's transport: QUIC (quinn) with TLS 1.3 (rustls).
+//!
+//! A store's TLS identity is a self-signed certificate for the Ed25519 key
+//! it signs roots with (`KEYRING_STORE_SIGNING_KEY`), so either end of a
+//! link proves it holds its key.  No authority vouches for certificates:
+//! a key is trusted when it is a registered peer's (see `trust`).  The
+//! listener only completes handshakes with clients whose key is some
+//! registered peer's; the puller only with a server whose key is the one
+//! registered for the `--peer` address it dialled.
+//!
+//! quinn runs on a tokio runtime, while the rest of the port is threads
+//! doing blocking I/O, so `BlockingRecv` and `BlockingSend` let a thread
+//! use a QUIC stream as a plain `Read`/`Write`, with the usual framing.
+
+use crate::store::{SigningKey, Store};
+use anyhow::{anyhow, Context, Result};
+use ed25519_dalek::pkcs8::DecodePublicKey;
+use ed25519_dalek::VerifyingKey;
+use quinn::crypto::rustls::{QuicClientConfig, QuicServerConfig};
+use quinn::{Connection, Endpoint, RecvStream, SendStream, TransportConfig};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::{CertificateDer, PrivatePkcs8KeyDer, ServerName, UnixTime};
+use rustls::server::danger::{ClientCertVerified, ClientCertVerifier};
+use rustls::{CertificateError, DigitallySignedStruct, DistinguishedName, SignatureScheme};
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::runtime::{Handle, Runtime};
+
+/// Name every store's certificate is issued to, and that clients ask for;
+/// it identifies nothing, keys do.
+const SERVER_NAME: &str = "ringforge-store";
+
+/// ALPN protocol of the peer link.
+const ALPN: &[u8] = b"ringforge-peer/1";
+
+/// The runtime quinn's endpoints run on.  It has worker threads of its own,
+/// so `BlockingRecv` and `BlockingSend` can wait on it from any thread.
+pub fn runtime() -> Result<Runtime> {
+    tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(1)
+        .thread_name("quic")
+        .enable_all()
+        .build()
+        .context("starting the QUIC runtime")
+}
+
+/// A server endpoint on `addr` identified by `key`, completing handshakes
+/// only with registered peers of `store`.  One request stream per
+/// connection.  Call within `runtime`'s context.
+pub fn server(addr: SocketAddr, key: &SigningKey, store: Store, idle: Duration) -> Result<Endpoint> {
+    let (cert, private) = identity(key)?;
+    let verifier = Arc::new(RegisteredPeers {
+        store,
+        provider: provider(),
+    });
+    let mut tls = rustls::ServerConfig::builder_with_provider(provider())
+        .with_protocol_versions(&[&rustls::version::TLS13])?
+        .with_client_cert_verifier(verifier)
+        .with_single_cert(vec![cert], private.into())?;
+    tls.alpn_protocols = vec![ALPN.to_vec()];
+    let mut config = quinn::ServerConfig::with_crypto(Arc::new(QuicServerConfig::try_from(tls)?));
+    let mut transport = TransportConfig::default();
+    transport
+        .max_concurrent_bidi_streams(1u32.into())
+        .max_concurrent_uni_streams(0u32.into())
+        .max_idle_timeout(Some(idle.try_into()?));
+    config.transport_config(Arc::new(transport));
+    Endpoint::server(config, addr).with_context(|| format!("listening on {addr}"))
+}
+
+/// A client endpoint identified by `key`, for a server whose key must be
+/// `expected`.  Call within `runtime`'s context.
+pub fn client(key: &SigningKey, expected: [u8; 32], idle: Duration) -> Result<Endpoint> {
+    let (cert, private) = identity(key)?;
+    let verifier = Arc::new(PinnedKey {
+        expected,
+        provider: provider(),
+    });
+    let mut tls = rustls::ClientConfig::builder_with_provider(provider())
+        .with_protocol_versions(&[&rustls::version::TLS13])?
+        .dangerous()
+        .with_custom_certificate_verifier(verifier)
+        .with_client_auth_cert(vec![cert], private.into())?;
+    tls.alpn_protocols = vec![ALPN.to_vec()];
+    let mut config = quinn::ClientConfig::new(Arc::new(QuicClientConfig::try_from(tls)?));
+    let mut transport = TransportConfig::default();
+    transport.max_idle_timeout(Some(idle.try_into()?));
+    config.transport_config(Arc::new(transport));
+    let bind: SocketAddr = ([0, 0, 0, 0, 0, 0, 0, 0], 0).into();
+    let mut endpoint = Endpoint::client(bind)
+        .or_else(|_| Endpoint::client(([0, 0, 0, 0], 0).into()))
+        .context("opening a QUIC socket")?;
+    endpoint.set_default_client_config(config);
+    Ok(endpoint)
+}
+
+/// `connect` to `addr` and open the request stream.
+pub async fn connect(
+    endpoint: &Endpoint,
+    addr: SocketAddr,
+) -> Result<(Connection, SendStream, RecvStream)> {
+    let connection = endpoint.connect(addr, SERVER_NAME)?.await?;
+    let (send, recv) = connection.open_bi().await?;
+    Ok((connection, send, recv))
+}
+
+/// The Ed25519 key of the peer at the other end of `connection`, as its
+/// certificate carries it.
+pub fn peer_key(connection: &Connection) -> Result<[u8; 32]> {
+    let certs = connection
+        .peer_identity()
+        .and_then(|identity| identity.downcast::<Vec<CertificateDer<'static>>>().ok())
+        .context("the peer presented no certificate")?;
+    let cert = certs.first().context("the peer presented no certificate")?;
+    cert_key(cert).map_err(|e| anyhow!("{e}"))
+}
+
+/// A QUIC receive stream read from a thread outside the runtime.
+pub struct BlockingRecv {
+    pub handle: Handle,
+    pub stream: RecvStream,
+}
+
+impl Read for BlockingRecv {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.handle.block_on(self.stream.read(buf));
+        Ok(read.map_err(io::Error::from)?.unwrap_or(0))
+    }
+}
+
+/// A QUIC send stream written from a thread outside the runtime.
+pub struct BlockingSend {
+    pub handle: Handle,
+    pub stream: SendStream,
+}
+
+impl Write for BlockingSend {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.handle.block_on(self.stream.write(buf));
+        written.map_err(io::Error::from)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+/// A self-signed certificate for `key`, and the key as rustls takes it.
+fn identity(key: &SigningKey) -> Result<(CertificateDer<'static>, PrivatePkcs8KeyDer<'static>)> {
+    let pkcs8 = key.to_pkcs8_der()?;
+    let key_pair = rcgen::KeyPair::try_from(pkcs8.as_slice()).context("loading signing key")?;
+    let cert = rcgen::CertificateParams::new(vec![SERVER_NAME.to_string()])?
+        .self_signed(&key_pair)
+        .context("making a certificate")?;
+    Ok((cert.der().clone(), PrivatePkcs8KeyDer::from(pkcs8.to_vec())))
+}
+
+/// The Ed25519 key `cert` is for.
+fn cert_key(cert: &CertificateDer<'_>) -> Result<[u8; 32], rustls::Error> {
+    let bad = |error| rustls::Error::InvalidCertificate(error);
+    let cert = webpki::EndEntityCert::try_from(cert).map_err(|_| bad(CertificateError::BadEncoding))?;
+    let spki = cert.subject_public_key_info();
+    let key = VerifyingKey::from_public_key_der(spki.as_ref())
+        .map_err(|_| bad(CertificateError::ApplicationVerificationFailure))?;
+    Ok(key.to_bytes())
+}
+
+/// Accepts a client certificate for the key of a registered peer.
+struct RegisteredPeers {
+    store: Store,
+    provider: Arc<CryptoProvider>,
+}
+
+impl fmt::Debug for RegisteredPeers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RegisteredPeers").finish_non_exhaustive()
+    }
+}
+
+impl ClientCertVerifier for RegisteredPeers {
+    fn root_hint_subjects(&self) -> &[DistinguishedName] {
+        &[]
+    }
+
+    fn verify_client_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _now: UnixTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        let key = cert_key(end_entity)?;
+        let peers = self
+            .store
+            .peers()
+            .map_err(|e| rustls::Error::General(format!("reading peers: {e:#}")))?;
+        if peers.iter().any(|peer| peer.public_key == key) {
+            Ok(ClientCertVerified::assertion())
+        } else {
+            Err(rustls::Error::InvalidCertificate(CertificateError::ApplicationVerificationFailure))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+/// Accepts a server certificate for one key only.
+#[derive(Debug)]
+struct PinnedKey {
+    expected: [u8; 32],
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedKey {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if cert_key(end_entity)? == self.expected {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::InvalidCertificate(CertificateError::ApplicationVerificationFailure))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_certificate_carries_the_signing_key() {
+        let key = SigningKey::from_bytes(&[5; 32]).unwrap();
+        let (cert, _) = identity(&key).unwrap();
+        assert_eq!(cert_key(&cert).unwrap(), key.public());
+        assert!(cert_key(&CertificateDer::from(vec![0x30, 0x00])).is_err());
+    }
+}
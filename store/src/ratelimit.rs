@@ -0,0 +1,151 @@
+//! Token-bucket rate limiting per request type.
+//!
+//! Limits are configured as `kind=rate[/burst]`, e.g. `apply_changes=5/20`
+//! allows 5 requests per second with bursts of up to 20.  Request types
+//! without a configured limit are never throttled.
+
+use anyhow::{bail, Context, Result};
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// A configured limit for one request type.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RateLimit {
+    pub kind: String,
+    /// Sustained requests per second.
+    pub rate: f64,
+    /// Maximum tokens that can accumulate while idle.
+    pub burst: f64,
+}
+
+impl FromStr for RateLimit {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (kind, spec) = s
+            .split_once('=')
+            .context("expected kind=rate[/burst]")?;
+        let (rate, burst) = match spec.split_once('/') {
+            Some((r, b)) => (r, Some(b)),
+            None => (spec, None),
+        };
+        let rate: f64 = rate.parse().context("invalid rate")?;
+        let burst: f64 = match burst {
+            Some(b) => b.parse().context("invalid burst")?,
+            None => rate.max(1.0),
+        };
+        if kind.is_empty() || rate <= 0.0 || burst < 1.0 {
+            bail!("rate must be positive and burst at least 1");
+        }
+        Ok(Self {
+            kind: kind.to_string(),
+            rate,
+            burst,
+        })
+    }
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: f64, burst: f64, now: Instant) -> Self {
+        Self {
+            rate,
+            burst,
+            tokens: burst,
+            last: now,
+        }
+    }
+
+    /// Take one token, or return how long until one is available.
+    fn try_acquire(&mut self, now: Instant) -> Result<(), Duration> {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.last = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - self.tokens) / self.rate))
+        }
+    }
+}
+
+/// Per-request-type token buckets.
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    buckets: Mutex<HashMap<String, TokenBucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(limits: &[RateLimit]) -> Self {
+        let now = Instant::now();
+        let buckets = limits
+            .iter()
+            .map(|l| (l.kind.clone(), TokenBucket::new(l.rate, l.burst, now)))
+            .collect();
+        Self {
+            buckets: Mutex::new(buckets),
+        }
+    }
+
+    /// Admit a request of `kind`, or return the suggested retry delay.
+    pub fn check(&self, kind: &str) -> Result<(), Duration> {
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        match buckets.get_mut(kind) {
+            Some(bucket) => bucket.try_acquire(Instant::now()),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_limit() {
+        let l: RateLimit = "apply_changes=5/20".parse().unwrap();
+        assert_eq!(l.kind, "apply_changes");
+        assert_eq!(l.rate, 5.0);
+        assert_eq!(l.burst, 20.0);
+
+        let l: RateLimit = "get_changes=0.5".parse().unwrap();
+        assert_eq!(l.burst, 1.0);
+
+        assert!("get_changes".parse::<RateLimit>().is_err());
+        assert!("get_changes=0".parse::<RateLimit>().is_err());
+    }
+
+    #[test]
+    fn test_bucket_refills() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(2.0, 2.0, start);
+        assert!(bucket.try_acquire(start).is_ok());
+        assert!(bucket.try_acquire(start).is_ok());
+
+        let wait = bucket.try_acquire(start).unwrap_err();
+        assert_eq!(wait, Duration::from_millis(500));
+
+        assert!(bucket.try_acquire(start + Duration::from_millis(500)).is_ok());
+    }
+
+    #[test]
+    fn test_unlimited_kinds_pass() {
+        let limiter = RateLimiter::new(&["apply_changes=1/1".parse().unwrap()]);
+        for _ in 0..10 {
+            assert!(limiter.check("get_document").is_ok());
+        }
+        assert!(limiter.check("apply_changes").is_ok());
+        assert!(limiter.check("apply_changes").is_err());
+    }
+}
@@ -0,0 +1,309 @@
+//! Interactive shell for poking a store by hand.
+//!
+//! Each line is a command such as `put-doc notes/1 @file.bin` or
+//! `get-blob <hex>`, turned into the request the port would get and sent
+//! either to an in-process server over a data dir or, with `--connect`, to
+//! a running endpoint in the port's framing.  Replies are printed in a
+//! readable form rather than as bincode.  Values are given as `@path` to
+//! read a file or as literal text.
+
+use crate::frame::{read_frame, write_frame};
+use crate::protocol::{Envelope, RefId, Request, Response};
+use crate::server::Server;
+use crate::store::{from_hex, to_hex};
+use crate::transport::{self, AuthToken, Connection};
+use anyhow::{bail, Context, Result};
+use std::fmt::Write as _;
+use std::fs;
+use std::io::{BufRead, Write};
+
+/// Bytes of a value shown before it is cut short.
+const PREVIEW_BYTES: usize = 64;
+
+const HELP: &str = "\
+commands (VALUE is @path or literal text):
+  put-doc ID VALUE [META]   store a document with VALUE as its CRDT state
+  get-doc ID [@OUT]         show a document, or write its state to OUT
+  del-doc ID                delete a document
+  ls [PREFIX]               list document ids
+  put-blob VALUE            store a blob and print its hash
+  get-blob HASH [@OUT]      show a blob, or write it to OUT
+  has-blob HASH             whether a blob is stored
+  roots [ID...]             combined root and per-document roots
+  stats                     database size and tables
+  verify [deep]             check integrity
+  ns [NAME]                 show or switch the namespace ('' is the default)
+  help                      this list
+  quit                      leave";
+
+/// Where requests go.
+pub enum Endpoint {
+    Local(Box<Server>),
+    Remote {
+        connection: Connection,
+        next_ref: RefId,
+    },
+}
+
+impl Endpoint {
+    pub fn connect(addr: &str, token: Option<&AuthToken>) -> Result<Self> {
+        Ok(Endpoint::Remote {
+            connection: transport::connect(addr, token)?,
+            next_ref: 0,
+        })
+    }
+
+    /// Send one request and collect its replies: one, or every part of a
+    /// streamed reply.
+    fn call(&mut self, namespace: &str, request: Request) -> Result<Vec<Response>> {
+        match self {
+            Endpoint::Local(server) => {
+                let envelope = envelope(1, namespace, request);
+                let mut frames: Vec<Vec<u8>> = Vec::new();
+                server.handle_frame(&bincode::serialize(&envelope)?, &mut frames)?;
+                frames
+                    .iter()
+                    .map(|frame| Ok(bincode::deserialize::<(RefId, Response)>(frame)?.1))
+                    .collect()
+            }
+            Endpoint::Remote {
+                connection,
+                next_ref,
+            } => {
+                *next_ref += 1;
+                let ref_id = *next_ref;
+                let envelope = envelope(ref_id, namespace, request);
+                write_frame(&mut connection.writer, &bincode::serialize(&envelope)?)?;
+                let mut replies = Vec::new();
+                loop {
+                    let frame = read_frame(&mut connection.reader)?
+                        .context("endpoint closed the connection")?;
+                    let (got, response): (RefId, Response) = bincode::deserialize(&frame)?;
+                    if got != ref_id {
+                        // Pushed notifications and the like.
+                        println!("(ref_id {got}) {}", show(&response));
+                        continue;
+                    }
+                    let more = matches!(response, Response::ChangesPart { last: false, .. });
+                    replies.push(response);
+                    if !more {
+                        return Ok(replies);
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn envelope(ref_id: RefId, namespace: &str, request: Request) -> Envelope {
+    Envelope {
+        ref_id,
+        trace_id: None,
+        namespace: namespace.to_string(),
+        tenant: None,
+        durability: None,
+        request,
+    }
+}
+
+/// Read commands from `input` until it ends or `quit`.
+pub fn run(endpoint: &mut Endpoint, input: impl BufRead, prompt: bool) -> Result<()> {
+    let mut namespace = String::new();
+    let mut lines = input.lines();
+    loop {
+        if prompt {
+            print!("{}> ", if namespace.is_empty() { "default" } else { &namespace });
+            std::io::stdout().flush()?;
+        }
+        let Some(line) = lines.next() else {
+            return Ok(());
+        };
+        let line = line?;
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            [] => {}
+            ["quit" | "exit"] => return Ok(()),
+            ["help"] => println!("{HELP}"),
+            ["ns"] => println!("{:?}", namespace),
+            ["ns", name] => namespace = name.trim_matches('\'').to_string(),
+            _ => {
+                if let Err(e) = execute(endpoint, &namespace, &words) {
+                    println!("error: {e:#}");
+                }
+            }
+        }
+    }
+}
+
+fn execute(endpoint: &mut Endpoint, namespace: &str, words: &[&str]) -> Result<()> {
+    let (request, out) = match words {
+        ["put-doc", id, state, rest @ ..] if rest.len() <= 1 => (
+            Request::PutDocument {
+                id: id.to_string(),
+                meta: rest.first().map(|m| value(m)).transpose()?.unwrap_or_default(),
+                crdt_state: value(state)?,
+                index: None,
+                create_only: false,
+            },
+            None,
+        ),
+        ["get-doc", id, out @ ..] if out.len() <= 1 => (
+            Request::GetDocument {
+                id: id.to_string(),
+                with_timestamps: true,
+            },
+            out.first().copied(),
+        ),
+        ["del-doc", id] => (
+            Request::DeleteDocument {
+                id: id.to_string(),
+                cascade: false,
+            },
+            None,
+        ),
+        ["ls", prefix @ ..] if prefix.len() <= 1 => (
+            Request::ListDocuments {
+                prefix: prefix.first().unwrap_or(&"").to_string(),
+                with_timestamps: false,
+            },
+            None,
+        ),
+        ["put-blob", data] => (
+            Request::PutBlob {
+                data: value(data)?,
+                ttl_secs: None,
+            },
+            None,
+        ),
+        ["get-blob", hash, out @ ..] if out.len() <= 1 => (
+            Request::GetBlob {
+                hash: from_hex(hash)?,
+            },
+            out.first().copied(),
+        ),
+        ["has-blob", hash] => (
+            Request::HasBlob {
+                hash: from_hex(hash)?,
+            },
+            None,
+        ),
+        ["roots", ids @ ..] => {
+            let doc_ids: Vec<String> = ids.iter().map(|id| id.to_string()).collect();
+            for reply in endpoint.call(
+                namespace,
+                Request::GetCombinedRoot {
+                    doc_ids: doc_ids.clone(),
+                },
+            )? {
+                println!("{}", show(&reply));
+            }
+            (Request::GetRoots { doc_ids }, None)
+        }
+        ["stats"] => (Request::Stats, None),
+        ["verify"] => (Request::Verify { deep: false }, None),
+        ["verify", "deep"] => (Request::Verify { deep: true }, None),
+        _ => bail!("unknown command {:?}; try `help`", words.join(" ")),
+    };
+
+    for reply in endpoint.call(namespace, request)? {
+        let data = match (&reply, out) {
+            (Response::Blob { data }, Some(_)) => Some(data),
+            (Response::Document { crdt_state, .. }, Some(_)) => Some(crdt_state),
+            _ => None,
+        };
+        match (data, out) {
+            (Some(data), Some(out)) => {
+                let path = out.strip_prefix('@').context("output file must be given as @path")?;
+                fs::write(path, data).with_context(|| format!("writing {path}"))?;
+                println!("wrote {} bytes to {path}", data.len());
+            }
+            _ => println!("{}", show(&reply)),
+        }
+    }
+    Ok(())
+}
+
+/// A value argument: the contents of the file after `@`, or the text itself.
+fn value(arg: &str) -> Result<Vec<u8>> {
+    match arg.strip_prefix('@') {
+        Some(path) => fs::read(path).with_context(|| format!("reading {path}")),
+        None => Ok(arg.as_bytes().to_vec()),
+    }
+}
+
+/// Bytes as quoted text if they are printable UTF-8, else as hex; cut
+/// short after `PREVIEW_BYTES`.
+fn preview(bytes: &[u8]) -> String {
+    let head = &bytes[..bytes.len().min(PREVIEW_BYTES)];
+    let mut out = match std::str::from_utf8(head) {
+        Ok(text) if !text.chars().any(|c| c.is_control() && c != '\n' && c != '\t') => {
+            format!("{text:?}")
+        }
+        _ => format!("0x{}", to_hex(head)),
+    };
+    if bytes.len() > head.len() {
+        let _ = write!(out, "… ({} bytes)", bytes.len());
+    }
+    out
+}
+
+/// A reply for people: the common ones spelled out, the rest as `Debug`.
+fn show(response: &Response) -> String {
+    match response {
+        Response::Ok => "ok".to_string(),
+        Response::NotFound => "not found".to_string(),
+        Response::BlobStored { hash } => to_hex(hash),
+        Response::BlobExists { exists } => exists.to_string(),
+        Response::Blob { data } => format!("{} bytes: {}", data.len(), preview(data)),
+        Response::Document {
+            id,
+            meta,
+            crdt_state,
+            version,
+            times,
+        } => {
+            let mut out = format!("{id} v{version}");
+            if let Some(times) = times {
+                let _ = write!(out, " (created {}, updated {})", times.created_at, times.updated_at);
+            }
+            let _ = write!(out, "\n  meta:  {}\n  state: {}", preview(meta), preview(crdt_state));
+            out
+        }
+        Response::DocumentList { ids } => {
+            let mut out = format!("{} documents", ids.len());
+            for id in ids {
+                let _ = write!(out, "\n  {id}");
+            }
+            out
+        }
+        Response::Roots { roots } => {
+            let mut out = format!("{} roots", roots.len());
+            for root in roots {
+                let _ = write!(out, "\n  {} {}", to_hex(&root.hash), root.doc_id);
+            }
+            out
+        }
+        Response::CombinedRoot { root } => format!("combined root {}", to_hex(root)),
+        Response::Error { code, message } => format!("{code:?}: {message}"),
+        other => format!("{other:#?}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_preview() {
+        assert_eq!(preview(b"hello"), "\"hello\"");
+        assert_eq!(preview(&[0, 1, 0xff]), "0x0001ff");
+        let long = vec![b'a'; PREVIEW_BYTES + 1];
+        assert!(preview(&long).ends_with(&format!("… ({} bytes)", PREVIEW_BYTES + 1)));
+    }
+
+    #[test]
+    fn test_value() {
+        assert_eq!(value("text").unwrap(), b"text");
+        assert!(value("@/nonexistent/file").is_err());
+    }
+}
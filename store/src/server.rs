@@ -0,0 +1,1346 @@
+//! Serving loop: reads request frames, dispatches them, writes responses.
+
+use crate::antientropy;
+use crate::capture::{Direction, Recorder};
+use crate::dispatch::{
+    continue_sync, handle_request, is_groupable, stream_changes, stream_changes_bloom,
+    stream_changes_since, write_op, write_response, Paced, Streaming,
+};
+use crate::frame::{peek_ref_id, read_frame, write_frame};
+use crate::maintenance::{self, Maintenance, Window};
+use crate::peer;
+use crate::protocol::{Envelope, ErrorCode, RefId, Request, Response, ThrottleStats, NO_REF_ID};
+use crate::ratelimit::{RateLimit, RateLimiter};
+use crate::snapshotter;
+use crate::store::{validate_namespace, Store};
+use crate::sweeper;
+use crate::tenants::{validate_tenant, Tenants};
+use crate::throttle::{Deferred, Throttles};
+use crate::transport::{Connection, Listener};
+use crate::txns::Transactions;
+use crate::watch::{Filter, Watches};
+use anyhow::Result;
+use std::any::Any;
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::mem;
+use std::net::SocketAddr;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use tracing::{debug, error, info, info_span, warn, Span};
+
+/// Requests taking at least this long are logged at warn level.
+const SLOW_REQUEST: Duration = Duration::from_millis(500);
+
+/// How often an idle serving loop checks for a shutdown request.
+const SHUTDOWN_POLL: Duration = Duration::from_millis(100);
+
+/// Frames buffered between the reader thread and the serving loop.
+const READ_AHEAD: usize = 16;
+
+/// Destination for outbound response frames.
+pub trait FrameSink {
+    fn send_frame(&mut self, payload: &[u8]) -> Result<()>;
+}
+
+/// Writes frames to the wire, mirroring them into a capture when recording.
+struct WireSink<W: Write> {
+    out: W,
+    recorder: Option<Arc<Recorder>>,
+}
+
+impl<W: Write> FrameSink for WireSink<W> {
+    fn send_frame(&mut self, payload: &[u8]) -> Result<()> {
+        if let Some(rec) = &self.recorder {
+            rec.record(Direction::Outbound, payload)?;
+        }
+        write_frame(&mut self.out, payload)
+    }
+}
+
+/// Tunables for the serving loop.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Soft upper bound on the change bytes carried by one `ChangesPart` frame.
+    pub changes_chunk_bytes: usize,
+    /// Per-request-type token-bucket limits.
+    pub rate_limits: Vec<RateLimit>,
+    /// How often expired blobs are swept; `None` disables the sweeper.
+    pub ttl_sweep_interval: Option<Duration>,
+    /// Most single-item writes committed in one transaction; 1 disables
+    /// group commit.
+    pub group_commit_max_ops: usize,
+    /// How long a write waits for others to share its commit.  Zero only
+    /// groups writes that are already queued.
+    pub group_commit_window: Duration,
+    /// When scheduled maintenance runs; `None` disables it.
+    pub maintenance_window: Option<Window>,
+    /// How often the doc hash buckets are checked against doc_hashes;
+    /// `None` disables the checks.
+    pub anti_entropy_interval: Option<Duration>,
+    /// Where other stores connect to sync from this one; `None` accepts no
+    /// peers.
+    pub peer_listen: Option<SocketAddr>,
+    /// Peer connections served at once; more are refused.
+    pub peer_max_connections: usize,
+    /// Client connections `run_listener` serves at once; more are refused.
+    pub max_clients: usize,
+    /// Addresses of the stores to pull from.
+    pub peers: Vec<String>,
+    /// How often each of `peers` is pulled from.
+    pub peer_sync_interval: Duration,
+    /// Wrap replies to requests that changed documents in `WithRoot`.
+    pub root_in_replies: bool,
+    /// Bytes-per-second budgets for sync streaming, see `throttle`.
+    pub bandwidth_limits: Vec<RateLimit>,
+    /// How often a snapshot is published for read-only openers; `None`
+    /// publishes none.
+    pub snapshot_interval: Option<Duration>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            changes_chunk_bytes: 4 * 1024 * 1024,
+            rate_limits: Vec::new(),
+            ttl_sweep_interval: Some(Duration::from_secs(60)),
+            group_commit_max_ops: 64,
+            group_commit_window: Duration::ZERO,
+            maintenance_window: None,
+            anti_entropy_interval: None,
+            peer_listen: None,
+            peer_max_connections: 16,
+            max_clients: 64,
+            peers: Vec::new(),
+            peer_sync_interval: Duration::from_secs(30),
+            root_in_replies: false,
+            bandwidth_limits: Vec::new(),
+            snapshot_interval: None,
+        }
+    }
+}
+
+/// Identifies a client connection, which owns the watches and
+/// transactions it opens.
+pub type ClientId = u64;
+
+/// The client of `handle_frame`, which has no connection of its own.
+const LOCAL_CLIENT: ClientId = 0;
+
+/// Frames waiting for a client's serving loop to send them: those pushed
+/// with `NO_REF_ID` by background work, which every client gets, and watch
+/// events for changes made by another client.
+#[derive(Debug, Default)]
+pub struct Notifications {
+    outboxes: Mutex<Outboxes>,
+}
+
+#[derive(Debug, Default)]
+struct Outboxes {
+    /// Id of the last client to connect; ids are never reused.
+    last: ClientId,
+    by_client: HashMap<ClientId, Vec<(RefId, Response)>>,
+    /// Pushed while no client was connected, for the next one.
+    unclaimed: Vec<Response>,
+}
+
+impl Notifications {
+    pub fn push(&self, notification: Response) {
+        let mut outboxes = self.lock();
+        if outboxes.by_client.is_empty() {
+            outboxes.unclaimed.push(notification);
+            return;
+        }
+        for outbox in outboxes.by_client.values_mut() {
+            outbox.push((NO_REF_ID, notification.clone()));
+        }
+    }
+
+    /// Queue a frame for `client`, unless it has disconnected.
+    fn deliver(&self, client: ClientId, ref_id: RefId, response: Response) {
+        if let Some(outbox) = self.lock().by_client.get_mut(&client) {
+            outbox.push((ref_id, response));
+        }
+    }
+
+    /// Give a new client an outbox, holding what was pushed while no
+    /// client was connected.
+    fn connect(&self) -> ClientId {
+        let mut outboxes = self.lock();
+        outboxes.last += 1;
+        let client = outboxes.last;
+        let unclaimed = mem::take(&mut outboxes.unclaimed);
+        outboxes.by_client.insert(
+            client,
+            unclaimed.into_iter().map(|n| (NO_REF_ID, n)).collect(),
+        );
+        client
+    }
+
+    fn disconnect(&self, client: ClientId) {
+        self.lock().by_client.remove(&client);
+    }
+
+    /// Take every frame queued for `client` since the last call.
+    fn take(&self, client: ClientId) -> Vec<(RefId, Response)> {
+        self.lock()
+            .by_client
+            .get_mut(&client)
+            .map(mem::take)
+            .unwrap_or_default()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Outboxes> {
+        self.outboxes.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Response frames for a single request, all tagged with its ref_id.
+pub struct Reply<'a> {
+    ref_id: RefId,
+    sink: &'a mut dyn FrameSink,
+}
+
+impl<'a> Reply<'a> {
+    pub fn new(ref_id: RefId, sink: &'a mut dyn FrameSink) -> Self {
+        Self { ref_id, sink }
+    }
+
+    pub fn send(&mut self, response: &Response) -> Result<()> {
+        debug!(?response, "sending response");
+        let resp_bytes = bincode::serialize(&(self.ref_id, response))?;
+        self.sink.send_frame(&resp_bytes)
+    }
+}
+
+/// A decoded request, resolved to the store it runs against.
+struct Prepared {
+    ref_id: RefId,
+    span: Span,
+    store: Store,
+    request: Request,
+}
+
+/// What one client connection's requests leave behind between frames.
+struct Session {
+    client: ClientId,
+    /// Work the bandwidth budgets held back.
+    held: Deferred<Held>,
+}
+
+impl Session {
+    fn new(client: ClientId) -> Self {
+        Self {
+            client,
+            held: Deferred::default(),
+        }
+    }
+}
+
+/// Work the bandwidth budgets held back, with the request it answers.
+struct Held {
+    ref_id: RefId,
+    span: Span,
+    work: HeldWork,
+}
+
+enum HeldWork {
+    /// The rest of a change stream.
+    Stream(Paced),
+    /// A reply to send as it is.
+    Reply(Response),
+    /// A `PushBlobs` whose bytes the budget has already taken.
+    PushBlobs(Store, Request),
+}
+
+/// A frame after decoding: ready to run, or already answered.
+enum Incoming {
+    Ready(Prepared),
+    Rejected(Rejected),
+}
+
+/// A request answered without running it (undecodable, bad target, rate
+/// limited).
+struct Rejected {
+    ref_id: RefId,
+    span: Span,
+    response: Response,
+}
+
+impl Rejected {
+    fn send(self, sink: &mut dyn FrameSink) -> Result<()> {
+        let _guard = self.span.enter();
+        Reply {
+            ref_id: self.ref_id,
+            sink,
+        }
+        .send(&self.response)
+    }
+}
+
+pub struct Server {
+    tenants: Tenants,
+    config: Config,
+    limiter: RateLimiter,
+    throttles: Arc<Throttles>,
+    watches: Watches,
+    txns: Transactions,
+    maintenance: Arc<Maintenance>,
+    notifications: Arc<Notifications>,
+    shutdown: Arc<AtomicBool>,
+}
+
+impl Server {
+    pub fn new(store: Store, config: Config) -> Self {
+        let limiter = RateLimiter::new(&config.rate_limits);
+        let throttles = Arc::new(Throttles::new(&config.bandwidth_limits));
+        let maintenance = Arc::new(Maintenance::new(config.maintenance_window.clone()));
+        Self {
+            tenants: Tenants::new(store),
+            config,
+            limiter,
+            throttles,
+            watches: Watches::default(),
+            txns: Transactions::default(),
+            maintenance,
+            notifications: Arc::default(),
+            shutdown: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Decode one request frame, run it, and emit the response frame(s).
+    /// What the bandwidth budgets hold back is waited out here, as there is
+    /// nothing else to serve meanwhile.
+    pub fn handle_frame(&self, frame: &[u8], sink: &mut dyn FrameSink) -> Result<()> {
+        let mut session = Session::new(LOCAL_CLIENT);
+        match self.prepare(frame) {
+            Incoming::Ready(prepared) => self.execute(prepared, sink, &mut session)?,
+            Incoming::Rejected(rejected) => rejected.send(sink)?,
+        }
+        while let Some(deadline) = session.held.next_deadline() {
+            thread::sleep(deadline.saturating_duration_since(Instant::now()));
+            self.release(&mut session, sink)?;
+        }
+        Ok(())
+    }
+
+    /// Decode a frame and resolve the store it targets, or produce the reply
+    /// that rejects it.
+    fn prepare(&self, frame: &[u8]) -> Incoming {
+        let Envelope {
+            ref_id,
+            trace_id,
+            namespace,
+            tenant,
+            durability,
+            request,
+        } = match bincode::deserialize(frame) {
+            Ok(envelope) => envelope,
+            Err(e) => {
+                // The ref_id is the leading u64, so it usually survives a
+                // body that fails to decode; echo it so the caller unblocks.
+                let ref_id = peek_ref_id(frame).unwrap_or(NO_REF_ID);
+                warn!(ref_id, len = frame.len(), error = %e, "malformed request frame");
+                return Incoming::Rejected(Rejected {
+                    ref_id,
+                    span: Span::none(),
+                    response: Response::error(
+                        ErrorCode::Decode,
+                        format!("decoding request frame: {e}"),
+                    ),
+                });
+            }
+        };
+
+        // Store spans nest under this one, so every log line for the request
+        // carries the caller's trace id.
+        let span = info_span!(
+            "request",
+            ref_id,
+            trace_id = trace_id.as_deref(),
+            namespace = (!namespace.is_empty()).then_some(namespace.as_str()),
+            tenant = tenant.as_deref(),
+        );
+        let reject = |response| {
+            Incoming::Rejected(Rejected {
+                ref_id,
+                span: span.clone(),
+                response,
+            })
+        };
+        let _guard = span.enter();
+        debug!(?request, "received request");
+
+        if let Err(e) = validate_namespace(&namespace) {
+            return reject(Response::error(ErrorCode::BadRequest, format!("{e:#}")));
+        }
+        let store = match tenant.as_deref() {
+            None => self.tenants.root().clone(),
+            Some(name) => match self.tenants.get(name) {
+                Some(store) => store,
+                None => {
+                    return reject(Response::error(
+                        ErrorCode::BadRequest,
+                        format!("tenant {name:?} is not open"),
+                    ))
+                }
+            },
+        };
+        let store = match store.namespace(&namespace) {
+            Ok(store) => store,
+            Err(e) => return reject(e.into()),
+        };
+        let store = match durability {
+            Some(durability) => store.with_durability(durability),
+            None => store,
+        };
+        if store.needs_key() && !matches!(request, Request::ProvideKey { .. }) {
+            return reject(Response::error(
+                ErrorCode::BadRequest,
+                "the database is encrypted: send ProvideKey first",
+            ));
+        }
+        if let Err(wait) = self.limiter.check(request.kind()) {
+            debug!(kind = request.kind(), "rate limited");
+            return reject(Response::Busy {
+                retry_after_ms: wait.as_millis().max(1) as u64,
+            });
+        }
+
+        drop(_guard);
+        Incoming::Ready(Prepared {
+            ref_id,
+            span,
+            store,
+            request,
+        })
+    }
+
+    /// Run `prepared` for `session`'s client, putting what the bandwidth
+    /// budgets hold back in its queue.
+    fn execute(
+        &self,
+        prepared: Prepared,
+        sink: &mut dyn FrameSink,
+        session: &mut Session,
+    ) -> Result<()> {
+        let Prepared {
+            ref_id,
+            span,
+            store,
+            request,
+        } = prepared;
+        let _guard = span.enter();
+        let mut reply = Reply { ref_id, sink };
+
+        let started = Instant::now();
+        let generation = store.root_generation();
+        let client = session.client;
+        let mut hold = |deadline, work| {
+            session.held.push(
+                deadline,
+                Held {
+                    ref_id,
+                    span: span.clone(),
+                    work,
+                },
+            )
+        };
+        // A panic while serving one request must not take down the port and
+        // every other caller's in-flight request with it.
+        let outcome = panic::catch_unwind(AssertUnwindSafe(|| match request {
+            Request::GetChanges {
+                known_roots,
+                doc_ids,
+                prefix,
+                namespace,
+            } => stream_changes(
+                &store,
+                known_roots,
+                doc_ids,
+                prefix,
+                namespace,
+                self.streaming(),
+                &mut reply,
+            )
+            .map(|paced| hold_stream(&mut hold, paced)),
+            Request::GetChangesBloom { bloom } => {
+                stream_changes_bloom(&store, bloom, self.streaming(), &mut reply)
+                    .map(|paced| hold_stream(&mut hold, paced))
+            }
+            Request::GetChangesSince { peer_id } => {
+                stream_changes_since(&store, &peer_id, self.streaming(), &mut reply)
+                    .map(|paced| hold_stream(&mut hold, paced))
+            }
+            Request::ContinueSync { session_id, ack } => {
+                match continue_sync(&store, session_id, ack, self.streaming()) {
+                    (response, None) => reply.send(&response),
+                    (response, Some(deadline)) => {
+                        hold(deadline, HeldWork::Reply(response));
+                        Ok(())
+                    }
+                }
+            }
+            Request::OpenTenant { name } => reply.send(&self.open_tenant(&name)),
+            Request::CloseTenant { name } => reply.send(&self.close_tenant(&name)),
+            Request::Watch { ids, prefix } => {
+                let added = Filter::new(ids, prefix)
+                    .and_then(|filter| self.watches.add(client, ref_id, &store, filter));
+                reply.send(&match added {
+                    Ok(()) => Response::Ok,
+                    Err(e) => Response::error(ErrorCode::BadRequest, format!("{e:#}")),
+                })
+            }
+            Request::Unwatch { watch_ref } => {
+                reply.send(&if self.watches.remove(client, watch_ref) {
+                    Response::Ok
+                } else {
+                    Response::NotFound
+                })
+            }
+            Request::BeginTxn => reply.send(&match self.txns.begin(client, &store) {
+                Ok(txn) => Response::TxnStarted { txn },
+                Err(e) => Response::error(ErrorCode::BadRequest, format!("{e:#}")),
+            }),
+            Request::TxnWrite { txn, request } => {
+                reply.send(&self.stage(client, txn, &store, *request))
+            }
+            Request::Commit { txn } => {
+                let response = self.commit(client, txn, &store);
+                reply.send(&self.with_root(&store, generation, response))
+            }
+            Request::Abort { txn } => reply.send(&match self.txns.take(client, txn, &store) {
+                Ok(Some(_)) => Response::Ok,
+                Ok(None) => Response::NotFound,
+                Err(e) => Response::error(ErrorCode::BadRequest, format!("{e:#}")),
+            }),
+            Request::MaintenanceStatus => reply.send(&self.maintenance.status()),
+            Request::Stats => {
+                let mut response = handle_request(&store, Request::Stats);
+                if let Response::Stats { throttles, .. } = &mut response {
+                    *throttles = self.throttle_stats();
+                }
+                reply.send(&response)
+            }
+            Request::PushBlobs { blobs, peer_id } => {
+                let bytes = blobs.iter().map(|blob| blob.data.len()).sum();
+                let request = Request::PushBlobs { blobs, peer_id };
+                match self.throttles.reserve("push_blobs", bytes) {
+                    None => reply.send(&handle_request(&store, request)),
+                    Some(deadline) => {
+                        hold(deadline, HeldWork::PushBlobs(store.clone(), request));
+                        Ok(())
+                    }
+                }
+            }
+            request => {
+                let response = handle_request(&store, request);
+                reply.send(&self.with_root(&store, generation, response))
+            }
+        }));
+        let result = match outcome {
+            Ok(result) => result,
+            Err(payload) => {
+                let message = panic_message(payload.as_ref());
+                error!(%message, "request handler panicked");
+                reply.send(&Response::error(
+                    ErrorCode::Internal,
+                    format!("internal error: {message}"),
+                ))
+            }
+        };
+
+        let elapsed = started.elapsed();
+        if elapsed >= SLOW_REQUEST {
+            warn!(elapsed_ms = elapsed.as_millis() as u64, "slow request");
+        }
+        result?;
+        self.send_events(&store, session.client, sink)
+    }
+
+    /// Carry on with `session`'s held-back work whose deadline has passed.
+    fn release(&self, session: &mut Session, sink: &mut dyn FrameSink) -> Result<()> {
+        for Held { ref_id, span, work } in session.held.due() {
+            let _guard = span.enter();
+            let mut reply = Reply { ref_id, sink };
+            let mut changed = None;
+            let outcome = panic::catch_unwind(AssertUnwindSafe(|| match work {
+                HeldWork::Stream(paced) => paced.resume(self.streaming(), &mut reply),
+                HeldWork::Reply(response) => reply.send(&response).map(|()| None),
+                HeldWork::PushBlobs(store, request) => {
+                    let response = handle_request(&store, request);
+                    changed = Some(store);
+                    reply.send(&response).map(|()| None)
+                }
+            }));
+            match outcome {
+                Ok(paced) => {
+                    if let Some(paced) = paced? {
+                        let deadline = paced.deadline;
+                        let span = span.clone();
+                        let work = HeldWork::Stream(paced);
+                        session.held.push(deadline, Held { ref_id, span, work });
+                    }
+                }
+                Err(payload) => {
+                    let message = panic_message(payload.as_ref());
+                    error!(%message, "held-back request panicked");
+                    reply.send(&Response::error(
+                        ErrorCode::Internal,
+                        format!("internal error: {message}"),
+                    ))?;
+                }
+            }
+            if let Some(store) = changed {
+                self.send_events(&store, session.client, sink)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Group commit: run `first` together with the writes queued behind it
+    /// that target the same store, in one transaction, then reply to each.
+    /// The first queued frame that can't join is handled afterwards.
+    fn coalesce(
+        &self,
+        first: Prepared,
+        frames: &Receiver<Result<Vec<u8>>>,
+        sink: &mut WireSink<impl Write>,
+        session: &mut Session,
+    ) -> Result<()> {
+        let deadline = Instant::now() + self.config.group_commit_window;
+        let mut batch = vec![first];
+        let mut next = None;
+        while batch.len() < self.config.group_commit_max_ops {
+            let frame = match frames.try_recv() {
+                Ok(frame) => frame?,
+                Err(_) => {
+                    let wait = deadline.saturating_duration_since(Instant::now());
+                    match frames.recv_timeout(wait) {
+                        Ok(frame) => frame?,
+                        // Disconnection is noticed by the serving loop.
+                        Err(_) => break,
+                    }
+                }
+            };
+            if let Some(rec) = &sink.recorder {
+                rec.record(Direction::Inbound, &frame)?;
+            }
+            match self.prepare(&frame) {
+                Incoming::Ready(p)
+                    if is_groupable(&p.request) && p.store.same_target(&batch[0].store) =>
+                {
+                    batch.push(p)
+                }
+                other => {
+                    next = Some(other);
+                    break;
+                }
+            }
+        }
+
+        if batch.len() == 1 {
+            self.execute(batch.pop().unwrap(), sink, session)?;
+        } else {
+            self.execute_batch(batch, sink, session)?;
+        }
+        match next {
+            Some(Incoming::Ready(prepared)) => self.execute(prepared, sink, session),
+            Some(Incoming::Rejected(rejected)) => rejected.send(sink),
+            None => Ok(()),
+        }
+    }
+
+    fn execute_batch(
+        &self,
+        batch: Vec<Prepared>,
+        sink: &mut dyn FrameSink,
+        session: &mut Session,
+    ) -> Result<()> {
+        let store = batch[0].store.clone();
+        let mut callers = Vec::with_capacity(batch.len());
+        let mut ops = Vec::with_capacity(batch.len());
+        for Prepared {
+            ref_id,
+            span,
+            request,
+            ..
+        } in batch
+        {
+            callers.push((ref_id, span));
+            ops.push(write_op(request).expect("only groupable requests are batched"));
+        }
+
+        debug!(count = ops.len(), "group commit");
+        let generation = store.root_generation();
+        let started = Instant::now();
+        let outcome = panic::catch_unwind(AssertUnwindSafe(|| store.write_batch(&ops)));
+        let elapsed = started.elapsed();
+        if elapsed >= SLOW_REQUEST {
+            warn!(
+                elapsed_ms = elapsed.as_millis() as u64,
+                count = ops.len(),
+                "slow write batch"
+            );
+        }
+
+        match outcome {
+            Ok(Ok(outcomes)) => {
+                for ((ref_id, span), outcome) in callers.into_iter().zip(outcomes) {
+                    let _guard = span.enter();
+                    let response = self.with_root(&store, generation, write_response(outcome));
+                    Reply { ref_id, sink }.send(&response)?;
+                }
+                self.send_events(&store, session.client, sink)
+            }
+            // One bad write fails the shared transaction; rerun each on its
+            // own so only that caller sees the error.
+            Ok(Err(e)) => {
+                warn!(error = %e, count = ops.len(), "write batch failed, retrying individually");
+                for ((ref_id, span), op) in callers.into_iter().zip(ops) {
+                    let prepared = Prepared {
+                        ref_id,
+                        span,
+                        store: store.clone(),
+                        request: op.into(),
+                    };
+                    self.execute(prepared, sink, session)?;
+                }
+                Ok(())
+            }
+            Err(payload) => {
+                let message = panic_message(payload.as_ref());
+                error!(%message, "write batch panicked");
+                for (ref_id, span) in callers {
+                    let _guard = span.enter();
+                    Reply { ref_id, sink }.send(&Response::error(
+                        ErrorCode::Internal,
+                        format!("internal error: {message}"),
+                    ))?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Push the events of the watches on `store`'s database for what has
+    /// been committed to it since the last call: `client`'s own on `sink`,
+    /// other clients' through their outboxes.
+    fn send_events(&self, store: &Store, client: ClientId, sink: &mut dyn FrameSink) -> Result<()> {
+        let events = match self.watches.events(store) {
+            Ok(events) => events,
+            Err(e) => {
+                warn!(error = %format!("{e:#}"), "collecting watch events failed");
+                return Ok(());
+            }
+        };
+        for (owner, ref_id, event) in events {
+            if owner == client {
+                Reply { ref_id, sink }.send(&event)?;
+            } else {
+                self.notifications.deliver(owner, ref_id, event);
+            }
+        }
+        Ok(())
+    }
+
+    fn stage(&self, client: ClientId, txn: u64, store: &Store, request: Request) -> Response {
+        let kind = request.kind();
+        let op = match write_op(request) {
+            Ok(op) => op,
+            Err(_) => {
+                return Response::error(
+                    ErrorCode::BadRequest,
+                    format!("{kind} can't be part of a transaction"),
+                )
+            }
+        };
+        match self.txns.stage(client, txn, store, op) {
+            Ok(true) => Response::Ok,
+            Ok(false) => Response::NotFound,
+            Err(e) => Response::error(ErrorCode::BadRequest, format!("{e:#}")),
+        }
+    }
+
+    /// How `GetChanges` and its kin stream: in `changes_chunk_bytes`
+    /// chunks, paced by the bandwidth budgets.
+    fn streaming(&self) -> Streaming<'_> {
+        Streaming {
+            chunk_bytes: self.config.changes_chunk_bytes,
+            throttles: &self.throttles,
+        }
+    }
+
+    fn throttle_stats(&self) -> Vec<ThrottleStats> {
+        self.throttles
+            .states()
+            .into_iter()
+            .map(|state| ThrottleStats {
+                kind: state.kind,
+                bytes_per_sec: state.bytes_per_sec,
+                burst_bytes: state.burst_bytes,
+                available_bytes: state.available_bytes,
+                bytes: state.bytes,
+                waited_ms: state.waited.as_millis() as u64,
+                waiting: state.waiting,
+            })
+            .collect()
+    }
+
+    /// `response` wrapped in `WithRoot` if `root_in_replies` is set and
+    /// the store's documents changed since `generation` was taken.
+    fn with_root(&self, store: &Store, generation: u64, response: Response) -> Response {
+        if !self.config.root_in_replies || store.root_generation() == generation {
+            return response;
+        }
+        match store.combined_root() {
+            Ok(root) => Response::WithRoot {
+                root,
+                response: Box::new(response),
+            },
+            Err(e) => {
+                warn!(error = %format!("{e:#}"), "computing the combined root failed");
+                response
+            }
+        }
+    }
+
+    fn commit(&self, client: ClientId, txn: u64, store: &Store) -> Response {
+        let ops = match self.txns.take(client, txn, store) {
+            Ok(Some(ops)) => ops,
+            Ok(None) => return Response::NotFound,
+            Err(e) => return Response::error(ErrorCode::BadRequest, format!("{e:#}")),
+        };
+        debug!(txn, count = ops.len(), "committing transaction");
+        match store.write_batch(&ops) {
+            Ok(outcomes) => Response::Committed {
+                results: outcomes.into_iter().map(write_response).collect(),
+            },
+            Err(e) => e.into(),
+        }
+    }
+
+    fn close_tenant(&self, name: &str) -> Response {
+        match self.tenants.get(name) {
+            Some(store) => {
+                self.watches.forget_database(&store);
+                self.txns.forget_database(&store);
+                self.tenants.close(name);
+                Response::Ok
+            }
+            None => Response::NotFound,
+        }
+    }
+
+    fn open_tenant(&self, name: &str) -> Response {
+        if let Err(e) = validate_tenant(name) {
+            return Response::error(ErrorCode::BadRequest, format!("{e:#}"));
+        }
+        match self.tenants.open(name) {
+            Ok(()) => Response::Ok,
+            Err(e) => e.into(),
+        }
+    }
+
+    /// Flag that stops `run` after the in-flight request completes.
+    /// Set from signal handlers.
+    pub fn shutdown_flag(&self) -> Arc<AtomicBool> {
+        self.shutdown.clone()
+    }
+
+    /// Serve requests from `input` until it is closed or shutdown is
+    /// requested.
+    ///
+    /// Frames are read on a separate thread so a shutdown request is noticed
+    /// even while the caller is idle.  The request being handled when the
+    /// flag is raised always runs to completion (and commits) first.
+    pub fn run(
+        &self,
+        input: impl Read + Send + 'static,
+        output: impl Write,
+        recorder: Option<Recorder>,
+    ) -> Result<()> {
+        let background = self.spawn_background()?;
+        let mut sink = WireSink {
+            out: output,
+            recorder: recorder.map(Arc::new),
+        };
+        let result = self.serve_frames(spawn_reader(input), &mut sink);
+        if self.shutdown.load(Ordering::SeqCst) {
+            info!("shutdown requested, exiting cleanly");
+        } else if result.is_ok() {
+            info!("stdin closed, shutting down");
+        }
+        self.stop_background(background);
+        result
+    }
+
+    /// Serve the clients `listener` accepts, each on a thread of its own
+    /// and at most `max_clients` at once, until shutdown is requested.  A
+    /// client that disconnects or whose connection fails ends only its own
+    /// session, watches and open transactions included.
+    pub fn run_listener(&self, listener: Listener, recorder: Option<Recorder>) -> Result<()> {
+        let background = self.spawn_background()?;
+        let recorder = recorder.map(Arc::new);
+        let active = AtomicUsize::new(0);
+        let result = thread::scope(|scope| {
+            let accepted = self.accept_clients(&listener, &recorder, &active, scope);
+            // Connection threads stop once shutdown is set, so the scope
+            // can end even if accepting failed.
+            self.shutdown.store(true, Ordering::SeqCst);
+            accepted
+        });
+        self.stop_background(background);
+        result
+    }
+
+    fn accept_clients<'scope>(
+        &'scope self,
+        listener: &Listener,
+        recorder: &Option<Arc<Recorder>>,
+        active: &'scope AtomicUsize,
+        scope: &'scope thread::Scope<'scope, '_>,
+    ) -> Result<()> {
+        while !self.shutdown.load(Ordering::SeqCst) {
+            let Some(connection) = listener.accept()? else {
+                thread::sleep(SHUTDOWN_POLL);
+                continue;
+            };
+            let max_clients = self.config.max_clients;
+            if active.load(Ordering::SeqCst) >= max_clients {
+                let peer = &connection.peer;
+                warn!(%peer, max_clients, "refusing a client: too many connections");
+                continue;
+            }
+            active.fetch_add(1, Ordering::SeqCst);
+            let recorder = recorder.clone();
+            thread::Builder::new()
+                .name("client".into())
+                .spawn_scoped(scope, move || {
+                    self.serve_connection(connection, recorder);
+                    active.fetch_sub(1, Ordering::SeqCst);
+                })?;
+        }
+        info!("shutdown requested, exiting cleanly");
+        Ok(())
+    }
+
+    /// Serve one accepted client until it disconnects, once it has
+    /// presented the auth token its listener requires.
+    fn serve_connection(&self, mut connection: Connection, recorder: Option<Arc<Recorder>>) {
+        let peer = connection.peer.clone();
+        let authenticated = connection.authenticate();
+        let mut sink = WireSink {
+            out: connection.writer,
+            recorder,
+        };
+        if let Err(e) = authenticated {
+            warn!(%peer, error = %format!("{e:#}"), "refusing a client: not authenticated");
+            let refusal = Response::error(ErrorCode::Unauthorized, format!("{e:#}"));
+            let _ = Reply::new(NO_REF_ID, &mut sink).send(&refusal);
+            return;
+        }
+        info!(%peer, "client connected");
+        match self.serve_frames(spawn_reader(connection.reader), &mut sink) {
+            Ok(()) => info!(%peer, "client disconnected"),
+            Err(e) => warn!(%peer, error = %format!("{e:#}"), "client connection failed"),
+        }
+    }
+
+    /// Start the threads that run beside the serving loop.
+    fn spawn_background(&self) -> Result<Vec<JoinHandle<()>>> {
+        // Bind first, so a taken address fails before anything has started.
+        let peer_listener = self
+            .config
+            .peer_listen
+            .map(|addr| {
+                peer::spawn_listener(
+                    addr,
+                    self.tenants.root().clone(),
+                    self.config.changes_chunk_bytes,
+                    self.config.peer_max_connections,
+                    self.throttles.clone(),
+                    self.shutdown.clone(),
+                )
+            })
+            .transpose()?;
+        let sweeper = self.config.ttl_sweep_interval.map(|interval| {
+            sweeper::spawn(self.tenants.clone(), interval, self.shutdown.clone())
+        });
+        let maintenance = maintenance::spawn(
+            self.tenants.clone(),
+            self.maintenance.clone(),
+            self.shutdown.clone(),
+        );
+        let snapshotter = self.config.snapshot_interval.map(|interval| {
+            snapshotter::spawn(self.tenants.clone(), interval, self.shutdown.clone())
+        });
+        let anti_entropy = self.config.anti_entropy_interval.map(|interval| {
+            antientropy::spawn(
+                self.tenants.clone(),
+                interval,
+                self.notifications.clone(),
+                self.shutdown.clone(),
+            )
+        });
+
+        let peer_puller = (!self.config.peers.is_empty())
+            .then(|| {
+                peer::spawn_puller(
+                    self.tenants.root().clone(),
+                    self.config.peers.clone(),
+                    self.config.peer_sync_interval,
+                    self.throttles.clone(),
+                    self.notifications.clone(),
+                    self.shutdown.clone(),
+                )
+            })
+            .transpose()?;
+
+        let handles = [
+            sweeper,
+            maintenance,
+            snapshotter,
+            anti_entropy,
+            peer_listener,
+            peer_puller,
+        ];
+        Ok(handles.into_iter().flatten().collect())
+    }
+
+    /// Stop background work whichever way serving ended.
+    fn stop_background(&self, handles: Vec<JoinHandle<()>>) {
+        self.shutdown.store(true, Ordering::SeqCst);
+        for handle in handles {
+            let _ = handle.join();
+        }
+        for store in self.tenants.stores() {
+            if let Err(e) = store.flush_access() {
+                let dir = store.dir().display();
+                warn!(dir = %dir, error = %e, "flushing access statistics failed");
+            }
+        }
+    }
+
+    /// Serve `frames` as one client until they end or shutdown is
+    /// requested, then drop the client's watches and open transactions.
+    fn serve_frames<W: Write>(
+        &self,
+        frames: Receiver<Result<Vec<u8>>>,
+        sink: &mut WireSink<W>,
+    ) -> Result<()> {
+        let mut session = Session::new(self.notifications.connect());
+        let result = self.serve_session(&frames, sink, &mut session);
+        self.notifications.disconnect(session.client);
+        self.watches.forget_client(session.client);
+        self.txns.forget_client(session.client);
+        result
+    }
+
+    /// Work the bandwidth budgets hold back waits in a queue of its own,
+    /// so other requests are served meanwhile.  Frames for the client from
+    /// elsewhere are sent between requests, at least every
+    /// `SHUTDOWN_POLL`.
+    fn serve_session<W: Write>(
+        &self,
+        frames: &Receiver<Result<Vec<u8>>>,
+        sink: &mut WireSink<W>,
+        session: &mut Session,
+    ) -> Result<()> {
+        loop {
+            if self.shutdown.load(Ordering::SeqCst) {
+                break;
+            }
+            for (ref_id, response) in self.notifications.take(session.client) {
+                Reply { ref_id, sink }.send(&response)?;
+            }
+            self.release(session, sink)?;
+            let frame = match frames.recv_timeout(session.held.wait(SHUTDOWN_POLL)) {
+                Ok(frame) => frame?,
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+            if let Some(rec) = &sink.recorder {
+                rec.record(Direction::Inbound, &frame)?;
+            }
+
+            match self.prepare(&frame) {
+                Incoming::Ready(p)
+                    if self.config.group_commit_max_ops > 1 && is_groupable(&p.request) =>
+                {
+                    self.coalesce(p, frames, sink, session)?
+                }
+                Incoming::Ready(p) => self.execute(p, sink, session)?,
+                Incoming::Rejected(rejected) => rejected.send(sink)?,
+            }
+        }
+
+        // The input may have ended with replies still held back, which a
+        // caller that closed its end of stdin can still be reading.
+        while !self.shutdown.load(Ordering::SeqCst) && session.held.next_deadline().is_some() {
+            thread::sleep(session.held.wait(SHUTDOWN_POLL));
+            self.release(session, sink)?;
+        }
+        Ok(())
+    }
+}
+
+/// Read frames on a background thread.  The channel disconnects on EOF; a
+/// read error is delivered as the final item.
+pub fn spawn_reader(mut input: impl Read + Send + 'static) -> Receiver<Result<Vec<u8>>> {
+    let (tx, rx) = mpsc::sync_channel(READ_AHEAD);
+    thread::spawn(move || loop {
+        match read_frame(&mut input) {
+            Ok(Some(frame)) => {
+                if tx.send(Ok(frame)).is_err() {
+                    break;
+                }
+            }
+            Ok(None) => break,
+            Err(e) => {
+                let _ = tx.send(Err(e));
+                break;
+            }
+        }
+    });
+    rx
+}
+
+/// Hold the rest of a change stream, if the budget held a part back.
+fn hold_stream(hold: &mut impl FnMut(Instant, HeldWork), paced: Option<Paced>) {
+    if let Some(paced) = paced {
+        hold(paced.deadline, HeldWork::Stream(paced));
+    }
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "panic".to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::StoreOptions;
+    use crate::throttle::parse_limit;
+    use crate::transport::{self, AuthToken, Transport};
+    use std::io::Cursor;
+
+    fn frame(ref_id: RefId, request: Request) -> Vec<u8> {
+        let envelope = Envelope {
+            ref_id,
+            trace_id: None,
+            namespace: String::new(),
+            tenant: None,
+            durability: None,
+            request,
+        };
+        let mut out = Vec::new();
+        write_frame(&mut out, &bincode::serialize(&envelope).unwrap()).unwrap();
+        out
+    }
+
+    fn replies(output: &[u8]) -> Vec<(RefId, Response)> {
+        let mut input = output;
+        let mut replies = Vec::new();
+        while let Some(frame) = read_frame(&mut input).unwrap() {
+            replies.push(bincode::deserialize(&frame).unwrap());
+        }
+        replies
+    }
+
+    #[test]
+    fn test_group_commit() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = StoreOptions {
+            max_doc_bytes: Some(64),
+            ..Default::default()
+        };
+        let store = Store::open(dir.path(), options).unwrap();
+        let put = |id: &str, crdt_state: &[u8], create_only| Request::PutDocument {
+            id: id.into(),
+            meta: b"meta".to_vec(),
+            crdt_state: crdt_state.to_vec(),
+            index: None,
+            create_only,
+        };
+        let server = Server::new(store.clone(), Config::default());
+        let mut input = frame(1, put("a", b"a", true));
+        input.extend(frame(2, put("a", b"again", true)));
+        input.extend(frame(3, put("b", b"b", false)));
+        input.extend(frame(4, Request::Stats));
+        // One write too large fails the batch, and is then the only one
+        // that fails.
+        input.extend(frame(5, put("c", b"c", false)));
+        input.extend(frame(6, put("d", &[0; 100], false)));
+        input.extend(frame(7, put("e", b"e", false)));
+        let mut output = Vec::new();
+        server.run(Cursor::new(input), &mut output, None).unwrap();
+
+        let replies = replies(&output);
+        let ref_ids: Vec<RefId> = replies.iter().map(|(ref_id, _)| *ref_id).collect();
+        assert_eq!(ref_ids, [1, 2, 3, 4, 5, 6, 7]);
+        for (ref_id, response) in replies {
+            let ok = match ref_id {
+                2 => matches!(response, Response::AlreadyExists),
+                4 => matches!(response, Response::Stats { .. }),
+                6 => matches!(response, Response::Error { code: ErrorCode::TooLarge, .. }),
+                _ => matches!(response, Response::DocumentStored { .. }),
+            };
+            assert!(ok, "{ref_id}: {response:?}");
+        }
+        for (id, state) in [("a", &b"a"[..]), ("b", b"b"), ("c", b"c"), ("e", b"e")] {
+            assert_eq!(store.get_document(id).unwrap().unwrap().crdt_state, state);
+        }
+        assert!(store.get_document("d").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_throttled_stream_does_not_hold_up_other_requests() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Store::open(dir.path(), StoreOptions::default()).unwrap();
+        for i in 0..3u8 {
+            store.put_document(&format!("doc-{i}"), b"meta", &[i; 100], None, false).unwrap();
+        }
+        let config = Config {
+            changes_chunk_bytes: 1,
+            bandwidth_limits: vec![parse_limit("get_changes=1000/100").unwrap()],
+            ttl_sweep_interval: None,
+            ..Config::default()
+        };
+        let server = Server::new(store, config);
+        let get_changes = Request::GetChanges {
+            known_roots: Vec::new(),
+            doc_ids: Vec::new(),
+            prefix: None,
+            namespace: None,
+        };
+        let mut input = frame(1, get_changes);
+        input.extend(frame(2, Request::Stats));
+        let mut output = Vec::new();
+        server.run(Cursor::new(input), &mut output, None).unwrap();
+
+        let replies = replies(&output);
+        let (ref_id, Response::Stats { throttles, .. }) = &replies[0] else {
+            panic!("expected Stats first, got {:?}", replies[0]);
+        };
+        assert_eq!(*ref_id, 2);
+        assert_eq!(throttles[0].waiting, 1);
+        let parts: Vec<_> = replies[1..]
+            .iter()
+            .map(|(ref_id, response)| match response {
+                Response::ChangesPart { seq, last, changes, .. } => {
+                    assert_eq!(*ref_id, 1);
+                    (*seq, *last, changes.len())
+                }
+                other => panic!("unexpected reply {other:?}"),
+            })
+            .collect();
+        assert_eq!(parts, [(0, false, 1), (1, false, 1), (2, false, 1), (3, true, 0)]);
+    }
+
+    #[test]
+    fn test_clients_are_served_at_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Store::open(&dir.path().join("data"), StoreOptions::default()).unwrap();
+        let config = Config {
+            ttl_sweep_interval: None,
+            ..Config::default()
+        };
+        let server = Server::new(store, config);
+        let socket = dir.path().join("store.sock");
+        let socket = socket.to_str().unwrap();
+        let listener = Listener::bind(Transport::Uds, socket, None).unwrap();
+        let call = |connection: &mut Connection, ref_id, request| {
+            connection.writer.write_all(&frame(ref_id, request)).unwrap();
+            let frame = read_frame(&mut connection.reader).unwrap().unwrap();
+            bincode::deserialize::<(RefId, Response)>(&frame).unwrap()
+        };
+
+        thread::scope(|scope| {
+            scope.spawn(|| server.run_listener(listener, None).unwrap());
+            let mut watcher = transport::connect(socket, None).unwrap();
+            let mut writer = transport::connect(socket, None).unwrap();
+            let watch = Request::Watch {
+                ids: vec!["doc".into()],
+                prefix: None,
+            };
+            assert!(matches!(call(&mut watcher, 1, watch), (1, Response::Ok)));
+
+            // The watcher's connection stays open while the writer is served.
+            let (_, Response::TxnStarted { txn }) = call(&mut writer, 1, Request::BeginTxn) else {
+                panic!("expected TxnStarted");
+            };
+            let abort = Request::Abort { txn };
+            assert!(matches!(call(&mut watcher, 2, abort), (2, Response::NotFound)));
+            let put = Request::PutDocument {
+                id: "doc".into(),
+                meta: b"meta".to_vec(),
+                crdt_state: b"state".to_vec(),
+                index: None,
+                create_only: false,
+            };
+            call(&mut writer, 2, put);
+
+            let frame = read_frame(&mut watcher.reader).unwrap().unwrap();
+            let (ref_id, event) = bincode::deserialize::<(RefId, Response)>(&frame).unwrap();
+            assert_eq!(ref_id, 1);
+            assert!(matches!(event, Response::DocumentChanged { seq: 0, id, .. } if id == "doc"));
+            let committed = call(&mut writer, 3, Request::Commit { txn });
+            assert!(matches!(committed, (3, Response::Committed { .. })));
+            server.shutdown_flag().store(true, Ordering::SeqCst);
+        });
+    }
+
+    #[test]
+    fn test_tcp_client_must_authenticate() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Store::open(dir.path(), StoreOptions::default()).unwrap();
+        let config = Config {
+            ttl_sweep_interval: None,
+            ..Config::default()
+        };
+        let server = Server::new(store, config);
+        let token = AuthToken::new("secret".into()).unwrap();
+        let listener = Listener::bind(Transport::Tcp, "127.0.0.1:0", Some(token.clone())).unwrap();
+        let Listener::Tcp(tcp, _) = &listener else {
+            unreachable!()
+        };
+        let addr = tcp.local_addr().unwrap().to_string();
+
+        thread::scope(|scope| {
+            scope.spawn(|| server.run_listener(listener, None).unwrap());
+            let wrong = AuthToken::new("guess".into()).unwrap();
+            for (token, authenticated) in [(&wrong, false), (&token, true)] {
+                let mut client = transport::connect(&addr, Some(token)).unwrap();
+                if authenticated {
+                    client.writer.write_all(&frame(1, Request::Stats)).unwrap();
+                }
+                let frame = read_frame(&mut client.reader).unwrap().unwrap();
+                let reply = bincode::deserialize::<(RefId, Response)>(&frame).unwrap();
+                if authenticated {
+                    assert!(matches!(reply, (1, Response::Stats { .. })), "{reply:?}");
+                } else {
+                    assert!(
+                        matches!(
+                            reply,
+                            (NO_REF_ID, Response::Error { code: ErrorCode::Unauthorized, .. })
+                        ),
+                        "{reply:?}"
+                    );
+                    assert!(read_frame(&mut client.reader).unwrap().is_none());
+                }
+            }
+            server.shutdown_flag().store(true, Ordering::SeqCst);
+        });
+    }
+}
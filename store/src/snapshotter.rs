@@ -0,0 +1,44 @@
+//! Background thread that publishes snapshots for read-only openers.
+//!
+//! With `--snapshot-interval-secs`, every open database publishes a
+//! snapshot at startup and then at that interval (see
+//! `Store::publish_snapshot`), so a second process started with
+//! `--read-only` sees data at most that old.  A store that is itself a
+//! read-only snapshot publishes nothing.
+
+use crate::tenants::Tenants;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+/// Granularity at which the thread notices shutdown.
+const TICK: Duration = Duration::from_millis(200);
+
+/// Publish a snapshot of every open database now and each `interval`
+/// until `shutdown` is set.
+pub fn spawn(tenants: Tenants, interval: Duration, shutdown: Arc<AtomicBool>) -> JoinHandle<()> {
+    thread::Builder::new()
+        .name("snapshotter".into())
+        .spawn(move || {
+            info!(interval_secs = interval.as_secs(), "snapshot publishing started");
+            let mut next = Instant::now();
+            while !shutdown.load(Ordering::SeqCst) {
+                if Instant::now() < next {
+                    thread::sleep(TICK);
+                    continue;
+                }
+                for store in tenants.stores() {
+                    if store.is_read_only() {
+                        continue;
+                    }
+                    if let Err(e) = store.publish_snapshot() {
+                        warn!(dir = %store.dir().display(), error = %e, "publishing a snapshot failed");
+                    }
+                }
+                next = Instant::now() + interval;
+            }
+        })
+        .expect("spawning snapshotter thread")
+}
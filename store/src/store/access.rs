@@ -0,0 +1,188 @@
+//! Read statistics per document, for finding hot and cold documents.
+//!
+//! With `StoreOptions::track_access`, every document a GetDocument or
+//! GetDocuments finds bumps an in-memory counter; nothing touches the
+//! database on the read path.  `flush_access` folds the counters into
+//! doc_access (doc id → (reads, last read)) in one write transaction.  It
+//! runs once `FLUSH_AFTER` documents are pending, before `hot_documents`
+//! answers, from the port's periodic sweep and at shutdown, so a crash
+//! loses at most the reads since the last flush.
+
+use super::{unix_now, Store};
+use anyhow::Result;
+use redb::ReadableTable;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use tracing::{debug, warn};
+
+/// Pending documents that trigger a flush from the read path.
+const FLUSH_AFTER: usize = 4096;
+
+#[derive(Debug, Default)]
+pub(super) struct AccessLog {
+    /// (namespace, document id) → (reads, last read) since the last flush.
+    pending: Mutex<HashMap<(String, String), (u64, u64)>>,
+}
+
+/// One document's entry in `hot_documents`.
+#[derive(Debug, Clone)]
+pub struct AccessStats {
+    pub id: String,
+    pub reads: u64,
+    /// Unix seconds; `None` if the document hasn't been read while
+    /// tracking was on.
+    pub last_read: Option<u64>,
+}
+
+impl Store {
+    pub fn tracks_access(&self) -> bool {
+        self.options.track_access
+    }
+
+    /// Count a read of `id` if `track_access` is on, flushing once enough
+    /// documents are pending.  Sync reads are not counted.
+    pub fn record_read(&self, id: &str) {
+        if !self.options.track_access {
+            return;
+        }
+        let pending_len = {
+            let mut pending = self.access.pending.lock().unwrap_or_else(|e| e.into_inner());
+            let entry = pending
+                .entry((self.namespace.clone(), id.to_string()))
+                .or_insert((0, 0));
+            entry.0 += 1;
+            entry.1 = unix_now();
+            pending.len()
+        };
+        if pending_len >= FLUSH_AFTER {
+            if let Err(e) = self.flush_access() {
+                warn!(error = %e, "flushing access statistics failed");
+            }
+        }
+    }
+
+    /// Write the pending read counts of every namespace to doc_access.
+    /// Reads of documents deleted in the meantime are dropped.
+    pub fn flush_access(&self) -> Result<()> {
+        let pending = std::mem::take(
+            &mut *self.access.pending.lock().unwrap_or_else(|e| e.into_inner()),
+        );
+        if pending.is_empty() {
+            return Ok(());
+        }
+        let mut by_namespace: HashMap<String, Vec<(String, u64, u64)>> = HashMap::new();
+        for ((namespace, id), (reads, last_read)) in pending {
+            by_namespace.entry(namespace).or_default().push((id, reads, last_read));
+        }
+
+        let txn = self.begin_write()?;
+        let mut flushed = 0;
+        for (namespace, reads) in by_namespace {
+            let handle = self.namespace(&namespace)?;
+            let docs = txn.open_table(handle.tables.documents())?;
+            let mut access = txn.open_table(handle.tables.doc_access())?;
+            for (id, count, last_read) in reads {
+                if docs.get(id.as_str())?.is_none() {
+                    continue;
+                }
+                let total = access.get(id.as_str())?.map_or(0, |v| v.value().0) + count;
+                access.insert(id.as_str(), (total, last_read))?;
+                flushed += 1;
+            }
+        }
+        txn.commit()?;
+        debug!(documents = flushed, "access statistics flushed");
+        Ok(())
+    }
+
+    /// Up to `limit` documents, most read first; or with `coldest_first`,
+    /// least recently read first, starting with those never read.
+    pub fn hot_documents(&self, limit: usize, coldest_first: bool) -> Result<Vec<AccessStats>> {
+        self.flush_access()?;
+        let txn = self.db.begin_read()?;
+        let access = txn.open_table(self.tables.doc_access())?;
+        let mut stats = Vec::new();
+        if coldest_first {
+            for entry in txn.open_table(self.tables.documents())?.iter()? {
+                let (id, _) = entry?;
+                let row = access.get(id.value())?.map(|v| v.value());
+                stats.push(AccessStats {
+                    id: id.value().to_string(),
+                    reads: row.map_or(0, |(reads, _)| reads),
+                    last_read: row.map(|(_, last_read)| last_read),
+                });
+            }
+            stats.sort_by(|a, b| a.last_read.cmp(&b.last_read).then_with(|| a.id.cmp(&b.id)));
+        } else {
+            for entry in access.iter()? {
+                let (id, row) = entry?;
+                let (reads, last_read) = row.value();
+                stats.push(AccessStats {
+                    id: id.value().to_string(),
+                    reads,
+                    last_read: Some(last_read),
+                });
+            }
+            stats.sort_by(|a, b| b.reads.cmp(&a.reads).then_with(|| a.id.cmp(&b.id)));
+        }
+        stats.truncate(limit);
+        Ok(stats)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::StoreOptions;
+
+    fn open(dir: &std::path::Path, track_access: bool) -> Store {
+        let options = StoreOptions {
+            track_access,
+            ..StoreOptions::default()
+        };
+        Store::open(dir, options).unwrap()
+    }
+
+    #[test]
+    fn test_hot_and_cold_documents() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = open(dir.path(), true);
+        for id in ["a", "b", "c"] {
+            store.put_document(id, b"meta", b"state", None, false).unwrap();
+        }
+        store.record_read("b");
+        store.record_read("b");
+        store.record_read("a");
+
+        let hot = store.hot_documents(10, false).unwrap();
+        let counts: Vec<_> = hot.iter().map(|s| (s.id.as_str(), s.reads)).collect();
+        assert_eq!(counts, [("b", 2), ("a", 1)]);
+        let cold = store.hot_documents(1, true).unwrap();
+        assert_eq!((cold[0].id.as_str(), cold[0].last_read), ("c", None));
+    }
+
+    #[test]
+    fn test_reads_of_deleted_documents_are_dropped() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = open(dir.path(), true);
+        for id in ["a", "b"] {
+            store.put_document(id, b"meta", b"state", None, false).unwrap();
+            store.record_read(id);
+        }
+        assert!(store.delete_document("a", false).unwrap());
+
+        store.flush_access().unwrap();
+        let hot = store.hot_documents(10, false).unwrap();
+        let ids: Vec<_> = hot.iter().map(|s| s.id.as_str()).collect();
+        assert_eq!(ids, ["b"]);
+    }
+
+    #[test]
+    fn test_untracked_reads() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = open(dir.path(), false);
+        store.put_document("a", b"meta", b"state", None, false).unwrap();
+        store.record_read("a");
+        assert!(store.hot_documents(10, false).unwrap().is_empty());
+    }
+}
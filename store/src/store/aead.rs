@@ -0,0 +1,107 @@
+//! XChaCha20-Poly1305: the ChaCha20-Poly1305 AEAD of RFC 8439 with the
+//! 192-bit nonces of draft-irtf-cfrg-xchacha, long enough to pick at
+//! random for every value.  Used by `encryption`; the cipher is the
+//! `chacha20poly1305` crate's.
+
+use anyhow::{anyhow, bail, Context, Result};
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, KeyInit, OsRng, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+
+pub const KEY_LEN: usize = 32;
+pub const NONCE_LEN: usize = 24;
+pub const TAG_LEN: usize = 16;
+
+/// A fresh nonce from the operating system's random source.
+pub fn random_nonce() -> Result<[u8; NONCE_LEN]> {
+    let mut nonce = [0u8; NONCE_LEN];
+    OsRng
+        .try_fill_bytes(&mut nonce)
+        .map_err(|e| anyhow!("{e}"))
+        .context("reading a random nonce")?;
+    Ok(nonce)
+}
+
+/// Encrypt `plaintext`, returning the ciphertext followed by the tag that
+/// authenticates it together with `aad`.
+pub fn seal(
+    key: &[u8; KEY_LEN],
+    nonce: &[u8; NONCE_LEN],
+    aad: &[u8],
+    plaintext: &[u8],
+) -> Result<Vec<u8>> {
+    let payload = Payload { msg: plaintext, aad };
+    XChaCha20Poly1305::new(key.into())
+        .encrypt(XNonce::from_slice(nonce), payload)
+        .map_err(|_| anyhow!("value is too long to encrypt"))
+}
+
+/// Check the tag of `sealed` (as returned by `seal`) and decrypt it.
+pub fn open(
+    key: &[u8; KEY_LEN],
+    nonce: &[u8; NONCE_LEN],
+    aad: &[u8],
+    sealed: &[u8],
+) -> Result<Vec<u8>> {
+    if sealed.len() < TAG_LEN {
+        bail!("sealed value is shorter than its tag");
+    }
+    let payload = Payload { msg: sealed, aad };
+    XChaCha20Poly1305::new(key.into())
+        .decrypt(XNonce::from_slice(nonce), payload)
+        .map_err(|_| anyhow!("authentication failed: wrong key or corrupted value"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(text: &str) -> Vec<u8> {
+        let text: String = text.split_whitespace().collect();
+        (0..text.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&text[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn sequential_key(start: u8) -> [u8; 32] {
+        std::array::from_fn(|i| start + i as u8)
+    }
+
+    const SUNSCREEN: &[u8] = b"Ladies and Gentlemen of the class of '99: If I could offer you \
+        only one tip for the future, sunscreen would be it.";
+
+    #[test]
+    fn test_xchacha20_poly1305() {
+        // draft-irtf-cfrg-xchacha, A.3.1
+        let aad = hex("50515253c0c1c2c3c4c5c6c7");
+        let nonce: [u8; NONCE_LEN] = std::array::from_fn(|i| 0x40 + i as u8);
+        let sealed = seal(&sequential_key(0x80), &nonce, &aad, SUNSCREEN).unwrap();
+        assert_eq!(sealed.len(), SUNSCREEN.len() + TAG_LEN);
+        assert_eq!(sealed[..16].to_vec(), hex("bd6d179d3e83d43b9576579493c0e939"));
+        assert_eq!(
+            sealed[SUNSCREEN.len()..].to_vec(),
+            hex("c0875924c1c7987947deafd8780acf49")
+        );
+        assert_eq!(open(&sequential_key(0x80), &nonce, &aad, &sealed).unwrap(), SUNSCREEN);
+    }
+
+    #[test]
+    fn test_open_rejects_tampering() {
+        let (key, nonce) = (sequential_key(1), [7; NONCE_LEN]);
+        let sealed = seal(&key, &nonce, b"row", b"secret").unwrap();
+        assert_eq!(open(&key, &nonce, b"row", &sealed).unwrap(), b"secret");
+
+        let mut flipped = sealed.clone();
+        flipped[0] ^= 1;
+        assert!(open(&key, &nonce, b"row", &flipped).is_err());
+        assert!(open(&key, &nonce, b"other row", &sealed).is_err());
+        assert!(open(&sequential_key(2), &nonce, b"row", &sealed).is_err());
+        assert!(open(&key, &nonce, b"row", &sealed[..TAG_LEN - 1]).is_err());
+    }
+
+    #[test]
+    fn test_random_nonces_differ() {
+        assert_ne!(random_nonce().unwrap(), random_nonce().unwrap());
+    }
+}
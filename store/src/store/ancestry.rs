@@ -0,0 +1,162 @@
+//! Per-document hash chains, for telling a fast-forward from divergence.
+//!
+//! Every write that changes a document's doc_hashes entry — a put with a
+//! new state, a deletion — appends an entry to doc_ancestry under (doc id,
+//! sequence number): the new hash followed by its parents' hashes.  The
+//! parent is the hash it replaced; a state applied from a peer also takes
+//! the change's hash and the ancestors it carried, so versions we skipped
+//! over are known too.  Only the newest `ANCESTRY_DEPTH` entries of each
+//! document are kept.  Unlike history, they outlive a deletion, so a peer
+//! that missed it can't resurrect the document with an older version.
+//!
+//! Values are the 32-byte hashes concatenated, the entry's own first.
+
+use super::{hashing, ApplyBatch, Store, Tables};
+use anyhow::{bail, Result};
+use redb::{ReadableTable, WriteTransaction};
+
+/// Entries kept per document, and most ancestors a change may carry.
+pub const ANCESTRY_DEPTH: usize = 16;
+
+const HASH_LEN: usize = 32;
+
+/// The hashes of an entry, its own first.
+fn entry_hashes(value: &[u8]) -> Result<impl Iterator<Item = &[u8]>> {
+    if value.is_empty() || !value.len().is_multiple_of(HASH_LEN) {
+        bail!("malformed ancestry entry");
+    }
+    Ok(value.chunks(HASH_LEN))
+}
+
+/// `value` with each of `parents` it lacks appended, up to
+/// `ANCESTRY_DEPTH` parents.
+fn with_parents<'a>(
+    value: &[u8],
+    parents: impl IntoIterator<Item = &'a [u8]>,
+) -> Result<Vec<u8>> {
+    let mut out = entry_hashes(value)?.collect::<Vec<_>>().concat();
+    for parent in parents {
+        if out.len() / HASH_LEN > ANCESTRY_DEPTH {
+            break;
+        }
+        if parent.len() == HASH_LEN && !out.chunks(HASH_LEN).any(|hash| hash == parent) {
+            out.extend_from_slice(parent);
+        }
+    }
+    Ok(out)
+}
+
+/// Record that `id`'s doc_hashes entry became `hash`, descending from
+/// `parents`.  If it already was `hash`, the parents join its entry.
+pub(super) fn record<'a>(
+    txn: &WriteTransaction,
+    tables: &Tables,
+    id: &str,
+    hash: &[u8],
+    parents: impl IntoIterator<Item = &'a [u8]>,
+) -> Result<()> {
+    let mut table = txn.open_table(tables.doc_ancestry())?;
+    let seqs: Vec<u64> = table
+        .range((id, 0)..=(id, u64::MAX))?
+        .map(|entry| entry.map(|(key, _)| key.value().1))
+        .collect::<Result<_, _>>()?;
+
+    if let Some(&last) = seqs.last() {
+        let value = table.get((id, last))?.map(|v| v.value().to_vec());
+        if let Some(value) = value.filter(|value| value.starts_with(hash)) {
+            let value = with_parents(&value, parents)?;
+            table.insert((id, last), value.as_slice())?;
+            return Ok(());
+        }
+    }
+    let value = with_parents(hash, parents)?;
+    let next = seqs.last().map_or(0, |s| s + 1);
+    table.insert((id, next), value.as_slice())?;
+
+    let excess = (seqs.len() + 1).saturating_sub(ANCESTRY_DEPTH);
+    for &seq in &seqs[..excess] {
+        table.remove((id, seq))?;
+    }
+    Ok(())
+}
+
+/// `id`'s ancestry, as `Store::document_ancestry` describes it, from
+/// doc_ancestry open in a read or write transaction.
+fn chain(
+    table: &impl ReadableTable<(&'static str, u64), &'static [u8]>,
+    id: &str,
+) -> Result<Vec<Vec<u8>>> {
+    let mut ancestry: Vec<Vec<u8>> = Vec::new();
+    for entry in table.range((id, 0)..=(id, u64::MAX))?.rev() {
+        let (_, value) = entry?;
+        for hash in entry_hashes(value.value())? {
+            if ancestry.len() == ANCESTRY_DEPTH {
+                return Ok(ancestry);
+            }
+            if !ancestry.iter().any(|known| known == hash) {
+                ancestry.push(hash.to_vec());
+            }
+        }
+    }
+    Ok(ancestry)
+}
+
+impl Store {
+    /// Hashes of the versions `id` descends from, its current doc_hashes
+    /// entry first, then newest to oldest: at most `ANCESTRY_DEPTH`.
+    /// Documents last written before chains were recorded have none.
+    pub fn document_ancestry(&self, id: &str) -> Result<Vec<Vec<u8>>> {
+        let txn = self.db.begin_read()?;
+        chain(&txn.open_table(self.tables.doc_ancestry())?, id)
+    }
+}
+
+impl ApplyBatch<'_> {
+    /// `id`'s ancestry, as `Store::document_ancestry`.
+    pub fn ancestry(&self, id: &str) -> Result<Vec<Vec<u8>>> {
+        chain(&self.txn.open_table(self.store.tables.doc_ancestry())?, id)
+    }
+
+    /// `put_document` of a state applied from a peer, whose own hash and
+    /// ancestors are among `parents`.
+    pub fn put_applied_document(
+        &mut self,
+        id: &str,
+        meta: &[u8],
+        crdt_state: &[u8],
+        parents: &[Vec<u8>],
+    ) -> Result<u64> {
+        let store = self.store;
+        let version = store.put_document_in(&self.txn, id, meta, crdt_state, None)?;
+        let hash = hashing::hash(crdt_state);
+        record(&self.txn, &store.tables, id, hash.as_bytes(), parents.iter().map(Vec::as_slice))?;
+        self.touched.push(store.document_key(id));
+        Ok(version)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_with_parents() {
+        let (a, b, c) = ([1; HASH_LEN], [2; HASH_LEN], [3; HASH_LEN]);
+        let entry = with_parents(&a, [&b[..], &a[..], &b[..], &c[..5]]).unwrap();
+        assert_eq!(entry, [a, b].concat());
+        let entry = with_parents(&entry, [&c[..]]).unwrap();
+        let hashes: Vec<&[u8]> = entry_hashes(&entry).unwrap().collect();
+        assert_eq!(hashes, [&a[..], &b[..], &c[..]]);
+
+        let many: Vec<[u8; HASH_LEN]> = (0..40).map(|n| [n + 10; HASH_LEN]).collect();
+        let entry = with_parents(&a, many.iter().map(|h| &h[..])).unwrap();
+        assert_eq!(entry.len(), (ANCESTRY_DEPTH + 1) * HASH_LEN);
+    }
+
+    #[test]
+    fn test_malformed_entries() {
+        assert!(entry_hashes(&[]).is_err());
+        assert!(entry_hashes(&[0; HASH_LEN + 1]).is_err());
+        assert!(with_parents(&[0; 3], []).is_err());
+    }
+}
@@ -0,0 +1,68 @@
+//! Applying a batch of changes from a peer in one write transaction.
+//!
+//! `Store::apply_changes` checks the batch's signature, records its epoch
+//! (see `signing`) and hands the caller an `ApplyBatch` to read and write
+//! the documents through.  Everything commits together, or, on an error,
+//! nothing does, the epoch included.  Reads through the batch see its
+//! earlier writes, so they bypass the read cache.
+
+use super::cache::CacheKey;
+use super::{signing, Document, SignedRoot, Store};
+use anyhow::Result;
+use redb::{ReadableTable, WriteTransaction};
+
+/// The write transaction of a batch being applied.
+pub struct ApplyBatch<'a> {
+    pub(super) store: &'a Store,
+    pub(super) txn: WriteTransaction,
+    /// Cache keys of the documents written, invalidated once committed.
+    pub(super) touched: Vec<CacheKey>,
+}
+
+impl Store {
+    /// Run `apply` in one write transaction, after checking `signed`, the
+    /// batch's signature, against `digest`, its digest, and recording its
+    /// epoch.  A refused signature fails with `RootRejected`.
+    pub fn apply_changes<T>(
+        &self,
+        signed: Option<&SignedRoot>,
+        digest: &[u8],
+        apply: impl FnOnce(&mut ApplyBatch<'_>) -> Result<T>,
+    ) -> Result<T> {
+        self.check_signed_changes(signed, digest)?;
+        let txn = self.begin_write()?;
+        if let Some(signed) = signed {
+            signing::record_epoch(&txn, &self.tables, signed)?;
+        }
+        let mut batch = ApplyBatch {
+            store: self,
+            txn,
+            touched: Vec::new(),
+        };
+        let out = apply(&mut batch)?;
+        batch.txn.commit()?;
+        self.committed(&batch.touched);
+        Ok(out)
+    }
+}
+
+impl ApplyBatch<'_> {
+    /// The state hash of `id`, as `Store::get_doc_hash`.
+    pub fn doc_hash(&self, id: &str) -> Result<Option<Vec<u8>>> {
+        let hashes = self.txn.open_table(self.store.tables.doc_hashes())?;
+        let hash = hashes.get(id)?.map(|v| v.value().to_vec());
+        Ok(hash)
+    }
+
+    /// `id`'s document, as `Store::get_document`.
+    pub fn document(&self, id: &str) -> Result<Option<Document>> {
+        let tables = &self.store.tables;
+        self.store.document_from(
+            id,
+            &self.txn.open_table(tables.documents())?,
+            &self.txn.open_table(tables.doc_data())?,
+            &self.txn.open_table(tables.doc_versions())?,
+            &self.txn.open_table(tables.doc_times())?,
+        )
+    }
+}
@@ -0,0 +1,635 @@
+//! Portable export archives.
+//!
+//! An archive is a tar file that doesn't depend on redb's file format, so it
+//! can move a database between hosts and store versions:
+//!
+//! ```text
+//! ringforge-archive               format, store_version, created_at (key\tvalue lines)
+//! <ns>/documents.tsv              n\tstate hash\tid, one line per document
+//! <ns>/documents/<n>.meta         metadata
+//! <ns>/documents/<n>.state        CRDT state
+//! <ns>/documents/<n>.index        key\tvalue lines, for indexed documents
+//! <ns>/tombstones.tsv             deletion hash\tdeleted state hash\tdeleted at\tid
+//! <ns>/blob-expiry.tsv            blob hash\texpires at, for blobs with a TTL
+//! <ns>/blobs/<hash>               blob contents, uncompressed
+//! ```
+//!
+//! `<ns>` is `default` or `namespaces/<name>`.  Hashes are lowercase hex
+//! blake3; ids, keys and values escape `%`, tab, CR and LF as `%XX`.
+//! Document history is not exported.  Import verifies every blob and
+//! document state against its hash and recomputes doc_hashes.
+
+use super::cache::CacheKey;
+use super::encryption::open_state;
+use super::{
+    from_hex, hashing, qualified_name, spill, to_hex, tombstones, unix_now, validate_namespace,
+    Store, Tables,
+};
+use crate::tar;
+use anyhow::{bail, Context, Result};
+use redb::{ReadTransaction, ReadableTable, WriteTransaction};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::io::{Read, Write};
+use std::sync::Arc;
+use tracing::{info, instrument, warn};
+
+/// Name of the archive's first entry.
+pub const MANIFEST: &str = "ringforge-archive";
+
+/// Version of the layout above.
+pub const FORMAT: u64 = 1;
+
+/// What an export wrote or an import loaded.
+#[derive(Debug, Clone, Default)]
+pub struct ArchiveReport {
+    pub documents: u64,
+    pub tombstones: u64,
+    pub blobs: u64,
+    /// Documents and tombstones an import left alone because the id
+    /// already existed (`ImportPolicy::Merge` only).
+    pub skipped: u64,
+}
+
+/// What an import does with a document id that already exists locally,
+/// live or deleted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ImportPolicy {
+    /// Keep the local document or tombstone.
+    Merge,
+    /// Overwrite it with the archive's.
+    Replace,
+}
+
+impl Store {
+    /// Write the database to `out` as an archive, read from one consistent
+    /// snapshot: the namespaces in `only` (`""` being the default one), or
+    /// every namespace if it is empty.
+    #[instrument(skip(self, out))]
+    pub fn export(&self, out: impl Write, only: &[String]) -> Result<ArchiveReport> {
+        let existing = self.namespaces()?;
+        if let Some(missing) = only
+            .iter()
+            .find(|ns| !ns.is_empty() && !existing.contains(ns))
+        {
+            bail!("no namespace {missing:?} to export");
+        }
+        // Opening each namespace first creates any tables an older one
+        // lacks, so the snapshot can read them all.
+        let mut handles = Vec::new();
+        for namespace in std::iter::once(String::new()).chain(existing) {
+            if only.is_empty() || only.contains(&namespace) {
+                handles.push(self.namespace(&namespace)?);
+            }
+        }
+
+        let txn = self.db.begin_read()?;
+        let mut archive = tar::Writer::new(out);
+        let manifest = format!(
+            "format\t{FORMAT}\nstore_version\t{}\ncreated_at\t{}\n",
+            env!("CARGO_PKG_VERSION"),
+            unix_now()
+        );
+        archive.append(MANIFEST, manifest.as_bytes())?;
+
+        let mut report = ArchiveReport::default();
+        for handle in &handles {
+            handle.export_namespace(&txn, &mut archive, &mut report)?;
+        }
+        archive.finish()?;
+
+        info!(
+            documents = report.documents,
+            tombstones = report.tombstones,
+            blobs = report.blobs,
+            "archive exported"
+        );
+        Ok(report)
+    }
+
+    fn export_namespace<W: Write>(
+        &self,
+        txn: &ReadTransaction,
+        archive: &mut tar::Writer<W>,
+        report: &mut ArchiveReport,
+    ) -> Result<()> {
+        let dir = archive_dir(&self.namespace);
+
+        let docs = txn.open_table(self.tables.documents())?;
+        let data = txn.open_table(self.tables.doc_data())?;
+        let hashes = txn.open_table(self.tables.doc_hashes())?;
+        let index_keys = txn.open_table(self.tables.doc_index_keys())?;
+        let mut listing = String::new();
+        for (n, entry) in docs.iter()?.enumerate() {
+            let (id, _) = entry?;
+            let hash = hashes
+                .get(id.value())?
+                .with_context(|| format!("document {:?} has no hash; repair it", id.value()))?;
+            writeln!(listing, "{n}\t{}\t{}", to_hex(hash.value()), escape(id.value()))?;
+        }
+        archive.append(&format!("{dir}/documents.tsv"), listing.as_bytes())?;
+        let keys = self.keys();
+        for (n, entry) in docs.iter()?.enumerate() {
+            let (id, meta) = entry?;
+            let state = match data.get(id.value())? {
+                Some(state) => open_state(&keys, id.value(), state.value())?,
+                None => Vec::new(),
+            };
+            archive.append(&format!("{dir}/documents/{n}.meta"), meta.value())?;
+            archive.append(&format!("{dir}/documents/{n}.state"), &state)?;
+            if let Some(encoded) = index_keys.get(id.value())? {
+                let pairs: Vec<(String, String)> = bincode::deserialize(encoded.value())?;
+                let mut text = String::new();
+                for (key, value) in &pairs {
+                    writeln!(text, "{}\t{}", escape(key), escape(value))?;
+                }
+                archive.append(&format!("{dir}/documents/{n}.index"), text.as_bytes())?;
+            }
+            report.documents += 1;
+        }
+
+        let mut listing = String::new();
+        for entry in txn.open_table(self.tables.tombstones())?.iter()? {
+            let (id, value) = entry?;
+            let tombstone = tombstones::parse(value.value())?;
+            writeln!(
+                listing,
+                "{}\t{}\t{}\t{}",
+                to_hex(&tombstone.hash),
+                to_hex(&tombstone.deleted_state),
+                tombstone.deleted_at,
+                escape(id.value())
+            )?;
+            report.tombstones += 1;
+        }
+        archive.append(&format!("{dir}/tombstones.tsv"), listing.as_bytes())?;
+
+        let mut listing = String::new();
+        for entry in txn.open_table(self.tables.blob_ttl())?.iter()? {
+            let (hash, expires_at) = entry?;
+            writeln!(listing, "{}\t{}", to_hex(hash.value()), expires_at.value())?;
+        }
+        archive.append(&format!("{dir}/blob-expiry.tsv"), listing.as_bytes())?;
+
+        for entry in txn.open_table(self.tables.blobs())?.iter()? {
+            let (hash, stored) = entry?;
+            let blob = self.decode_blob(hash.value(), stored.value())?;
+            archive.append(&format!("{dir}/blobs/{}", to_hex(hash.value())), &blob)?;
+            report.blobs += 1;
+        }
+        Ok(())
+    }
+
+    /// Load an archive written by `export` into this database, every
+    /// namespace in one write transaction: either all of it lands or none.
+    /// With a non-empty `only`, the archive's other namespaces are skipped.
+    #[instrument(skip(self, input))]
+    pub fn import(
+        &self,
+        input: impl Read,
+        policy: ImportPolicy,
+        only: &[String],
+    ) -> Result<ArchiveReport> {
+        let mut archive = tar::Reader::new(input);
+        match archive.next_file()? {
+            Some((path, manifest)) if path == MANIFEST => check_manifest(&manifest)?,
+            _ => bail!("not a ringforge archive: it doesn't start with {MANIFEST}"),
+        }
+
+        let txn = self.begin_write()?;
+        let mut importer = Importer {
+            store: self,
+            txn: &txn,
+            policy,
+            only,
+            handle: None,
+            listing: HashMap::new(),
+            expiry: HashMap::new(),
+            pending: None,
+            created: Vec::new(),
+            touched: Vec::new(),
+            report: ArchiveReport::default(),
+        };
+        while let Some((path, data)) = archive.next_file()? {
+            importer.add(&path, data)?;
+        }
+        importer.end_namespace()?;
+        let Importer {
+            created,
+            touched,
+            report,
+            ..
+        } = importer;
+        txn.commit()?;
+
+        let mut known = self.namespaces.lock().unwrap_or_else(|e| e.into_inner());
+        known.extend(created);
+        drop(known);
+        self.committed(&touched);
+
+        info!(
+            documents = report.documents,
+            tombstones = report.tombstones,
+            blobs = report.blobs,
+            skipped = report.skipped,
+            "archive imported"
+        );
+        Ok(report)
+    }
+}
+
+fn check_manifest(manifest: &[u8]) -> Result<()> {
+    let manifest = std::str::from_utf8(manifest).context("archive manifest is not utf-8")?;
+    let format = manifest
+        .lines()
+        .find_map(|line| line.strip_prefix("format\t"))
+        .context("archive manifest has no format")?;
+    if format != FORMAT.to_string() {
+        bail!("unsupported archive format {format} (this store reads {FORMAT})");
+    }
+    Ok(())
+}
+
+/// A document whose files are still being read.
+struct PendingDoc {
+    n: u64,
+    meta: Option<Vec<u8>>,
+    state: Option<Vec<u8>>,
+    index: Vec<(String, String)>,
+}
+
+/// Applies archive entries, in archive order, inside one write
+/// transaction.
+struct Importer<'a> {
+    store: &'a Store,
+    txn: &'a WriteTransaction,
+    policy: ImportPolicy,
+    /// Namespaces to import; empty for all of them.
+    only: &'a [String],
+    /// Handle on the namespace being imported.
+    handle: Option<Store>,
+    /// documents.tsv of the current namespace: n → (state hash, id).
+    listing: HashMap<u64, (Vec<u8>, String)>,
+    /// blob-expiry.tsv of the current namespace.
+    expiry: HashMap<Vec<u8>, u64>,
+    pending: Option<PendingDoc>,
+    /// Namespaces whose tables this import created, to register once the
+    /// transaction commits.
+    created: Vec<(String, Arc<Tables>)>,
+    /// Cache entries to invalidate after the commit.
+    touched: Vec<CacheKey>,
+    report: ArchiveReport,
+}
+
+impl Importer<'_> {
+    fn add(&mut self, path: &str, data: Vec<u8>) -> Result<()> {
+        let Some((namespace, name)) = split_entry(path) else {
+            warn!(path, "skipping unknown archive entry");
+            return Ok(());
+        };
+        if !self.only.is_empty() && !self.only.iter().any(|ns| ns == namespace) {
+            return Ok(());
+        }
+        if self.handle.as_ref().is_none_or(|h| h.namespace != namespace) {
+            self.end_namespace()?;
+            self.begin_namespace(namespace)?;
+        }
+
+        if let Some(file) = name.strip_prefix("documents/") {
+            let (n, kind) = file
+                .split_once('.')
+                .and_then(|(n, kind)| Some((n.parse::<u64>().ok()?, kind)))
+                .with_context(|| format!("malformed archive entry {path}"))?;
+            if self.pending.as_ref().is_some_and(|p| p.n != n) {
+                self.flush_document()?;
+            }
+            let pending = self.pending.get_or_insert(PendingDoc {
+                n,
+                meta: None,
+                state: None,
+                index: Vec::new(),
+            });
+            match kind {
+                "meta" => pending.meta = Some(data),
+                "state" => pending.state = Some(data),
+                "index" => {
+                    pending.index =
+                        parse_index(&data).with_context(|| format!("importing {path}"))?
+                }
+                _ => warn!(path, "skipping unknown archive entry"),
+            }
+            return Ok(());
+        }
+
+        self.flush_document()?;
+        self.add_file(name, &data)
+            .with_context(|| format!("importing {path}"))
+    }
+
+    /// Apply a namespace-level entry: a listing or a blob.
+    fn add_file(&mut self, name: &str, data: &[u8]) -> Result<()> {
+        match name {
+            "documents.tsv" => {
+                for line in lines(data)? {
+                    let [n, hash, id] = fields(line)?;
+                    self.listing
+                        .insert(n.parse()?, (from_hex(hash)?, unescape(id)?));
+                }
+            }
+            "blob-expiry.tsv" => {
+                for line in lines(data)? {
+                    let [hash, expires_at] = fields(line)?;
+                    self.expiry.insert(from_hex(hash)?, expires_at.parse()?);
+                }
+            }
+            "tombstones.tsv" => {
+                for line in lines(data)? {
+                    let [hash, deleted_state, deleted_at, id] = fields(line)?;
+                    let id = unescape(id)?;
+                    self.import_tombstone(
+                        &id,
+                        &from_hex(hash)?,
+                        &from_hex(deleted_state)?,
+                        deleted_at.parse()?,
+                    )?;
+                }
+            }
+            _ => match name.strip_prefix("blobs/") {
+                Some(hash) => self.import_blob(&from_hex(hash)?, data)?,
+                None => warn!(name, "skipping unknown archive entry"),
+            },
+        }
+        Ok(())
+    }
+
+    fn begin_namespace(&mut self, namespace: &str) -> Result<()> {
+        validate_namespace(namespace)?;
+        let known = self.store.namespaces.lock().unwrap_or_else(|e| e.into_inner());
+        let tables = match known.get(namespace) {
+            Some(tables) => tables.clone(),
+            // `Store::namespace` would start a second write transaction,
+            // so create the tables in this one.
+            None => {
+                let tables = Arc::new(Tables::new(namespace));
+                tables.create_all(self.txn)?;
+                self.created.push((namespace.to_string(), tables.clone()));
+                tables
+            }
+        };
+        drop(known);
+        self.handle = Some(Store {
+            namespace: namespace.to_string(),
+            tables,
+            spill_dir: self
+                .store
+                .dir
+                .join(qualified_name(spill::SPILL_DIR, namespace)),
+            ..self.store.clone()
+        });
+        Ok(())
+    }
+
+    fn end_namespace(&mut self) -> Result<()> {
+        self.flush_document()?;
+        if let Some((_, id)) = self.listing.values().next() {
+            bail!("document {id:?} is listed in documents.tsv but missing from the archive");
+        }
+        self.expiry.clear();
+        Ok(())
+    }
+
+    fn flush_document(&mut self) -> Result<()> {
+        let Some(PendingDoc {
+            n,
+            meta,
+            state,
+            index,
+        }) = self.pending.take()
+        else {
+            return Ok(());
+        };
+        let (hash, id) = self
+            .listing
+            .remove(&n)
+            .with_context(|| format!("document {n} is not in documents.tsv"))?;
+        let meta = meta.with_context(|| format!("document {id:?} has no meta"))?;
+        let state = state.with_context(|| format!("document {id:?} has no state"))?;
+        if hash.is_empty() {
+            bail!("document {id:?} has no state hash");
+        }
+        if hashing::hash(&state).as_bytes() != hash.as_slice() {
+            bail!("state of document {id:?} does not match its hash");
+        }
+
+        let handle = self.handle.clone().expect("documents belong to a namespace");
+        if self.skip_existing(&handle, &id)? {
+            return Ok(());
+        }
+        handle.put_document_in(self.txn, &id, &meta, &state, Some(&index))?;
+        self.touched.push(handle.document_key(&id));
+        self.report.documents += 1;
+        Ok(())
+    }
+
+    fn import_tombstone(
+        &mut self,
+        id: &str,
+        hash: &[u8],
+        deleted_state: &[u8],
+        deleted_at: u64,
+    ) -> Result<()> {
+        let handle = self.handle.clone().expect("tombstones belong to a namespace");
+        handle.validate_id(id)?;
+        if self.skip_existing(&handle, id)? {
+            return Ok(());
+        }
+        handle.remove_document(self.txn, id)?;
+        tombstones::write_tombstone(self.txn, &handle.tables, id, hash, deleted_state, deleted_at)?;
+        self.touched.push(handle.document_key(id));
+        self.report.tombstones += 1;
+        Ok(())
+    }
+
+    fn import_blob(&mut self, hash: &[u8], data: &[u8]) -> Result<()> {
+        if hashing::hash(data).as_bytes() != hash {
+            bail!("blob does not match its hash");
+        }
+        let ttl_secs = self
+            .expiry
+            .get(hash)
+            .map(|&expires_at| expires_at.saturating_sub(unix_now()));
+        let handle = self.handle.as_ref().expect("blobs belong to a namespace");
+        handle.put_blob_in(self.txn, data, ttl_secs)?;
+        self.report.blobs += 1;
+        Ok(())
+    }
+
+    /// Under `Merge`, whether `id` already exists (live or deleted) and
+    /// should be left alone.
+    fn skip_existing(&mut self, handle: &Store, id: &str) -> Result<bool> {
+        if self.policy == ImportPolicy::Replace {
+            return Ok(false);
+        }
+        let exists = self
+            .txn
+            .open_table(handle.tables.documents())?
+            .get(id)?
+            .is_some()
+            || self
+                .txn
+                .open_table(handle.tables.tombstones())?
+                .get(id)?
+                .is_some();
+        if exists {
+            self.report.skipped += 1;
+        }
+        Ok(exists)
+    }
+}
+
+/// Split an entry path into its namespace and the path within it.
+fn split_entry(path: &str) -> Option<(&str, &str)> {
+    if let Some(rest) = path.strip_prefix("default/") {
+        return Some(("", rest));
+    }
+    path.strip_prefix("namespaces/")?.split_once('/')
+}
+
+fn parse_index(data: &[u8]) -> Result<Vec<(String, String)>> {
+    lines(data)?
+        .map(|line| {
+            let [key, value] = fields(line)?;
+            Ok((unescape(key)?, unescape(value)?))
+        })
+        .collect()
+}
+
+fn lines(data: &[u8]) -> Result<impl Iterator<Item = &str>> {
+    let text = std::str::from_utf8(data).context("listing is not utf-8")?;
+    Ok(text.lines().filter(|line| !line.is_empty()))
+}
+
+/// The `N` tab-separated fields of `line`.
+fn fields<const N: usize>(line: &str) -> Result<[&str; N]> {
+    let fields: Vec<&str> = line.split('\t').collect();
+    fields
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("expected {N} fields in {line:?}"))
+}
+
+/// Directory of `namespace` inside an archive.
+fn archive_dir(namespace: &str) -> String {
+    if namespace.is_empty() {
+        "default".to_string()
+    } else {
+        format!("namespaces/{namespace}")
+    }
+}
+
+/// Escape the characters that would break a tab-separated line.
+fn escape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '%' | '\t' | '\n' | '\r' => out.push_str(&format!("%{:02X}", c as u8)),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Undo `escape`.
+fn unescape(text: &str) -> Result<String> {
+    let bytes = text.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = text.get(i + 1..i + 3).context("truncated escape")?;
+            out.push(u8::from_str_radix(hex, 16).context("malformed escape")?);
+            i += 3;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    Ok(String::from_utf8(out)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(escape("notes/2024"), "notes/2024");
+        assert_eq!(escape("a\tb%\r\n"), "a%09b%25%0D%0A");
+        for text in ["", "plain", "a\tb%\r\n", "100%", "ünïcode\t"] {
+            assert_eq!(unescape(&escape(text)).unwrap(), text);
+        }
+        assert!(unescape("%4").is_err());
+    }
+
+    #[test]
+    fn test_hex() {
+        let hash = blake3::hash(b"blob");
+        assert_eq!(from_hex(&to_hex(hash.as_bytes())).unwrap(), hash.as_bytes());
+        assert_eq!(from_hex("").unwrap(), Vec::<u8>::new());
+        assert!(from_hex("abc").is_err());
+        assert!(from_hex("zz").is_err());
+    }
+
+    #[test]
+    fn test_split_entry() {
+        assert_eq!(split_entry("default/blobs/ab"), Some(("", "blobs/ab")));
+        assert_eq!(split_entry("namespaces/team/documents.tsv"), Some(("team", "documents.tsv")));
+        assert_eq!(split_entry("other"), None);
+    }
+
+    #[test]
+    fn test_document_needs_its_hash() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Store::open(&dir.path().join("a"), Default::default()).unwrap();
+        store.put_document("doc", b"meta", b"state", None, false).unwrap();
+        let mut exported = Vec::new();
+        store.export(&mut exported, &[]).unwrap();
+        let target = Store::open(&dir.path().join("b"), Default::default()).unwrap();
+        let report = target.import(exported.as_slice(), ImportPolicy::Replace, &[]).unwrap();
+        assert_eq!(report.documents, 1);
+
+        let import = |entries: &[(&str, &[u8])]| {
+            let mut archive = tar::Writer::new(Vec::new());
+            for (path, data) in entries {
+                archive.append(path, data).unwrap();
+            }
+            let archive = archive.finish().unwrap();
+            target.import(archive.as_slice(), ImportPolicy::Replace, &[])
+        };
+        let manifest = format!("format\t{FORMAT}\n");
+        let hash = to_hex(blake3::hash(b"state").as_bytes());
+        let listings = [(format!("0\t{hash}\tdoc\n"), true), ("0\t\tdoc\n".into(), false)];
+        for (listing, ok) in listings {
+            let imported = import(&[
+                (MANIFEST, manifest.as_bytes()),
+                ("default/documents.tsv", listing.as_bytes()),
+                ("default/documents/0.meta", b"meta"),
+                ("default/documents/0.state", b"state"),
+            ]);
+            assert_eq!(imported.is_ok(), ok, "{listing:?}: {imported:?}");
+        }
+
+        // A store that lost a hash can't export the document without it.
+        let txn = store.begin_write().unwrap();
+        txn.open_table(store.tables.doc_hashes()).unwrap().remove("doc").unwrap();
+        txn.commit().unwrap();
+        let refused = store.export(Vec::new(), &[]).unwrap_err();
+        assert!(refused.to_string().contains("has no hash"), "{refused:#}");
+    }
+
+    #[test]
+    fn test_archive_dir() {
+        assert_eq!(archive_dir(""), "default");
+        assert_eq!(archive_dir("team-a"), "namespaces/team-a");
+    }
+}
@@ -0,0 +1,245 @@
+//! Named links from documents to blobs.
+//!
+//! attachments maps (doc id, name) → blob hash; attached_blobs is the
+//! reverse, (blob hash, doc id, name) → (), so deleting a document can tell
+//! which of its blobs nothing else is attached to.  Attached blobs count as
+//! referenced for GC, and attaching a blob clears its TTL (see `ttl`).
+//! Removing a document drops its attachment rows; `delete_document` with
+//! `cascade` also removes the blobs that were attached only to it, unless
+//! another document references them (see `refs`).
+
+use super::{codec, refs, ttl, Store, Tables};
+use anyhow::Result;
+use redb::{ReadableTable, WriteTransaction};
+use std::collections::HashSet;
+use tracing::debug;
+
+/// One row of `list_attachments`.
+#[derive(Debug, Clone)]
+pub struct Attachment {
+    pub name: String,
+    pub hash: Vec<u8>,
+    /// Size of the blob before compression, or `None` if it is missing.
+    pub size: Option<u64>,
+}
+
+/// Remove every attachment of `id`.  Returns the hashes that were attached,
+/// deduplicated, in hash order.
+pub(super) fn clear_attachments(
+    txn: &WriteTransaction,
+    tables: &Tables,
+    id: &str,
+) -> Result<Vec<Vec<u8>>> {
+    let mut attachments = txn.open_table(tables.attachments())?;
+    let mut rows = Vec::new();
+    for entry in attachments.range((id, "")..)? {
+        let (key, hash) = entry?;
+        let (doc_id, name) = key.value();
+        if doc_id != id {
+            break;
+        }
+        rows.push((name.to_string(), hash.value().to_vec()));
+    }
+    let mut reverse = txn.open_table(tables.attached_blobs())?;
+    for (name, hash) in &rows {
+        attachments.remove((id, name.as_str()))?;
+        reverse.remove((hash.as_slice(), id, name.as_str()))?;
+    }
+    let mut hashes: Vec<Vec<u8>> = rows.into_iter().map(|(_, hash)| hash).collect();
+    hashes.sort();
+    hashes.dedup();
+    Ok(hashes)
+}
+
+/// Whether any document has `hash` attached as of `txn`.
+fn is_attached(txn: &WriteTransaction, tables: &Tables, hash: &[u8]) -> Result<bool> {
+    let reverse = txn.open_table(tables.attached_blobs())?;
+    let first = reverse.range((hash, "", "")..)?.next().transpose()?;
+    Ok(first.is_some_and(|(key, _)| key.value().0 == hash))
+}
+
+/// Of `hashes`, those no document has attached, and no document but `id`
+/// references, as of `txn`: the blobs deleting `id` leaves unused.
+pub(super) fn unattached(
+    txn: &WriteTransaction,
+    tables: &Tables,
+    id: &str,
+    hashes: Vec<Vec<u8>>,
+) -> Result<Vec<Vec<u8>>> {
+    let mut out = Vec::new();
+    for hash in hashes {
+        if !is_attached(txn, tables, &hash)? && !refs::is_referenced(txn, tables, &hash, id)? {
+            out.push(hash);
+        }
+    }
+    Ok(out)
+}
+
+impl Store {
+    /// Attach the blob `hash` to document `doc_id` as `name`, replacing any
+    /// attachment of that name.  Returns false, changing nothing, if the
+    /// document or the blob does not exist.
+    pub fn attach_blob(&self, doc_id: &str, hash: &[u8], name: &str) -> Result<bool> {
+        let txn = self.begin_write()?;
+        let attached = self.attach_blob_in(&txn, doc_id, hash, name)?;
+        if attached {
+            txn.commit()?;
+        }
+        Ok(attached)
+    }
+
+    /// `attach_blob` inside a caller's write transaction.
+    pub(super) fn attach_blob_in(
+        &self,
+        txn: &WriteTransaction,
+        doc_id: &str,
+        hash: &[u8],
+        name: &str,
+    ) -> Result<bool> {
+        let blob_exists = txn.open_table(self.tables.blobs())?.get(hash)?.is_some();
+        if !blob_exists || !self.document_exists_in(txn, doc_id)? {
+            return Ok(false);
+        }
+        {
+            let mut attachments = txn.open_table(self.tables.attachments())?;
+            let mut reverse = txn.open_table(self.tables.attached_blobs())?;
+            if let Some(old) = attachments.insert((doc_id, name), hash)? {
+                reverse.remove((old.value(), doc_id, name))?;
+            }
+            reverse.insert((hash, doc_id, name), ())?;
+        }
+        ttl::clear_expiry(txn, &self.tables, hash)?;
+        debug!(doc_id, name, "blob attached");
+        Ok(true)
+    }
+
+    /// Remove the attachment `name` of `doc_id`, leaving the blob itself.
+    /// Returns false if there was none.
+    pub fn detach_blob(&self, doc_id: &str, name: &str) -> Result<bool> {
+        let txn = self.begin_write()?;
+        let removed = self.detach_blob_in(&txn, doc_id, name)?;
+        txn.commit()?;
+        Ok(removed)
+    }
+
+    /// `detach_blob` inside a caller's write transaction.
+    pub(super) fn detach_blob_in(
+        &self,
+        txn: &WriteTransaction,
+        doc_id: &str,
+        name: &str,
+    ) -> Result<bool> {
+        let mut attachments = txn.open_table(self.tables.attachments())?;
+        let old = attachments.remove((doc_id, name))?.map(|v| v.value().to_vec());
+        if let Some(hash) = &old {
+            txn.open_table(self.tables.attached_blobs())?
+                .remove((hash.as_slice(), doc_id, name))?;
+        }
+        Ok(old.is_some())
+    }
+
+    /// The attachments of `doc_id` in name order.
+    pub fn list_attachments(&self, doc_id: &str) -> Result<Vec<Attachment>> {
+        let txn = self.db.begin_read()?;
+        let attachments = txn.open_table(self.tables.attachments())?;
+        let blobs = txn.open_table(self.tables.blobs())?;
+        let mut out = Vec::new();
+        for entry in attachments.range((doc_id, "")..)? {
+            let (key, hash) = entry?;
+            let (id, name) = key.value();
+            if id != doc_id {
+                break;
+            }
+            let size = match blobs.get(hash.value())? {
+                Some(stored) => Some(codec::original_len(stored.value())?),
+                None => None,
+            };
+            out.push(Attachment {
+                name: name.to_string(),
+                hash: hash.value().to_vec(),
+                size,
+            });
+        }
+        Ok(out)
+    }
+
+    /// Add every attached blob to `refs`, for GC marking.
+    pub(super) fn attachment_refs(
+        &self,
+        txn: &WriteTransaction,
+        refs: &mut HashSet<Vec<u8>>,
+    ) -> Result<()> {
+        let reverse = txn.open_table(self.tables.attached_blobs())?;
+        for entry in reverse.iter()? {
+            let (key, _) = entry?;
+            refs.insert(key.value().0.to_vec());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::{to_hex, StoreOptions};
+
+    #[test]
+    fn test_cascade_keeps_referenced_blobs() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Store::open(dir.path(), StoreOptions::default()).unwrap();
+        let shared = store.put_blob(b"shared", None).unwrap();
+        let own = store.put_blob(b"own", None).unwrap();
+        for id in ["a", "b"] {
+            store.put_document(id, b"meta", b"state", None, false).unwrap();
+        }
+        assert!(store.attach_blob("a", &shared, "shared").unwrap());
+        assert!(store.attach_blob("a", &own, "own").unwrap());
+        // "b" only names the shared blob in its state.
+        let state = format!("see {}", to_hex(&shared));
+        store.put_document("b", b"meta", state.as_bytes(), None, false).unwrap();
+
+        assert!(store.delete_document("a", true).unwrap());
+        assert!(store.has_blob(&shared).unwrap());
+        assert!(!store.has_blob(&own).unwrap());
+        assert!(store.get_tombstone("a").unwrap().is_some());
+    }
+
+    #[test]
+    fn test_attach_and_detach() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Store::open(dir.path(), StoreOptions::default()).unwrap();
+        let first = store.put_blob(b"first", None).unwrap();
+        let second = store.put_blob(b"second", Some(10)).unwrap();
+        let missing = blake3::hash(b"missing");
+        store.put_document("doc", b"meta", b"state", None, false).unwrap();
+
+        assert!(!store.attach_blob("doc", missing.as_bytes(), "x").unwrap());
+        assert!(!store.attach_blob("nodoc", &first, "x").unwrap());
+        assert!(store.attach_blob("doc", &first, "x").unwrap());
+        assert!(store.attach_blob("doc", &second, "x").unwrap());
+        let listed = store.list_attachments("doc").unwrap();
+        assert_eq!(listed.len(), 1);
+        assert_eq!((listed[0].name.as_str(), &listed[0].hash), ("x", &second));
+        assert_eq!(listed[0].size, Some(6));
+        // Attaching made the blob permanent.
+        assert_eq!(store.count_expired(u64::MAX).unwrap(), 0);
+
+        assert!(store.detach_blob("doc", "x").unwrap());
+        assert!(!store.detach_blob("doc", "x").unwrap());
+        assert!(store.list_attachments("doc").unwrap().is_empty());
+        assert!(store.has_blob(&second).unwrap());
+    }
+
+    #[test]
+    fn test_delete_without_cascade_keeps_blobs() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Store::open(dir.path(), StoreOptions::default()).unwrap();
+        let hash = store.put_blob(b"blob", None).unwrap();
+        store.put_document("doc", b"meta", b"state", None, false).unwrap();
+        assert!(store.attach_blob("doc", &hash, "x").unwrap());
+
+        assert!(store.delete_document("doc", false).unwrap());
+        assert!(store.has_blob(&hash).unwrap());
+        assert!(store.list_attachments("doc").unwrap().is_empty());
+    }
+}
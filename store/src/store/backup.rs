@@ -0,0 +1,119 @@
+//! Online backups.
+//!
+//! A backup is a data directory of its own: a fresh keyring.redb with every
+//! table copied inside one read transaction — a consistent snapshot even
+//! while writers keep committing — plus the spill files that snapshot
+//! refers to.  `--data-dir` can point straight at it.
+
+use super::{codec, qualified_name, spill, Store, Tables, DB_FILE, PEERS, STORE_KEYS, STORE_META};
+use anyhow::{bail, Context, Result};
+use redb::{
+    Database, Key, ReadTransaction, ReadableTable, TableDefinition, TableError, Value,
+    WriteTransaction,
+};
+use std::fs;
+use std::path::Path;
+use tracing::{info, instrument};
+
+/// Outcome of a backup.
+#[derive(Debug, Clone, Default)]
+pub struct BackupReport {
+    /// Size of the backup's keyring.redb.
+    pub file_bytes: u64,
+    /// Spill files copied alongside it.
+    pub spilled_blobs: u64,
+}
+
+/// Copy every entry of `table` from `src` into `dst`.  A table missing from
+/// `src` (a namespace created before the table existed) is skipped.
+pub(super) fn copy_table<K: Key + 'static, V: Value + 'static>(
+    src: &ReadTransaction,
+    dst: &WriteTransaction,
+    table: TableDefinition<K, V>,
+) -> Result<()> {
+    let from = match src.open_table(table) {
+        Ok(from) => from,
+        Err(TableError::TableDoesNotExist(_)) => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut to = dst.open_table(table)?;
+    for entry in from.iter()? {
+        let (key, value) = entry?;
+        to.insert(key.value(), value.value())?;
+    }
+    Ok(())
+}
+
+impl Store {
+    /// Write a consistent copy of the whole database (every namespace) to
+    /// the directory `dest`, which must not already hold a database.
+    ///
+    /// The database file is written under a temporary name and renamed
+    /// last, so a keyring.redb in `dest` is always complete.  A spill file
+    /// deleted by GC or expiry after the snapshot was taken fails the
+    /// backup; retrying is safe.
+    #[instrument(skip(self))]
+    pub fn backup(&self, dest: &Path) -> Result<BackupReport> {
+        let db_path = dest.join(DB_FILE);
+        if db_path.exists() {
+            bail!("{} already exists", db_path.display());
+        }
+        fs::create_dir_all(dest).with_context(|| format!("creating {}", dest.display()))?;
+        let partial = db_path.with_extension("redb.partial");
+        // Left behind by an interrupted backup.
+        spill::remove(&partial)?;
+
+        let mut report = BackupReport::default();
+        let src = self.db.begin_read()?;
+        {
+            let copy = Database::create(&partial)
+                .with_context(|| format!("creating {}", partial.display()))?;
+            let dst = copy.begin_write()?;
+            copy_table(&src, &dst, STORE_META)?;
+            copy_table(&src, &dst, STORE_KEYS)?;
+            copy_table(&src, &dst, PEERS)?;
+            let mut namespaces = vec![String::new()];
+            namespaces.extend(super::namespaces_of(src.list_tables()?));
+            for namespace in &namespaces {
+                let tables = Tables::new(namespace);
+                tables.copy_all(&src, &dst)?;
+                report.spilled_blobs += self.copy_spill_files(&src, &tables, namespace, dest)?;
+            }
+            dst.commit()?;
+        }
+        fs::rename(&partial, &db_path)
+            .with_context(|| format!("renaming into {}", db_path.display()))?;
+
+        report.file_bytes = fs::metadata(&db_path)?.len();
+        info!(
+            file_bytes = report.file_bytes,
+            spilled_blobs = report.spilled_blobs,
+            "backup written"
+        );
+        Ok(report)
+    }
+
+    fn copy_spill_files(
+        &self,
+        src: &ReadTransaction,
+        tables: &Tables,
+        namespace: &str,
+        dest: &Path,
+    ) -> Result<u64> {
+        let dir_name = qualified_name(spill::SPILL_DIR, namespace);
+        let (from_dir, to_dir) = (self.dir.join(&dir_name), dest.join(&dir_name));
+        let mut copied = 0;
+        for entry in src.open_table(tables.blobs())?.iter()? {
+            let (hash, value) = entry?;
+            if !codec::is_external(value.value()) {
+                continue;
+            }
+            spill::copy(
+                &spill::path_of(&from_dir, hash.value(), value.value()),
+                &spill::path_of(&to_dir, hash.value(), value.value()),
+            )?;
+            copied += 1;
+        }
+        Ok(copied)
+    }
+}
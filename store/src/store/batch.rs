@@ -0,0 +1,169 @@
+//! Several single-item writes committed as one transaction (group commit
+//! and client transactions).
+
+use super::Store;
+use anyhow::Result;
+use tracing::{debug, instrument};
+
+/// A write that can share a transaction with others.
+#[derive(Debug)]
+pub enum WriteOp {
+    PutBlob {
+        data: Vec<u8>,
+        ttl_secs: Option<u64>,
+    },
+    PutDocument {
+        id: String,
+        meta: Vec<u8>,
+        crdt_state: Vec<u8>,
+        index: Option<Vec<(String, String)>>,
+        create_only: bool,
+    },
+    DeleteDocument {
+        id: String,
+    },
+    AttachBlob {
+        doc_id: String,
+        hash: Vec<u8>,
+        name: String,
+    },
+    DetachBlob {
+        doc_id: String,
+        name: String,
+    },
+}
+
+#[derive(Debug)]
+pub enum WriteOutcome {
+    BlobStored(Vec<u8>),
+    /// The document's new version.
+    DocumentStored(u64),
+    /// A `create_only` put found the document already there.
+    DocumentExists,
+    /// Whether the document existed.
+    DocumentDeleted(bool),
+    /// Whether the document and blob existed.
+    BlobAttached(bool),
+    /// Whether there was such an attachment.
+    BlobDetached(bool),
+}
+
+impl Store {
+    /// Apply `ops` in order in a single write transaction.  Either every op
+    /// commits or, on the first error, none does.
+    #[instrument(skip(self, ops), fields(count = ops.len()))]
+    pub fn write_batch(&self, ops: &[WriteOp]) -> Result<Vec<WriteOutcome>> {
+        let txn = self.begin_write()?;
+        let mut outcomes = Vec::with_capacity(ops.len());
+        let mut touched = Vec::new();
+        for op in ops {
+            outcomes.push(match op {
+                WriteOp::PutBlob { data, ttl_secs } => {
+                    WriteOutcome::BlobStored(self.put_blob_in(&txn, data, *ttl_secs)?)
+                }
+                WriteOp::PutDocument {
+                    id,
+                    meta,
+                    crdt_state,
+                    index,
+                    create_only,
+                } => {
+                    if *create_only && self.document_exists_in(&txn, id)? {
+                        WriteOutcome::DocumentExists
+                    } else {
+                        let version =
+                            self.put_document_in(&txn, id, meta, crdt_state, index.as_deref())?;
+                        touched.push(self.document_key(id));
+                        WriteOutcome::DocumentStored(version)
+                    }
+                }
+                WriteOp::DeleteDocument { id } => {
+                    let existed = self.delete_document_in(&txn, id)?;
+                    touched.push(self.document_key(id));
+                    WriteOutcome::DocumentDeleted(existed)
+                }
+                WriteOp::AttachBlob { doc_id, hash, name } => {
+                    WriteOutcome::BlobAttached(self.attach_blob_in(&txn, doc_id, hash, name)?)
+                }
+                WriteOp::DetachBlob { doc_id, name } => {
+                    WriteOutcome::BlobDetached(self.detach_blob_in(&txn, doc_id, name)?)
+                }
+            });
+        }
+        txn.commit()?;
+        self.committed(&touched);
+
+        debug!(count = ops.len(), "write batch committed");
+        Ok(outcomes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::StoreOptions;
+
+    fn put(id: &str, crdt_state: &[u8]) -> WriteOp {
+        WriteOp::PutDocument {
+            id: id.into(),
+            meta: b"meta".to_vec(),
+            crdt_state: crdt_state.to_vec(),
+            index: None,
+            create_only: false,
+        }
+    }
+
+    #[test]
+    fn test_write_batch() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = StoreOptions {
+            max_doc_bytes: Some(64),
+            ..Default::default()
+        };
+        let store = Store::open(dir.path(), options).unwrap();
+        store.put_document("old", b"meta", b"state", None, false).unwrap();
+
+        let ops = [
+            put("a", b"state-a"),
+            WriteOp::PutBlob {
+                data: b"blob".to_vec(),
+                ttl_secs: None,
+            },
+            put("a", b"state-a2"),
+            WriteOp::DeleteDocument { id: "old".into() },
+            WriteOp::PutDocument {
+                id: "a".into(),
+                meta: Vec::new(),
+                crdt_state: Vec::new(),
+                index: None,
+                create_only: true,
+            },
+        ];
+        let outcomes = store.write_batch(&ops).unwrap();
+        assert!(matches!(
+            outcomes.as_slice(),
+            [
+                WriteOutcome::DocumentStored(1),
+                WriteOutcome::BlobStored(_),
+                WriteOutcome::DocumentStored(2),
+                WriteOutcome::DocumentDeleted(true),
+                WriteOutcome::DocumentExists,
+            ]
+        ));
+        assert_eq!(store.get_document("a").unwrap().unwrap().crdt_state, b"state-a2");
+        assert!(store.get_document("old").unwrap().is_none());
+
+        // The last write is too large, so none of the batch commits.
+        let root = store.combined_root().unwrap();
+        let ops = [
+            put("b", b"state-b"),
+            WriteOp::DeleteDocument { id: "a".into() },
+            put("c", &[0; 100]),
+        ];
+        assert!(store.write_batch(&ops).is_err());
+        assert!(store.get_document("b").unwrap().is_none());
+        assert_eq!(store.get_document("a").unwrap().unwrap().version, 2);
+        assert_eq!(store.combined_root().unwrap(), root);
+        assert_eq!(store.verify(true).unwrap().problems.count, 0);
+    }
+}
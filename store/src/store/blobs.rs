@@ -0,0 +1,345 @@
+//! Content-addressed blob operations.
+
+use super::bloom::BlobFilter;
+use super::cache::Cached;
+use super::{
+    codec, encryption, hashing, spill, stats, ttl, unix_now, BlobBackend, Store, Tables, Timestamps,
+};
+use anyhow::Result;
+use redb::{ReadableTable, ReadableTableMetadata, WriteTransaction};
+use std::ops::Bound;
+use tracing::{debug, instrument};
+
+/// One entry of `list_blobs`.
+#[derive(Debug, Clone)]
+pub struct BlobListing {
+    pub hash: Vec<u8>,
+    /// Size before compression.
+    pub size: u64,
+    /// `None` for blobs last stored before timestamps were recorded.
+    pub times: Option<Timestamps>,
+}
+
+/// What `remove_blobs` took out, for `blobs_removed` once committed.
+pub(super) struct RemovedBlobs {
+    hashes: Vec<Vec<u8>>,
+    /// Those of `hashes` whose content is in a spill file.
+    spilled: Vec<Vec<u8>>,
+}
+
+/// Note in blob_times that `hash` was stored just now.
+fn touch_blob(txn: &WriteTransaction, tables: &Tables, hash: &[u8]) -> Result<()> {
+    let mut times = txn.open_table(tables.blob_times())?;
+    let now = unix_now();
+    let created = times.get(hash)?.map_or(now, |v| v.value().0);
+    times.insert(hash, (created, now))?;
+    Ok(())
+}
+
+impl Store {
+    /// Store `data`, return its blake3 hash (32 bytes).
+    ///
+    /// With `ttl_secs` the blob becomes eligible for the expiry sweeper after
+    /// that many seconds (see `ttl` for how this combines with existing
+    /// copies of the same content).
+    #[instrument(skip(self, data), fields(len = data.len()))]
+    pub fn put_blob(&self, data: &[u8], ttl_secs: Option<u64>) -> Result<Vec<u8>> {
+        let txn = self.begin_write()?;
+        let hash = self.put_blob_in(&txn, data, ttl_secs)?;
+        txn.commit()?;
+        Ok(hash)
+    }
+
+    /// `put_blob` inside a caller's write transaction.
+    pub(super) fn put_blob_in(
+        &self,
+        txn: &WriteTransaction,
+        data: &[u8],
+        ttl_secs: Option<u64>,
+    ) -> Result<Vec<u8>> {
+        self.check_blob_size(data)?;
+        let hash = hashing::hash(data);
+        let hash_bytes = hash.as_bytes();
+
+        let stored = self.encode_blob(hash_bytes, data)?;
+        {
+            let mut table = txn.open_table(self.tables.blobs())?;
+            let existed = table.insert(hash_bytes.as_slice(), stored.as_slice())?.is_some();
+            if !existed {
+                self.blob_filter.insert(&self.namespace, hash_bytes);
+            }
+            ttl::set_expiry(txn, &self.tables, hash_bytes, ttl_secs, existed)?;
+            touch_blob(txn, &self.tables, hash_bytes)?;
+            stats::count_blob_put(txn, &self.tables, data.len(), existed)?;
+        }
+
+        debug!(hash = %hash, "blob stored");
+        Ok(hash_bytes.to_vec())
+    }
+
+    /// Store many blobs in a single write transaction, returning their
+    /// hashes in input order.
+    #[instrument(skip(self, blobs), fields(count = blobs.len()))]
+    pub fn put_blobs(&self, blobs: &[Vec<u8>]) -> Result<Vec<Vec<u8>>> {
+        for data in blobs {
+            self.check_blob_size(data)?;
+        }
+        let mut hashes = Vec::with_capacity(blobs.len());
+
+        let txn = self.begin_write()?;
+        {
+            let mut table = txn.open_table(self.tables.blobs())?;
+            for data in blobs {
+                let hash = hashing::hash(data);
+                let stored = self.encode_blob(hash.as_bytes(), data)?;
+                let existed = table.insert(hash.as_bytes().as_slice(), stored.as_slice())?.is_some();
+                if !existed {
+                    self.blob_filter.insert(&self.namespace, hash.as_bytes());
+                }
+                ttl::set_expiry(&txn, &self.tables, hash.as_bytes(), None, existed)?;
+                touch_blob(&txn, &self.tables, hash.as_bytes())?;
+                stats::count_blob_put(&txn, &self.tables, data.len(), existed)?;
+                hashes.push(hash.as_bytes().to_vec());
+            }
+        }
+        txn.commit()?;
+
+        debug!(count = hashes.len(), "blobs stored");
+        Ok(hashes)
+    }
+
+    /// Retrieve a blob by its blake3 hash.
+    #[instrument(skip(self))]
+    pub fn get_blob(&self, hash: &[u8]) -> Result<Option<Vec<u8>>> {
+        let key = self.blob_key(hash);
+        if let Some(Cached::Blob(data)) = self.blob_cache.get(&key) {
+            return Ok(Some(data));
+        }
+        let generation = self.blob_cache.generation();
+
+        let txn = self.db.begin_read()?;
+        let table = txn.open_table(self.tables.blobs())?;
+        match table.get(hash)? {
+            Some(v) => {
+                let data = self.decode_blob(hash, v.value())?;
+                self.blob_cache.insert(key, Cached::Blob(data.clone()), generation);
+                Ok(Some(data))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Retrieve many blobs in one read transaction, serving what it can
+    /// from the cache.  The result is aligned with `hashes`, with `None` for
+    /// blobs that are missing.
+    #[instrument(skip(self, hashes), fields(count = hashes.len()))]
+    pub fn get_blobs(&self, hashes: &[Vec<u8>]) -> Result<Vec<Option<Vec<u8>>>> {
+        let generation = self.blob_cache.generation();
+        let txn = self.db.begin_read()?;
+        let table = txn.open_table(self.tables.blobs())?;
+        let mut out = Vec::with_capacity(hashes.len());
+        for hash in hashes {
+            let key = self.blob_key(hash);
+            if let Some(Cached::Blob(data)) = self.blob_cache.get(&key) {
+                out.push(Some(data));
+                continue;
+            }
+            let data = match table.get(hash.as_slice())? {
+                Some(v) => {
+                    let data = self.decode_blob(hash, v.value())?;
+                    self.blob_cache.insert(key, Cached::Blob(data.clone()), generation);
+                    Some(data)
+                }
+                None => None,
+            };
+            out.push(data);
+        }
+        Ok(out)
+    }
+
+    /// Check whether a blob exists.  Most absent blobs are ruled out by the
+    /// in-memory filter without a read transaction.
+    pub fn has_blob(&self, hash: &[u8]) -> Result<bool> {
+        if !self.blob_filter.may_contain(&self.namespace, hash) {
+            return Ok(false);
+        }
+        let txn = self.db.begin_read()?;
+        let table = txn.open_table(self.tables.blobs())?;
+        Ok(table.get(hash)?.is_some())
+    }
+
+    /// Those of `hashes` the store lacks, in input order.
+    pub fn missing_blobs(&self, hashes: &[Vec<u8>]) -> Result<Vec<Vec<u8>>> {
+        let txn = self.db.begin_read()?;
+        let table = txn.open_table(self.tables.blobs())?;
+        let mut missing = Vec::new();
+        for hash in hashes {
+            let known = self.blob_filter.may_contain(&self.namespace, hash)
+                && table.get(hash.as_slice())?.is_some();
+            if !known {
+                missing.push(hash.clone());
+            }
+        }
+        Ok(missing)
+    }
+
+    /// List blob hashes, sizes and timestamps in hash order, starting after
+    /// `cursor`.  Returns at most `limit` entries.
+    pub fn list_blobs(&self, cursor: Option<&[u8]>, limit: usize) -> Result<Vec<BlobListing>> {
+        let txn = self.db.begin_read()?;
+        let table = txn.open_table(self.tables.blobs())?;
+        let times = txn.open_table(self.tables.blob_times())?;
+        let start = match cursor {
+            Some(c) => Bound::Excluded(c),
+            None => Bound::Unbounded,
+        };
+        let mut out = Vec::new();
+        for entry in table.range::<&[u8]>((start, Bound::Unbounded))?.take(limit) {
+            let (k, v) = entry?;
+            out.push(BlobListing {
+                hash: k.value().to_vec(),
+                size: codec::original_len(v.value())?,
+                times: times.get(k.value())?.map(|t| Timestamps::from_row(t.value())),
+            });
+        }
+        Ok(out)
+    }
+
+    /// Number of blobs whose hash starts with `prefix`.
+    pub fn count_blobs(&self, prefix: &[u8]) -> Result<u64> {
+        let txn = self.db.begin_read()?;
+        let table = txn.open_table(self.tables.blobs())?;
+        if prefix.is_empty() {
+            return Ok(table.len()?);
+        }
+        let mut count = 0;
+        for entry in table.range::<&[u8]>(prefix..)? {
+            let (k, _) = entry?;
+            if !k.value().starts_with(prefix) {
+                break;
+            }
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Encode a blob for the blobs table, spilling it to disk when it is
+    /// over the threshold or the backend is `Files`.  Spill files are
+    /// written before the redb commit; a failed commit leaves an
+    /// unreferenced file, never a dangling row.
+    fn encode_blob(&self, hash: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+        let keys = self.keys();
+        let spill = self.options.blob_backend == BlobBackend::Files
+            || self.options.spill_threshold.is_some_and(|t| data.len() >= t);
+        if spill {
+            self.journal.record(&self.namespace, &[hash])?;
+            return Ok(match keys.current() {
+                Some(key) => {
+                    let sealed = encryption::seal(key, hash, data)?;
+                    spill::write(&spill::sealed_path_for(&self.spill_dir, hash), &sealed)?;
+                    codec::sealed_external(data.len())
+                }
+                None => {
+                    spill::write(&spill::path_for(&self.spill_dir, hash), data)?;
+                    codec::external(data.len())
+                }
+            });
+        }
+        encryption::encode(keys.current(), hash, data, self.options.compression_level)
+    }
+
+    /// Decode a blobs-table value, reading the spill file if it has one.
+    pub(super) fn decode_blob(&self, hash: &[u8], stored: &[u8]) -> Result<Vec<u8>> {
+        if codec::is_sealed_external(stored) {
+            let sealed = spill::read(&spill::sealed_path_for(&self.spill_dir, hash))?;
+            encryption::open(&self.keys(), hash, &sealed)
+        } else if codec::is_external(stored) {
+            spill::read(&spill::path_for(&self.spill_dir, hash))
+        } else {
+            encryption::decode(&self.keys(), hash, stored)
+        }
+    }
+
+    /// The hash of a blobs-table value's contents.  A plain spill file is
+    /// hashed from disk rather than read into memory.
+    pub(super) fn hash_stored_blob(&self, hash: &[u8], stored: &[u8]) -> Result<blake3::Hash> {
+        if codec::is_external(stored) && !codec::is_sealed_external(stored) {
+            return hashing::hash_file(&spill::path_for(&self.spill_dir, hash));
+        }
+        Ok(hashing::hash(&self.decode_blob(hash, stored)?))
+    }
+
+    /// Drop removed blobs from the read cache.
+    /// Finish removing blobs once the transaction that removed their rows
+    /// has committed: drop them from the cache and the blob filter, and
+    /// delete their spill files.
+    pub(super) fn blobs_removed(&self, removed: RemovedBlobs) -> Result<()> {
+        let keys: Vec<_> = removed.hashes.iter().map(|h| self.blob_key(h)).collect();
+        self.blob_cache.invalidate(&keys);
+        for hash in &removed.hashes {
+            self.blob_filter.remove(&self.namespace, hash);
+        }
+        for hash in &removed.spilled {
+            spill::remove(&spill::path_for(&self.spill_dir, hash))?;
+            spill::remove(&spill::sealed_path_for(&self.spill_dir, hash))?;
+        }
+        Ok(())
+    }
+
+    /// Remove blobs and their bookkeeping rows inside `txn`.  The caller
+    /// passes the result to `blobs_removed` once the transaction has
+    /// committed — spill files go only after the rows, and are journaled
+    /// first, so a crash in between leaves the removal for the journal to
+    /// finish.
+    pub(super) fn remove_blobs(
+        &self,
+        txn: &WriteTransaction,
+        hashes: &[Vec<u8>],
+    ) -> Result<RemovedBlobs> {
+        let mut blobs = txn.open_table(self.tables.blobs())?;
+        let mut removed = Vec::new();
+        let mut spilled = Vec::new();
+        for hash in hashes {
+            if let Some(old) = blobs.remove(hash.as_slice())? {
+                if codec::is_external(old.value()) {
+                    spilled.push(hash.clone());
+                }
+                removed.push(hash.clone());
+            }
+            ttl::clear_expiry(txn, &self.tables, hash)?;
+        }
+        let mut times = txn.open_table(self.tables.blob_times())?;
+        for hash in hashes {
+            times.remove(hash.as_slice())?;
+        }
+        let journaled: Vec<&[u8]> = spilled.iter().map(Vec::as_slice).collect();
+        self.journal.record(&self.namespace, &journaled)?;
+        Ok(RemovedBlobs {
+            hashes: removed,
+            spilled,
+        })
+    }
+
+    /// Load every namespace's blob hashes into a new filter.
+    pub(super) fn build_blob_filter(&self) -> Result<BlobFilter> {
+        let mut handles = vec![self.namespace("")?];
+        for namespace in self.namespaces()? {
+            handles.push(self.namespace(&namespace)?);
+        }
+
+        let txn = self.db.begin_read()?;
+        let mut count = 0;
+        for handle in &handles {
+            count += txn.open_table(handle.tables.blobs())?.len()?;
+        }
+        let filter = BlobFilter::with_capacity(count);
+        for handle in &handles {
+            for entry in txn.open_table(handle.tables.blobs())?.iter()? {
+                let (hash, _) = entry?;
+                filter.insert(&handle.namespace, hash.value());
+            }
+        }
+        debug!(blobs = count, bytes = filter.len(), "blob filter built");
+        Ok(filter)
+    }
+}
@@ -0,0 +1,156 @@
+//! Counting Bloom filter over the blob hashes of every namespace.
+//!
+//! `HasBlob` probes arrive in bursts during sync negotiation, mostly for
+//! blobs this side lacks.  The filter answers those without a read
+//! transaction: a miss is definite, a hit still goes to redb.  It lives in
+//! memory only and is rebuilt when the database is opened, sized for twice
+//! the blobs stored then, about a 1% false-positive rate at that size.
+//!
+//! Hashes are added before their transaction commits and removed only
+//! after, so the filter never misses a committed blob.  An aborted put
+//! leaves its hash behind, which costs a false positive and nothing more.
+//! Counters are bytes that stick at their maximum once reached.
+
+use std::sync::atomic::{AtomicU8, Ordering};
+
+/// Capacity of the filter of a database with few blobs.
+const MIN_CAPACITY: u64 = 1 << 16;
+
+/// Counters per expected blob, and counters set per blob; ~1% false
+/// positives at capacity.
+const COUNTERS_PER_BLOB: u64 = 10;
+const PROBES: u64 = 7;
+
+pub(super) struct BlobFilter {
+    counters: Vec<AtomicU8>,
+}
+
+impl Default for BlobFilter {
+    /// A filter that keeps nothing and so must answer "maybe" to everything;
+    /// stands in until the real one has been built.
+    fn default() -> Self {
+        Self {
+            counters: Vec::new(),
+        }
+    }
+}
+
+impl BlobFilter {
+    /// An empty filter with room for `blobs` blobs, and then as many again.
+    pub(super) fn with_capacity(blobs: u64) -> Self {
+        let len = blobs.saturating_mul(2).max(MIN_CAPACITY) * COUNTERS_PER_BLOB;
+        Self {
+            counters: (0..len).map(|_| AtomicU8::new(0)).collect(),
+        }
+    }
+
+    /// Counters of the filter, i.e. its size in bytes.
+    pub(super) fn len(&self) -> usize {
+        self.counters.len()
+    }
+
+    pub(super) fn insert(&self, namespace: &str, hash: &[u8]) {
+        for i in self.positions(namespace, hash) {
+            let _ = self.counters[i].fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| {
+                n.checked_add(1)
+            });
+        }
+    }
+
+    /// Forget one insertion of `hash`.  Only call this for a hash that was
+    /// inserted and whose removal has committed.
+    pub(super) fn remove(&self, namespace: &str, hash: &[u8]) {
+        for i in self.positions(namespace, hash) {
+            // A saturated counter no longer knows how many blobs it counts.
+            let _ = self.counters[i].fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| {
+                (n != 0 && n != u8::MAX).then(|| n - 1)
+            });
+        }
+    }
+
+    /// False if no blob `hash` is stored in `namespace`; true if it may be.
+    pub(super) fn may_contain(&self, namespace: &str, hash: &[u8]) -> bool {
+        self.counters.is_empty()
+            || self
+                .positions(namespace, hash)
+                .all(|i| self.counters[i].load(Ordering::Relaxed) != 0)
+    }
+
+    /// Counter indexes for `hash`, by double hashing.  Blob hashes are
+    /// blake3 output, uniform already, so their bytes are used as they are;
+    /// the namespace is mixed in so that namespaces holding the same
+    /// content don't share counters.
+    fn positions(&self, namespace: &str, hash: &[u8]) -> impl Iterator<Item = usize> {
+        let len = self.counters.len() as u64;
+        let salt = if namespace.is_empty() {
+            0
+        } else {
+            word(blake3::hash(namespace.as_bytes()).as_bytes())
+        };
+        let h1 = word(hash) ^ salt;
+        let h2 = word(hash.get(8..).unwrap_or_default()) | 1;
+        (0..PROBES).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % len) as usize)
+    }
+}
+
+/// The first 8 bytes of `bytes`, zero-padded, as a little-endian u64.
+fn word(bytes: &[u8]) -> u64 {
+    let mut buf = [0u8; 8];
+    let n = bytes.len().min(8);
+    buf[..n].copy_from_slice(&bytes[..n]);
+    u64::from_le_bytes(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hash(n: u32) -> [u8; 32] {
+        *blake3::hash(&n.to_le_bytes()).as_bytes()
+    }
+
+    #[test]
+    fn test_no_false_negatives() {
+        let filter = BlobFilter::with_capacity(1000);
+        for n in 0..2000 {
+            filter.insert("", &hash(n));
+        }
+        assert!((0..2000).all(|n| filter.may_contain("", &hash(n))));
+    }
+
+    #[test]
+    fn test_false_positive_rate() {
+        let filter = BlobFilter::with_capacity(MIN_CAPACITY);
+        for n in 0..MIN_CAPACITY as u32 * 2 {
+            filter.insert("", &hash(n));
+        }
+        let probes = 10_000;
+        let start = MIN_CAPACITY as u32 * 2;
+        let hits = (start..start + probes).filter(|&n| filter.may_contain("", &hash(n))).count();
+        assert!(hits < probes as usize / 50, "{hits} false positives");
+    }
+
+    #[test]
+    fn test_remove() {
+        let filter = BlobFilter::with_capacity(10);
+        filter.insert("", &hash(1));
+        filter.insert("", &hash(2));
+        filter.remove("", &hash(1));
+        assert!(!filter.may_contain("", &hash(1)));
+        assert!(filter.may_contain("", &hash(2)));
+    }
+
+    #[test]
+    fn test_namespaces_are_separate() {
+        let filter = BlobFilter::with_capacity(10);
+        filter.insert("team", &hash(1));
+        assert!(filter.may_contain("team", &hash(1)));
+        assert!(!filter.may_contain("", &hash(1)));
+        assert!(!filter.may_contain("other", &hash(1)));
+    }
+
+    #[test]
+    fn test_empty_filter_answers_maybe() {
+        assert!(BlobFilter::default().may_contain("", &hash(1)));
+    }
+}
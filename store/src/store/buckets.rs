@@ -0,0 +1,361 @@
+//! Doc hashes bucketed by prefix, so two peers can find the documents they
+//! differ on without exchanging every root.
+//!
+//! Each document sits in a leaf of a trie of fan-out `BUCKET_FANOUT` and
+//! depth `BUCKET_DEPTH`, picked by the leading nibbles of blake3 of its id,
+//! so it stays in the same bucket whatever its state.  Every node records
+//! how many documents lie under it and a digest: the XOR of blake3 over
+//! each one's id and doc_hashes entry.  XOR lets a write update the nodes
+//! on its path without reading the rest of their documents.  Peers compare
+//! root digests, then descend with `GetBucket` into the children whose
+//! digests differ, until a bucket is small enough to list its roots.
+//!
+//! doc_buckets holds the nodes, keyed by their prefix with one nibble per
+//! byte, and bucket_docs the leaf of every document.  Both are derived from
+//! doc_hashes, which is written only through `set_doc_hash`; repair and
+//! migrations rebuild them.
+
+use super::{roots, Store, Tables};
+use anyhow::{bail, Context, Result};
+use redb::{ReadableTable, WriteTransaction};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Children of every inner bucket.
+pub const BUCKET_FANOUT: u8 = 16;
+
+/// Nibbles in the prefix of a leaf bucket.
+pub const BUCKET_DEPTH: usize = 4;
+
+/// Buckets holding at most this many documents list their roots instead
+/// of their children.
+const MAX_LISTED: u64 = 64;
+
+const DIGEST_LEN: usize = 32;
+
+type Digest = [u8; DIGEST_LEN];
+
+/// Node prefix → (count, digest).
+type Nodes = BTreeMap<Vec<u8>, (u64, Digest)>;
+
+/// (leaf prefix, document id).
+type Members = BTreeSet<(Vec<u8>, String)>;
+
+/// One node of the trie, as `get_bucket` returns it.
+#[derive(Debug, Clone)]
+pub struct Bucket {
+    /// Documents (and tombstones) under the bucket.
+    pub count: u64,
+    /// XOR of the digests of those documents; all zeros when empty.
+    pub digest: Vec<u8>,
+    /// `(count, digest)` of each child in nibble order; empty when the
+    /// bucket lists its roots instead.
+    pub children: Vec<(u64, Vec<u8>)>,
+    /// `(doc id, hash)` of every document under a leaf, or under a bucket
+    /// of at most `MAX_LISTED` documents.
+    pub roots: Vec<(String, Vec<u8>)>,
+}
+
+/// Whether `prefix` names a bucket: at most `BUCKET_DEPTH` nibbles, one
+/// per byte.  The empty prefix is the root.
+pub fn valid_bucket_prefix(prefix: &[u8]) -> bool {
+    prefix.len() <= BUCKET_DEPTH && prefix.iter().all(|&nibble| nibble < BUCKET_FANOUT)
+}
+
+/// Prefix of the leaf bucket holding `id`.
+fn leaf_of(id: &str) -> [u8; BUCKET_DEPTH] {
+    let hash = blake3::hash(id.as_bytes());
+    let bytes = hash.as_bytes();
+    std::array::from_fn(|i| {
+        let byte = bytes[i / 2];
+        if i % 2 == 0 {
+            byte >> 4
+        } else {
+            byte & 0x0f
+        }
+    })
+}
+
+/// What `id` with doc_hashes entry `hash` contributes to its buckets.
+pub(super) fn item_digest(id: &str, hash: &[u8]) -> Digest {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(&(id.len() as u64).to_le_bytes());
+    hasher.update(id.as_bytes());
+    hasher.update(hash);
+    *hasher.finalize().as_bytes()
+}
+
+fn xor(into: &mut Digest, other: &Digest) {
+    for (a, b) in into.iter_mut().zip(other) {
+        *a ^= b;
+    }
+}
+
+/// doc_buckets value: `[8-byte LE count][digest]`.
+fn encode(count: u64, digest: &Digest) -> Vec<u8> {
+    let mut value = Vec::with_capacity(8 + DIGEST_LEN);
+    value.extend_from_slice(&count.to_le_bytes());
+    value.extend_from_slice(digest);
+    value
+}
+
+fn decode(value: &[u8]) -> Result<(u64, Digest)> {
+    if value.len() != 8 + DIGEST_LEN {
+        bail!("malformed doc bucket");
+    }
+    let (count, digest) = value.split_at(8);
+    Ok((
+        u64::from_le_bytes(count.try_into().unwrap()),
+        digest.try_into().unwrap(),
+    ))
+}
+
+/// Set `id`'s doc_hashes entry to `hash`, or remove it with `None`, and
+/// update the buckets on its path.  Returns the previous entry.
+pub(super) fn set_doc_hash(
+    txn: &WriteTransaction,
+    tables: &Tables,
+    id: &str,
+    hash: Option<&[u8]>,
+) -> Result<Option<Vec<u8>>> {
+    let old = {
+        let mut hashes = txn.open_table(tables.doc_hashes())?;
+        let old = match hash {
+            Some(hash) => hashes.insert(id, hash)?,
+            None => hashes.remove(id)?,
+        };
+        old.map(|v| v.value().to_vec())
+    };
+    if old.as_deref() == hash {
+        return Ok(old);
+    }
+    roots::mark_dirty(txn, tables)?;
+
+    let leaf = leaf_of(id);
+    let mut change = [0; DIGEST_LEN];
+    if let Some(old) = &old {
+        xor(&mut change, &item_digest(id, old));
+    }
+    if let Some(hash) = hash {
+        xor(&mut change, &item_digest(id, hash));
+    }
+    let mut docs = txn.open_table(tables.bucket_docs())?;
+    let delta: i64 = match (&old, hash) {
+        (None, Some(_)) => {
+            docs.insert((leaf.as_slice(), id), ())?;
+            1
+        }
+        (Some(_), None) => {
+            docs.remove((leaf.as_slice(), id))?;
+            -1
+        }
+        _ => 0,
+    };
+
+    let mut buckets = txn.open_table(tables.doc_buckets())?;
+    for depth in 0..=BUCKET_DEPTH {
+        let prefix = &leaf[..depth];
+        let (count, mut digest) = match buckets.get(prefix)? {
+            Some(value) => decode(value.value())?,
+            None => (0, [0; DIGEST_LEN]),
+        };
+        let count = count
+            .checked_add_signed(delta)
+            .context("doc bucket count out of step with doc_hashes")?;
+        xor(&mut digest, &change);
+        if count == 0 {
+            buckets.remove(prefix)?;
+        } else {
+            buckets.insert(prefix, encode(count, &digest).as_slice())?;
+        }
+    }
+    Ok(old)
+}
+
+/// The nodes and leaf members the buckets should hold for `hashes`.
+fn expected_buckets(
+    hashes: &impl ReadableTable<&'static str, &'static [u8]>,
+) -> Result<(Nodes, Members)> {
+    let mut nodes = Nodes::new();
+    let mut members = BTreeSet::new();
+    for entry in hashes.iter()? {
+        let (id, hash) = entry?;
+        let leaf = leaf_of(id.value());
+        let item = item_digest(id.value(), hash.value());
+        for depth in 0..=BUCKET_DEPTH {
+            let node = nodes
+                .entry(leaf[..depth].to_vec())
+                .or_insert((0, [0; DIGEST_LEN]));
+            node.0 += 1;
+            xor(&mut node.1, &item);
+        }
+        members.insert((leaf.to_vec(), id.value().to_string()));
+    }
+    Ok((nodes, members))
+}
+
+/// Make doc_buckets and bucket_docs agree with doc_hashes.  Returns how
+/// many rows had to change.
+pub(super) fn rebuild_buckets(txn: &WriteTransaction, tables: &Tables) -> Result<u64> {
+    roots::mark_dirty(txn, tables)?;
+    let (mut nodes, mut members) = expected_buckets(&txn.open_table(tables.doc_hashes())?)?;
+
+    let mut changed = 0;
+    let mut buckets = txn.open_table(tables.doc_buckets())?;
+    let mut stale = Vec::new();
+    for entry in buckets.iter()? {
+        let (prefix, value) = entry?;
+        match nodes.get(prefix.value()) {
+            Some((count, digest)) if encode(*count, digest) == value.value() => {
+                nodes.remove(prefix.value());
+            }
+            Some(_) => {}
+            None => stale.push(prefix.value().to_vec()),
+        }
+    }
+    for prefix in stale {
+        buckets.remove(prefix.as_slice())?;
+        changed += 1;
+    }
+    for (prefix, (count, digest)) in nodes {
+        buckets.insert(prefix.as_slice(), encode(count, &digest).as_slice())?;
+        changed += 1;
+    }
+
+    let mut docs = txn.open_table(tables.bucket_docs())?;
+    let mut stale = Vec::new();
+    for entry in docs.iter()? {
+        let (key, _) = entry?;
+        let (leaf, id) = key.value();
+        if !members.remove(&(leaf.to_vec(), id.to_string())) {
+            stale.push((leaf.to_vec(), id.to_string()));
+        }
+    }
+    for (leaf, id) in stale {
+        docs.remove((leaf.as_slice(), id.as_str()))?;
+        changed += 1;
+    }
+    for (leaf, id) in members {
+        docs.insert((leaf.as_slice(), id.as_str()), ())?;
+        changed += 1;
+    }
+    Ok(changed)
+}
+
+impl Store {
+    /// Recompute the buckets from doc_hashes and compare them with the
+    /// stored ones.  Returns the prefixes of the nodes that differ, and of
+    /// the leaves whose member lists do, in order.
+    pub fn check_buckets(&self) -> Result<Vec<Vec<u8>>> {
+        let txn = self.db.begin_read()?;
+        let (mut nodes, mut members) =
+            expected_buckets(&txn.open_table(self.tables.doc_hashes())?)?;
+        let mut drifted = BTreeSet::new();
+        for entry in txn.open_table(self.tables.doc_buckets())?.iter()? {
+            let (prefix, value) = entry?;
+            match nodes.remove(prefix.value()) {
+                Some((count, digest)) if encode(count, &digest) == value.value() => {}
+                _ => {
+                    drifted.insert(prefix.value().to_vec());
+                }
+            }
+        }
+        drifted.extend(nodes.into_keys());
+        for entry in txn.open_table(self.tables.bucket_docs())?.iter()? {
+            let (key, _) = entry?;
+            let (leaf, id) = key.value();
+            if !members.remove(&(leaf.to_vec(), id.to_string())) {
+                drifted.insert(leaf.to_vec());
+            }
+        }
+        drifted.extend(members.into_iter().map(|(leaf, _)| leaf));
+        Ok(drifted.into_iter().collect())
+    }
+
+    /// The bucket at `prefix`, which must pass `valid_bucket_prefix`.
+    pub fn get_bucket(&self, prefix: &[u8]) -> Result<Bucket> {
+        if !valid_bucket_prefix(prefix) {
+            bail!("invalid bucket prefix {prefix:?}");
+        }
+        let txn = self.db.begin_read()?;
+        let buckets = txn.open_table(self.tables.doc_buckets())?;
+        let node = |prefix: &[u8]| -> Result<(u64, Digest)> {
+            match buckets.get(prefix)? {
+                Some(value) => decode(value.value()),
+                None => Ok((0, [0; DIGEST_LEN])),
+            }
+        };
+
+        let (count, digest) = node(prefix)?;
+        let mut bucket = Bucket {
+            count,
+            digest: digest.to_vec(),
+            children: Vec::new(),
+            roots: Vec::new(),
+        };
+        if prefix.len() < BUCKET_DEPTH && count > MAX_LISTED {
+            for nibble in 0..BUCKET_FANOUT {
+                let child = [prefix, &[nibble]].concat();
+                let (count, digest) = node(&child)?;
+                bucket.children.push((count, digest.to_vec()));
+            }
+            return Ok(bucket);
+        }
+
+        let docs = txn.open_table(self.tables.bucket_docs())?;
+        let hashes = txn.open_table(self.tables.doc_hashes())?;
+        // Leaves sort bytewise, so those under the prefix are one run.
+        for entry in docs.range((prefix, "")..)? {
+            let (key, _) = entry?;
+            let (leaf, id) = key.value();
+            if !leaf.starts_with(prefix) {
+                break;
+            }
+            if let Some(hash) = hashes.get(id)? {
+                bucket.roots.push((id.to_string(), hash.value().to_vec()));
+            }
+        }
+        Ok(bucket)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_valid_prefix() {
+        assert!(valid_bucket_prefix(&[]));
+        assert!(valid_bucket_prefix(&[15, 0, 3, 9]));
+        assert!(!valid_bucket_prefix(&[16]));
+        assert!(!valid_bucket_prefix(&[0, 0, 0, 0, 0]));
+        assert!(leaf_of("doc").iter().all(|&n| n < BUCKET_FANOUT));
+    }
+
+    #[test]
+    fn test_digest_is_order_independent() {
+        let items = [("a", b"1"), ("b", b"2"), ("c", b"3")];
+        let mut forward = [0; DIGEST_LEN];
+        for (id, hash) in items {
+            xor(&mut forward, &item_digest(id, hash));
+        }
+        let mut backward = [0; DIGEST_LEN];
+        for (id, hash) in items.iter().rev() {
+            xor(&mut backward, &item_digest(id, *hash));
+        }
+        assert_eq!(forward, backward);
+
+        // Removing an item XORs it out again.
+        xor(&mut forward, &item_digest("b", b"2"));
+        let mut without = [0; DIGEST_LEN];
+        xor(&mut without, &item_digest("a", b"1"));
+        xor(&mut without, &item_digest("c", b"3"));
+        assert_eq!(forward, without);
+    }
+
+    #[test]
+    fn test_bucket_value_roundtrip() {
+        let digest = item_digest("doc", b"hash");
+        assert_eq!(decode(&encode(7, &digest)).unwrap(), (7, digest));
+        assert!(decode(b"short").is_err());
+    }
+}
@@ -0,0 +1,160 @@
+//! In-process LRU cache for hot documents and blobs, bounded by bytes.
+//!
+//! One cache serves every namespace of a database, so keys carry the
+//! namespace.  Writers invalidate after committing; a generation counter
+//! stops a reader that loaded a value before the commit from caching it
+//! after the invalidation.
+
+use super::Document;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Mutex, MutexGuard};
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(super) enum CacheKey {
+    Document { namespace: String, id: String },
+    Blob { namespace: String, hash: Vec<u8> },
+}
+
+#[derive(Debug, Clone)]
+pub(super) enum Cached {
+    Document(Document),
+    Blob(Vec<u8>),
+}
+
+impl Cached {
+    fn size(&self) -> usize {
+        match self {
+            Cached::Document(doc) => doc.meta.len() + doc.crdt_state.len(),
+            Cached::Blob(data) => data.len(),
+        }
+    }
+}
+
+pub(super) struct ReadCache {
+    inner: Mutex<Lru>,
+}
+
+struct Lru {
+    capacity: usize,
+    used: usize,
+    /// Bumped by every invalidation.
+    generation: u64,
+    /// Monotonic use counter; the smallest tick is least recently used.
+    tick: u64,
+    entries: HashMap<CacheKey, (Cached, u64)>,
+    order: BTreeMap<u64, CacheKey>,
+}
+
+impl ReadCache {
+    pub(super) fn new(capacity: usize) -> Self {
+        Self {
+            inner: Mutex::new(Lru {
+                capacity,
+                used: 0,
+                generation: 0,
+                tick: 0,
+                entries: HashMap::new(),
+                order: BTreeMap::new(),
+            }),
+        }
+    }
+
+    /// Take before reading from redb and pass to `insert` afterwards.
+    pub(super) fn generation(&self) -> u64 {
+        self.lock().generation
+    }
+
+    pub(super) fn get(&self, key: &CacheKey) -> Option<Cached> {
+        let mut guard = self.lock();
+        let lru = &mut *guard;
+        lru.tick += 1;
+        let (value, last_used) = lru.entries.get_mut(key)?;
+        let previous = std::mem::replace(last_used, lru.tick);
+        lru.order.remove(&previous);
+        lru.order.insert(lru.tick, key.clone());
+        Some(value.clone())
+    }
+
+    /// Cache `value` unless something was invalidated since `generation`
+    /// was taken.  Values larger than the whole cache are not kept.
+    pub(super) fn insert(&self, key: CacheKey, value: Cached, generation: u64) {
+        let mut lru = self.lock();
+        let size = value.size();
+        if lru.generation != generation || size > lru.capacity {
+            return;
+        }
+        lru.remove(&key);
+        while lru.used + size > lru.capacity {
+            let Some((_, oldest)) = lru.order.pop_first() else { break };
+            lru.remove(&oldest);
+        }
+        lru.tick += 1;
+        let tick = lru.tick;
+        lru.used += size;
+        lru.order.insert(tick, key.clone());
+        lru.entries.insert(key, (value, tick));
+    }
+
+    pub(super) fn invalidate<'a>(&self, keys: impl IntoIterator<Item = &'a CacheKey>) {
+        let mut lru = self.lock();
+        lru.generation += 1;
+        for key in keys {
+            lru.remove(key);
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Lru> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Lru {
+    fn remove(&mut self, key: &CacheKey) {
+        if let Some((value, tick)) = self.entries.remove(key) {
+            self.used -= value.size();
+            self.order.remove(&tick);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn blob(n: u8) -> CacheKey {
+        CacheKey::Blob {
+            namespace: String::new(),
+            hash: vec![n],
+        }
+    }
+
+    #[test]
+    fn test_evicts_least_recently_used() {
+        let cache = ReadCache::new(10);
+        let generation = cache.generation();
+        cache.insert(blob(1), Cached::Blob(vec![0; 4]), generation);
+        cache.insert(blob(2), Cached::Blob(vec![0; 4]), generation);
+        assert!(cache.get(&blob(1)).is_some());
+
+        cache.insert(blob(3), Cached::Blob(vec![0; 4]), generation);
+        assert!(cache.get(&blob(1)).is_some());
+        assert!(cache.get(&blob(2)).is_none());
+        assert!(cache.get(&blob(3)).is_some());
+    }
+
+    #[test]
+    fn test_stale_insert_after_invalidation_is_dropped() {
+        let cache = ReadCache::new(10);
+        let generation = cache.generation();
+        cache.invalidate([&blob(1)]);
+        cache.insert(blob(1), Cached::Blob(vec![1]), generation);
+        assert!(cache.get(&blob(1)).is_none());
+    }
+
+    #[test]
+    fn test_oversized_values_are_not_cached() {
+        let cache = ReadCache::new(3);
+        cache.insert(blob(1), Cached::Blob(vec![0; 4]), cache.generation());
+        assert!(cache.get(&blob(1)).is_none());
+    }
+}
@@ -0,0 +1,260 @@
+//! Just enough of a CBOR (RFC 8949) reader to pull the top-level fields out
+//! of a document's metadata for `filter`.
+//!
+//! Metadata that is meant to be filtered on is a CBOR map with text keys.
+//! Scalar values come back as `MetaValue`s; arrays, nested maps,
+//! `undefined`, other simple values and integers outside i64 come back as
+//! `None` and never match a predicate.  Tags are skipped in favour of the
+//! value they wrap.
+
+use super::filter::MetaValue;
+use anyhow::{bail, Context, Result};
+
+/// Nesting allowed inside a field's value before the reader gives up.
+const MAX_DEPTH: usize = 32;
+
+/// Additional-info value marking an indefinite-length item.
+const INDEFINITE: u8 = 31;
+
+/// The top-level fields of `meta`, in encoded order.  Errors if `meta` is
+/// not a CBOR map with text keys.
+pub(super) fn top_level_fields(meta: &[u8]) -> Result<Vec<(String, Option<MetaValue>)>> {
+    let mut reader = Reader { buf: meta, pos: 0 };
+    let (major, info) = reader.head()?;
+    if major != 5 {
+        bail!("metadata is not a CBOR map");
+    }
+    let len = reader.length(info)?;
+    let mut fields = Vec::new();
+    loop {
+        match len {
+            Some(n) if fields.len() as u64 == n => break,
+            None if reader.at_break()? => break,
+            _ => {}
+        }
+        let key = match reader.item(0)? {
+            Item::Text(key) => key,
+            _ => bail!("metadata map has a non-text key"),
+        };
+        let value = match reader.item(0)? {
+            Item::Value(value) => Some(value),
+            Item::Text(text) => Some(MetaValue::Text(text)),
+            Item::Other => None,
+        };
+        fields.push((key, value));
+    }
+    Ok(fields)
+}
+
+enum Item {
+    Value(MetaValue),
+    Text(String),
+    /// Anything `MetaValue` has no room for.
+    Other,
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn take(&mut self, n: usize) -> Result<&[u8]> {
+        let end = self.pos.checked_add(n).filter(|&end| end <= self.buf.len());
+        let end = end.context("truncated CBOR")?;
+        let bytes = &self.buf[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    /// Major type and additional info of the next item.
+    fn head(&mut self) -> Result<(u8, u8)> {
+        let byte = self.take(1)?[0];
+        Ok((byte >> 5, byte & 0x1f))
+    }
+
+    /// The argument following a head: a length, count or integer.
+    fn argument(&mut self, info: u8) -> Result<u64> {
+        Ok(match info {
+            0..=23 => info as u64,
+            24 => self.take(1)?[0] as u64,
+            25 => u16::from_be_bytes(self.take(2)?.try_into()?) as u64,
+            26 => u32::from_be_bytes(self.take(4)?.try_into()?) as u64,
+            27 => u64::from_be_bytes(self.take(8)?.try_into()?),
+            _ => bail!("malformed CBOR argument {info}"),
+        })
+    }
+
+    /// A length, or `None` for an indefinite-length item.
+    fn length(&mut self, info: u8) -> Result<Option<u64>> {
+        if info == INDEFINITE {
+            return Ok(None);
+        }
+        self.argument(info).map(Some)
+    }
+
+    /// Consume the "break" ending an indefinite-length item, if it is next.
+    fn at_break(&mut self) -> Result<bool> {
+        if *self.buf.get(self.pos).context("truncated CBOR")? == 0xff {
+            self.pos += 1;
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    /// A byte or text string's contents, joining indefinite-length chunks.
+    fn string(&mut self, major: u8, info: u8) -> Result<Vec<u8>> {
+        let Some(len) = self.length(info)? else {
+            let mut out = Vec::new();
+            while !self.at_break()? {
+                let (chunk_major, chunk_info) = self.head()?;
+                if chunk_major != major || chunk_info == INDEFINITE {
+                    bail!("malformed CBOR string chunk");
+                }
+                out.extend(self.string(major, chunk_info)?);
+            }
+            return Ok(out);
+        };
+        Ok(self.take(usize::try_from(len)?)?.to_vec())
+    }
+
+    fn item(&mut self, depth: usize) -> Result<Item> {
+        if depth > MAX_DEPTH {
+            bail!("CBOR nested too deeply");
+        }
+        let (major, info) = self.head()?;
+        Ok(match major {
+            0 => match i64::try_from(self.argument(info)?) {
+                Ok(n) => Item::Value(MetaValue::Int(n)),
+                Err(_) => Item::Other,
+            },
+            1 => match i64::try_from(self.argument(info)?) {
+                Ok(n) => Item::Value(MetaValue::Int(-1 - n)),
+                Err(_) => Item::Other,
+            },
+            2 => Item::Value(MetaValue::Bytes(self.string(major, info)?)),
+            3 => Item::Text(String::from_utf8(self.string(major, info)?)?),
+            4 | 5 => {
+                let per_entry = if major == 5 { 2 } else { 1 };
+                match self.length(info)? {
+                    Some(len) => {
+                        for _ in 0..len.saturating_mul(per_entry) {
+                            self.item(depth + 1)?;
+                        }
+                    }
+                    None => {
+                        while !self.at_break()? {
+                            for _ in 0..per_entry {
+                                self.item(depth + 1)?;
+                            }
+                        }
+                    }
+                }
+                Item::Other
+            }
+            6 => {
+                self.argument(info)?;
+                self.item(depth + 1)?
+            }
+            _ => self.simple(info)?,
+        })
+    }
+
+    /// Major type 7: simple values and floats.
+    fn simple(&mut self, info: u8) -> Result<Item> {
+        Ok(match info {
+            20 => Item::Value(MetaValue::Bool(false)),
+            21 => Item::Value(MetaValue::Bool(true)),
+            22 => Item::Value(MetaValue::Null),
+            25 => {
+                let bits = u16::from_be_bytes(self.take(2)?.try_into()?);
+                Item::Value(MetaValue::Float(half_to_f64(bits)))
+            }
+            26 => {
+                let bits = u32::from_be_bytes(self.take(4)?.try_into()?);
+                Item::Value(MetaValue::Float(f32::from_bits(bits) as f64))
+            }
+            27 => {
+                let bits = u64::from_be_bytes(self.take(8)?.try_into()?);
+                Item::Value(MetaValue::Float(f64::from_bits(bits)))
+            }
+            24 => {
+                self.take(1)?;
+                Item::Other
+            }
+            0..=23 => Item::Other,
+            _ => bail!("malformed CBOR simple value {info}"),
+        })
+    }
+}
+
+/// IEEE 754 half precision, as RFC 8949 appendix D decodes it.
+fn half_to_f64(bits: u16) -> f64 {
+    let exponent = (bits >> 10) & 0x1f;
+    let mantissa = (bits & 0x3ff) as f64;
+    let magnitude = match exponent {
+        0 => mantissa * 2f64.powi(-24),
+        31 if mantissa == 0.0 => f64::INFINITY,
+        31 => f64::NAN,
+        _ => (mantissa + 1024.0) * 2f64.powi(exponent as i32 - 25),
+    };
+    if bits & 0x8000 != 0 {
+        -magnitude
+    } else {
+        magnitude
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scalar_fields() {
+        // {"n": 10, "neg": -500, "s": "hi", "b": h'0102', "t": true, "z": null, "f": 1.5}
+        let meta = [
+            0xa7, 0x61, b'n', 0x0a, 0x63, b'n', b'e', b'g', 0x39, 0x01, 0xf3, 0x61, b's', 0x62,
+            b'h', b'i', 0x61, b'b', 0x42, 0x01, 0x02, 0x61, b't', 0xf5, 0x61, b'z', 0xf6, 0x61,
+            b'f', 0xf9, 0x3e, 0x00,
+        ];
+        let fields = top_level_fields(&meta).unwrap();
+        let values: Vec<_> = fields.into_iter().map(|(k, v)| (k, v.unwrap())).collect();
+        assert_eq!(
+            values,
+            vec![
+                ("n".to_string(), MetaValue::Int(10)),
+                ("neg".to_string(), MetaValue::Int(-500)),
+                ("s".to_string(), MetaValue::Text("hi".to_string())),
+                ("b".to_string(), MetaValue::Bytes(vec![1, 2])),
+                ("t".to_string(), MetaValue::Bool(true)),
+                ("z".to_string(), MetaValue::Null),
+                ("f".to_string(), MetaValue::Float(1.5)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_nested_and_indefinite() {
+        // {_ "a": [1, {"x": 2}], "c": (_ "ab", "c"), "d": 1(1700000000)}
+        let meta = [
+            0xbf, 0x61, b'a', 0x82, 0x01, 0xa1, 0x61, b'x', 0x02, 0x61, b'c', 0x7f, 0x62, b'a',
+            b'b', 0x61, b'c', 0xff, 0x61, b'd', 0xc1, 0x1a, 0x65, 0x53, 0xf1, 0x00, 0xff,
+        ];
+        let fields = top_level_fields(&meta).unwrap();
+        assert_eq!(fields.len(), 3);
+        assert_eq!(fields[0], ("a".to_string(), None));
+        assert_eq!(fields[1].1, Some(MetaValue::Text("abc".to_string())));
+        assert_eq!(fields[2].1, Some(MetaValue::Int(1_700_000_000)));
+    }
+
+    #[test]
+    fn test_rejects_non_maps() {
+        assert!(top_level_fields(b"").is_err());
+        assert!(top_level_fields(b"{\"json\":1}").is_err());
+        assert!(top_level_fields(&[0x82, 0x01, 0x02]).is_err());
+        // Truncated: a map of one entry with no entry.
+        assert!(top_level_fields(&[0xa1]).is_err());
+        // Integer key.
+        assert!(top_level_fields(&[0xa1, 0x01, 0x02]).is_err());
+    }
+}
@@ -0,0 +1,99 @@
+//! Append-only log of document mutations.
+//!
+//! Every put and deletion — local, applied from a peer or imported — adds
+//! a changelog row in the same write transaction, under the next sequence
+//! number of its namespace (starting at 1).  Consumers such as indexers
+//! page through it with `changelog` and remember the last seq they saw.
+//!
+//! Values are `[1-byte kind][8-byte LE unix seconds][32-byte hash][id]`,
+//! the hash being the new state hash or, for a deletion, the deletion hash.
+
+use super::{unix_now, Store, Tables};
+use anyhow::{bail, Result};
+use redb::{ReadableTable, WriteTransaction};
+
+const HASH_LEN: usize = 32;
+const PREFIX_LEN: usize = 1 + 8 + HASH_LEN;
+
+const PUT: u8 = 0;
+const DELETE: u8 = 1;
+
+#[derive(Debug, Clone)]
+pub struct LogEntry {
+    pub seq: u64,
+    pub id: String,
+    pub hash: Vec<u8>,
+    pub deleted: bool,
+    /// Unix seconds at which the mutation was committed.
+    pub at: u64,
+}
+
+/// Log a put (or, with `deleted`, a deletion) of `id` in `txn`.
+pub(super) fn append(
+    txn: &WriteTransaction,
+    tables: &Tables,
+    id: &str,
+    hash: &[u8],
+    deleted: bool,
+) -> Result<()> {
+    if hash.len() != HASH_LEN {
+        bail!("malformed changelog hash for {id:?}");
+    }
+    let mut log = txn.open_table(tables.changelog())?;
+    let seq = log.last()?.map_or(0, |(seq, _)| seq.value()) + 1;
+    let mut value = Vec::with_capacity(PREFIX_LEN + id.len());
+    value.push(if deleted { DELETE } else { PUT });
+    value.extend_from_slice(&unix_now().to_le_bytes());
+    value.extend_from_slice(hash);
+    value.extend_from_slice(id.as_bytes());
+    log.insert(seq, value.as_slice())?;
+    Ok(())
+}
+
+pub(super) fn parse(seq: u64, value: &[u8]) -> Result<LogEntry> {
+    if value.len() < PREFIX_LEN || value[0] > DELETE {
+        bail!("malformed changelog entry {seq}");
+    }
+    Ok(LogEntry {
+        seq,
+        id: String::from_utf8(value[PREFIX_LEN..].to_vec())?,
+        hash: value[9..PREFIX_LEN].to_vec(),
+        deleted: value[0] == DELETE,
+        at: u64::from_le_bytes(value[1..9].try_into().unwrap()),
+    })
+}
+
+impl Store {
+    /// Up to `limit` changelog entries from `from_seq` on, oldest first.
+    pub fn changelog(&self, from_seq: u64, limit: usize) -> Result<Vec<LogEntry>> {
+        let txn = self.db.begin_read()?;
+        let log = txn.open_table(self.tables.changelog())?;
+        let mut entries = Vec::new();
+        for entry in log.range(from_seq..)?.take(limit) {
+            let (seq, value) = entry?;
+            entries.push(parse(seq.value(), value.value())?);
+        }
+        Ok(entries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let mut value = vec![DELETE];
+        value.extend_from_slice(&7u64.to_le_bytes());
+        value.extend_from_slice(&[9; HASH_LEN]);
+        value.extend_from_slice(b"doc/1");
+        let entry = parse(3, &value).unwrap();
+        assert_eq!((entry.seq, entry.id.as_str(), entry.at), (3, "doc/1", 7));
+        assert!(entry.deleted);
+        assert_eq!(entry.hash, [9; HASH_LEN]);
+
+        value[0] = 2;
+        assert!(parse(3, &value).is_err());
+        assert!(parse(3, &value[..PREFIX_LEN - 1]).is_err());
+    }
+}
@@ -0,0 +1,85 @@
+//! Feed of committed document changes, for the port's watches.
+//!
+//! Recording is off until `track_changes` turns it on, so a database nobody
+//! watches keeps nothing.  Writers note the documents they touched as they
+//! invalidate the read cache after committing; `take_changes` drains them,
+//! each with the document's hash as of the drain.  A document written twice
+//! between drains is reported once.
+
+use super::cache::CacheKey;
+use super::Store;
+use anyhow::Result;
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+#[derive(Debug, Default)]
+pub(super) struct ChangeFeed {
+    enabled: AtomicBool,
+    /// (namespace, document id), oldest first.
+    pending: Mutex<Vec<(String, String)>>,
+}
+
+/// A document written or deleted since the previous `take_changes`.
+#[derive(Debug, Clone)]
+pub struct DocumentChange {
+    pub namespace: String,
+    pub id: String,
+    /// State hash, or deletion hash if `deleted`.
+    pub hash: Vec<u8>,
+    pub deleted: bool,
+}
+
+impl ChangeFeed {
+    pub(super) fn record<'a>(&self, keys: impl IntoIterator<Item = &'a CacheKey>) {
+        if !self.enabled.load(Ordering::Relaxed) {
+            return;
+        }
+        let mut pending = self.pending.lock().unwrap_or_else(|e| e.into_inner());
+        for key in keys {
+            if let CacheKey::Document { namespace, id } = key {
+                pending.push((namespace.clone(), id.clone()));
+            }
+        }
+    }
+}
+
+impl Store {
+    /// Start or stop recording the database's document changes.
+    pub fn track_changes(&self, enabled: bool) {
+        self.changes.enabled.store(enabled, Ordering::Relaxed);
+        if !enabled {
+            self.changes.pending.lock().unwrap_or_else(|e| e.into_inner()).clear();
+        }
+    }
+
+    /// Documents of any namespace changed since the last call, in the order
+    /// they were first changed.
+    pub fn take_changes(&self) -> Result<Vec<DocumentChange>> {
+        let pending = std::mem::take(
+            &mut *self.changes.pending.lock().unwrap_or_else(|e| e.into_inner()),
+        );
+        let mut seen = HashSet::new();
+        let mut changes = Vec::new();
+        for (namespace, id) in pending {
+            if !seen.insert((namespace.clone(), id.clone())) {
+                continue;
+            }
+            let handle = self.namespace(&namespace)?;
+            let txn = self.db.begin_read()?;
+            let live = txn.open_table(handle.tables.documents())?.get(id.as_str())?.is_some();
+            let hash = txn
+                .open_table(handle.tables.doc_hashes())?
+                .get(id.as_str())?
+                .map(|h| h.value().to_vec())
+                .unwrap_or_default();
+            changes.push(DocumentChange {
+                namespace,
+                id,
+                hash,
+                deleted: !live,
+            });
+        }
+        Ok(changes)
+    }
+}
@@ -0,0 +1,196 @@
+//! Per-peer sync checkpoints, so a peer needn't remember what it was sent.
+//!
+//! `GetChangesSince { peer_id }` sends the documents the changelog shows
+//! changed after the seq in the peer's checkpoint, or every document if it
+//! has none.  The transaction that reads them also reads the changelog
+//! head and the combined root, and leaves them in sync_offers for the peer.
+//! The checkpoint only moves there once the peer has applied every part
+//! and acknowledges the head with `AckChangesSince`: a peer that lost the
+//! stream, or restarted before applying it, is sent the same changes
+//! again.  A newer stream replaces the offer, so acknowledging an older
+//! head moves nothing.  A peer that lost its data can be sent everything
+//! again after `ResetSyncCheckpoint`.
+//!
+//! Documents written before the changelog existed never appear in it, so
+//! a database migrated from then should give each peer one full sync
+//! (without a checkpoint) before relying on them.
+
+use super::{changelog, unix_now, Store};
+use anyhow::Result;
+use redb::ReadableTable;
+use std::collections::BTreeSet;
+
+/// What a peer has acknowledged.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Checkpoint {
+    /// Changelog seq the peer has every change up to.
+    pub seq: u64,
+    /// Combined root as of that seq.
+    pub root: Vec<u8>,
+    /// Unix seconds when the peer acknowledged it.
+    pub at: u64,
+}
+
+/// What `GetChangesSince` sends a peer.
+#[derive(Debug)]
+pub struct ChangesSince {
+    /// (id, doc hash) of each document changed, deletions included, in id
+    /// order.
+    pub pairs: Vec<(String, Vec<u8>)>,
+    /// Changelog seq they bring the peer up to, for it to acknowledge.
+    pub head: u64,
+}
+
+impl Store {
+    pub fn sync_checkpoint(&self, peer_id: &str) -> Result<Option<Checkpoint>> {
+        let txn = self.db.begin_read()?;
+        let table = txn.open_table(self.tables.sync_checkpoints())?;
+        Ok(table.get(peer_id)?.map(|v| {
+            let (seq, root, at) = v.value();
+            Checkpoint {
+                seq,
+                root: root.to_vec(),
+                at,
+            }
+        }))
+    }
+
+    /// The documents changed since `peer_id`'s checkpoint, offering the
+    /// peer the changelog head they bring it up to.
+    pub fn changed_since_checkpoint(&self, peer_id: &str) -> Result<ChangesSince> {
+        let txn = self.begin_write()?;
+        let checkpoints = txn.open_table(self.tables.sync_checkpoints())?;
+        let since = checkpoints.get(peer_id)?.map(|v| v.value().0);
+        let log = txn.open_table(self.tables.changelog())?;
+        let head = log.last()?.map_or(0, |(seq, _)| seq.value());
+        let hashes = txn.open_table(self.tables.doc_hashes())?;
+
+        let mut pairs = Vec::new();
+        match since {
+            None => {
+                for entry in hashes.iter()? {
+                    let (k, v) = entry?;
+                    pairs.push((k.value().to_string(), v.value().to_vec()));
+                }
+            }
+            Some(since) => {
+                let mut ids = BTreeSet::new();
+                for entry in log.range(since + 1..)? {
+                    let (seq, value) = entry?;
+                    ids.insert(changelog::parse(seq.value(), value.value())?.id);
+                }
+                for id in ids {
+                    if let Some(v) = hashes.get(id.as_str())? {
+                        pairs.push((id, v.value().to_vec()));
+                    }
+                }
+            }
+        }
+        drop((checkpoints, log, hashes));
+        let root = self.combined_root_in(&txn)?;
+        txn.open_table(self.tables.sync_offers())?
+            .insert(peer_id, (head, root.as_slice()))?;
+        txn.commit()?;
+        Ok(ChangesSince { pairs, head })
+    }
+
+    /// Record that `peer_id` has every change up to `head`, which must be
+    /// the head its last `GetChangesSince` offered; false, changing
+    /// nothing, if it isn't.
+    pub fn ack_sync_checkpoint(&self, peer_id: &str, head: u64) -> Result<bool> {
+        let txn = self.begin_write()?;
+        {
+            let mut offers = txn.open_table(self.tables.sync_offers())?;
+            let root = match offers.get(peer_id)? {
+                Some(v) if v.value().0 == head => v.value().1.to_vec(),
+                _ => return Ok(false),
+            };
+            offers.remove(peer_id)?;
+            txn.open_table(self.tables.sync_checkpoints())?
+                .insert(peer_id, (head, root.as_slice(), unix_now()))?;
+        }
+        txn.commit()?;
+        Ok(true)
+    }
+
+    /// Forget `peer_id`'s checkpoint, and any head offered to it; false if
+    /// it had neither.
+    pub fn reset_sync_checkpoint(&self, peer_id: &str) -> Result<bool> {
+        let txn = self.begin_write()?;
+        let removed = txn.open_table(self.tables.sync_checkpoints())?.remove(peer_id)?.is_some();
+        let offered = txn.open_table(self.tables.sync_offers())?.remove(peer_id)?.is_some();
+        txn.commit()?;
+        Ok(removed || offered)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::StoreOptions;
+
+    fn ids(since: &ChangesSince) -> Vec<&str> {
+        since.pairs.iter().map(|(id, _)| id.as_str()).collect()
+    }
+
+    #[test]
+    fn test_unacknowledged_stream_is_sent_again() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Store::open(dir.path(), StoreOptions::default()).unwrap();
+        store.put_document("a", b"meta", b"a", None, false).unwrap();
+        let first = store.changed_since_checkpoint("peer").unwrap();
+        assert_eq!(ids(&first), ["a"]);
+
+        // The peer never acknowledged the stream, e.g. it restarted.
+        store.put_document("b", b"meta", b"b", None, false).unwrap();
+        assert!(store.sync_checkpoint("peer").unwrap().is_none());
+        let again = store.changed_since_checkpoint("peer").unwrap();
+        assert_eq!(ids(&again), ["a", "b"]);
+        // Only the latest offer can be acknowledged.
+        assert!(!store.ack_sync_checkpoint("peer", first.head).unwrap());
+        assert!(!store.ack_sync_checkpoint("other", again.head).unwrap());
+        assert!(store.ack_sync_checkpoint("peer", again.head).unwrap());
+        assert!(!store.ack_sync_checkpoint("peer", again.head).unwrap());
+        assert!(store.changed_since_checkpoint("peer").unwrap().pairs.is_empty());
+    }
+
+    #[test]
+    fn test_write_during_stream() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Store::open(dir.path(), StoreOptions::default()).unwrap();
+        store.put_document("a", b"meta", b"a", None, false).unwrap();
+        let sent = store.changed_since_checkpoint("peer").unwrap();
+        let root = store.combined_root().unwrap();
+
+        // Committed while the stream is under way, so not in it.
+        store.put_document("b", b"meta", b"b", None, false).unwrap();
+        store.delete_document("a", false).unwrap();
+        assert!(store.ack_sync_checkpoint("peer", sent.head).unwrap());
+        let checkpoint = store.sync_checkpoint("peer").unwrap().unwrap();
+        assert_eq!((checkpoint.seq, checkpoint.root), (sent.head, root));
+
+        let next = store.changed_since_checkpoint("peer").unwrap();
+        assert_eq!(ids(&next), ["a", "b"]);
+        let tombstone = store.get_tombstone("a").unwrap().unwrap();
+        assert_eq!(next.pairs[0].1, tombstone.hash);
+        assert!(next.head > sent.head);
+    }
+
+    #[test]
+    fn test_reset() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Store::open(dir.path(), StoreOptions::default()).unwrap();
+        store.put_document("a", b"meta", b"a", None, false).unwrap();
+        assert!(!store.reset_sync_checkpoint("peer").unwrap());
+        let sent = store.changed_since_checkpoint("peer").unwrap();
+        assert!(store.ack_sync_checkpoint("peer", sent.head).unwrap());
+        assert!(store.changed_since_checkpoint("peer").unwrap().pairs.is_empty());
+
+        // The reset drops the checkpoint and the offer just made, so a
+        // late acknowledgement can't skip what the peer lost.
+        assert!(store.reset_sync_checkpoint("peer").unwrap());
+        assert!(store.sync_checkpoint("peer").unwrap().is_none());
+        assert!(!store.ack_sync_checkpoint("peer", sent.head).unwrap());
+        assert_eq!(ids(&store.changed_since_checkpoint("peer").unwrap()), ["a"]);
+    }
+}
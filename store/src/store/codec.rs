@@ -0,0 +1,172 @@
+//! On-disk encoding of blob values.
+//!
+//! Stored values carry a small header so the codec can change per blob:
+//!   [4-byte magic][1-byte codec][8-byte LE original length][body]
+//!
+//! `External` values have an empty body: the bytes live in a spill file
+//! (see `spill`).  `Sealed` values hold an encrypted codec value and
+//! `SealedExternal` ones an encrypted spill file (see `encryption`); both
+//! keep the original length readable without the key.  Values written
+//! before the header existed have no magic and are returned as-is.
+
+use anyhow::{bail, Context, Result};
+
+const MAGIC: [u8; 4] = *b"\xB1KB\x01";
+const HEADER_LEN: usize = 4 + 1 + 8;
+
+/// Values shorter than this are never worth compressing.
+const MIN_COMPRESS_LEN: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Codec {
+    Raw = 0,
+    Zstd = 1,
+    External = 2,
+    Sealed = 3,
+    SealedExternal = 4,
+}
+
+impl Codec {
+    fn from_byte(b: u8) -> Result<Self> {
+        match b {
+            0 => Ok(Codec::Raw),
+            1 => Ok(Codec::Zstd),
+            2 => Ok(Codec::External),
+            3 => Ok(Codec::Sealed),
+            4 => Ok(Codec::SealedExternal),
+            other => bail!("unknown blob codec {other}"),
+        }
+    }
+}
+
+/// Encode `data` for storage, compressing with zstd at `level` when that
+/// actually saves space.  `None` disables compression.
+pub fn encode(data: &[u8], level: Option<i32>) -> Result<Vec<u8>> {
+    if let Some(level) = level.filter(|_| data.len() >= MIN_COMPRESS_LEN) {
+        let compressed = zstd::bulk::compress(data, level).context("compressing blob")?;
+        if compressed.len() + HEADER_LEN < data.len() {
+            return Ok(with_header(Codec::Zstd, data.len(), &compressed));
+        }
+    }
+    Ok(with_header(Codec::Raw, data.len(), data))
+}
+
+/// Header-only value marking a blob stored in a spill file.
+pub fn external(original_len: usize) -> Vec<u8> {
+    with_header(Codec::External, original_len, &[])
+}
+
+/// Value wrapping `sealed`, the encryption of a codec value holding
+/// `original_len` bytes.
+pub fn sealed(original_len: usize, sealed: &[u8]) -> Vec<u8> {
+    with_header(Codec::Sealed, original_len, sealed)
+}
+
+/// Header-only value marking a blob stored encrypted in a spill file.
+pub fn sealed_external(original_len: usize) -> Vec<u8> {
+    with_header(Codec::SealedExternal, original_len, &[])
+}
+
+/// Whether a stored value refers to a spill file.
+pub fn is_external(stored: &[u8]) -> bool {
+    matches!(
+        split_header(stored),
+        Ok(Some((Codec::External | Codec::SealedExternal, _, _)))
+    )
+}
+
+/// Whether a stored value refers to an encrypted spill file.
+pub fn is_sealed_external(stored: &[u8]) -> bool {
+    matches!(split_header(stored), Ok(Some((Codec::SealedExternal, _, _))))
+}
+
+/// The encrypted body of a `Sealed` value; `None` for any other value.
+pub fn sealed_body(stored: &[u8]) -> Result<Option<&[u8]>> {
+    Ok(match split_header(stored)? {
+        Some((Codec::Sealed, _, body)) => Some(body),
+        _ => None,
+    })
+}
+
+fn with_header(codec: Codec, original_len: usize, body: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(HEADER_LEN + body.len());
+    out.extend_from_slice(&MAGIC);
+    out.push(codec as u8);
+    out.extend_from_slice(&(original_len as u64).to_le_bytes());
+    out.extend_from_slice(body);
+    out
+}
+
+/// Decode a stored value back into the original blob bytes.
+pub fn decode(stored: &[u8]) -> Result<Vec<u8>> {
+    let Some((codec, original_len, body)) = split_header(stored)? else {
+        return Ok(stored.to_vec());
+    };
+    match codec {
+        Codec::Raw => Ok(body.to_vec()),
+        Codec::Zstd => zstd::bulk::decompress(body, original_len as usize)
+            .context("decompressing blob"),
+        Codec::External | Codec::SealedExternal => bail!("blob is stored in a spill file"),
+        Codec::Sealed => bail!("value is encrypted"),
+    }
+}
+
+/// Original (decoded) length of a stored value, without decoding it.
+pub fn original_len(stored: &[u8]) -> Result<u64> {
+    Ok(match split_header(stored)? {
+        Some((_, len, _)) => len,
+        None => stored.len() as u64,
+    })
+}
+
+fn split_header(stored: &[u8]) -> Result<Option<(Codec, u64, &[u8])>> {
+    if stored.len() < HEADER_LEN || stored[..4] != MAGIC {
+        return Ok(None);
+    }
+    let codec = Codec::from_byte(stored[4])?;
+    let len = u64::from_le_bytes(stored[5..HEADER_LEN].try_into().unwrap());
+    Ok(Some((codec, len, &stored[HEADER_LEN..])))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compressible_roundtrip() {
+        let data = b"{\"title\":\"hello\"}".repeat(100);
+        let stored = encode(&data, Some(3)).unwrap();
+        assert!(stored.len() < data.len());
+        assert_eq!(stored[4], Codec::Zstd as u8);
+        assert_eq!(original_len(&stored).unwrap(), data.len() as u64);
+        assert_eq!(decode(&stored).unwrap(), data);
+    }
+
+    #[test]
+    fn test_small_values_stay_raw() {
+        let stored = encode(b"tiny", Some(3)).unwrap();
+        assert_eq!(stored[4], Codec::Raw as u8);
+        assert_eq!(decode(&stored).unwrap(), b"tiny");
+    }
+
+    #[test]
+    fn test_sealed_headers() {
+        let stored = sealed(100, b"ciphertext");
+        assert_eq!(original_len(&stored).unwrap(), 100);
+        assert_eq!(sealed_body(&stored).unwrap(), Some(&b"ciphertext"[..]));
+        assert!(!is_external(&stored));
+        assert!(decode(&stored).is_err());
+
+        let stored = sealed_external(100);
+        assert!(is_external(&stored) && is_sealed_external(&stored));
+        assert!(!is_sealed_external(&external(100)));
+        assert_eq!(sealed_body(&stored).unwrap(), None);
+    }
+
+    #[test]
+    fn test_legacy_values_pass_through() {
+        assert_eq!(decode(b"written before headers").unwrap(), b"written before headers");
+        assert_eq!(original_len(b"abc").unwrap(), 3);
+    }
+}
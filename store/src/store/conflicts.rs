@@ -0,0 +1,277 @@
+//! Resolving changes that conflict with the local version of a document.
+//!
+//! A document whose metadata names a CRDT engine the store has is always
+//! merged by it.  For any other document, a conflicting change (see
+//! `ApplyChanges`) is settled by the `ConflictPolicy` of its namespace,
+//! `StoreOptions::namespace_conflict_policies`, or else the store's,
+//! `StoreOptions::conflict_policy`.  The default, `Merge`, hands the
+//! change to `StoreOptions::merger` (see `merge`).
+//!
+//! `KeepBoth` leaves the local version in place and keeps the incoming
+//! state as a sibling in doc_siblings, keyed by (doc id, state hash), for
+//! the application to resolve by putting the document again, which drops
+//! the siblings.  Values are `[8-byte LE unix seconds][codec value]`, the
+//! codec value sealed if the database is encrypted.
+
+use super::{encryption, hashing, unix_now, ApplyBatch, Store, Tables};
+use anyhow::{bail, Context, Result};
+use redb::WriteTransaction;
+use std::fmt;
+use std::str::FromStr;
+
+/// How conflicts on documents without a CRDT engine are settled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ConflictPolicy {
+    /// Merge with `StoreOptions::merger`, or keep both if it can't.
+    #[default]
+    Merge,
+    /// Keep whichever version was written last, by the time it was written
+    /// on its store; ties go to the greater hash, so every store picks the
+    /// same one.
+    LastWriterWins,
+    /// Keep the local version.
+    PreferLocal,
+    /// Take the incoming version as it is.
+    PreferRemote,
+    /// Keep the local version and the incoming one as a sibling.  Between
+    /// an edit and a deletion, the edit is kept.
+    KeepBoth,
+}
+
+impl FromStr for ConflictPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "merge" => Ok(ConflictPolicy::Merge),
+            "lww" => Ok(ConflictPolicy::LastWriterWins),
+            "prefer-local" => Ok(ConflictPolicy::PreferLocal),
+            "prefer-remote" => Ok(ConflictPolicy::PreferRemote),
+            "keep-both" => Ok(ConflictPolicy::KeepBoth),
+            other => bail!(
+                "unknown conflict policy {other:?} \
+                 (merge, lww, prefer-local, prefer-remote, keep-both)"
+            ),
+        }
+    }
+}
+
+/// A namespace's conflict policy, configured as `namespace=policy`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NamespacePolicy {
+    pub namespace: String,
+    pub policy: ConflictPolicy,
+}
+
+impl FromStr for NamespacePolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (namespace, policy) = s.split_once('=').context("expected namespace=policy")?;
+        Ok(Self {
+            namespace: namespace.to_string(),
+            policy: policy.parse()?,
+        })
+    }
+}
+
+/// What to do with a conflicting change.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resolution {
+    /// Apply it as usual, merging states if it conflicts.
+    Merge,
+    /// Replace the local version with it, without merging.
+    TakeRemote,
+    /// Drop it.
+    KeepLocal,
+    /// Drop it, keeping its state as a sibling.
+    KeepBoth,
+}
+
+/// The incoming side of a conflict.
+#[derive(Debug, Clone, Copy)]
+pub struct Incoming<'a> {
+    pub hash: &'a [u8],
+    pub deleted: bool,
+    /// When it was written, in unix seconds, if the peer said.
+    pub updated_at: Option<u64>,
+}
+
+/// A state kept alongside a document by `KeepBoth`.
+#[derive(Debug, Clone)]
+pub struct Sibling {
+    pub hash: Vec<u8>,
+    /// Unix seconds at which the sibling was kept.
+    pub saved_at: u64,
+    pub crdt_state: Vec<u8>,
+}
+
+impl fmt::Display for ConflictPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ConflictPolicy::Merge => "merge",
+            ConflictPolicy::LastWriterWins => "lww",
+            ConflictPolicy::PreferLocal => "prefer-local",
+            ConflictPolicy::PreferRemote => "prefer-remote",
+            ConflictPolicy::KeepBoth => "keep-both",
+        })
+    }
+}
+
+/// Forget every sibling of `id`.
+pub(super) fn clear_siblings(txn: &WriteTransaction, tables: &Tables, id: &str) -> Result<()> {
+    let mut siblings = txn.open_table(tables.doc_siblings())?;
+    siblings.retain_in((id, &[][..])..=(id, &[0xff; 32][..]), |_, _| false)?;
+    Ok(())
+}
+
+fn split_value(value: &[u8]) -> Result<(u64, &[u8])> {
+    if value.len() < 8 {
+        bail!("truncated sibling");
+    }
+    let (saved_at, state) = value.split_at(8);
+    Ok((u64::from_le_bytes(saved_at.try_into().unwrap()), state))
+}
+
+/// `value` with its codec value replaced by `f`'s, if `f` returns one.
+pub(super) fn rewrite_state(
+    value: &[u8],
+    f: impl FnOnce(&[u8]) -> Result<Option<Vec<u8>>>,
+) -> Result<Option<Vec<u8>>> {
+    let (_, state) = split_value(value)?;
+    Ok(f(state)?.map(|state| [&value[..8], &state].concat()))
+}
+
+/// Whether the incoming side wins under last-writer-wins against the local
+/// version `local_hash`, written at `local_at`.
+fn remote_is_later(local_at: u64, local_hash: &[u8], incoming: Incoming) -> bool {
+    (incoming.updated_at.unwrap_or(0), incoming.hash) > (local_at, local_hash)
+}
+
+impl Store {
+    /// The conflict policy of this handle's namespace.
+    pub fn conflict_policy(&self) -> ConflictPolicy {
+        self.options
+            .namespace_conflict_policies
+            .get(self.namespace_name())
+            .copied()
+            .unwrap_or(self.options.conflict_policy)
+    }
+
+    /// The siblings kept for `id`, in hash order.
+    pub fn document_siblings(&self, id: &str) -> Result<Vec<Sibling>> {
+        let txn = self.db.begin_read()?;
+        let table = txn.open_table(self.tables.doc_siblings())?;
+        let mut siblings = Vec::new();
+        for entry in table.range((id, &[][..])..=(id, &[0xff; 32][..]))? {
+            let (key, value) = entry?;
+            let (saved_at, state) = split_value(value.value())?;
+            siblings.push(Sibling {
+                hash: key.value().1.to_vec(),
+                saved_at,
+                crdt_state: encryption::decode(&self.keys(), id.as_bytes(), state)?,
+            });
+        }
+        Ok(siblings)
+    }
+}
+
+impl ApplyBatch<'_> {
+    /// How to settle `incoming` conflicting with the local version of `id`:
+    /// by its CRDT engine if its metadata names one, which must exist, else
+    /// by the namespace's policy.
+    pub fn resolve_conflict(&self, id: &str, incoming: Incoming) -> Result<Resolution> {
+        let doc = self.document(id)?;
+        if let Some(doc) = &doc {
+            if self.store.crdt_engine(id, &doc.meta)?.is_some() {
+                return Ok(Resolution::Merge);
+            }
+        }
+        let local_deleted = doc.is_none();
+        Ok(match self.store.conflict_policy() {
+            ConflictPolicy::Merge => Resolution::Merge,
+            ConflictPolicy::PreferRemote => Resolution::TakeRemote,
+            ConflictPolicy::PreferLocal => Resolution::KeepLocal,
+            ConflictPolicy::KeepBoth if incoming.deleted => Resolution::KeepLocal,
+            ConflictPolicy::KeepBoth if local_deleted => Resolution::TakeRemote,
+            ConflictPolicy::KeepBoth => Resolution::KeepBoth,
+            ConflictPolicy::LastWriterWins => {
+                let local_at = match &doc {
+                    Some(doc) => doc.times.map_or(0, |times| times.updated_at),
+                    None => self.tombstone(id)?.map_or(0, |t| t.deleted_at),
+                };
+                let local_hash = self.doc_hash(id)?.unwrap_or_default();
+                if remote_is_later(local_at, &local_hash, incoming) {
+                    Resolution::TakeRemote
+                } else {
+                    Resolution::KeepLocal
+                }
+            }
+        })
+    }
+
+    /// Keep `crdt_state` as a sibling of `id`.
+    pub fn add_sibling(&mut self, id: &str, crdt_state: &[u8]) -> Result<()> {
+        let store = self.store;
+        let hash = hashing::hash(crdt_state);
+        let encoded = encryption::encode(
+            store.keys().current(),
+            id.as_bytes(),
+            crdt_state,
+            store.options.compression_level,
+        )?;
+        let value = [&unix_now().to_le_bytes()[..], &encoded].concat();
+        self.txn
+            .open_table(store.tables.doc_siblings())?
+            .insert((id, hash.as_bytes().as_slice()), value.as_slice())?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_policies() {
+        for policy in [
+            ConflictPolicy::Merge,
+            ConflictPolicy::LastWriterWins,
+            ConflictPolicy::PreferLocal,
+            ConflictPolicy::PreferRemote,
+            ConflictPolicy::KeepBoth,
+        ] {
+            assert_eq!(policy.to_string().parse::<ConflictPolicy>().unwrap(), policy);
+        }
+        assert!("newest".parse::<ConflictPolicy>().is_err());
+
+        let parsed: NamespacePolicy = "drafts=keep-both".parse().unwrap();
+        assert_eq!(parsed.namespace, "drafts");
+        assert_eq!(parsed.policy, ConflictPolicy::KeepBoth);
+        assert!("keep-both".parse::<NamespacePolicy>().is_err());
+    }
+
+    #[test]
+    fn test_last_writer_wins() {
+        let incoming = |updated_at, hash| Incoming {
+            hash,
+            deleted: false,
+            updated_at,
+        };
+        assert!(remote_is_later(10, b"a", incoming(Some(11), b"a")));
+        assert!(!remote_is_later(10, b"a", incoming(Some(9), b"z")));
+        assert!(!remote_is_later(10, b"a", incoming(None, b"z")));
+        // A tie goes to the greater hash, the same way on both sides.
+        assert!(remote_is_later(10, b"a", incoming(Some(10), b"b")));
+        assert!(!remote_is_later(10, b"b", incoming(Some(10), b"a")));
+    }
+
+    #[test]
+    fn test_rewrite_state() {
+        let value = [&7u64.to_le_bytes()[..], b"state"].concat();
+        let rewritten = rewrite_state(&value, |state| Ok(Some([state, b"!"].concat())));
+        let rewritten = rewritten.unwrap().unwrap();
+        assert_eq!(split_value(&rewritten).unwrap(), (7, &b"state!"[..]));
+        assert!(rewrite_state(b"short", |_| Ok(None)).is_err());
+    }
+}
@@ -0,0 +1,359 @@
+//! Optional encryption at rest of blob and CRDT values.
+//!
+//! Once a database has a key, every blob, spill file, CRDT state and history
+//! version written is sealed with XChaCha20-Poly1305 (see `aead`) under a
+//! random nonce.  The row's key (blob hash or document id) is the associated
+//! data, so a value moved to another row fails to open.  Ids, hashes,
+//! metadata and the secondary index stay in the clear: sync, GC marking and
+//! queries need them.  Sealed bytes are
+//!   [8-byte LE key id][24-byte nonce][ciphertext][16-byte tag]
+//! and, except in spill files, what is sealed is a codec value, so blobs are
+//! compressed before they are encrypted.
+//!
+//! STORE_META records the key's id when a database first gets a key; from
+//! then on it only accepts that key.  Values written before then stay
+//! readable as they are until rewritten.  Keys retired by `rotation` are
+//! kept in STORE_KEYS, each sealed under the current key, until no value
+//! needs them.
+
+use super::{aead, codec, from_hex, Store, KEY_ID, STORE_KEYS, STORE_META};
+use anyhow::{anyhow, bail, Context, Result};
+use redb::ReadableTable;
+use std::borrow::Cow;
+use std::fmt;
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, RwLock};
+use tracing::info;
+use zeroize::{Zeroize, Zeroizing};
+
+const KEY_ID_LEN: usize = 8;
+const PREFIX_LEN: usize = KEY_ID_LEN + aead::NONCE_LEN;
+
+/// A 256-bit encryption key.  Its id, derived from it, identifies the key
+/// in sealed values and STORE_META; `Debug` shows only the id.  The key's
+/// bytes are wiped when it is dropped.
+#[derive(Clone)]
+pub struct Key {
+    bytes: [u8; aead::KEY_LEN],
+    id: u64,
+}
+
+impl Key {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let bytes: [u8; aead::KEY_LEN] = bytes.try_into().map_err(|_| {
+            anyhow!("encryption key must be {} bytes, not {}", aead::KEY_LEN, bytes.len())
+        })?;
+        let derived = blake3::derive_key("ringforge-store 2026 encryption key id", &bytes);
+        Ok(Self {
+            bytes,
+            id: u64::from_le_bytes(derived[..KEY_ID_LEN].try_into().unwrap()),
+        })
+    }
+
+    /// Parse a key written as hex, e.g. in an environment variable.
+    pub fn from_hex(text: &str) -> Result<Self> {
+        // Not `from_hex`'s own error, which would quote the key.
+        let bytes = from_hex(text.trim()).map_err(|_| anyhow!("encryption key is not hex"))?;
+        Self::from_bytes(&Zeroizing::new(bytes))
+    }
+
+    pub(super) fn id(&self) -> u64 {
+        self.id
+    }
+}
+
+impl Drop for Key {
+    fn drop(&mut self) {
+        self.bytes.zeroize();
+    }
+}
+
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Key({:016x})", self.id)
+    }
+}
+
+/// The current key of a database and the retired keys its older values
+/// may still be sealed with.
+#[derive(Debug, Default)]
+pub(super) struct Keys {
+    current: Option<Key>,
+    retired: Vec<Key>,
+}
+
+impl Keys {
+    pub(super) fn new(current: Key, retired: Vec<Key>) -> Self {
+        Self {
+            current: Some(current),
+            retired,
+        }
+    }
+
+    /// Key new values are sealed with.
+    pub(super) fn current(&self) -> Option<&Key> {
+        self.current.as_ref()
+    }
+
+    pub(super) fn retired(&self) -> &[Key] {
+        &self.retired
+    }
+
+    fn get(&self, id: u64) -> Option<&Key> {
+        self.current.iter().chain(&self.retired).find(|key| key.id == id)
+    }
+}
+
+/// The keys of one database, shared by every handle on it.
+#[derive(Debug, Default)]
+pub(super) struct KeySlot {
+    state: RwLock<KeyState>,
+    /// Set while `rotation` re-encrypts values under a new key.
+    pub(super) rotating: AtomicBool,
+}
+
+#[derive(Debug, Default)]
+pub(super) struct KeyState {
+    pub(super) keys: Arc<Keys>,
+    /// Id of the key the database is encrypted with, from STORE_META.
+    pub(super) recorded: Option<u64>,
+}
+
+impl KeySlot {
+    pub(super) fn new(recorded: Option<u64>) -> Self {
+        Self {
+            state: RwLock::new(KeyState {
+                keys: Arc::default(),
+                recorded,
+            }),
+            rotating: AtomicBool::new(false),
+        }
+    }
+
+    pub(super) fn state(&self) -> std::sync::RwLockWriteGuard<'_, KeyState> {
+        self.state.write().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Store {
+    /// Encrypt what is written from now on with `key`.  A database keeps the
+    /// first key it is given; after that only the same key (or, after a
+    /// rotation, the new one) is accepted.
+    pub fn provide_key(&self, key: Key) -> Result<()> {
+        let mut state = self.keys.state();
+        if let Some(current) = state.keys.current() {
+            if current.id == key.id {
+                return Ok(());
+            }
+            bail!("a different encryption key is already in use");
+        }
+        match state.recorded {
+            Some(id) if id != key.id => {
+                bail!("wrong encryption key: the database uses key {id:016x}, not {:016x}", key.id)
+            }
+            Some(_) => {
+                let txn = self.db.begin_read()?;
+                let retired = load_retired(&txn.open_table(STORE_KEYS)?, &key)?;
+                state.keys = Arc::new(Keys::new(key, retired));
+                // The states written under the key can be read now.
+                drop(state);
+                self.index_refs_once()?;
+            }
+            None => {
+                let txn = self.db.begin_write()?;
+                txn.open_table(STORE_META)?.insert(KEY_ID, key.id)?;
+                txn.commit()?;
+                state.recorded = Some(key.id);
+                info!(key_id = format!("{:016x}", key.id), "encryption at rest enabled");
+                state.keys = Arc::new(Keys::new(key, Vec::new()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the database is encrypted and its key hasn't been provided.
+    pub fn needs_key(&self) -> bool {
+        let state = self.keys.state.read().unwrap_or_else(|e| e.into_inner());
+        state.recorded.is_some() && state.keys.current().is_none()
+    }
+
+    pub(super) fn keys(&self) -> Arc<Keys> {
+        self.keys.state.read().unwrap_or_else(|e| e.into_inner()).keys.clone()
+    }
+}
+
+/// Retired keys in STORE_KEYS, unwrapped with the current key.
+pub(super) fn load_retired(
+    table: &impl ReadableTable<u64, &'static [u8]>,
+    current: &Key,
+) -> Result<Vec<Key>> {
+    let mut retired = Vec::new();
+    for entry in table.iter()? {
+        let (id, wrapped) = entry?;
+        let keys = Keys::new(current.clone(), Vec::new());
+        let bytes = open(&keys, &id.value().to_le_bytes(), wrapped.value())
+            .with_context(|| format!("unwrapping retired key {:016x}", id.value()))
+            .map(Zeroizing::new)?;
+        retired.push(Key::from_bytes(&bytes)?);
+    }
+    Ok(retired)
+}
+
+/// STORE_KEYS value of `retired`: the key sealed under `current`.
+pub(super) fn wrap(current: &Key, retired: &Key) -> Result<Vec<u8>> {
+    seal(current, &retired.id.to_le_bytes(), &retired.bytes)
+}
+
+/// Id of the key `sealed` (a value written by `seal`) is sealed with.
+pub(super) fn sealed_key_id(sealed: &[u8]) -> Result<u64> {
+    if sealed.len() < PREFIX_LEN {
+        bail!("truncated encrypted value");
+    }
+    Ok(u64::from_le_bytes(sealed[..KEY_ID_LEN].try_into().unwrap()))
+}
+
+/// Seal `plaintext` for the row `aad` under a fresh random nonce.
+pub(super) fn seal(key: &Key, aad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>> {
+    let nonce = aead::random_nonce()?;
+    let mut out = Vec::with_capacity(PREFIX_LEN + plaintext.len() + aead::TAG_LEN);
+    out.extend_from_slice(&key.id.to_le_bytes());
+    out.extend_from_slice(&nonce);
+    out.extend_from_slice(&aead::seal(&key.bytes, &nonce, aad, plaintext)?);
+    Ok(out)
+}
+
+/// Undo `seal`.
+pub(super) fn open(keys: &Keys, aad: &[u8], sealed: &[u8]) -> Result<Vec<u8>> {
+    let id = sealed_key_id(sealed)?;
+    if keys.current.is_none() {
+        bail!("value is encrypted and no key has been provided");
+    }
+    let Some(key) = keys.get(id) else {
+        bail!("value is encrypted with key {id:016x}, which has not been provided");
+    };
+    let nonce = sealed[KEY_ID_LEN..PREFIX_LEN].try_into().unwrap();
+    aead::open(&key.bytes, nonce, aad, &sealed[PREFIX_LEN..])
+}
+
+/// `sealed` (a value written by `seal`) sealed again under the current key,
+/// or `None` if it already is.
+pub(super) fn reseal(keys: &Keys, aad: &[u8], sealed: &[u8]) -> Result<Option<Vec<u8>>> {
+    let current = keys.current().context("no key has been provided")?;
+    if sealed_key_id(sealed)? == current.id {
+        return Ok(None);
+    }
+    seal(current, aad, &open(keys, aad, sealed)?).map(Some)
+}
+
+/// Like `reseal`, for a value written by `encode`.  Plaintext values are
+/// left alone.
+pub(super) fn reseal_value(
+    keys: &Keys,
+    aad: &[u8],
+    stored: &[u8],
+) -> Result<Option<Vec<u8>>> {
+    let Some(sealed) = codec::sealed_body(stored)? else {
+        return Ok(None);
+    };
+    let original_len = codec::original_len(stored)? as usize;
+    Ok(reseal(keys, aad, sealed)?.map(|sealed| codec::sealed(original_len, &sealed)))
+}
+
+/// Encode `data` as a codec value for the row `aad`, sealed if there is a
+/// key.
+pub(super) fn encode(
+    key: Option<&Key>,
+    aad: &[u8],
+    data: &[u8],
+    level: Option<i32>,
+) -> Result<Vec<u8>> {
+    let value = codec::encode(data, level)?;
+    match key {
+        Some(key) => Ok(codec::sealed(data.len(), &seal(key, aad, &value)?)),
+        None => Ok(value),
+    }
+}
+
+/// Decode a value written by `encode`.
+pub(super) fn decode(keys: &Keys, aad: &[u8], stored: &[u8]) -> Result<Vec<u8>> {
+    match codec::sealed_body(stored)? {
+        Some(sealed) => codec::decode(&open(keys, aad, sealed)?),
+        None => codec::decode(stored),
+    }
+}
+
+/// doc_data value of a CRDT state: the state itself, or with a key a
+/// sealed codec value.
+pub(super) fn seal_state<'a>(
+    key: Option<&Key>,
+    id: &str,
+    state: &'a [u8],
+) -> Result<Cow<'a, [u8]>> {
+    match key {
+        Some(_) => Ok(Cow::Owned(encode(key, id.as_bytes(), state, None)?)),
+        None => Ok(Cow::Borrowed(state)),
+    }
+}
+
+/// CRDT state held by a doc_data value.  A value with a codec header that
+/// doesn't parse is an error, not a raw state.
+pub(super) fn open_state(keys: &Keys, id: &str, stored: &[u8]) -> Result<Vec<u8>> {
+    // Unsealed states are raw bytes, not codec values.
+    let sealed = codec::sealed_body(stored)
+        .with_context(|| format!("state of {id:?} has a malformed header"))?;
+    match sealed {
+        Some(_) => decode(keys, id.as_bytes(), stored),
+        None => Ok(stored.to_vec()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_parsing() {
+        let key = Key::from_hex(&"ab".repeat(32)).unwrap();
+        assert_eq!(key.id, Key::from_bytes(&[0xab; 32]).unwrap().id);
+        assert_ne!(key.id, Key::from_bytes(&[0xac; 32]).unwrap().id);
+        assert!(!format!("{key:?}").contains("abab"));
+        assert!(Key::from_hex("abcd").is_err());
+        assert!(Key::from_hex(&"zz".repeat(32)).is_err());
+    }
+
+    #[test]
+    fn test_values_roundtrip() {
+        let key = Key::from_bytes(&[1; 32]).unwrap();
+        let other = Key::from_bytes(&[2; 32]).unwrap();
+        let keys = Keys::new(key.clone(), Vec::new());
+        let (others, none) = (Keys::new(other.clone(), Vec::new()), Keys::default());
+        let data = b"{\"title\":\"hello\"}".repeat(100);
+
+        let stored = encode(Some(&key), b"row", &data, Some(3)).unwrap();
+        assert_eq!(codec::original_len(&stored).unwrap(), data.len() as u64);
+        assert_eq!(decode(&keys, b"row", &stored).unwrap(), data);
+        assert!(decode(&keys, b"other row", &stored).is_err());
+        assert!(decode(&others, b"row", &stored).is_err());
+        assert!(decode(&none, b"row", &stored).is_err());
+        assert_eq!(decode(&keys, b"row", &codec::encode(&data, None).unwrap()).unwrap(), data);
+
+        let sealed = seal_state(Some(&key), "doc", b"state").unwrap();
+        assert_ne!(sealed.as_ref(), b"state");
+        assert_eq!(open_state(&keys, "doc", &sealed).unwrap(), b"state");
+        assert_eq!(open_state(&keys, "doc", b"plain state").unwrap(), b"plain state");
+        let mut bad_header = sealed.to_vec();
+        bad_header[4] = 0xee;
+        assert!(open_state(&keys, "doc", &bad_header).is_err());
+        assert_eq!(seal_state(None, "doc", b"state").unwrap().as_ref(), b"state");
+
+        // After a rotation values sealed with the retired key still open.
+        let rotated = Keys::new(other.clone(), vec![key.clone()]);
+        assert_eq!(decode(&rotated, b"row", &stored).unwrap(), data);
+        let fresh = encode(rotated.current(), b"row", &data, None).unwrap();
+        assert!(decode(&keys, b"row", &fresh).is_err());
+        assert_eq!(decode(&rotated, b"row", &fresh).unwrap(), data);
+        let resealed = reseal_value(&rotated, b"row", &stored).unwrap().unwrap();
+        assert_eq!(decode(&others, b"row", &resealed).unwrap(), data);
+        assert!(reseal_value(&rotated, b"row", &resealed).unwrap().is_none());
+    }
+}
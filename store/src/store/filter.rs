@@ -0,0 +1,162 @@
+//! Server-side filtering of documents by their metadata.
+//!
+//! A filter is a list of predicates over the top-level fields of the meta,
+//! read as a CBOR map (see `cbor`); a document matches when every predicate
+//! holds.  A predicate on a field the meta lacks never holds, and meta that
+//! isn't a CBOR map matches no predicate at all.  Ints and floats compare
+//! numerically with each other; other values compare only with their own
+//! kind (text and bytes bytewise), so `Lt` on a text field against an int
+//! never holds, while `Ne` does.
+
+use super::{cbor, Store};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+
+/// A scalar metadata value.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum MetaValue {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    Text(String),
+    Bytes(Vec<u8>),
+}
+
+impl MetaValue {
+    /// How `self` orders against `other`, or `None` if they don't compare.
+    fn compare(&self, other: &MetaValue) -> Option<Ordering> {
+        use MetaValue::*;
+        match (self, other) {
+            (Null, Null) => Some(Ordering::Equal),
+            (Bool(a), Bool(b)) => Some(a.cmp(b)),
+            (Int(a), Int(b)) => Some(a.cmp(b)),
+            (Float(a), Float(b)) => a.partial_cmp(b),
+            (Int(a), Float(b)) => (*a as f64).partial_cmp(b),
+            (Float(a), Int(b)) => a.partial_cmp(&(*b as f64)),
+            (Text(a), Text(b)) => Some(a.cmp(b)),
+            (Bytes(a), Bytes(b)) => Some(a.cmp(b)),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CompareOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+/// `field op value`, e.g. `created_at Ge Int(1709251200)`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Predicate {
+    pub field: String,
+    pub op: CompareOp,
+    pub value: MetaValue,
+}
+
+impl Predicate {
+    /// Whether the predicate holds for a field's value; `None` is a field
+    /// that is missing or not a scalar.
+    fn holds(&self, field: Option<&MetaValue>) -> bool {
+        let Some(field) = field else {
+            return false;
+        };
+        let ordering = field.compare(&self.value);
+        match self.op {
+            CompareOp::Eq => ordering == Some(Ordering::Equal),
+            CompareOp::Ne => ordering != Some(Ordering::Equal),
+            CompareOp::Lt => ordering == Some(Ordering::Less),
+            CompareOp::Le => matches!(ordering, Some(Ordering::Less | Ordering::Equal)),
+            CompareOp::Gt => ordering == Some(Ordering::Greater),
+            CompareOp::Ge => matches!(ordering, Some(Ordering::Greater | Ordering::Equal)),
+        }
+    }
+}
+
+/// Whether a document with metadata `meta` passes every predicate.
+pub(super) fn passes(meta: &[u8], filter: &[Predicate]) -> bool {
+    if filter.is_empty() {
+        return true;
+    }
+    let Ok(fields) = cbor::top_level_fields(meta) else {
+        return false;
+    };
+    filter.iter().all(|predicate| {
+        let field = fields.iter().find(|(name, _)| *name == predicate.field);
+        predicate.holds(field.and_then(|(_, value)| value.as_ref()))
+    })
+}
+
+impl Store {
+    /// Ids starting with `prefix` whose metadata passes `filter`, in id
+    /// order.  Reads the meta of every document under the prefix.
+    pub fn filter_documents(&self, prefix: &str, filter: &[Predicate]) -> Result<Vec<String>> {
+        let txn = self.db.begin_read()?;
+        let docs = txn.open_table(self.tables.documents())?;
+        let mut ids = Vec::new();
+        for entry in docs.range(prefix..)? {
+            let (k, meta) = entry?;
+            let id = k.value();
+            if !id.starts_with(prefix) {
+                break;
+            }
+            if passes(meta.value(), filter) {
+                ids.push(id.to_string());
+            }
+        }
+        Ok(ids)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn predicate(field: &str, op: CompareOp, value: MetaValue) -> Predicate {
+        Predicate {
+            field: field.to_string(),
+            op,
+            value,
+        }
+    }
+
+    // {"kind": "note", "created": 1709251200, "score": 2.5}
+    const META: [u8; 33] = [
+        0xa3, 0x64, b'k', b'i', b'n', b'd', 0x64, b'n', b'o', b't', b'e', 0x67, b'c', b'r', b'e',
+        b'a', b't', b'e', b'd', 0x1a, 0x65, 0xe1, 0x1a, 0x80, 0x65, b's', b'c', b'o', b'r', b'e',
+        0xf9, 0x41, 0x00,
+    ];
+
+    #[test]
+    fn test_equality_and_ranges() {
+        use CompareOp::*;
+        let kind = MetaValue::Text("note".to_string());
+        assert!(passes(&META, &[predicate("kind", Eq, kind.clone())]));
+        assert!(!passes(&META, &[predicate("kind", Ne, kind)]));
+        let march = [
+            predicate("created", Ge, MetaValue::Int(1709251200)),
+            predicate("created", Lt, MetaValue::Int(1711929600)),
+        ];
+        assert!(passes(&META, &march));
+        assert!(!passes(&META, &[predicate("created", Gt, MetaValue::Int(1709251200))]));
+        // Ints and floats compare with each other.
+        assert!(passes(&META, &[predicate("score", Gt, MetaValue::Int(2))]));
+        assert!(passes(&META, &[predicate("score", Le, MetaValue::Float(2.5))]));
+    }
+
+    #[test]
+    fn test_missing_fields_and_kinds() {
+        use CompareOp::*;
+        assert!(!passes(&META, &[predicate("owner", Ne, MetaValue::Null)]));
+        assert!(!passes(&META, &[predicate("kind", Lt, MetaValue::Int(3))]));
+        assert!(passes(&META, &[predicate("kind", Ne, MetaValue::Int(3))]));
+        assert!(!passes(b"{\"kind\":\"note\"}", &[predicate("kind", Ne, MetaValue::Null)]));
+        assert!(passes(b"not cbor", &[]));
+    }
+}
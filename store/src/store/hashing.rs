@@ -0,0 +1,61 @@
+//! blake3 of large blobs and CRDT states, spread over several threads.
+//!
+//! Inputs of `PARALLEL_THRESHOLD` or more go to blake3's own rayon support
+//! (`Hasher::update_rayon`), which hashes the subtrees of BLAKE3's chunk
+//! tree on the rayon pool and gives the same hash as `blake3::hash`.  Below
+//! the threshold the threads would cost more than they save.  A spill file
+//! is hashed where it lies (`hash_file`): memory-mapped and hashed the same
+//! way if it is large, else read in chunks, so checking a large blob never
+//! loads it whole.
+
+use anyhow::{Context, Result};
+use blake3::{Hash, Hasher};
+use std::path::Path;
+
+/// Inputs at least this large are hashed in parallel.
+const PARALLEL_THRESHOLD: usize = 4 * 1024 * 1024;
+
+/// blake3 of `data`.
+pub(super) fn hash(data: &[u8]) -> Hash {
+    if data.len() < PARALLEL_THRESHOLD {
+        return blake3::hash(data);
+    }
+    Hasher::new().update_rayon(data).finalize()
+}
+
+/// blake3 of the contents of the file at `path`.
+pub(super) fn hash_file(path: &Path) -> Result<Hash> {
+    let mut hasher = Hasher::new();
+    hasher
+        .update_mmap_rayon(path)
+        .with_context(|| format!("hashing {}", path.display()))?;
+    Ok(hasher.finalize())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn input(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn test_large_input() {
+        let data = input(PARALLEL_THRESHOLD + 12_345);
+        assert_eq!(hash(&data), blake3::hash(&data));
+        assert_eq!(hash(b"small"), blake3::hash(b"small"));
+    }
+
+    #[test]
+    fn test_hash_file() {
+        let path = std::env::temp_dir().join(format!("hashing-{}", std::process::id()));
+        for len in [0, 100_003, PARALLEL_THRESHOLD + 12_345] {
+            let data = input(len);
+            std::fs::write(&path, &data).unwrap();
+            assert_eq!(hash_file(&path).unwrap(), blake3::hash(&data), "len {len}");
+        }
+        std::fs::remove_file(&path).unwrap();
+        assert!(hash_file(&path).is_err());
+    }
+}
@@ -0,0 +1,255 @@
+//! Per-document version history.
+//!
+//! Every `put_document` appends the new CRDT state to doc_history under
+//! (doc id, sequence number) and trims the document's entries to the
+//! newest `StoreOptions::history_depth`.  The current state is the newest
+//! entry, so a bad merge can be undone by re-putting an older version.
+//!
+//! Values are `[32-byte state hash][8-byte LE unix seconds][codec value]`,
+//! the codec value sealed if the database is encrypted.
+
+use super::{codec, encryption, unix_now, ApplyBatch, Store, Tables};
+use anyhow::{bail, Result};
+use redb::{ReadableTable, WriteTransaction};
+
+const HASH_LEN: usize = 32;
+const PREFIX_LEN: usize = HASH_LEN + 8;
+
+/// One retained version of a document.
+#[derive(Debug, Clone)]
+pub struct DocVersion {
+    pub hash: Vec<u8>,
+    /// Unix seconds at which this version was written.
+    pub saved_at: u64,
+    /// Length of the CRDT state.
+    pub size: u64,
+}
+
+/// Versions beyond `history_depth`, as `trim_history` finds them.
+#[derive(Debug, Clone, Default)]
+pub struct HistoryTrim {
+    pub documents: u64,
+    pub versions: u64,
+    /// Stored (compressed, sealed) bytes of those versions.
+    pub bytes: u64,
+    /// Whether they were actually deleted.
+    pub trimmed: bool,
+}
+
+/// Forget every retained version of `id`.
+pub(super) fn clear_history(txn: &WriteTransaction, tables: &Tables, id: &str) -> Result<()> {
+    let mut history = txn.open_table(tables.doc_history())?;
+    history.retain_in((id, 0)..=(id, u64::MAX), |_, _| false)?;
+    Ok(())
+}
+
+fn split_value(value: &[u8]) -> Result<(&[u8], u64, &[u8])> {
+    if value.len() < PREFIX_LEN {
+        bail!("truncated history entry");
+    }
+    let saved_at = u64::from_le_bytes(value[HASH_LEN..PREFIX_LEN].try_into().unwrap());
+    Ok((&value[..HASH_LEN], saved_at, &value[PREFIX_LEN..]))
+}
+
+/// `value` with its codec value replaced by `f`'s, if `f` returns one.
+pub(super) fn rewrite_state(
+    value: &[u8],
+    f: impl FnOnce(&[u8]) -> Result<Option<Vec<u8>>>,
+) -> Result<Option<Vec<u8>>> {
+    let (_, _, state) = split_value(value)?;
+    Ok(f(state)?.map(|state| [&value[..PREFIX_LEN], &state].concat()))
+}
+
+impl Store {
+    /// Append a version of `id` written in `txn`, keeping the newest
+    /// `history_depth`.
+    pub(super) fn record_version(
+        &self,
+        txn: &WriteTransaction,
+        id: &str,
+        hash: &[u8],
+        crdt_state: &[u8],
+    ) -> Result<()> {
+        let mut history = txn.open_table(self.tables.doc_history())?;
+        let seqs: Vec<u64> = history
+            .range((id, 0)..=(id, u64::MAX))?
+            .map(|entry| entry.map(|(key, _)| key.value().1))
+            .collect::<Result<_, _>>()?;
+
+        // Re-putting the current state is not a new version.
+        if let Some(&last) = seqs.last() {
+            if let Some(value) = history.get((id, last))? {
+                if &value.value()[..HASH_LEN] == hash {
+                    return Ok(());
+                }
+            }
+        }
+
+        let encoded = encryption::encode(
+            self.keys().current(),
+            id.as_bytes(),
+            crdt_state,
+            self.options.compression_level,
+        )?;
+        let mut value = Vec::with_capacity(PREFIX_LEN + encoded.len());
+        value.extend_from_slice(hash);
+        value.extend_from_slice(&unix_now().to_le_bytes());
+        value.extend_from_slice(&encoded);
+        let next = seqs.last().map_or(0, |s| s + 1);
+        history.insert((id, next), value.as_slice())?;
+
+        let excess = (seqs.len() + 1).saturating_sub(self.options.history_depth);
+        for &seq in &seqs[..excess.min(seqs.len())] {
+            history.remove((id, seq))?;
+        }
+        Ok(())
+    }
+
+    /// Delete the versions of every document beyond the newest
+    /// `history_depth`.  Puts trim only the document they write, so these
+    /// are left behind when the depth is lowered.  With `dry_run` they are
+    /// only counted.
+    pub fn trim_history(&self, dry_run: bool) -> Result<HistoryTrim> {
+        let txn = self.begin_write()?;
+        let mut report = HistoryTrim::default();
+        {
+            let mut history = txn.open_table(self.tables.doc_history())?;
+            // Keys are in (id, seq) order, so each document's versions are
+            // consecutive, oldest first.
+            let mut by_doc: Vec<(String, Vec<(u64, u64)>)> = Vec::new();
+            for entry in history.iter()? {
+                let (key, value) = entry?;
+                let (id, seq) = key.value();
+                let size = value.value().len() as u64;
+                match by_doc.last_mut() {
+                    Some((last, seqs)) if last == id => seqs.push((seq, size)),
+                    _ => by_doc.push((id.to_string(), vec![(seq, size)])),
+                }
+            }
+            for (id, seqs) in &by_doc {
+                let excess = seqs.len().saturating_sub(self.options.history_depth);
+                if excess == 0 {
+                    continue;
+                }
+                report.documents += 1;
+                for &(seq, size) in &seqs[..excess] {
+                    report.versions += 1;
+                    report.bytes += size;
+                    if !dry_run {
+                        history.remove((id.as_str(), seq))?;
+                    }
+                }
+            }
+        }
+        if dry_run || report.versions == 0 {
+            txn.abort()?;
+        } else {
+            txn.commit()?;
+            report.trimmed = true;
+        }
+        Ok(report)
+    }
+
+    /// Retained versions of a document, newest first.
+    pub fn document_history(&self, id: &str) -> Result<Vec<DocVersion>> {
+        let txn = self.db.begin_read()?;
+        let history = txn.open_table(self.tables.doc_history())?;
+        let mut versions = Vec::new();
+        for entry in history.range((id, 0)..=(id, u64::MAX))?.rev() {
+            let (_, value) = entry?;
+            let (hash, saved_at, state) = split_value(value.value())?;
+            versions.push(DocVersion {
+                hash: hash.to_vec(),
+                saved_at,
+                size: codec::original_len(state)?,
+            });
+        }
+        Ok(versions)
+    }
+
+    /// CRDT state of the retained version of `id` with state hash `hash`.
+    pub fn document_version(&self, id: &str, hash: &[u8]) -> Result<Option<Vec<u8>>> {
+        let txn = self.db.begin_read()?;
+        self.find_version(&txn.open_table(self.tables.doc_history())?, id, hash)
+    }
+
+    /// `document_version` from doc_history open in a read or write
+    /// transaction.
+    fn find_version(
+        &self,
+        history: &impl ReadableTable<(&'static str, u64), &'static [u8]>,
+        id: &str,
+        hash: &[u8],
+    ) -> Result<Option<Vec<u8>>> {
+        for entry in history.range((id, 0)..=(id, u64::MAX))?.rev() {
+            let (_, value) = entry?;
+            let (version_hash, _, state) = split_value(value.value())?;
+            if version_hash == hash {
+                return encryption::decode(&self.keys(), id.as_bytes(), state).map(Some);
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl ApplyBatch<'_> {
+    /// As `Store::document_version`.
+    pub fn document_version(&self, id: &str, hash: &[u8]) -> Result<Option<Vec<u8>>> {
+        let history = self.txn.open_table(self.store.tables.doc_history())?;
+        self.store.find_version(&history, id, hash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::StoreOptions;
+
+    fn open(dir: &std::path::Path, history_depth: usize) -> Store {
+        let options = StoreOptions {
+            history_depth,
+            ..StoreOptions::default()
+        };
+        Store::open(dir, options).unwrap()
+    }
+
+    #[test]
+    fn test_puts_keep_the_newest_versions() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = open(dir.path(), 2);
+        for state in [&b"one"[..], b"two", b"two", b"three"] {
+            store.put_document("doc", b"meta", state, None, false).unwrap();
+        }
+
+        // The repeated put added no version, and "one" was dropped.
+        let versions = store.document_history("doc").unwrap();
+        let hashes: Vec<&[u8]> = versions.iter().map(|v| v.hash.as_slice()).collect();
+        let [one, two, three] = [&b"one"[..], b"two", b"three"].map(blake3::hash);
+        assert_eq!(hashes, [three.as_bytes(), two.as_bytes()]);
+        assert_eq!(store.document_version("doc", one.as_bytes()).unwrap(), None);
+        assert_eq!(store.document_version("doc", two.as_bytes()).unwrap().unwrap(), b"two");
+    }
+
+    #[test]
+    fn test_trim_after_lowering_depth() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let store = open(dir.path(), 5);
+            for state in [&b"one"[..], b"two", b"three"] {
+                store.put_document("a", b"meta", state, None, false).unwrap();
+            }
+            store.put_document("b", b"meta", b"only", None, false).unwrap();
+        }
+        let store = open(dir.path(), 1);
+
+        let report = store.trim_history(true).unwrap();
+        assert_eq!((report.documents, report.versions, report.trimmed), (1, 2, false));
+        assert_eq!(store.document_history("a").unwrap().len(), 3);
+
+        let report = store.trim_history(false).unwrap();
+        assert_eq!((report.documents, report.versions, report.trimmed), (1, 2, true));
+        assert_eq!(store.document_history("a").unwrap().len(), 1);
+        assert_eq!(store.document_history("b").unwrap().len(), 1);
+        assert!(!store.trim_history(false).unwrap().trimmed);
+    }
+}
@@ -0,0 +1,272 @@
+//! Invertible Bloom lookup tables over the doc hashes, for set
+//! reconciliation.
+//!
+//! Each document is an element keyed by the digest the buckets XOR: blake3
+//! over its id and doc_hashes entry.  A table of `n` cells is split into
+//! `PARTITIONS` equal parts, and an element is added to one cell of each:
+//! in part `j`, the cell its key's bytes `8j..8j + 8` pick, read as a
+//! little-endian integer modulo the part's size.  A cell keeps how many
+//! elements it holds, the XOR of their keys and the XOR of their checks
+//! (the first 8 bytes of blake3 of the key, little-endian).
+//!
+//! Subtracting a peer's table of the same size from ours leaves only the
+//! elements one side has and the other lacks, whatever the size of the
+//! stores.  Decoding peels them off: a cell holding one element (count
+//! ±1 and a matching check) yields that element, which is then removed
+//! from its other cells, until none is left.  It succeeds with high
+//! probability when the table has about twice as many cells as the
+//! difference has elements; otherwise some remain and the peer should
+//! retry with a bigger table.
+
+use super::buckets::item_digest;
+use super::Store;
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Cells each element is added to, one per part of the table.
+const PARTITIONS: usize = 3;
+
+/// Most cells a table may have.
+pub const MAX_IBLT_CELLS: u32 = 1 << 20;
+
+const KEY_LEN: usize = 32;
+
+type Key = [u8; KEY_LEN];
+
+/// One cell of a table.  `key_sum` is 32 bytes.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IbltCell {
+    pub count: i64,
+    pub key_sum: Vec<u8>,
+    pub check_sum: u64,
+}
+
+impl Default for IbltCell {
+    fn default() -> Self {
+        Self {
+            count: 0,
+            key_sum: vec![0; KEY_LEN],
+            check_sum: 0,
+        }
+    }
+}
+
+impl IbltCell {
+    fn is_empty(&self) -> bool {
+        self.count == 0 && self.check_sum == 0 && self.key_sum.iter().all(|&b| b == 0)
+    }
+
+    /// The key of the one element in the cell, if that is all it holds.
+    fn pure_key(&self) -> Option<Key> {
+        if self.count != 1 && self.count != -1 {
+            return None;
+        }
+        let key: Key = self.key_sum.as_slice().try_into().ok()?;
+        (check(&key) == self.check_sum).then_some(key)
+    }
+
+    fn toggle(&mut self, key: &Key, count: i64) {
+        self.count = self.count.wrapping_add(count);
+        for (a, b) in self.key_sum.iter_mut().zip(key) {
+            *a ^= b;
+        }
+        self.check_sum ^= check(key);
+    }
+}
+
+/// What decoding the difference of two tables recovered.
+#[derive(Debug, Default)]
+struct Peeled {
+    /// Keys of the elements only the first table holds.
+    ours: Vec<Key>,
+    /// Keys of the elements only the second table holds.
+    theirs: Vec<Key>,
+    /// Every element was recovered; if not, the lists are partial.
+    complete: bool,
+}
+
+/// Reply to a peer's table.
+#[derive(Debug, Clone)]
+pub struct IbltDiff {
+    /// `(doc id, hash)` of each document the peer lacks or holds in another
+    /// version.
+    pub local_only: Vec<(String, Vec<u8>)>,
+    /// Keys of the elements only the peer holds.
+    pub remote_only: Vec<Vec<u8>>,
+    /// Every element was recovered; if not, the lists are partial.
+    pub complete: bool,
+}
+
+/// Whether a table of `cells` cells can be built: at least one per part
+/// and at most `MAX_IBLT_CELLS`.
+pub fn valid_iblt_size(cells: u32) -> bool {
+    (PARTITIONS as u32..=MAX_IBLT_CELLS).contains(&cells)
+}
+
+/// Whether a peer's table can be decoded: of a valid size, with 32-byte
+/// key sums.
+pub fn valid_iblt(cells: &[IbltCell]) -> bool {
+    u32::try_from(cells.len()).is_ok_and(valid_iblt_size)
+        && cells.iter().all(|cell| cell.key_sum.len() == KEY_LEN)
+}
+
+fn check(key: &Key) -> u64 {
+    let hash = blake3::hash(key);
+    u64::from_le_bytes(hash.as_bytes()[..8].try_into().unwrap())
+}
+
+/// The cells of a table of `len` cells that `key` is added to.
+fn positions(key: &Key, len: usize) -> [usize; PARTITIONS] {
+    let part = len / PARTITIONS;
+    std::array::from_fn(|j| {
+        let word = u64::from_le_bytes(key[8 * j..8 * j + 8].try_into().unwrap());
+        j * part + (word % part as u64) as usize
+    })
+}
+
+/// A table of `cells` cells holding `keys`.
+fn build<'a>(keys: impl IntoIterator<Item = &'a Key>, cells: usize) -> Vec<IbltCell> {
+    let mut table = vec![IbltCell::default(); cells];
+    for key in keys {
+        for i in positions(key, cells) {
+            table[i].toggle(key, 1);
+        }
+    }
+    table
+}
+
+/// The elements `ours` holds and `theirs` lacks, and the other way round.
+/// The tables must be the same size with 32-byte key sums.
+fn difference(mut ours: Vec<IbltCell>, theirs: &[IbltCell]) -> Result<Peeled> {
+    if ours.len() != theirs.len() {
+        bail!("IBLT has {} cells, expected {}", theirs.len(), ours.len());
+    }
+    for (cell, other) in ours.iter_mut().zip(theirs) {
+        if other.key_sum.len() != KEY_LEN {
+            bail!("IBLT key sums must be {KEY_LEN} bytes");
+        }
+        cell.count = cell.count.wrapping_sub(other.count);
+        for (a, b) in cell.key_sum.iter_mut().zip(&other.key_sum) {
+            *a ^= b;
+        }
+        cell.check_sum ^= other.check_sum;
+    }
+
+    let len = ours.len();
+    let mut diff = Peeled::default();
+    let mut queue: Vec<usize> = (0..len).collect();
+    while let Some(i) = queue.pop() {
+        // A crafted table could otherwise keep yielding elements.
+        if diff.ours.len() + diff.theirs.len() > len {
+            break;
+        }
+        let Some(key) = ours[i].pure_key() else {
+            continue;
+        };
+        let count = ours[i].count;
+        for j in positions(&key, len) {
+            ours[j].toggle(&key, -count);
+            queue.push(j);
+        }
+        if count == 1 {
+            diff.ours.push(key);
+        } else {
+            diff.theirs.push(key);
+        }
+    }
+    diff.complete = ours.iter().all(IbltCell::is_empty);
+    Ok(diff)
+}
+
+impl Store {
+    /// A table of `cells` cells over the doc hashes; `cells` must pass
+    /// `valid_iblt_size`.
+    pub fn iblt(&self, cells: u32) -> Result<Vec<IbltCell>> {
+        let keys: Vec<Key> = self
+            .all_doc_hashes()?
+            .iter()
+            .map(|(id, hash)| item_digest(id, hash))
+            .collect();
+        Ok(build(&keys, cells as usize))
+    }
+
+    /// Subtract the peer's table `theirs`, which must pass `valid_iblt`,
+    /// from ours of the same size and decode what is left.
+    pub fn reconcile_iblt(&self, theirs: &[IbltCell]) -> Result<IbltDiff> {
+        let mut docs: HashMap<Key, (String, Vec<u8>)> = self
+            .all_doc_hashes()?
+            .into_iter()
+            .map(|(id, hash)| (item_digest(&id, &hash), (id, hash)))
+            .collect();
+        let peeled = difference(build(docs.keys(), theirs.len()), theirs)?;
+        Ok(IbltDiff {
+            local_only: peeled.ours.iter().filter_map(|key| docs.remove(key)).collect(),
+            remote_only: peeled.theirs.iter().map(|key| key.to_vec()).collect(),
+            complete: peeled.complete,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(n: u32) -> Key {
+        *blake3::hash(&n.to_le_bytes()).as_bytes()
+    }
+
+    #[test]
+    fn test_difference() {
+        let shared: Vec<Key> = (0..10_000).map(key).collect();
+        let mut ours = shared.clone();
+        ours.extend((10_000..10_020).map(key));
+        let mut theirs = shared;
+        theirs.extend((20_000..20_010).map(key));
+
+        let diff = difference(build(&ours, 90), &build(&theirs, 90)).unwrap();
+        assert!(diff.complete);
+        let mut found = diff.ours;
+        found.sort();
+        let mut expected: Vec<Key> = (10_000..10_020).map(key).collect();
+        expected.sort();
+        assert_eq!(found, expected);
+        assert_eq!(diff.theirs.len(), 10);
+    }
+
+    #[test]
+    fn test_identical_sets() {
+        let keys: Vec<Key> = (0..100).map(key).collect();
+        let diff = difference(build(&keys, 30), &build(&keys, 30)).unwrap();
+        assert!(diff.complete);
+        assert!(diff.ours.is_empty() && diff.theirs.is_empty());
+    }
+
+    #[test]
+    fn test_too_small_table_is_incomplete() {
+        let ours: Vec<Key> = (0..200).map(key).collect();
+        let diff = difference(build(&ours, 30), &build(&[], 30)).unwrap();
+        assert!(!diff.complete);
+        assert!(diff.ours.len() < 200);
+    }
+
+    #[test]
+    fn test_mismatched_tables() {
+        assert!(difference(build(&[], 30), &build(&[], 33)).is_err());
+        let mut bad = build(&[], 30);
+        bad[0].key_sum.pop();
+        assert!(difference(build(&[], 30), &bad).is_err());
+    }
+
+    #[test]
+    fn test_valid_iblt() {
+        assert!(!valid_iblt_size(2));
+        assert!(valid_iblt_size(3));
+        assert!(valid_iblt_size(MAX_IBLT_CELLS));
+        assert!(!valid_iblt_size(MAX_IBLT_CELLS + 1));
+        let mut cells = build(&[], 3);
+        assert!(valid_iblt(&cells));
+        cells[2].key_sum.push(0);
+        assert!(!valid_iblt(&cells));
+    }
+}
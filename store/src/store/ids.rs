@@ -0,0 +1,156 @@
+//! Document id policy enforced when documents and tombstones are written.
+//!
+//! Control characters are never accepted; `IdPolicy` can also cap the
+//! length, restrict ids to a character set and reserve prefixes.  Every
+//! path that creates a document or tombstone checks, sync and archive
+//! imports included, and fails with `InvalidId`, which the port answers
+//! with `ErrorCode::InvalidId`.  Ids already stored are left alone: they
+//! can still be read, patched and deleted.
+
+use super::Store;
+use anyhow::{bail, Result};
+use std::fmt;
+use std::str::FromStr;
+
+/// Which document ids writes accept.
+#[derive(Debug, Clone, Default)]
+pub struct IdPolicy {
+    /// Longest id, in bytes.
+    pub max_len: Option<usize>,
+    /// Characters an id may consist of; `None` allows any but control
+    /// characters.
+    pub allowed: Option<CharSet>,
+    /// Prefixes no id may start with.
+    pub reserved_prefixes: Vec<String>,
+}
+
+/// Characters given as ranges and single characters, e.g. `A-Za-z0-9_.-`.
+/// A `-` is literal at either end.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CharSet {
+    ranges: Vec<(char, char)>,
+}
+
+impl CharSet {
+    fn contains(&self, c: char) -> bool {
+        self.ranges.iter().any(|&(lo, hi)| (lo..=hi).contains(&c))
+    }
+}
+
+impl FromStr for CharSet {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let chars: Vec<char> = s.chars().collect();
+        let mut ranges = Vec::new();
+        let mut i = 0;
+        while i < chars.len() {
+            if i + 2 < chars.len() && chars[i + 1] == '-' {
+                let (lo, hi) = (chars[i], chars[i + 2]);
+                if lo > hi {
+                    bail!("range {lo}-{hi} is backwards");
+                }
+                ranges.push((lo, hi));
+                i += 3;
+            } else {
+                ranges.push((chars[i], chars[i]));
+                i += 1;
+            }
+        }
+        if ranges.is_empty() {
+            bail!("character set is empty");
+        }
+        Ok(Self { ranges })
+    }
+}
+
+/// A write of a document id the policy rejects.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidId {
+    pub id: String,
+    pub reason: String,
+}
+
+impl fmt::Display for InvalidId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Debug-quoted, so control characters show escaped.
+        write!(f, "invalid document id {:?}: {}", self.id, self.reason)
+    }
+}
+
+impl std::error::Error for InvalidId {}
+
+impl IdPolicy {
+    /// Why `id` is not accepted, if it isn't.
+    fn violation(&self, id: &str) -> Option<String> {
+        if let Some(max) = self.max_len.filter(|&max| id.len() > max) {
+            return Some(format!("longer than {max} bytes"));
+        }
+        if let Some(c) = id.chars().find(|c| c.is_control()) {
+            return Some(format!("contains control character {c:?}"));
+        }
+        if let Some(allowed) = &self.allowed {
+            if let Some(c) = id.chars().find(|&c| !allowed.contains(c)) {
+                return Some(format!("contains {c:?}, which is not allowed"));
+            }
+        }
+        self.reserved_prefixes
+            .iter()
+            .find(|prefix| id.starts_with(prefix.as_str()))
+            .map(|prefix| format!("prefix {prefix:?} is reserved"))
+    }
+}
+
+impl Store {
+    /// Check `id` against `StoreOptions::id_policy`.
+    pub fn validate_id(&self, id: &str) -> Result<()> {
+        match self.options.id_policy.violation(id) {
+            Some(reason) => Err(InvalidId {
+                id: id.to_string(),
+                reason,
+            }
+            .into()),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_charset() {
+        let set: CharSet = "a-z0-9_-".parse().unwrap();
+        assert!(set.contains('q') && set.contains('7'));
+        assert!(set.contains('_') && set.contains('-'));
+        assert!(!set.contains('A') && !set.contains('/'));
+
+        let set: CharSet = "-.".parse().unwrap();
+        assert!(set.contains('-') && set.contains('.'));
+
+        assert!("".parse::<CharSet>().is_err());
+        assert!("z-a".parse::<CharSet>().is_err());
+    }
+
+    #[test]
+    fn test_control_characters_always_rejected() {
+        let policy = IdPolicy::default();
+        assert_eq!(policy.violation("notes/2024 ü"), None);
+        assert!(policy.violation("bad\u{0}id").is_some());
+        assert!(policy.violation("line\nbreak").is_some());
+    }
+
+    #[test]
+    fn test_policy() {
+        let policy = IdPolicy {
+            max_len: Some(8),
+            allowed: Some("a-z/".parse().unwrap()),
+            reserved_prefixes: vec!["sys/".into()],
+        };
+        assert_eq!(policy.violation("doc/a"), None);
+        assert!(policy.violation("toolongid").is_some());
+        assert!(policy.violation("Doc").is_some());
+        assert!(policy.violation("sys/x").is_some());
+    }
+}
@@ -0,0 +1,63 @@
+//! Secondary index over document metadata.
+//!
+//! Callers extract key/value pairs from a document's meta and pass them to
+//! `put_document`; each pair becomes a doc_index row keyed
+//! (key, value, doc id) so `query_documents` is a range scan.  doc_index_keys
+//! remembers each document's pairs so they can be replaced or removed.
+
+use super::{Store, Tables};
+use anyhow::Result;
+use redb::WriteTransaction;
+
+/// Replace the index entries of `id` with `pairs`.
+pub(super) fn set_index(
+    txn: &WriteTransaction,
+    tables: &Tables,
+    id: &str,
+    pairs: &[(String, String)],
+) -> Result<()> {
+    clear_index(txn, tables, id)?;
+    if pairs.is_empty() {
+        return Ok(());
+    }
+    let mut index = txn.open_table(tables.doc_index())?;
+    for (key, value) in pairs {
+        index.insert((key.as_str(), value.as_str(), id), ())?;
+    }
+    let encoded = bincode::serialize(pairs)?;
+    txn.open_table(tables.doc_index_keys())?
+        .insert(id, encoded.as_slice())?;
+    Ok(())
+}
+
+/// Remove every index entry of `id`.
+pub(super) fn clear_index(txn: &WriteTransaction, tables: &Tables, id: &str) -> Result<()> {
+    let mut keys = txn.open_table(tables.doc_index_keys())?;
+    let Some(encoded) = keys.remove(id)? else {
+        return Ok(());
+    };
+    let pairs: Vec<(String, String)> = bincode::deserialize(encoded.value())?;
+    let mut index = txn.open_table(tables.doc_index())?;
+    for (key, value) in &pairs {
+        index.remove((key.as_str(), value.as_str(), id))?;
+    }
+    Ok(())
+}
+
+impl Store {
+    /// Ids of the documents indexed with `key` = `value`, in id order.
+    pub fn query_documents(&self, key: &str, value: &str) -> Result<Vec<String>> {
+        let txn = self.db.begin_read()?;
+        let index = txn.open_table(self.tables.doc_index())?;
+        let mut ids = Vec::new();
+        for entry in index.range((key, value, "")..)? {
+            let (k, _) = entry?;
+            let (k_key, k_value, id) = k.value();
+            if k_key != key || k_value != value {
+                break;
+            }
+            ids.push(id.to_string());
+        }
+        Ok(ids)
+    }
+}
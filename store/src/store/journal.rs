@@ -0,0 +1,180 @@
+//! Write-ahead journal for spill files, which live outside redb's
+//! transactions.
+//!
+//! Before a spill file is written, or before the transaction that drops a
+//! spilled blob's row commits, the blob's namespace and hash are appended
+//! to `keyring.journal` and synced.  A crash can then leave a spill file
+//! without a row (a put that never committed, a removal that committed
+//! before its file went) but never an unrecorded one.
+//!
+//! Settling the journal brings each recorded blob's spill files in line
+//! with its row as last committed: the file the row refers to stays, every
+//! other spill file of the blob is removed — rolling removals forward and
+//! uncommitted puts back — and the journal is emptied.  It settles when the
+//! store opens and at the start of each write transaction, while no other
+//! write can be in flight.
+
+use super::{codec, qualified_name, spill, Store, Tables};
+use anyhow::{Context, Result};
+use redb::{ReadableTable, WriteTransaction};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
+use tracing::{debug, warn};
+
+pub(super) const JOURNAL_FILE: &str = "keyring.journal";
+
+const HASH_LEN: usize = 32;
+
+pub(super) struct Journal {
+    state: Mutex<JournalState>,
+}
+
+struct JournalState {
+    file: File,
+    /// (namespace, blob hash) of every line in the file.
+    entries: Vec<(String, Vec<u8>)>,
+}
+
+impl Journal {
+    /// Open the journal in `dir`, keeping the entries of an interrupted run.
+    pub(super) fn open(dir: &Path) -> Result<Self> {
+        let path = dir.join(JOURNAL_FILE);
+        let file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&path)
+            .with_context(|| format!("opening {}", path.display()))?;
+        let mut entries = Vec::new();
+        for line in BufReader::new(&file).lines() {
+            let line = line?;
+            // A line cut short by a crash belongs to an operation that never
+            // touched its file.
+            let Some((namespace, hash)) = line.split_once('\t') else { continue };
+            let Ok(hash) = super::from_hex(hash) else { continue };
+            if hash.len() != HASH_LEN {
+                continue;
+            }
+            entries.push((namespace.to_string(), hash));
+        }
+        Ok(Self {
+            state: Mutex::new(JournalState { file, entries }),
+        })
+    }
+
+    /// Durably record that spill files of `hashes` in `namespace` are about
+    /// to change.
+    pub(super) fn record(&self, namespace: &str, hashes: &[&[u8]]) -> Result<()> {
+        if hashes.is_empty() {
+            return Ok(());
+        }
+        let mut state = self.lock();
+        let mut lines = String::new();
+        for hash in hashes {
+            lines.push_str(&format!("{namespace}\t{}\n", super::to_hex(hash)));
+        }
+        state.file.write_all(lines.as_bytes())?;
+        state.file.sync_data().context("syncing the journal")?;
+        state
+            .entries
+            .extend(hashes.iter().map(|h| (namespace.to_string(), h.to_vec())));
+        Ok(())
+    }
+
+    fn lock(&self) -> MutexGuard<'_, JournalState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Store {
+    /// Settle the journal against what `txn` sees as committed.  Failing
+    /// to is logged, not fatal: the entries stay for the next attempt.
+    pub(super) fn settle_journal(&self, txn: &WriteTransaction) {
+        let mut state = self.journal.lock();
+        if state.entries.is_empty() {
+            return;
+        }
+        let settled = state
+            .entries
+            .iter()
+            .try_for_each(|(namespace, hash)| self.settle_spill(txn, namespace, hash))
+            .and_then(|()| Ok(state.file.set_len(0)?));
+        match settled {
+            Ok(()) => {
+                debug!(entries = state.entries.len(), "journal settled");
+                state.entries.clear();
+            }
+            Err(e) => warn!(error = %format!("{e:#}"), "settling the journal failed"),
+        }
+    }
+
+    fn settle_spill(&self, txn: &WriteTransaction, namespace: &str, hash: &[u8]) -> Result<()> {
+        let spill_dir = self.dir.join(qualified_name(spill::SPILL_DIR, namespace));
+        let blobs = txn.open_table(Tables::new(namespace).blobs())?;
+        let keep = blobs
+            .get(hash)?
+            .filter(|stored| codec::is_external(stored.value()))
+            .map(|stored| spill::path_of(&spill_dir, hash, stored.value()));
+        let plain = spill::path_for(&spill_dir, hash);
+        let sealed = spill::sealed_path_for(&spill_dir, hash);
+        for path in [plain.with_extension("tmp"), plain, sealed] {
+            if keep.as_ref() != Some(&path) {
+                spill::remove(&path)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::{to_hex, StoreOptions};
+    use std::fs;
+
+    #[test]
+    fn test_journal_settles_spill_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = StoreOptions {
+            spill_threshold: Some(16),
+            ..Default::default()
+        };
+        let store = Store::open(dir.path(), options.clone()).unwrap();
+        let kept = store.put_blob(&[1; 100], None).unwrap();
+        let kept_path = {
+            let txn = store.db.begin_read().unwrap();
+            let blobs = txn.open_table(store.tables.blobs()).unwrap();
+            let stored = blobs.get(kept.as_slice()).unwrap().unwrap();
+            spill::path_of(&store.spill_dir, &kept, stored.value())
+        };
+        assert!(kept_path.exists());
+        let spill_dir = store.spill_dir.clone();
+        drop(store);
+
+        // A crash after a put wrote its spill file but before it committed,
+        // with a line cut short after it.
+        let orphan = blake3::hash(b"never committed").as_bytes().to_vec();
+        let orphan_path = spill::path_for(&spill_dir, &orphan);
+        fs::create_dir_all(orphan_path.parent().unwrap()).unwrap();
+        fs::write(&orphan_path, [2; 100]).unwrap();
+        let journal = format!("\t{}\n\t{}\n\tab", to_hex(&kept), to_hex(&orphan));
+        fs::write(dir.path().join(JOURNAL_FILE), journal).unwrap();
+
+        let store = Store::open(dir.path(), options).unwrap();
+        assert!(!orphan_path.exists());
+        assert!(kept_path.exists());
+        assert_eq!(fs::metadata(dir.path().join(JOURNAL_FILE)).unwrap().len(), 0);
+        assert_eq!(store.get_blob(&kept).unwrap().unwrap(), [1; 100]);
+        assert_eq!(store.verify(true).unwrap().problems.count, 0);
+
+        // Entries recorded while serving settle with the next write.
+        fs::write(&orphan_path, [2; 100]).unwrap();
+        store.journal.record("", &[&orphan]).unwrap();
+        assert_eq!(store.journal.lock().entries.len(), 1);
+        store.put_document("a", b"meta", b"state", None, false).unwrap();
+        assert!(store.journal.lock().entries.is_empty());
+        assert!(!orphan_path.exists());
+    }
+}
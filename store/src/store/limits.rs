@@ -0,0 +1,103 @@
+//! Size limits enforced when blobs and documents are put.
+//!
+//! `StoreOptions::max_blob_bytes` caps a blob's content and
+//! `max_doc_bytes` a document's meta and CRDT state together.  Every put
+//! path checks before anything is hashed or written, and fails with
+//! `TooLarge`, which the port answers with `ErrorCode::TooLarge`.  Data
+//! already stored is left alone, so lowering a limit never makes existing
+//! content unreadable; rewriting it does fail.
+
+use super::Store;
+use anyhow::Result;
+use std::fmt;
+
+/// A put over the configured size limit.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TooLarge {
+    /// "blob" or "document".
+    pub what: &'static str,
+    pub size: u64,
+    pub limit: u64,
+}
+
+impl fmt::Display for TooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} of {} bytes exceeds the limit of {} bytes",
+            self.what, self.size, self.limit
+        )
+    }
+}
+
+impl std::error::Error for TooLarge {}
+
+fn check(what: &'static str, size: usize, limit: Option<u64>) -> Result<()> {
+    match limit {
+        Some(limit) if size as u64 > limit => Err(TooLarge {
+            what,
+            size: size as u64,
+            limit,
+        }
+        .into()),
+        _ => Ok(()),
+    }
+}
+
+impl Store {
+    pub(super) fn check_blob_size(&self, data: &[u8]) -> Result<()> {
+        check("blob", data.len(), self.options.max_blob_bytes)
+    }
+
+    pub(super) fn check_document_size(&self, meta: &[u8], crdt_state: &[u8]) -> Result<()> {
+        let size = meta.len().saturating_add(crdt_state.len());
+        check("document", size, self.options.max_doc_bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::StoreOptions;
+
+    fn open(dir: &std::path::Path, limit: Option<u64>) -> Store {
+        let options = StoreOptions {
+            max_blob_bytes: limit,
+            max_doc_bytes: limit,
+            ..StoreOptions::default()
+        };
+        Store::open(dir, options).unwrap()
+    }
+
+    #[test]
+    fn test_oversized_puts_write_nothing() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = open(dir.path(), Some(8));
+
+        let e = store.put_blob(b"0123456789", None).unwrap_err();
+        let too_large = e.downcast_ref::<TooLarge>().unwrap();
+        assert_eq!((too_large.what, too_large.size, too_large.limit), ("blob", 10, 8));
+        assert_eq!(store.count_blobs(&[]).unwrap(), 0);
+
+        let e = store.put_document("doc", b"meta", b"state", None, false).unwrap_err();
+        assert_eq!(e.downcast_ref::<TooLarge>().unwrap().size, 9);
+        assert!(store.get_document("doc").unwrap().is_none());
+        assert!(store.put_document("doc", b"meta", b"data", None, false).is_ok());
+    }
+
+    #[test]
+    fn test_lowered_limit_keeps_stored_data() {
+        let dir = tempfile::tempdir().unwrap();
+        let hash = {
+            let store = open(dir.path(), None);
+            store.put_document("doc", b"meta", b"a long state", None, false).unwrap();
+            store.put_blob(b"a long blob", None).unwrap()
+        };
+        let store = open(dir.path(), Some(8));
+
+        assert_eq!(store.get_blob(&hash).unwrap().unwrap(), b"a long blob");
+        assert!(store.get_document("doc").unwrap().is_some());
+        let e = store.put_document("doc", b"meta", b"a long state", None, false).unwrap_err();
+        assert!(e.downcast_ref::<TooLarge>().is_some());
+    }
+}
@@ -0,0 +1,187 @@
+//! Merging a peer's CRDT state into the local one.
+//!
+//! A change that builds on the local version of a document replaces it.
+//! One that conflicts with it (see `ApplyChanges`) is handed, with the
+//! local state, to a CRDT engine, and what the engine returns is stored
+//! with the local metadata.  A document picks its engine by name in the
+//! `crdt` text field of its (CBOR) metadata, looked up in
+//! `StoreOptions::crdt_engines`; documents without one go to
+//! `StoreOptions::merger` under the `Merge` conflict policy (see
+//! `conflicts`).  A conflict on a document naming an engine the store
+//! lacks fails, rather than being merged by an engine that doesn't know
+//! its format.  With the `automerge` feature, the default,
+//! `AutomergeMerger` is both the `automerge` engine and the default
+//! merger; no engine for Yjs (`yrs`) ships yet.  A default merger that
+//! can't read the states, or none at all, leaves the conflict to be
+//! resolved by hand: the local version stays and the incoming state is
+//! kept as its sibling.
+
+use super::filter::MetaValue;
+use super::{cbor, ApplyBatch, Store};
+use anyhow::{bail, Result};
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::sync::Arc;
+use tracing::warn;
+
+/// Top-level metadata field naming a document's CRDT engine.
+const ENGINE_FIELD: &str = "crdt";
+
+/// A CRDT engine: combines a remote state with the local one.
+pub trait StateMerger: Debug + Send + Sync {
+    /// The state to store when `remote` arrives for `doc_id`, whose state
+    /// is `local`.
+    fn merge(&self, doc_id: &str, local: &[u8], remote: &[u8]) -> Result<Vec<u8>>;
+}
+
+/// Merges Automerge documents: the result holds the changes of both.
+#[cfg(feature = "automerge")]
+#[derive(Debug, Default)]
+pub struct AutomergeMerger;
+
+#[cfg(feature = "automerge")]
+impl StateMerger for AutomergeMerger {
+    fn merge(&self, doc_id: &str, local: &[u8], remote: &[u8]) -> Result<Vec<u8>> {
+        use anyhow::Context;
+        let mut merged = automerge::Automerge::load(local)
+            .with_context(|| format!("local {doc_id:?} is not an Automerge document"))?;
+        let mut remote = automerge::Automerge::load(remote)
+            .with_context(|| format!("incoming {doc_id:?} is not an Automerge document"))?;
+        merged.merge(&mut remote).with_context(|| format!("merging {doc_id:?}"))?;
+        Ok(merged.save())
+    }
+}
+
+/// `StoreOptions::merger`'s default.
+pub(super) fn default_merger() -> Option<Arc<dyn StateMerger>> {
+    #[cfg(feature = "automerge")]
+    return Some(Arc::new(AutomergeMerger));
+    #[cfg(not(feature = "automerge"))]
+    return None;
+}
+
+/// `StoreOptions::crdt_engines`' default.
+pub(super) fn default_engines() -> HashMap<String, Arc<dyn StateMerger>> {
+    #[cfg(feature = "automerge")]
+    return HashMap::from([("automerge".to_string(), default_merger().unwrap())]);
+    #[cfg(not(feature = "automerge"))]
+    return HashMap::new();
+}
+
+/// The engine `meta` names, if it is CBOR with a text `crdt` field.
+fn engine_name(meta: &[u8]) -> Option<String> {
+    cbor::top_level_fields(meta)
+        .ok()?
+        .into_iter()
+        .find_map(|(key, value)| match value {
+            Some(MetaValue::Text(name)) if key == ENGINE_FIELD => Some(name),
+            _ => None,
+        })
+}
+
+impl Store {
+    /// The CRDT engine the metadata `meta` of `id` names, if it names one;
+    /// an error if the store lacks it.
+    pub(super) fn crdt_engine(&self, id: &str, meta: &[u8]) -> Result<Option<&dyn StateMerger>> {
+        let Some(name) = engine_name(meta) else {
+            return Ok(None);
+        };
+        match self.options.crdt_engines.get(&name) {
+            Some(engine) => Ok(Some(engine.as_ref())),
+            None => bail!("{id:?} names CRDT engine {name:?}, which this store doesn't have"),
+        }
+    }
+}
+
+impl ApplyBatch<'_> {
+    /// The metadata and state to store for `id` when `remote` arrives from
+    /// a peer in conflict with the local version: the local metadata and
+    /// the merge of both states, or empty metadata and `remote` if the
+    /// document doesn't exist.  `None` if the default merger can't merge
+    /// them, or there is none, for the caller to keep both.  An error if
+    /// the local metadata names an engine the store lacks.
+    pub fn merge_remote_state(&self, id: &str, remote: &[u8]) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        let Some(local) = self.document(id)? else {
+            return Ok(Some((Vec::new(), remote.to_vec())));
+        };
+        if let Some(engine) = self.store.crdt_engine(id, &local.meta)? {
+            let state = engine.merge(id, &local.crdt_state, remote)?;
+            return Ok(Some((local.meta, state)));
+        }
+        let Some(merger) = &self.store.options.merger else {
+            return Ok(None);
+        };
+        match merger.merge(id, &local.crdt_state, remote) {
+            Ok(state) => Ok(Some((local.meta, state))),
+            Err(e) => {
+                warn!(doc_id = id, error = %format!("{e:#}"), "can't merge, keeping both versions");
+                Ok(None)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::Incoming;
+
+    #[test]
+    fn test_engine_name() {
+        // {"crdt": "yrs", "n": 1}
+        let meta = [0xa2, 0x64, b'c', b'r', b'd', b't', 0x63, b'y', b'r', b's', 0x61, b'n', 0x01];
+        assert_eq!(engine_name(&meta).as_deref(), Some("yrs"));
+        // {"crdt": 1}
+        assert_eq!(engine_name(&[0xa1, 0x64, b'c', b'r', b'd', b't', 0x01]), None);
+        assert_eq!(engine_name(b"not cbor"), None);
+        assert_eq!(engine_name(&[]), None);
+    }
+
+    #[test]
+    fn test_unknown_engine() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Store::open(dir.path(), Default::default()).unwrap();
+        // {"crdt": "yrs"}
+        let meta = [0xa1, 0x64, b'c', b'r', b'd', b't', 0x63, b'y', b'r', b's'];
+        let err = store.crdt_engine("doc", &meta).unwrap_err();
+        assert!(err.to_string().contains("\"yrs\""), "{err:#}");
+        assert!(store.crdt_engine("doc", b"not cbor").unwrap().is_none());
+
+        // A conflict on the document fails rather than going to the
+        // default merger.
+        store.put_document("doc", &meta, b"local", None, false).unwrap();
+        let batch = ApplyBatch {
+            store: &store,
+            txn: store.begin_write().unwrap(),
+            touched: Vec::new(),
+        };
+        assert!(batch.merge_remote_state("doc", b"remote").is_err());
+        let hash = blake3::hash(b"remote");
+        let incoming = Incoming {
+            hash: hash.as_bytes(),
+            deleted: false,
+            updated_at: None,
+        };
+        assert!(batch.resolve_conflict("doc", incoming).is_err());
+    }
+
+    #[cfg(feature = "automerge")]
+    #[test]
+    fn test_automerge_merger() {
+        use automerge::transaction::Transactable;
+        use automerge::{AutoCommit, ReadDoc, ROOT};
+
+        let mut base = AutoCommit::new();
+        base.put(ROOT, "title", "notes").unwrap();
+        let mut ours = base.fork();
+        ours.put(ROOT, "ours", 1).unwrap();
+        let mut theirs = base.fork();
+        theirs.put(ROOT, "theirs", 2).unwrap();
+        let merged = AutomergeMerger.merge("doc", &ours.save(), &theirs.save()).unwrap();
+        let merged = AutoCommit::load(&merged).unwrap();
+        for key in ["title", "ours", "theirs"] {
+            assert!(merged.get(ROOT, key).unwrap().is_some(), "{key} was lost");
+        }
+        assert!(AutomergeMerger.merge("doc", &ours.save(), b"not automerge").is_err());
+    }
+}
@@ -0,0 +1,255 @@
+//! Schema versioning.
+//!
+//! STORE_META records the schema version a database was last migrated to.
+//! Opening an older database copies its file to `keyring.redb.v<N>.bak`
+//! and then runs each newer migration in its own write transaction, which
+//! also records the new version — a crash mid-upgrade resumes from the last
+//! migration that committed.  A new database starts at the current version.
+//!
+//! With `migrate_on_open` off, an older database is refused instead, so
+//! that upgrades run only when `keyring-store migrate` is run.
+//!
+//! Migrations are append-only: never edit or reorder a released one.
+
+use super::{
+    buckets, db_builder, namespaces_of, qualified_name, StoreOptions, Tables, DB_FILE,
+    SCHEMA_VERSION, STORE_META,
+};
+use anyhow::{bail, Context, Result};
+use redb::{Builder, Database, ReadableTable, TableDefinition, TableError, WriteTransaction};
+use std::fs;
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+struct Migration {
+    /// Version the database is at once this migration has run.
+    version: u64,
+    description: &'static str,
+    run: fn(&WriteTransaction) -> Result<()>,
+}
+
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "create the history, tombstone and index tables in every namespace",
+        run: create_namespace_tables,
+    },
+    Migration {
+        version: 2,
+        description: "start the version counter of every existing document at 1",
+        run: number_documents,
+    },
+    Migration {
+        version: 3,
+        description: "bucket the doc hashes of every namespace by prefix",
+        run: bucket_doc_hashes,
+    },
+    Migration {
+        version: 4,
+        description: "drop the search term tables; the search index moved to tantivy",
+        run: drop_search_terms,
+    },
+];
+
+/// Version a database is at after every migration has run.
+const CURRENT_VERSION: u64 = MIGRATIONS[MIGRATIONS.len() - 1].version;
+
+/// What migrating a database would do.
+#[derive(Debug, Clone)]
+pub struct MigrationPlan {
+    /// The database's schema version now.
+    pub version: u64,
+    /// The version this build migrates to.
+    pub target: u64,
+    /// (version, description) of each migration still to run, in order.
+    pub pending: Vec<(u64, &'static str)>,
+    /// Where the file is copied before the first of them runs.
+    pub backup: PathBuf,
+}
+
+/// The migrations the database in `dir` lacks, found without changing
+/// it.  Fails if there is no database, or it is newer than this build.
+pub fn plan_migrations(dir: &Path, options: &StoreOptions) -> Result<MigrationPlan> {
+    let path = dir.join(DB_FILE);
+    if !fs::metadata(&path).is_ok_and(|m| m.len() > 0) {
+        bail!("no database at {}", path.display());
+    }
+    let db = db_builder(options)
+        .open(&path)
+        .with_context(|| format!("opening database {}", path.display()))?;
+    let (_, version) = schema_version(&db)?;
+    check_not_newer(version, &path)?;
+    Ok(MigrationPlan {
+        version,
+        target: CURRENT_VERSION,
+        pending: MIGRATIONS
+            .iter()
+            .filter(|m| m.version > version)
+            .map(|m| (m.version, m.description))
+            .collect(),
+        backup: backup_path(&path, version),
+    })
+}
+
+/// Bring `db` (the file at `path`, opened by `builder`) up to the current
+/// schema version, or with `apply` unset refuse to open it if it is older.
+pub(super) fn migrate(
+    db: Database,
+    path: &Path,
+    builder: &Builder,
+    apply: bool,
+) -> Result<Database> {
+    let (fresh, version) = schema_version(&db)?;
+
+    if fresh {
+        set_version(&db, CURRENT_VERSION)?;
+        return Ok(db);
+    }
+    check_not_newer(version, path)?;
+    if version == CURRENT_VERSION {
+        return Ok(db);
+    }
+    if !apply {
+        bail!(
+            "{} has schema version {version}, older than this build's {CURRENT_VERSION}; \
+             run `keyring-store migrate` to upgrade it",
+            path.display()
+        );
+    }
+
+    // Copy the file with the database closed, so the backup is exactly
+    // what was last committed.
+    drop(db);
+    let backup = backup_path(path, version);
+    fs::copy(path, &backup)
+        .with_context(|| format!("backing up {} before migrating", path.display()))?;
+    info!(backup = %backup.display(), from = version, "backed up database before migrating");
+    let db = builder
+        .create(path)
+        .with_context(|| format!("reopening database {}", path.display()))?;
+
+    for migration in MIGRATIONS.iter().filter(|m| m.version > version) {
+        let txn = db.begin_write()?;
+        (migration.run)(&txn)
+            .with_context(|| format!("migrating to schema version {}", migration.version))?;
+        txn.open_table(STORE_META)?
+            .insert(SCHEMA_VERSION, migration.version)?;
+        txn.commit()?;
+        info!(
+            version = migration.version,
+            description = migration.description,
+            "migration applied"
+        );
+    }
+    Ok(db)
+}
+
+/// Whether `db` has no tables yet, and its schema version.
+fn schema_version(db: &Database) -> Result<(bool, u64)> {
+    let txn = db.begin_read()?;
+    let fresh = txn.list_tables()?.next().is_none();
+    let version = match txn.open_table(STORE_META) {
+        Ok(meta) => meta.get(SCHEMA_VERSION)?.map_or(0, |v| v.value()),
+        Err(TableError::TableDoesNotExist(_)) => 0,
+        Err(e) => return Err(e.into()),
+    };
+    Ok((fresh, version))
+}
+
+fn check_not_newer(version: u64, path: &Path) -> Result<()> {
+    if version > CURRENT_VERSION {
+        bail!(
+            "{} has schema version {version}, newer than this build's \
+             {CURRENT_VERSION}; upgrade keyring-store",
+            path.display()
+        );
+    }
+    Ok(())
+}
+
+fn backup_path(path: &Path, version: u64) -> PathBuf {
+    path.with_extension(format!("redb.v{version}.bak"))
+}
+
+fn set_version(db: &Database, version: u64) -> Result<()> {
+    let txn = db.begin_write()?;
+    txn.open_table(STORE_META)?.insert(SCHEMA_VERSION, version)?;
+    txn.commit()?;
+    Ok(())
+}
+
+/// Namespaces created before history, tombstones and the secondary index
+/// existed lack those tables until something opens the namespace.
+fn create_namespace_tables(txn: &WriteTransaction) -> Result<()> {
+    let mut namespaces = vec![String::new()];
+    namespaces.extend(namespaces_of(txn.list_tables()?));
+    for namespace in &namespaces {
+        Tables::new(namespace).create_all(txn)?;
+    }
+    Ok(())
+}
+
+fn number_documents(txn: &WriteTransaction) -> Result<()> {
+    let mut namespaces = vec![String::new()];
+    namespaces.extend(namespaces_of(txn.list_tables()?));
+    for namespace in &namespaces {
+        let tables = Tables::new(namespace);
+        tables.create_all(txn)?;
+        let docs = txn.open_table(tables.documents())?;
+        let mut versions = txn.open_table(tables.doc_versions())?;
+        for entry in docs.iter()? {
+            let (id, _) = entry?;
+            if versions.get(id.value())?.is_none() {
+                versions.insert(id.value(), 1)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn bucket_doc_hashes(txn: &WriteTransaction) -> Result<()> {
+    let mut namespaces = vec![String::new()];
+    namespaces.extend(namespaces_of(txn.list_tables()?));
+    for namespace in &namespaces {
+        let tables = Tables::new(namespace);
+        tables.create_all(txn)?;
+        buckets::rebuild_buckets(txn, &tables)?;
+    }
+    Ok(())
+}
+
+/// The index is rebuilt outside redb on the next open with search fields,
+/// since it has no commit of the fields yet.
+fn drop_search_terms(txn: &WriteTransaction) -> Result<()> {
+    let mut namespaces = vec![String::new()];
+    namespaces.extend(namespaces_of(txn.list_tables()?));
+    for namespace in &namespaces {
+        for name in ["search_terms", "search_docs"] {
+            let name = qualified_name(name, namespace);
+            txn.delete_table(TableDefinition::<&str, ()>::new(&name))?;
+        }
+        Tables::new(namespace).create_all(txn)?;
+    }
+    txn.open_table(STORE_META)?.remove("search_fields")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backup_path() {
+        assert_eq!(
+            backup_path(Path::new("/data/keyring.redb"), 2),
+            Path::new("/data/keyring.redb.v2.bak")
+        );
+    }
+
+    #[test]
+    fn test_versions_are_consecutive() {
+        for (i, migration) in MIGRATIONS.iter().enumerate() {
+            assert_eq!(migration.version, i as u64 + 1, "{}", migration.description);
+        }
+    }
+}
@@ -0,0 +1,1206 @@
+//! Content-addressed blob storage and document store backed by redb.
+
+mod access;
+mod aead;
+mod ancestry;
+mod apply;
+mod archive;
+mod attachments;
+mod backup;
+mod batch;
+mod bloom;
+mod blobs;
+mod buckets;
+mod cache;
+mod cbor;
+mod changelog;
+mod changes;
+mod checkpoints;
+mod codec;
+mod conflicts;
+mod encryption;
+mod filter;
+mod gc;
+mod hashing;
+mod history;
+mod iblt;
+mod ids;
+mod index;
+mod journal;
+mod limits;
+mod merge;
+mod migrations;
+mod refs;
+mod repair;
+mod restore;
+mod roots;
+mod rotation;
+mod search;
+mod sessions;
+mod signing;
+mod snapshot;
+mod spill;
+mod stats;
+mod tombstones;
+mod trust;
+mod ttl;
+mod verify;
+
+pub use ancestry::ANCESTRY_DEPTH;
+pub use apply::ApplyBatch;
+pub use archive::{ArchiveReport, ImportPolicy};
+pub use batch::{WriteOp, WriteOutcome};
+pub use buckets::{valid_bucket_prefix, BUCKET_DEPTH, BUCKET_FANOUT};
+pub use conflicts::{ConflictPolicy, Incoming, NamespacePolicy, Resolution};
+pub use encryption::Key;
+pub use filter::Predicate;
+pub use gc::{HexRefExtractor, RefExtractor};
+pub use iblt::{valid_iblt, valid_iblt_size, IbltCell, MAX_IBLT_CELLS};
+pub use ids::{CharSet, IdPolicy, InvalidId};
+pub use limits::TooLarge;
+pub use merge::StateMerger;
+pub use migrations::plan_migrations;
+pub use restore::restore;
+pub use sessions::SyncProgress;
+pub use signing::{parse_public_key, RootRejected, SignedRoot, SigningKey};
+pub use tombstones::deletion_hash;
+pub use trust::{Peer, PeerRejected};
+pub use verify::Problem;
+
+use access::AccessLog;
+use anyhow::{bail, Context, Result};
+use bloom::BlobFilter;
+use cache::{CacheKey, Cached, ReadCache};
+use changes::ChangeFeed;
+use encryption::{open_state, seal_state, KeySlot};
+use journal::Journal;
+use redb::{
+    Builder, Database, ReadTransaction, ReadableTable, ReadableTableMetadata, TableDefinition,
+    TableHandle, UntypedTableHandle, WriteTransaction,
+};
+use roots::RootCache;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::{debug, info, instrument};
+
+// ── Table definitions ─────────────────────────────────────────────────
+
+/// Declares the tables that exist once per namespace.  The default
+/// namespace uses the bare table names (so data dirs from before namespaces
+/// existed keep working); namespace `ns` uses `<name>@<ns>`.
+macro_rules! namespaced_tables {
+    ($( $(#[$doc:meta])* $field:ident: $name:literal => <$k:ty, $v:ty>; )*) => {
+        pub(crate) struct Tables {
+            $( $field: String, )*
+        }
+
+        impl Tables {
+            fn new(namespace: &str) -> Self {
+                Self {
+                    $( $field: qualified_name($name, namespace), )*
+                }
+            }
+
+            $(
+                $(#[$doc])*
+                pub(crate) fn $field(&self) -> TableDefinition<'_, $k, $v> {
+                    TableDefinition::new(&self.$field)
+                }
+            )*
+
+            /// Create any of this namespace's tables that don't exist yet.
+            fn create_all(&self, txn: &WriteTransaction) -> Result<()> {
+                $( txn.open_table(self.$field())?; )*
+                Ok(())
+            }
+
+            /// Copy this namespace's tables from `src` into `dst`.
+            fn copy_all(&self, src: &ReadTransaction, dst: &WriteTransaction) -> Result<()> {
+                $( backup::copy_table(src, dst, self.$field())?; )*
+                Ok(())
+            }
+        }
+    };
+}
+
+namespaced_tables! {
+    /// blake3 hash (32 bytes) → blob bytes, encoded by `codec`
+    blobs: "blobs" => <&'static [u8], &'static [u8]>;
+
+    /// blob hash → unix seconds after which the sweeper may delete it
+    blob_ttl: "blob_ttl" => <&'static [u8], u64>;
+
+    /// (expiry, blob hash) → () — blob_ttl ordered by expiry for sweeping
+    blob_expiry: "blob_expiry" => <(u64, &'static [u8]), ()>;
+
+    /// document id (utf-8) → serialised metadata
+    documents: "documents" => <&'static str, &'static [u8]>;
+
+    /// sequence → logged put or deletion, see `changelog`
+    changelog: "changelog" => <u64, &'static [u8]>;
+
+    /// document id → (created, updated) in unix seconds; dropped on
+    /// deletion, so a re-created document starts afresh
+    doc_times: "doc_times" => <&'static str, (u64, u64)>;
+
+    /// document id → (reads, last read in unix seconds), see `access`
+    doc_access: "doc_access" => <&'static str, (u64, u64)>;
+
+    /// blob hash → (first stored, last stored) in unix seconds
+    blob_times: "blob_times" => <&'static [u8], (u64, u64)>;
+
+    /// counter name → value, for `dedup_stats`
+    blob_puts: "blob_puts" => <&'static str, u64>;
+
+    /// document id → version, incremented by every put; kept after
+    /// deletion, so a re-created document's versions keep rising
+    doc_versions: "doc_versions" => <&'static str, u64>;
+
+    /// document id (utf-8) → CRDT state bytes
+    doc_data: "doc_data" => <&'static str, &'static [u8]>;
+
+    /// document id → blake3 hash of latest CRDT state (used for Merkle roots)
+    doc_hashes: "doc_hashes" => <&'static str, &'static [u8]>;
+
+    /// nibble prefix → (count, digest) of the documents under it, see
+    /// `buckets`
+    doc_buckets: "doc_buckets" => <&'static [u8], &'static [u8]>;
+
+    /// (leaf bucket prefix, document id) → () — the members of each bucket
+    bucket_docs: "bucket_docs" => <(&'static [u8], &'static str), ()>;
+
+    /// (document id, sequence) → retained version, see `history`
+    doc_history: "doc_history" => <(&'static str, u64), &'static [u8]>;
+
+    /// (document id, state hash) → state kept by the keep-both conflict
+    /// policy, see `conflicts`
+    doc_siblings: "doc_siblings" => <(&'static str, &'static [u8]), &'static [u8]>;
+
+    /// () → combined Merkle root of doc_hashes, absent while dirty, see
+    /// `roots`
+    doc_root: "doc_root" => <(), &'static [u8]>;
+
+    /// () → (epoch, root) last signed, see `signing`
+    root_epoch: "root_epoch" => <(), (u64, &'static [u8])>;
+
+    /// signer's public key → newest epoch accepted from it, see `signing`
+    signer_epochs: "signer_epochs" => <&'static [u8], u64>;
+
+    /// (document id, sequence) → hash and parents' hashes, see `ancestry`
+    doc_ancestry: "doc_ancestry" => <(&'static str, u64), &'static [u8]>;
+
+    /// deleted document id → tombstone, see `tombstones`
+    tombstones: "tombstones" => <&'static str, &'static [u8]>;
+
+    /// (meta key, meta value, document id) → (), see `index`
+    doc_index: "doc_index" => <(&'static str, &'static str, &'static str), ()>;
+
+    /// document id → bincode of its indexed (key, value) pairs
+    doc_index_keys: "doc_index_keys" => <&'static str, &'static [u8]>;
+
+    /// (blob hash, document id) → () — blobs referenced by documents, see
+    /// `refs`
+    blob_refs: "blob_refs" => <(&'static [u8], &'static str), ()>;
+
+    /// document id → bincode of the blob hashes it references
+    doc_refs: "doc_refs" => <&'static str, &'static [u8]>;
+
+    /// document id → (), documents the search index is behind on, see
+    /// `search`
+    search_queue: "search_queue" => <&'static str, ()>;
+
+    /// (document id, name) → attached blob hash, see `attachments`
+    attachments: "attachments" => <(&'static str, &'static str), &'static [u8]>;
+
+    /// (blob hash, document id, name) → () — attachments by blob
+    attached_blobs: "attached_blobs" => <(&'static [u8], &'static str, &'static str), ()>;
+
+    /// sync session id → bincode of its progress, see `sessions`
+    sync_sessions: "sync_sessions" => <u64, &'static [u8]>;
+
+    /// peer id → (changelog seq, combined root, unix seconds) it last
+    /// acknowledged, see `checkpoints`
+    sync_checkpoints: "sync_checkpoints" => <&'static str, (u64, &'static [u8], u64)>;
+
+    /// peer id → (changelog seq, combined root) its last `GetChangesSince`
+    /// brings it up to, until it acknowledges them, see `checkpoints`
+    sync_offers: "sync_offers" => <&'static str, (u64, &'static [u8])>;
+}
+
+/// Database-wide bookkeeping (not namespaced): key → value.
+const STORE_META: TableDefinition<&str, u64> = TableDefinition::new("store_meta");
+
+/// STORE_META key: unix seconds of the last successful compaction.
+const LAST_COMPACTION: &str = "last_compaction";
+
+/// STORE_META key: schema version the database was last migrated to, see
+/// `migrations`.
+const SCHEMA_VERSION: &str = "schema_version";
+
+/// STORE_META key: id of the key the database is encrypted with, see
+/// `encryption`.
+const KEY_ID: &str = "key_id";
+
+/// STORE_META key: id the next sync session gets, see `sessions`.
+const NEXT_SYNC_SESSION: &str = "next_sync_session";
+
+/// Retired encryption key id → the key sealed under the current key, see
+/// `rotation`.
+const STORE_KEYS: TableDefinition<u64, &[u8]> = TableDefinition::new("store_keys");
+
+/// Peer id → bincode of a registered sync peer, see `trust`.
+const PEERS: TableDefinition<&str, &[u8]> = TableDefinition::new("peers");
+
+const DB_FILE: &str = "keyring.redb";
+
+const NAMESPACE_SEPARATOR: char = '@';
+
+fn qualified_name(base: &str, namespace: &str) -> String {
+    if namespace.is_empty() {
+        base.to_string()
+    } else {
+        format!("{base}{NAMESPACE_SEPARATOR}{namespace}")
+    }
+}
+
+/// Check that `namespace` can be embedded in table and directory names.
+/// The empty string is the default namespace.
+pub fn validate_namespace(namespace: &str) -> Result<()> {
+    let valid = namespace.len() <= 64
+        && namespace
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'));
+    if !valid {
+        bail!("invalid namespace {namespace:?}: use at most 64 of [A-Za-z0-9_.-]");
+    }
+    Ok(())
+}
+
+/// Every non-default namespace among `tables` (a transaction's
+/// `list_tables`).
+fn namespaces_of(tables: impl Iterator<Item = UntypedTableHandle>) -> Vec<String> {
+    let prefix = qualified_name("documents", "x");
+    let prefix = &prefix[..prefix.len() - 1];
+    let mut out: Vec<String> = tables
+        .filter_map(|table| table.name().strip_prefix(prefix).map(str::to_string))
+        .collect();
+    out.sort();
+    out
+}
+
+// ── Store ─────────────────────────────────────────────────────────────
+
+/// Tunables fixed at open time.
+#[derive(Debug, Clone)]
+pub struct StoreOptions {
+    /// zstd level for blob values; `None` stores blobs uncompressed.
+    pub compression_level: Option<i32>,
+    /// Blobs at least this large are written to spill files instead of
+    /// redb; `None` keeps everything in the database.
+    pub spill_threshold: Option<usize>,
+    /// Where new blobs go; `Files` spills every blob, whatever its size.
+    /// Blobs already stored stay where they are.
+    pub blob_backend: BlobBackend,
+    /// Bytes of documents (and, without `blob_cache_bytes`, blobs) kept in
+    /// the in-process read cache (0 disables it).
+    pub cache_bytes: usize,
+    /// Bytes of decoded blobs kept in a cache of their own, so large blobs
+    /// don't evict documents; `None` caches blobs alongside documents
+    /// within `cache_bytes`.
+    pub blob_cache_bytes: Option<usize>,
+    /// Default durability of write transactions.
+    pub durability: Durability,
+    /// Versions of each document kept in its history (0 disables history).
+    pub history_depth: usize,
+    /// Key to encrypt blob and CRDT values with; without one, a database
+    /// that is already encrypted waits for `Store::provide_key`.
+    pub encryption_key: Option<Key>,
+    /// Key to sign combined roots with, see `signing`.
+    pub signing_key: Option<SigningKey>,
+    /// Public keys whose signed roots `ApplyChanges` trusts.
+    pub trusted_signers: Vec<[u8; 32]>,
+    /// Refuse `ApplyChanges` without a root signed by a trusted signer.
+    pub require_signed_roots: bool,
+    /// Finds the blobs each document references, for `who_references` and
+    /// GC marking.
+    pub ref_extractor: Arc<dyn RefExtractor>,
+    /// Combines the CRDT states peers send with conflicting local ones,
+    /// for documents whose metadata names no engine in `crdt_engines`;
+    /// `None` keeps both (see `merge`).
+    pub merger: Option<Arc<dyn StateMerger>>,
+    /// CRDT engines by the name a document's `crdt` metadata field gives;
+    /// a conflict on a document naming one not here fails.
+    pub crdt_engines: HashMap<String, Arc<dyn StateMerger>>,
+    /// How changes that conflict with a document without a CRDT engine are
+    /// settled, see `conflicts`.
+    pub conflict_policy: ConflictPolicy,
+    /// `conflict_policy` overrides by namespace name.
+    pub namespace_conflict_policies: HashMap<String, ConflictPolicy>,
+    /// Top-level text fields of the (CBOR) meta indexed for `search`.
+    pub search_fields: Vec<String>,
+    /// Count document reads for `hot_documents`.
+    pub track_access: bool,
+    /// Largest blob a put accepts, in bytes.
+    pub max_blob_bytes: Option<u64>,
+    /// Largest document (meta plus CRDT state) a put accepts, in bytes.
+    pub max_doc_bytes: Option<u64>,
+    /// Bytes of pages redb caches per database file, tenants included; a
+    /// tenth goes to writes.
+    pub db_cache_bytes: usize,
+    /// Create new database files in redb's v3 file format.  Existing files
+    /// keep their format.
+    pub db_file_format_v3: bool,
+    /// Whether `open` may create the database, or must.
+    pub create: CreateMode,
+    /// Whether `open` migrates a database from an older version, or
+    /// refuses it.
+    pub migrate_on_open: bool,
+    /// Which document ids writes accept.
+    pub id_policy: IdPolicy,
+    /// Open the snapshot the serving process last published instead of
+    /// the database, refusing writes (see `snapshot`).
+    pub read_only: bool,
+}
+
+impl Default for StoreOptions {
+    fn default() -> Self {
+        Self {
+            compression_level: Some(3),
+            spill_threshold: Some(1024 * 1024),
+            blob_backend: BlobBackend::Redb,
+            cache_bytes: 64 * 1024 * 1024,
+            blob_cache_bytes: None,
+            durability: Durability::Immediate,
+            history_depth: 10,
+            encryption_key: None,
+            signing_key: None,
+            trusted_signers: Vec::new(),
+            require_signed_roots: false,
+            ref_extractor: Arc::new(HexRefExtractor),
+            merger: merge::default_merger(),
+            crdt_engines: merge::default_engines(),
+            conflict_policy: ConflictPolicy::default(),
+            namespace_conflict_policies: HashMap::new(),
+            search_fields: Vec::new(),
+            track_access: false,
+            max_blob_bytes: None,
+            max_doc_bytes: None,
+            db_cache_bytes: 1024 * 1024 * 1024,
+            db_file_format_v3: false,
+            create: CreateMode::IfMissing,
+            migrate_on_open: true,
+            id_policy: IdPolicy::default(),
+            read_only: false,
+        }
+    }
+}
+
+/// How hard a write transaction tries to reach disk before it reports
+/// success.  Maps onto redb's durability levels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Durability {
+    /// No fsync; the commit is lost if the process crashes before a later
+    /// durable commit.  redb cannot free pages until one happens, so the
+    /// file grows while only non-durable commits are made.
+    None,
+    /// fsync in the background; survives a process crash but not
+    /// necessarily power loss.
+    Eventual,
+    /// fsync before the commit returns.
+    Immediate,
+}
+
+impl From<Durability> for redb::Durability {
+    fn from(d: Durability) -> Self {
+        match d {
+            Durability::None => redb::Durability::None,
+            Durability::Eventual => redb::Durability::Eventual,
+            Durability::Immediate => redb::Durability::Immediate,
+        }
+    }
+}
+
+impl FromStr for Durability {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "none" => Ok(Durability::None),
+            "eventual" => Ok(Durability::Eventual),
+            "immediate" => Ok(Durability::Immediate),
+            other => bail!("unknown durability {other:?} (none, eventual, immediate)"),
+        }
+    }
+}
+
+/// Where new blob contents go.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BlobBackend {
+    /// Inside redb, compressed, with blobs over `spill_threshold` in spill
+    /// files.
+    #[default]
+    Redb,
+    /// Every blob in a spill file, uncompressed, with only its header in
+    /// redb, so the blob directory can be rsynced or reflinked as is.
+    Files,
+}
+
+impl FromStr for BlobBackend {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "redb" => Ok(BlobBackend::Redb),
+            "files" => Ok(BlobBackend::Files),
+            other => bail!("unknown blob backend {other:?} (redb, files)"),
+        }
+    }
+}
+
+/// Whether `Store::open` may create the database file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CreateMode {
+    /// Create a new database; refuse to open one that already exists.
+    Always,
+    /// Open the database, creating it first if there is none.
+    #[default]
+    IfMissing,
+    /// Open the database; refuse if there is none, e.g. because the data
+    /// volume failed to mount.
+    Never,
+}
+
+impl FromStr for CreateMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "always" => Ok(CreateMode::Always),
+            "if-missing" => Ok(CreateMode::IfMissing),
+            "never" => Ok(CreateMode::Never),
+            other => bail!("unknown create mode {other:?} (always, if-missing, never)"),
+        }
+    }
+}
+
+/// Shared database.  Compaction needs `&mut Database`, so handles reach it
+/// through a lock held only while a transaction is being started.
+struct Db {
+    database: RwLock<Database>,
+    /// Set when the database is a private copy of a snapshot, which refuses
+    /// writes; dropped after `database`, removing the copy.
+    snapshot: OnceLock<snapshot::PrivateCopy>,
+}
+
+impl Db {
+    fn begin_read(&self) -> Result<ReadTransaction> {
+        Ok(self.database.read().unwrap_or_else(|e| e.into_inner()).begin_read()?)
+    }
+
+    fn begin_write(&self) -> Result<WriteTransaction> {
+        if self.snapshot.get().is_some() {
+            bail!("the store is open read-only, on a snapshot");
+        }
+        Ok(self.database.read().unwrap_or_else(|e| e.into_inner()).begin_write()?)
+    }
+
+    /// A write transaction for what redb only reports from one, such as
+    /// page usage.  The caller aborts it, so read-only stores allow it.
+    fn begin_inspection(&self) -> Result<WriteTransaction> {
+        Ok(self.database.read().unwrap_or_else(|e| e.into_inner()).begin_write()?)
+    }
+
+    /// Fails if any transaction is still open.
+    fn compact(&self) -> Result<bool> {
+        if self.snapshot.get().is_some() {
+            bail!("the store is open read-only, on a snapshot");
+        }
+        Ok(self.database.write().unwrap_or_else(|e| e.into_inner()).compact()?)
+    }
+}
+
+/// When a document or blob was first and last stored, in unix seconds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Timestamps {
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+impl Timestamps {
+    fn from_row((created_at, updated_at): (u64, u64)) -> Self {
+        Self {
+            created_at,
+            updated_at,
+        }
+    }
+}
+
+/// A document as `get_document` returns it.
+#[derive(Debug, Clone)]
+pub struct Document {
+    pub meta: Vec<u8>,
+    pub crdt_state: Vec<u8>,
+    /// Number of times the document has been put, see `doc_versions`.
+    pub version: u64,
+    /// `None` for documents last stored before timestamps were recorded.
+    pub times: Option<Timestamps>,
+}
+
+/// redb's builder for the database file, from `options`.
+fn db_builder(options: &StoreOptions) -> Builder {
+    let mut builder = Builder::new();
+    builder
+        .set_cache_size(options.db_cache_bytes)
+        .create_with_file_format_v3(options.db_file_format_v3)
+        .set_repair_callback(|session| {
+            let percent = (session.progress() * 100.0) as u32;
+            info!(percent, "repairing database after an unclean shutdown");
+        });
+    builder
+}
+
+/// Handle on the database, scoped to one namespace.  Cloning is cheap;
+/// `namespace` derives handles for other namespaces of the same database.
+#[derive(Clone)]
+pub struct Store {
+    db: Arc<Db>,
+    dir: PathBuf,
+    options: Arc<StoreOptions>,
+    /// Read cache shared by every namespace of the database.
+    cache: Arc<ReadCache>,
+    /// Cache of decoded blobs; the same as `cache` unless
+    /// `blob_cache_bytes` is set.
+    blob_cache: Arc<ReadCache>,
+    /// Namespaces whose tables are known to exist.
+    namespaces: Arc<Mutex<HashMap<String, Arc<Tables>>>>,
+    namespace: String,
+    tables: Arc<Tables>,
+    /// Directory holding this namespace's spill files.
+    spill_dir: PathBuf,
+    /// Durability of this handle's write transactions.
+    durability: Durability,
+    /// Encryption key, shared by every namespace of the database.
+    keys: Arc<KeySlot>,
+    /// Document changes awaiting the port's watches, from every namespace.
+    changes: Arc<ChangeFeed>,
+    /// Reads not yet written to doc_access, from every namespace.
+    access: Arc<AccessLog>,
+    /// Pending spill file changes, shared by every namespace.
+    journal: Arc<Journal>,
+    /// Blob hashes of every namespace, so `has_blob` can skip redb.
+    blob_filter: Arc<BlobFilter>,
+    /// Combined roots of every namespace, see `roots`.
+    roots: Arc<RootCache>,
+    /// Full-text index of every namespace, if search fields are set.
+    search: Option<Arc<search::SearchIndex>>,
+}
+
+impl Store {
+    /// Open (or, as `options.create` allows, create) the database at
+    /// `dir/keyring.redb`, returning a handle on the default namespace.
+    /// With `options.read_only`, open the snapshot last published in `dir`
+    /// instead.
+    pub fn open(dir: &Path, options: StoreOptions) -> Result<Self> {
+        if options.read_only {
+            return snapshot::open(dir, options);
+        }
+        let db_path = dir.join(DB_FILE);
+        // redb initializes an empty file as a new database.
+        let exists = std::fs::metadata(&db_path).is_ok_and(|m| m.len() > 0);
+        match (options.create, exists) {
+            (CreateMode::Never, false) => bail!(
+                "no database at {} and the create mode is `never`; is the data volume mounted?",
+                db_path.display()
+            ),
+            (CreateMode::Always, true) => bail!(
+                "database {} already exists and the create mode is `always`",
+                db_path.display()
+            ),
+            _ => {}
+        }
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("creating data dir {}", dir.display()))?;
+        let builder = db_builder(&options);
+        let db = match builder.create(&db_path) {
+            // redb locks the file for one process, readers included; a
+            // second process can only read a snapshot (see `snapshot`).
+            Err(redb::DatabaseError::DatabaseAlreadyOpen) => bail!(
+                "database {} is in use by another process; open it with --read-only to \
+                 read the snapshots that process publishes",
+                db_path.display()
+            ),
+            db => db.with_context(|| format!("opening database {}", db_path.display()))?,
+        };
+        let db = migrations::migrate(db, &db_path, &builder, options.migrate_on_open)?;
+
+        // Ensure all tables exist.
+        let tables = Arc::new(Tables::new(""));
+        let txn = db.begin_write()?;
+        tables.create_all(&txn)?;
+        let key_id = txn.open_table(STORE_META)?.get(KEY_ID)?.map(|v| v.value());
+        txn.open_table(STORE_KEYS)?;
+        txn.commit()?;
+
+        let namespaces = HashMap::from([(String::new(), tables.clone())]);
+        let cache = Arc::new(ReadCache::new(options.cache_bytes));
+        let blob_cache = match options.blob_cache_bytes {
+            Some(bytes) => Arc::new(ReadCache::new(bytes)),
+            None => cache.clone(),
+        };
+        let mut store = Self {
+            db: Arc::new(Db {
+                database: RwLock::new(db),
+                snapshot: OnceLock::new(),
+            }),
+            dir: dir.to_path_buf(),
+            durability: options.durability,
+            cache,
+            blob_cache,
+            options: Arc::new(options),
+            namespaces: Arc::new(Mutex::new(namespaces)),
+            namespace: String::new(),
+            tables,
+            spill_dir: dir.join(qualified_name(spill::SPILL_DIR, "")),
+            keys: Arc::new(KeySlot::new(key_id)),
+            changes: Arc::default(),
+            access: Arc::default(),
+            journal: Arc::new(Journal::open(dir)?),
+            blob_filter: Arc::default(),
+            roots: Arc::default(),
+            search: None,
+        };
+        // Settle what an interrupted run left in the journal.
+        store.begin_write()?.commit()?;
+        if let Some(key) = store.options.encryption_key.clone() {
+            store.provide_key(key)?;
+        }
+        store.index_refs_once()?;
+        store.open_search_index()?;
+        store.blob_filter = Arc::new(store.build_blob_filter()?);
+        Ok(store)
+    }
+
+    /// Handle on `namespace` of the same database, creating its tables on
+    /// first use.
+    pub fn namespace(&self, namespace: &str) -> Result<Store> {
+        if namespace == self.namespace {
+            return Ok(self.clone());
+        }
+        validate_namespace(namespace)?;
+
+        let mut known = self.namespaces.lock().unwrap_or_else(|e| e.into_inner());
+        let tables = match known.get(namespace) {
+            Some(tables) => tables.clone(),
+            None => {
+                let tables = Arc::new(Tables::new(namespace));
+                let txn = self.db.begin_write()?;
+                tables.create_all(&txn)?;
+                txn.commit()?;
+                debug!(namespace, "namespace tables ready");
+                known.insert(namespace.to_string(), tables.clone());
+                tables
+            }
+        };
+
+        Ok(Store {
+            namespace: namespace.to_string(),
+            tables,
+            spill_dir: self.dir.join(qualified_name(spill::SPILL_DIR, namespace)),
+            ..self.clone()
+        })
+    }
+
+    /// Namespace this handle is scoped to; empty for the default one.
+    pub fn namespace_name(&self) -> &str {
+        &self.namespace
+    }
+
+    /// Directory the database lives in.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn options(&self) -> &StoreOptions {
+        &self.options
+    }
+
+    /// The same handle with its writes committed at `durability`, e.g. for a
+    /// bulk import that will be followed by a durable commit.
+    pub fn with_durability(&self, durability: Durability) -> Store {
+        Store {
+            durability,
+            ..self.clone()
+        }
+    }
+
+    /// Whether two handles write to the same database and namespace at the
+    /// same durability, so their writes can share a transaction.
+    pub fn same_target(&self, other: &Store) -> bool {
+        Arc::ptr_eq(&self.db, &other.db)
+            && self.namespace == other.namespace
+            && self.durability == other.durability
+    }
+
+    /// Whether two handles are on the same database.
+    pub fn same_database(&self, other: &Store) -> bool {
+        Arc::ptr_eq(&self.db, &other.db)
+    }
+
+    /// Drop `keys` from the read cache, and their namespaces' combined
+    /// roots, once the writes to them have committed, and note the
+    /// documents among them for watches.
+    fn committed<'a>(&self, keys: impl IntoIterator<Item = &'a CacheKey> + Clone) {
+        self.cache.invalidate(keys.clone());
+        if !Arc::ptr_eq(&self.cache, &self.blob_cache) {
+            self.blob_cache.invalidate(keys.clone());
+        }
+        for key in keys.clone() {
+            if let CacheKey::Document { namespace, .. } = key {
+                self.roots.invalidate(namespace);
+            }
+        }
+        self.changes.record(keys);
+    }
+
+    /// Start a write transaction at this handle's durability.
+    fn begin_write(&self) -> Result<WriteTransaction> {
+        let mut txn = self.db.begin_write()?;
+        txn.set_durability(self.durability.into());
+        self.settle_journal(&txn);
+        Ok(txn)
+    }
+
+    fn document_key(&self, id: &str) -> CacheKey {
+        CacheKey::Document {
+            namespace: self.namespace.clone(),
+            id: id.to_string(),
+        }
+    }
+
+    fn blob_key(&self, hash: &[u8]) -> CacheKey {
+        CacheKey::Blob {
+            namespace: self.namespace.clone(),
+            hash: hash.to_vec(),
+        }
+    }
+
+    /// Every non-default namespace that has tables in the database.
+    pub fn namespaces(&self) -> Result<Vec<String>> {
+        Ok(namespaces_of(self.db.begin_read()?.list_tables()?))
+    }
+
+    // ── Documents ─────────────────────────────────────────────────────
+
+    /// Store or update a document (metadata + CRDT state).  `index`
+    /// replaces the document's secondary-index entries; `None` keeps them.
+    /// Returns the document's new version, or `None` if `create_only` is
+    /// set and the document already exists.
+    #[instrument(skip(self, meta, crdt_state, index))]
+    pub fn put_document(
+        &self,
+        id: &str,
+        meta: &[u8],
+        crdt_state: &[u8],
+        index: Option<&[(String, String)]>,
+        create_only: bool,
+    ) -> Result<Option<u64>> {
+        let txn = self.begin_write()?;
+        if create_only && self.document_exists_in(&txn, id)? {
+            return Ok(None);
+        }
+        let version = self.put_document_in(&txn, id, meta, crdt_state, index)?;
+        txn.commit()?;
+        self.committed([&self.document_key(id)]);
+        Ok(Some(version))
+    }
+
+    /// Replace the metadata of an existing document, keeping its CRDT
+    /// state.  Bumps the version and updated time, re-derives the blob
+    /// references, queues it for search, and logs the put with the unchanged
+    /// state hash; history is untouched.  `None` if there is no such
+    /// document.
+    pub fn put_document_meta(&self, id: &str, meta: &[u8]) -> Result<Option<u64>> {
+        let txn = self.begin_write()?;
+        let sealed = txn.open_table(self.tables.doc_data())?.get(id)?.map(|v| v.value().to_vec());
+        let Some(sealed) = sealed else {
+            return Ok(None);
+        };
+        let crdt_state = open_state(&self.keys(), id, &sealed)?;
+        self.check_document_size(meta, &crdt_state)?;
+        let state_hash = hashing::hash(&crdt_state);
+        let version = self.record_write(&txn, id, meta, &crdt_state, state_hash.as_bytes())?;
+        txn.commit()?;
+        self.committed([&self.document_key(id)]);
+        debug!(id, version, "document meta stored");
+        Ok(Some(version))
+    }
+
+    /// What every write of `id` does, whether or not its CRDT state
+    /// changed: store `meta`, bump the version and updated time, log the
+    /// put with `state_hash`, re-derive the blob references and queue it
+    /// for search.  Returns the new version.
+    fn record_write(
+        &self,
+        txn: &WriteTransaction,
+        id: &str,
+        meta: &[u8],
+        crdt_state: &[u8],
+        state_hash: &[u8],
+    ) -> Result<u64> {
+        txn.open_table(self.tables.documents())?.insert(id, meta)?;
+
+        let mut versions = txn.open_table(self.tables.doc_versions())?;
+        let version = versions.get(id)?.map_or(0, |v| v.value()) + 1;
+        versions.insert(id, version)?;
+
+        let mut times = txn.open_table(self.tables.doc_times())?;
+        let now = unix_now();
+        let created = times.get(id)?.map_or(now, |v| v.value().0);
+        times.insert(id, (created, now))?;
+
+        changelog::append(txn, &self.tables, id, state_hash, false)?;
+        let mut blob_refs = HashSet::new();
+        self.options.ref_extractor.extract(id, meta, crdt_state, &mut blob_refs);
+        refs::set_refs(txn, &self.tables, id, &blob_refs)?;
+        if !self.options.search_fields.is_empty() {
+            search::queue_document(txn, &self.tables, id)?;
+        }
+        Ok(version)
+    }
+
+    /// Whether `id` is a live document as of `txn`.
+    pub(super) fn document_exists_in(&self, txn: &WriteTransaction, id: &str) -> Result<bool> {
+        Ok(txn.open_table(self.tables.documents())?.get(id)?.is_some())
+    }
+
+    /// `put_document` inside a caller's write transaction.  The caller
+    /// invalidates the cache entry once the transaction commits.
+    pub(super) fn put_document_in(
+        &self,
+        txn: &WriteTransaction,
+        id: &str,
+        meta: &[u8],
+        crdt_state: &[u8],
+        index: Option<&[(String, String)]>,
+    ) -> Result<u64> {
+        self.validate_id(id)?;
+        self.check_document_size(meta, crdt_state)?;
+        let state_hash = hashing::hash(crdt_state);
+        txn.open_table(self.tables.doc_data())?
+            .insert(id, seal_state(self.keys().current(), id, crdt_state)?.as_ref())?;
+        let version = self.record_write(txn, id, meta, crdt_state, state_hash.as_bytes())?;
+        let previous = buckets::set_doc_hash(txn, &self.tables, id, Some(state_hash.as_bytes()))?;
+        ancestry::record(txn, &self.tables, id, state_hash.as_bytes(), previous.as_deref())?;
+        tombstones::clear_tombstone(txn, &self.tables, id)?;
+        conflicts::clear_siblings(txn, &self.tables, id)?;
+        if let Some(pairs) = index {
+            index::set_index(txn, &self.tables, id, pairs)?;
+        }
+        if self.options.history_depth > 0 {
+            self.record_version(txn, id, state_hash.as_bytes(), crdt_state)?;
+        }
+
+        debug!(id, hash = %state_hash, version, "document stored");
+        Ok(version)
+    }
+
+    /// Get a document by id.
+    pub fn get_document(&self, id: &str) -> Result<Option<Document>> {
+        let key = self.document_key(id);
+        if let Some(Cached::Document(doc)) = self.cache.get(&key) {
+            return Ok(Some(doc));
+        }
+        let generation = self.cache.generation();
+
+        let txn = self.db.begin_read()?;
+        let doc = self.read_document(&txn, id)?;
+        if let Some(doc) = &doc {
+            self.cache.insert(key, Cached::Document(doc.clone()), generation);
+        }
+        Ok(doc)
+    }
+
+    /// Get many documents in one read transaction, serving what it can from
+    /// the cache.  The result is aligned with `ids`, with `None` for
+    /// documents that don't exist.
+    #[instrument(skip(self, ids), fields(count = ids.len()))]
+    pub fn get_documents(&self, ids: &[String]) -> Result<Vec<Option<Document>>> {
+        let generation = self.cache.generation();
+        let txn = self.db.begin_read()?;
+        let mut out = Vec::with_capacity(ids.len());
+        for id in ids {
+            let key = self.document_key(id);
+            if let Some(Cached::Document(doc)) = self.cache.get(&key) {
+                out.push(Some(doc));
+                continue;
+            }
+            let doc = self.read_document(&txn, id)?;
+            if let Some(doc) = &doc {
+                self.cache.insert(key, Cached::Document(doc.clone()), generation);
+            }
+            out.push(doc);
+        }
+        Ok(out)
+    }
+
+    fn read_document(&self, txn: &ReadTransaction, id: &str) -> Result<Option<Document>> {
+        self.document_from(
+            id,
+            &txn.open_table(self.tables.documents())?,
+            &txn.open_table(self.tables.doc_data())?,
+            &txn.open_table(self.tables.doc_versions())?,
+            &txn.open_table(self.tables.doc_times())?,
+        )
+    }
+
+    /// `id`'s document from its tables, open in a read or write transaction.
+    fn document_from(
+        &self,
+        id: &str,
+        docs: &impl ReadableTable<&'static str, &'static [u8]>,
+        data: &impl ReadableTable<&'static str, &'static [u8]>,
+        versions: &impl ReadableTable<&'static str, u64>,
+        times: &impl ReadableTable<&'static str, (u64, u64)>,
+    ) -> Result<Option<Document>> {
+        let (Some(m), Some(d)) = (docs.get(id)?, data.get(id)?) else {
+            return Ok(None);
+        };
+        Ok(Some(Document {
+            meta: m.value().to_vec(),
+            crdt_state: open_state(&self.keys(), id, d.value())?,
+            version: versions.get(id)?.map_or(0, |v| v.value()),
+            times: times.get(id)?.map(|v| Timestamps::from_row(v.value())),
+        }))
+    }
+
+    /// Delete a document, its data, history and attachments, leaving a
+    /// tombstone so the deletion syncs to peers.  With `cascade`, the blobs
+    /// that were attached to it and that no other document attaches or
+    /// references are deleted as well.
+    pub fn delete_document(&self, id: &str, cascade: bool) -> Result<bool> {
+        let txn = self.begin_write()?;
+        let attached = if cascade {
+            attachments::clear_attachments(&txn, &self.tables, id)?
+        } else {
+            Vec::new()
+        };
+        let existed = self.delete_document_in(&txn, id)?;
+        let orphans = attachments::unattached(&txn, &self.tables, id, attached)?;
+        let removed = self.remove_blobs(&txn, &orphans)?;
+        txn.commit()?;
+        self.committed([&self.document_key(id)]);
+        self.blobs_removed(removed)?;
+        Ok(existed)
+    }
+
+    /// `delete_document` inside a caller's write transaction.  The caller
+    /// invalidates the cache entry once the transaction commits.
+    pub(super) fn delete_document_in(&self, txn: &WriteTransaction, id: &str) -> Result<bool> {
+        let deleted_state = self.remove_document(txn, id)?;
+        if let Some(state_hash) = &deleted_state {
+            let hash = deletion_hash(state_hash);
+            tombstones::write_tombstone(txn, &self.tables, id, &hash, state_hash, unix_now())?;
+        }
+        Ok(deleted_state.is_some())
+    }
+
+    /// Remove every row of a document in `txn`.  Returns its state hash if
+    /// the document existed.
+    fn remove_document(&self, txn: &WriteTransaction, id: &str) -> Result<Option<Vec<u8>>> {
+        let existed = txn.open_table(self.tables.documents())?.remove(id)?.is_some();
+        txn.open_table(self.tables.doc_data())?.remove(id)?;
+        txn.open_table(self.tables.doc_times())?.remove(id)?;
+        txn.open_table(self.tables.doc_access())?.remove(id)?;
+        let state_hash = buckets::set_doc_hash(txn, &self.tables, id, None)?;
+        history::clear_history(txn, &self.tables, id)?;
+        conflicts::clear_siblings(txn, &self.tables, id)?;
+        index::clear_index(txn, &self.tables, id)?;
+        refs::clear_refs(txn, &self.tables, id)?;
+        if !self.options.search_fields.is_empty() {
+            search::queue_document(txn, &self.tables, id)?;
+        }
+        attachments::clear_attachments(txn, &self.tables, id)?;
+        Ok(state_hash.filter(|_| existed))
+    }
+
+    /// List the ids starting with `prefix` (all ids for an empty prefix).
+    pub fn list_documents(&self, prefix: &str) -> Result<Vec<String>> {
+        let txn = self.db.begin_read()?;
+        let docs = txn.open_table(self.tables.documents())?;
+        let mut ids = Vec::new();
+        // Ids sort bytewise, so those with the prefix are one contiguous run.
+        for entry in docs.range(prefix..)? {
+            let (k, _v) = entry?;
+            let id = k.value();
+            if !id.starts_with(prefix) {
+                break;
+            }
+            ids.push(id.to_string());
+        }
+        Ok(ids)
+    }
+
+    /// `list_documents` with each document's timestamps.
+    pub fn list_documents_with_times(
+        &self,
+        prefix: &str,
+    ) -> Result<Vec<(String, Option<Timestamps>)>> {
+        let txn = self.db.begin_read()?;
+        let docs = txn.open_table(self.tables.documents())?;
+        let times = txn.open_table(self.tables.doc_times())?;
+        let mut out = Vec::new();
+        for entry in docs.range(prefix..)? {
+            let (k, _v) = entry?;
+            let id = k.value();
+            if !id.starts_with(prefix) {
+                break;
+            }
+            let row = times.get(id)?.map(|v| Timestamps::from_row(v.value()));
+            out.push((id.to_string(), row));
+        }
+        Ok(out)
+    }
+
+    /// Up to `limit` ids in `[start, end)` in id order (`end: None` runs to
+    /// the last id), plus the id the next page starts at if there are more.
+    pub fn list_documents_range(
+        &self,
+        start: &str,
+        end: Option<&str>,
+        limit: usize,
+    ) -> Result<(Vec<String>, Option<String>)> {
+        let txn = self.db.begin_read()?;
+        let docs = txn.open_table(self.tables.documents())?;
+        let range = match end {
+            Some(end) if end <= start => return Ok((Vec::new(), None)),
+            Some(end) => docs.range(start..end)?,
+            None => docs.range(start..)?,
+        };
+        let mut ids = Vec::new();
+        for entry in range {
+            let (k, _v) = entry?;
+            if ids.len() == limit {
+                return Ok((ids, Some(k.value().to_string())));
+            }
+            ids.push(k.value().to_string());
+        }
+        Ok((ids, None))
+    }
+
+    /// Number of documents whose id starts with `prefix`, counted from the
+    /// B-tree without collecting ids.
+    pub fn count_documents(&self, prefix: &str) -> Result<u64> {
+        let txn = self.db.begin_read()?;
+        let docs = txn.open_table(self.tables.documents())?;
+        if prefix.is_empty() {
+            return Ok(docs.len()?);
+        }
+        let mut count = 0;
+        for entry in docs.range(prefix..)? {
+            let (k, _v) = entry?;
+            if !k.value().starts_with(prefix) {
+                break;
+            }
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    // ── Hashes / roots ────────────────────────────────────────────────
+
+    /// Get the state hash for a document.
+    pub fn get_doc_hash(&self, id: &str) -> Result<Option<Vec<u8>>> {
+        let txn = self.db.begin_read()?;
+        let hashes = txn.open_table(self.tables.doc_hashes())?;
+        Ok(hashes.get(id)?.map(|v| v.value().to_vec()))
+    }
+
+    /// Get hashes for a set of document ids.
+    pub fn get_doc_hashes(&self, ids: &[String]) -> Result<Vec<(String, Vec<u8>)>> {
+        let txn = self.db.begin_read()?;
+        let hashes = txn.open_table(self.tables.doc_hashes())?;
+        let mut out = Vec::new();
+        for id in ids {
+            if let Some(v) = hashes.get(id.as_str())? {
+                out.push((id.clone(), v.value().to_vec()));
+            }
+        }
+        Ok(out)
+    }
+
+    /// Length of each document's CRDT state, aligned with `ids`, with
+    /// `None` for ids that aren't live documents.  States aren't decoded.
+    pub fn state_sizes(&self, ids: &[String]) -> Result<Vec<Option<u64>>> {
+        let txn = self.db.begin_read()?;
+        let data = txn.open_table(self.tables.doc_data())?;
+        let mut out = Vec::with_capacity(ids.len());
+        for id in ids {
+            let size = match data.get(id.as_str())? {
+                // Unsealed states are raw bytes, not codec values.
+                Some(v) => Some(match codec::sealed_body(v.value()) {
+                    Ok(Some(_)) => codec::original_len(v.value())?,
+                    _ => v.value().len() as u64,
+                }),
+                None => None,
+            };
+            out.push(size);
+        }
+        Ok(out)
+    }
+
+    /// Get the hashes of the ids starting with `prefix`, in id order.
+    pub fn doc_hashes_with_prefix(&self, prefix: &str) -> Result<Vec<(String, Vec<u8>)>> {
+        let txn = self.db.begin_read()?;
+        let hashes = txn.open_table(self.tables.doc_hashes())?;
+        let mut out = Vec::new();
+        for entry in hashes.range(prefix..)? {
+            let (k, v) = entry?;
+            if !k.value().starts_with(prefix) {
+                break;
+            }
+            out.push((k.value().to_string(), v.value().to_vec()));
+        }
+        Ok(out)
+    }
+
+    /// Get all document hashes (for full sync).
+    pub fn all_doc_hashes(&self) -> Result<Vec<(String, Vec<u8>)>> {
+        let txn = self.db.begin_read()?;
+        let hashes = txn.open_table(self.tables.doc_hashes())?;
+        let mut out = Vec::new();
+        let iter = hashes.iter()?;
+        for entry in iter {
+            let (k, v) = entry?;
+            out.push((k.value().to_string(), v.value().to_vec()));
+        }
+        Ok(out)
+    }
+}
+
+/// Current wall-clock time in unix seconds.
+pub(crate) fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Lowercase hex, as hashes appear in paths, archives and reports.
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Undo `to_hex` (either case).
+pub fn from_hex(text: &str) -> Result<Vec<u8>> {
+    if !text.len().is_multiple_of(2) || !text.bytes().all(|b| b.is_ascii_hexdigit()) {
+        bail!("malformed hex {text:?}");
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| Ok(u8::from_str_radix(&text[i..i + 2], 16)?))
+        .collect()
+}
@@ -0,0 +1,259 @@
+//! Reverse index from blobs to the documents that reference them.
+//!
+//! `put_document` runs `StoreOptions::ref_extractor` over the document and
+//! records each hash it finds as a blob_refs row (blob hash, doc id) → (),
+//! so `who_references` is a range scan and GC marks from the index instead
+//! of rereading every document.  doc_refs remembers each document's hashes
+//! so they can be replaced or removed.
+//!
+//! Databases from before the index are indexed once, on the first open
+//! (or `provide_key`) that can read their CRDT states; `REFS_INDEXED` in
+//! STORE_META records that this has happened.  A migration can't do it, as
+//! it runs before the key is known.
+
+use super::encryption::{open_state, Keys};
+use super::verify::Problems;
+use super::{to_hex, Store, Tables, STORE_META};
+use anyhow::Result;
+use redb::{ReadableTable, WriteTransaction};
+use std::collections::{HashMap, HashSet};
+use tracing::info;
+
+/// STORE_META key: set once every namespace's blob_refs is complete.
+const REFS_INDEXED: &str = "refs_indexed";
+
+/// Replace the references recorded for `id` with `refs`.
+pub(super) fn set_refs(
+    txn: &WriteTransaction,
+    tables: &Tables,
+    id: &str,
+    refs: &HashSet<Vec<u8>>,
+) -> Result<()> {
+    clear_refs(txn, tables, id)?;
+    if refs.is_empty() {
+        return Ok(());
+    }
+    let mut index = txn.open_table(tables.blob_refs())?;
+    for hash in refs {
+        index.insert((hash.as_slice(), id), ())?;
+    }
+    let mut sorted: Vec<&Vec<u8>> = refs.iter().collect();
+    sorted.sort();
+    let encoded = bincode::serialize(&sorted)?;
+    txn.open_table(tables.doc_refs())?
+        .insert(id, encoded.as_slice())?;
+    Ok(())
+}
+
+/// Remove every reference recorded for `id`.
+pub(super) fn clear_refs(txn: &WriteTransaction, tables: &Tables, id: &str) -> Result<()> {
+    let mut doc_refs = txn.open_table(tables.doc_refs())?;
+    let Some(encoded) = doc_refs.remove(id)? else {
+        return Ok(());
+    };
+    let hashes: Vec<Vec<u8>> = bincode::deserialize(encoded.value())?;
+    let mut index = txn.open_table(tables.blob_refs())?;
+    for hash in &hashes {
+        index.remove((hash.as_slice(), id))?;
+    }
+    Ok(())
+}
+
+/// Whether a document other than `except` references `hash` as of `txn`.
+pub(super) fn is_referenced(
+    txn: &WriteTransaction,
+    tables: &Tables,
+    hash: &[u8],
+    except: &str,
+) -> Result<bool> {
+    let index = txn.open_table(tables.blob_refs())?;
+    for entry in index.range((hash, "")..)? {
+        let (key, _) = entry?;
+        let (referenced, doc_id) = key.value();
+        if referenced != hash {
+            break;
+        }
+        if doc_id != except {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Make blob_refs and doc_refs match what the extractor finds in every
+/// document of `store`'s namespace, noting each row that changed.
+pub(super) fn rebuild_refs(
+    txn: &WriteTransaction,
+    store: &Store,
+    keys: &Keys,
+    fixes: &mut Problems,
+) -> Result<()> {
+    let tables = &store.tables;
+    let mut expected = HashMap::new();
+    {
+        let docs = txn.open_table(tables.documents())?;
+        let data = txn.open_table(tables.doc_data())?;
+        for entry in docs.iter()? {
+            let (id, meta) = entry?;
+            let state = match data.get(id.value())? {
+                Some(state) => open_state(keys, id.value(), state.value())?,
+                None => Vec::new(),
+            };
+            let mut refs = HashSet::new();
+            let extractor = &store.options.ref_extractor;
+            extractor.extract(id.value(), meta.value(), &state, &mut refs);
+            expected.insert(id.value().to_string(), refs);
+        }
+    }
+
+    let mut orphaned = Vec::new();
+    let mut outdated = HashSet::new();
+    {
+        let index = txn.open_table(tables.blob_refs())?;
+        for entry in txn.open_table(tables.doc_refs())?.iter()? {
+            let (id, encoded) = entry?;
+            let id = id.value();
+            let Some(refs) = expected.get(id) else {
+                orphaned.push(id.to_string());
+                continue;
+            };
+            let recorded: HashSet<Vec<u8>> = bincode::deserialize(encoded.value())?;
+            let mut complete = true;
+            for hash in refs {
+                complete &= index.get((hash.as_slice(), id))?.is_some();
+            }
+            if recorded != *refs || !complete {
+                outdated.insert(id.to_string());
+            }
+        }
+    }
+    for id in orphaned {
+        clear_refs(txn, tables, &id)?;
+        fixes.push(tables.doc_refs(), id, "removed references of a missing document");
+    }
+    let indexed: HashSet<String> = {
+        let doc_refs = txn.open_table(tables.doc_refs())?;
+        let mut ids = HashSet::new();
+        for entry in doc_refs.iter()? {
+            ids.insert(entry?.0.value().to_string());
+        }
+        ids
+    };
+    for (id, refs) in &expected {
+        if outdated.contains(id) || (!indexed.contains(id) && !refs.is_empty()) {
+            set_refs(txn, tables, id, refs)?;
+            fixes.push(tables.blob_refs(), id.as_str(), "re-indexed blob references");
+        }
+    }
+
+    let mut stale = Vec::new();
+    for entry in txn.open_table(tables.blob_refs())?.iter()? {
+        let (key, _) = entry?;
+        let (hash, id) = key.value();
+        if !expected.get(id).is_some_and(|refs| refs.contains(hash)) {
+            stale.push((hash.to_vec(), id.to_string()));
+        }
+    }
+    let mut index = txn.open_table(tables.blob_refs())?;
+    for (hash, id) in stale {
+        index.remove((hash.as_slice(), id.as_str()))?;
+        fixes.push(tables.blob_refs(), to_hex(&hash), "removed stale reference");
+    }
+    Ok(())
+}
+
+impl Store {
+    /// Ids of the documents whose metadata or CRDT state references the
+    /// blob `hash` or that have it attached, in id order.
+    pub fn who_references(&self, hash: &[u8]) -> Result<Vec<String>> {
+        let txn = self.db.begin_read()?;
+        let index = txn.open_table(self.tables.blob_refs())?;
+        let mut ids = Vec::new();
+        for entry in index.range((hash, "")..)? {
+            let (key, _) = entry?;
+            let (k_hash, id) = key.value();
+            if k_hash != hash {
+                break;
+            }
+            ids.push(id.to_string());
+        }
+        let attached = txn.open_table(self.tables.attached_blobs())?;
+        for entry in attached.range((hash, "", "")..)? {
+            let (key, _) = entry?;
+            let (k_hash, id, _) = key.value();
+            if k_hash != hash {
+                break;
+            }
+            ids.push(id.to_string());
+        }
+        ids.sort();
+        ids.dedup();
+        Ok(ids)
+    }
+
+    /// Hashes of the blobs `id` references or has attached, sorted, for
+    /// peers to fetch along with it.
+    pub fn document_blobs(&self, id: &str) -> Result<Vec<Vec<u8>>> {
+        let txn = self.db.begin_read()?;
+        let mut hashes: Vec<Vec<u8>> = match txn.open_table(self.tables.doc_refs())?.get(id)? {
+            Some(encoded) => bincode::deserialize(encoded.value())?,
+            None => Vec::new(),
+        };
+        let attachments = txn.open_table(self.tables.attachments())?;
+        for entry in attachments.range((id, "")..)? {
+            let (key, hash) = entry?;
+            if key.value().0 != id {
+                break;
+            }
+            hashes.push(hash.value().to_vec());
+        }
+        hashes.sort();
+        hashes.dedup();
+        Ok(hashes)
+    }
+
+    /// Add every blob some document references to `refs`, for GC marking.
+    pub(super) fn indexed_refs(
+        &self,
+        txn: &WriteTransaction,
+        refs: &mut HashSet<Vec<u8>>,
+    ) -> Result<()> {
+        let index = txn.open_table(self.tables.blob_refs())?;
+        for entry in index.iter()? {
+            let (key, _) = entry?;
+            refs.insert(key.value().0.to_vec());
+        }
+        Ok(())
+    }
+
+    /// Index every namespace's references unless `REFS_INDEXED` says that
+    /// has been done.  Does nothing while the key is missing.
+    pub(super) fn index_refs_once(&self) -> Result<()> {
+        if self.needs_key() {
+            return Ok(());
+        }
+        let done = {
+            let txn = self.db.begin_read()?;
+            let meta = txn.open_table(STORE_META)?;
+            meta.get(REFS_INDEXED)?.is_some()
+        };
+        if done {
+            return Ok(());
+        }
+
+        let mut handles = vec![self.namespace("")?];
+        for namespace in self.namespaces()? {
+            handles.push(self.namespace(&namespace)?);
+        }
+        let keys = self.keys();
+        let txn = self.begin_write()?;
+        let mut indexed = Problems::default();
+        for handle in &handles {
+            rebuild_refs(&txn, handle, &keys, &mut indexed)?;
+        }
+        txn.open_table(STORE_META)?.insert(REFS_INDEXED, 1)?;
+        txn.commit()?;
+        info!(rows = indexed.count, "blob references indexed");
+        Ok(())
+    }
+}
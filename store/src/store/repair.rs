@@ -0,0 +1,362 @@
+//! Repair of the inconsistencies `verify` finds, where the data to fix
+//! them is still there.
+//!
+//! doc_hashes and its buckets, doc_index, blob_refs, blob_expiry and
+//! attached_blobs are derived from other tables and are rebuilt from them;
+//! rows that point at nothing are dropped.  A document without its data, or a blob whose spill
+//! file is gone, can't be recovered here.  Such blobs are left for `verify`
+//! to keep reporting; such documents too, unless the caller asks to drop
+//! them, without a tombstone, so that sync can fetch them again from a
+//! peer that still has them.
+
+use super::encryption::{open_state, Keys};
+use super::verify::Problems;
+use super::{attachments, buckets, hashing, index, refs, to_hex, tombstones, Store, Tables};
+use anyhow::Result;
+use redb::{ReadableTable, WriteTransaction};
+use std::collections::HashMap;
+use tracing::{info, instrument};
+
+impl Store {
+    /// Fix every namespace of the database in one write transaction; with
+    /// `drop_documents_without_data`, also remove documents whose data is
+    /// gone.  Returns what was changed.
+    #[instrument(skip(self))]
+    pub fn repair(&self, drop_documents_without_data: bool) -> Result<Problems> {
+        // As in `export`: make sure every namespace has all its tables.
+        let mut handles = vec![self.namespace("")?];
+        for namespace in self.namespaces()? {
+            handles.push(self.namespace(&namespace)?);
+        }
+
+        let keys = self.keys();
+        let txn = self.begin_write()?;
+        let mut fixes = Problems::default();
+        let mut dropped = Vec::new();
+        for handle in &handles {
+            if drop_documents_without_data {
+                for id in handle.drop_documents_without_data(&txn, &mut fixes)? {
+                    dropped.push((handle, id));
+                }
+            }
+            repair_documents(&txn, &handle.tables, &mut fixes)?;
+            rebuild_doc_hashes(&txn, &handle.tables, &keys, &mut fixes)?;
+            refs::rebuild_refs(&txn, handle, &keys, &mut fixes)?;
+            repair_expiry(&txn, &handle.tables, &mut fixes)?;
+            repair_attachments(&txn, &handle.tables, &mut fixes)?;
+        }
+        txn.commit()?;
+        for handle in &handles {
+            handle.roots.invalidate(&handle.namespace);
+        }
+        for (handle, id) in dropped {
+            handle.committed([&handle.document_key(&id)]);
+        }
+
+        info!(fixes = fixes.count, "repair complete");
+        Ok(fixes)
+    }
+
+    /// Remove the documents that have no data, with everything derived
+    /// from them, returning their ids.
+    fn drop_documents_without_data(
+        &self,
+        txn: &WriteTransaction,
+        fixes: &mut Problems,
+    ) -> Result<Vec<String>> {
+        let mut dataless = Vec::new();
+        {
+            let data = txn.open_table(self.tables.doc_data())?;
+            for entry in txn.open_table(self.tables.documents())?.iter()? {
+                let (id, _) = entry?;
+                if data.get(id.value())?.is_none() {
+                    dataless.push(id.value().to_string());
+                }
+            }
+        }
+        for id in &dataless {
+            self.remove_document(txn, id)?;
+            fixes.push(self.tables.documents(), id.as_str(), "removed document without data");
+        }
+        Ok(dataless)
+    }
+}
+
+/// Drop document rows that belong to no document, and tombstones that
+/// are unreadable or shadow a live document.
+fn repair_documents(txn: &WriteTransaction, tables: &Tables, fixes: &mut Problems) -> Result<()> {
+    let docs = txn.open_table(tables.documents())?;
+
+    let mut data = txn.open_table(tables.doc_data())?;
+    let mut orphaned = Vec::new();
+    for entry in data.iter()? {
+        let (id, _) = entry?;
+        if docs.get(id.value())?.is_none() {
+            orphaned.push(id.value().to_string());
+        }
+    }
+    for id in orphaned {
+        data.remove(id.as_str())?;
+        fixes.push(tables.doc_data(), id, "removed data without a document");
+    }
+
+    let mut times = txn.open_table(tables.doc_times())?;
+    let mut orphaned = Vec::new();
+    for entry in times.iter()? {
+        let (id, _) = entry?;
+        if docs.get(id.value())?.is_none() {
+            orphaned.push(id.value().to_string());
+        }
+    }
+    for id in orphaned {
+        times.remove(id.as_str())?;
+        fixes.push(tables.doc_times(), id, "removed timestamps without a document");
+    }
+
+    let mut access = txn.open_table(tables.doc_access())?;
+    let mut orphaned = Vec::new();
+    for entry in access.iter()? {
+        let (id, _) = entry?;
+        if docs.get(id.value())?.is_none() {
+            orphaned.push(id.value().to_string());
+        }
+    }
+    for id in orphaned {
+        access.remove(id.as_str())?;
+        fixes.push(tables.doc_access(), id, "removed read statistics without a document");
+    }
+
+    let mut tombstones = txn.open_table(tables.tombstones())?;
+    let mut stale = Vec::new();
+    for entry in tombstones.iter()? {
+        let (id, value) = entry?;
+        if tombstones::parse(value.value()).is_err() {
+            stale.push((id.value().to_string(), "removed unreadable tombstone"));
+        } else if docs.get(id.value())?.is_some() {
+            stale.push((id.value().to_string(), "removed tombstone of a live document"));
+        }
+    }
+    for (id, action) in stale {
+        tombstones.remove(id.as_str())?;
+        fixes.push(tables.tombstones(), id, action);
+    }
+
+    let mut unindexed = Vec::new();
+    for entry in txn.open_table(tables.doc_index_keys())?.iter()? {
+        let (id, _) = entry?;
+        if docs.get(id.value())?.is_none() {
+            unindexed.push(id.value().to_string());
+        }
+    }
+    drop((docs, data, times, tombstones));
+    for id in unindexed {
+        index::clear_index(txn, tables, &id)?;
+        fixes.push(tables.doc_index_keys(), id, "removed index entries of a missing document");
+    }
+    Ok(())
+}
+
+/// Make doc_hashes agree with doc_data and the tombstones: the state hash
+/// of every live document, the deletion hash of every deleted one, nothing
+/// else; then the buckets agree with doc_hashes.  Encrypted states need
+/// `keys`.
+pub(super) fn rebuild_doc_hashes(
+    txn: &WriteTransaction,
+    tables: &Tables,
+    keys: &Keys,
+    fixes: &mut Problems,
+) -> Result<()> {
+    let mut expected = HashMap::new();
+    for entry in txn.open_table(tables.doc_data())?.iter()? {
+        let (id, state) = entry?;
+        let state = open_state(keys, id.value(), state.value())?;
+        let hash = hashing::hash(&state).as_bytes().to_vec();
+        expected.insert(id.value().to_string(), hash);
+    }
+    for entry in txn.open_table(tables.tombstones())?.iter()? {
+        let (id, value) = entry?;
+        let tombstone = tombstones::parse(value.value())?;
+        expected.entry(id.value().to_string()).or_insert(tombstone.hash);
+    }
+
+    let mut hashes = txn.open_table(tables.doc_hashes())?;
+    let mut orphaned = Vec::new();
+    for entry in hashes.iter()? {
+        let (id, hash) = entry?;
+        match expected.get(id.value()) {
+            Some(want) if want.as_slice() == hash.value() => {
+                expected.remove(id.value());
+            }
+            Some(_) => {}
+            None => orphaned.push(id.value().to_string()),
+        }
+    }
+
+    for id in orphaned {
+        hashes.remove(id.as_str())?;
+        fixes.push(tables.doc_hashes(), id, "removed hash without a document or tombstone");
+    }
+    for (id, hash) in expected {
+        hashes.insert(id.as_str(), hash.as_slice())?;
+        fixes.push(tables.doc_hashes(), id, "recomputed missing or stale hash");
+    }
+    drop(hashes);
+
+    let rows = buckets::rebuild_buckets(txn, tables)?;
+    if rows > 0 {
+        fixes.push(tables.doc_buckets(), format!("{rows} rows"), "rebuilt doc hash buckets");
+    }
+    Ok(())
+}
+
+/// Drop expiries and timestamps of missing blobs and rebuild blob_expiry
+/// from blob_ttl.
+fn repair_expiry(txn: &WriteTransaction, tables: &Tables, fixes: &mut Problems) -> Result<()> {
+    let blobs = txn.open_table(tables.blobs())?;
+    let mut ttls = txn.open_table(tables.blob_ttl())?;
+    let mut expiry = txn.open_table(tables.blob_expiry())?;
+
+    let mut missing = Vec::new();
+    for entry in ttls.iter()? {
+        let (hash, _) = entry?;
+        if blobs.get(hash.value())?.is_none() {
+            missing.push(hash.value().to_vec());
+        }
+    }
+    for hash in missing {
+        ttls.remove(hash.as_slice())?;
+        fixes.push(tables.blob_ttl(), to_hex(&hash), "removed expiry of a missing blob");
+    }
+
+    let mut times = txn.open_table(tables.blob_times())?;
+    let mut missing = Vec::new();
+    for entry in times.iter()? {
+        let (hash, _) = entry?;
+        if blobs.get(hash.value())?.is_none() {
+            missing.push(hash.value().to_vec());
+        }
+    }
+    for hash in missing {
+        times.remove(hash.as_slice())?;
+        fixes.push(tables.blob_times(), to_hex(&hash), "removed timestamps of a missing blob");
+    }
+
+    let mut stale = Vec::new();
+    for entry in expiry.iter()? {
+        let (key, _) = entry?;
+        let (expires_at, hash) = key.value();
+        if ttls.get(hash)?.is_none_or(|t| t.value() != expires_at) {
+            stale.push((expires_at, hash.to_vec()));
+        }
+    }
+    for (expires_at, hash) in stale {
+        expiry.remove((expires_at, hash.as_slice()))?;
+        fixes.push(tables.blob_expiry(), to_hex(&hash), "removed stale sweep index entry");
+    }
+
+    let mut unindexed = Vec::new();
+    for entry in ttls.iter()? {
+        let (hash, expires_at) = entry?;
+        if expiry.get((expires_at.value(), hash.value()))?.is_none() {
+            unindexed.push((expires_at.value(), hash.value().to_vec()));
+        }
+    }
+    for (expires_at, hash) in unindexed {
+        expiry.insert((expires_at, hash.as_slice()), ())?;
+        fixes.push(tables.blob_expiry(), to_hex(&hash), "added missing sweep index entry");
+    }
+    Ok(())
+}
+
+/// Drop the attachments of missing documents, then make attached_blobs
+/// agree with attachments.  Attachments of missing blobs are left alone.
+fn repair_attachments(txn: &WriteTransaction, tables: &Tables, fixes: &mut Problems) -> Result<()> {
+    let mut orphaned = Vec::new();
+    {
+        let docs = txn.open_table(tables.documents())?;
+        for entry in txn.open_table(tables.attachments())?.iter()? {
+            let (key, _) = entry?;
+            let (id, _) = key.value();
+            if docs.get(id)?.is_none() && orphaned.last().map(String::as_str) != Some(id) {
+                orphaned.push(id.to_string());
+            }
+        }
+    }
+    for id in orphaned {
+        attachments::clear_attachments(txn, tables, &id)?;
+        fixes.push(tables.attachments(), id, "removed attachments of a missing document");
+    }
+
+    let attachments = txn.open_table(tables.attachments())?;
+    let mut reverse = txn.open_table(tables.attached_blobs())?;
+    let mut stale = Vec::new();
+    for entry in reverse.iter()? {
+        let (key, _) = entry?;
+        let (hash, id, name) = key.value();
+        if attachments.get((id, name))?.is_none_or(|h| h.value() != hash) {
+            stale.push((hash.to_vec(), id.to_string(), name.to_string()));
+        }
+    }
+    for (hash, id, name) in stale {
+        reverse.remove((hash.as_slice(), id.as_str(), name.as_str()))?;
+        fixes.push(tables.attached_blobs(), to_hex(&hash), "removed stale blob index entry");
+    }
+
+    let mut unindexed = Vec::new();
+    for entry in attachments.iter()? {
+        let (key, hash) = entry?;
+        let (id, name) = key.value();
+        if reverse.get((hash.value(), id, name))?.is_none() {
+            unindexed.push((hash.value().to_vec(), id.to_string(), name.to_string()));
+        }
+    }
+    for (hash, id, name) in unindexed {
+        reverse.insert((hash.as_slice(), id.as_str(), name.as_str()), ())?;
+        fixes.push(tables.attached_blobs(), to_hex(&hash), "added missing blob index entry");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::StoreOptions;
+
+    #[test]
+    fn test_repair() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Store::open(dir.path(), StoreOptions::default()).unwrap();
+        store.put_document("a", b"meta", b"state-a", None, false).unwrap();
+        store.put_document("b", b"meta", b"state-b", None, false).unwrap();
+        let hash = store.get_doc_hash("a").unwrap();
+        assert_eq!(store.repair(false).unwrap().count, 0);
+
+        // A lost doc_hashes entry, data without a document, and a document
+        // without data.
+        let txn = store.begin_write().unwrap();
+        {
+            let mut data = txn.open_table(store.tables.doc_data()).unwrap();
+            let value = data.get("a").unwrap().unwrap().value().to_vec();
+            data.insert("ghost", value.as_slice()).unwrap();
+            data.remove("b").unwrap();
+            txn.open_table(store.tables.doc_hashes()).unwrap().remove("a").unwrap();
+        }
+        txn.commit().unwrap();
+        assert!(store.verify(false).unwrap().problems.count >= 3);
+
+        let fixes = store.repair(false).unwrap();
+        let fixed: Vec<_> = fixes.listed.iter().map(|p| (&*p.table, &*p.key)).collect();
+        assert!(fixed.contains(&("doc_data", "ghost")), "{fixed:?}");
+        assert!(fixed.contains(&("doc_hashes", "a")), "{fixed:?}");
+        assert_eq!(store.get_doc_hash("a").unwrap(), hash);
+        // Only the document without data is left, for a peer to resend.
+        let problems = store.verify(false).unwrap().problems;
+        assert_eq!(problems.count, 1, "{:?}", problems.listed);
+
+        let fixes = store.repair(true).unwrap();
+        assert_eq!(fixes.count, 1, "{:?}", fixes.listed);
+        assert_eq!(store.list_documents("").unwrap(), ["a"]);
+        assert!(store.get_tombstone("b").unwrap().is_none());
+        assert_eq!(store.verify(true).unwrap().problems.count, 0);
+    }
+}
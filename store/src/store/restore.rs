@@ -0,0 +1,167 @@
+//! Restoring a data directory from a backup (see `backup`).
+//!
+//! The backup's database is copied next to the live one, checked, and has
+//! its derived tables rebuilt before it is renamed over keyring.redb, so
+//! the data directory holds either the old database or the restored one,
+//! never a mix.  The replaced file is kept as keyring.redb.pre-restore.
+
+use super::encryption::{load_retired, Key, Keys};
+use super::repair::rebuild_doc_hashes;
+use super::verify::Problems;
+use super::{codec, namespaces_of, qualified_name, spill, Tables, DB_FILE, STORE_KEYS};
+use anyhow::{bail, Context, Result};
+use redb::{Database, ReadableTable, ReadableTableMetadata};
+use std::fs;
+use std::path::Path;
+use tracing::{info, instrument, warn};
+
+/// Outcome of a restore.
+#[derive(Debug, Clone, Default)]
+pub struct RestoreReport {
+    /// Documents in the restored database, across all namespaces.
+    pub documents: u64,
+    /// doc_hashes entries that were missing, stale or orphaned and were
+    /// rewritten.
+    pub rebuilt_hashes: u64,
+    /// Spill files copied from the backup.
+    pub spilled_blobs: u64,
+}
+
+/// Replace the database in `data_dir` with the backup in `backup`.  No port
+/// may be serving `data_dir`.  An encrypted backup needs its `key` to
+/// rebuild document hashes.
+#[instrument(skip(key))]
+pub fn restore(backup: &Path, data_dir: &Path, key: Option<&Key>) -> Result<RestoreReport> {
+    let source = backup.join(DB_FILE);
+    if !source.is_file() {
+        bail!("{} is not a backup: it has no {DB_FILE}", backup.display());
+    }
+    fs::create_dir_all(data_dir)
+        .with_context(|| format!("creating data dir {}", data_dir.display()))?;
+    let live = data_dir.join(DB_FILE);
+    if live.exists() {
+        // redb locks the file, so this fails while a port has it open.
+        Database::open(&live).with_context(|| {
+            format!("opening {}; is a port still serving it?", live.display())
+        })?;
+    }
+
+    let staged = live.with_extension("redb.restore");
+    fs::copy(&source, &staged)
+        .with_context(|| format!("copying {} to {}", source.display(), staged.display()))?;
+    let report = match prepare(&staged, backup, data_dir, key) {
+        Ok(report) => report,
+        Err(e) => {
+            spill::remove(&staged)?;
+            return Err(e);
+        }
+    };
+
+    if live.exists() {
+        let previous = live.with_extension("redb.pre-restore");
+        spill::remove(&previous)?;
+        fs::hard_link(&live, &previous)
+            .with_context(|| format!("keeping the replaced database as {}", previous.display()))?;
+    }
+    fs::rename(&staged, &live).with_context(|| format!("renaming into {}", live.display()))?;
+
+    info!(
+        documents = report.documents,
+        rebuilt_hashes = report.rebuilt_hashes,
+        spilled_blobs = report.spilled_blobs,
+        "database restored"
+    );
+    Ok(report)
+}
+
+/// Check the staged copy, rebuild its doc_hashes, and copy the spill files
+/// it refers to into `data_dir`.
+fn prepare(
+    staged: &Path,
+    backup: &Path,
+    data_dir: &Path,
+    key: Option<&Key>,
+) -> Result<RestoreReport> {
+    let mut db = Database::open(staged)
+        .with_context(|| format!("opening backup database {}", staged.display()))?;
+    if !db.check_integrity().context("checking backup integrity")? {
+        warn!("backup database needed repair");
+    }
+
+    let mut namespaces = vec![String::new()];
+    namespaces.extend(namespaces_of(db.begin_read()?.list_tables()?));
+
+    let mut report = RestoreReport::default();
+    let txn = db.begin_write()?;
+    let keys = match key {
+        Some(key) => Keys::new(key.clone(), load_retired(&txn.open_table(STORE_KEYS)?, key)?),
+        None => Keys::default(),
+    };
+    for namespace in &namespaces {
+        let tables = Tables::new(namespace);
+        tables.create_all(&txn)?;
+        report.documents += txn.open_table(tables.documents())?.len()?;
+        let mut fixes = Problems::default();
+        rebuild_doc_hashes(&txn, &tables, &keys, &mut fixes)?;
+        report.rebuilt_hashes += fixes.count;
+
+        let dir_name = qualified_name(spill::SPILL_DIR, namespace);
+        let (from_dir, to_dir) = (backup.join(&dir_name), data_dir.join(&dir_name));
+        for entry in txn.open_table(tables.blobs())?.iter()? {
+            let (hash, value) = entry?;
+            if codec::is_external(value.value()) {
+                spill::copy(
+                    &spill::path_of(&from_dir, hash.value(), value.value()),
+                    &spill::path_of(&to_dir, hash.value(), value.value()),
+                )?;
+                report.spilled_blobs += 1;
+            }
+        }
+    }
+    txn.commit()?;
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::{Store, StoreOptions};
+
+    #[test]
+    fn test_backup_and_restore() {
+        let tmp = tempfile::tempdir().unwrap();
+        let (live, backup) = (tmp.path().join("live"), tmp.path().join("backup"));
+        let options = StoreOptions {
+            spill_threshold: Some(16),
+            ..Default::default()
+        };
+        let store = Store::open(&live, options.clone()).unwrap();
+        store.put_document("a", b"meta", b"state-a", None, false).unwrap();
+        let other = store.namespace("other").unwrap();
+        other.put_document("b", b"meta", b"state-b", None, false).unwrap();
+        let spilled = store.put_blob(&[9; 1000], None).unwrap();
+        let root = store.combined_root().unwrap();
+
+        let report = store.backup(&backup).unwrap();
+        assert_eq!(report.spilled_blobs, 1);
+        assert!(store.backup(&backup).is_err());
+        store.delete_document("a", false).unwrap();
+        store.put_document("later", b"meta", b"state", None, false).unwrap();
+        assert!(restore(&backup, &live, None).is_err(), "the store is still open");
+        drop((store, other));
+
+        let report = restore(&backup, &live, None).unwrap();
+        assert_eq!((report.documents, report.rebuilt_hashes), (2, 0));
+        assert!(live.join(DB_FILE).with_extension("redb.pre-restore").exists());
+        let store = Store::open(&live, options).unwrap();
+        assert_eq!(store.get_document("a").unwrap().unwrap().crdt_state, b"state-a");
+        assert!(store.get_document("later").unwrap().is_none());
+        let other = store.namespace("other").unwrap();
+        assert_eq!(other.get_document("b").unwrap().unwrap().crdt_state, b"state-b");
+        assert_eq!(store.get_blob(&spilled).unwrap().unwrap(), [9; 1000]);
+        assert_eq!(store.combined_root().unwrap(), root);
+        assert_eq!(store.verify(true).unwrap().problems.count, 0);
+
+        assert!(restore(&tmp.path().join("nothing"), &live, None).is_err());
+    }
+}
@@ -0,0 +1,150 @@
+//! The combined Merkle root of every document, kept between requests.
+//!
+//! `merkle::compute_root` over all of doc_hashes reads every hash, so the
+//! result is kept: in doc_root, a one-row table per namespace, and in
+//! memory.  Every write that changes doc_hashes (`set_doc_hash`, bucket
+//! rebuilds) removes the doc_root row in its own transaction, marking the
+//! root dirty, and `Store::committed` drops the copy in memory.  The next
+//! `combined_root` computes it again, at most once per burst of writes.
+//!
+//! The root depends on every document's position in id order, so it can't
+//! be patched for one document; being dirty only means it is computed on
+//! demand rather than on every write.  A generation counter per namespace,
+//! as in `cache`, stops a reader that computed the root before a commit
+//! from keeping it after.
+
+use super::{Store, Tables};
+use crate::merkle;
+use anyhow::Result;
+use redb::{ReadableTable, WriteTransaction};
+use std::collections::HashMap;
+use std::sync::{Mutex, MutexGuard};
+
+/// In-memory combined roots of a database's namespaces.
+#[derive(Debug, Default)]
+pub(super) struct RootCache {
+    namespaces: Mutex<HashMap<String, CachedRoot>>,
+}
+
+#[derive(Debug, Default)]
+struct CachedRoot {
+    /// Bumped by every invalidation.
+    generation: u64,
+    root: Option<Vec<u8>>,
+}
+
+impl RootCache {
+    fn lock(&self) -> MutexGuard<'_, HashMap<String, CachedRoot>> {
+        self.namespaces.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Take before reading from redb and pass to `insert` afterwards.
+    fn generation(&self, namespace: &str) -> u64 {
+        self.lock().get(namespace).map_or(0, |cached| cached.generation)
+    }
+
+    fn get(&self, namespace: &str) -> Option<Vec<u8>> {
+        self.lock().get(namespace)?.root.clone()
+    }
+
+    /// Keep `root` unless `namespace` was invalidated since `generation`
+    /// was taken.
+    fn insert(&self, namespace: &str, root: Vec<u8>, generation: u64) {
+        let mut namespaces = self.lock();
+        let cached = namespaces.entry(namespace.to_string()).or_default();
+        if cached.generation == generation {
+            cached.root = Some(root);
+        }
+    }
+
+    pub(super) fn invalidate(&self, namespace: &str) {
+        let mut namespaces = self.lock();
+        let cached = namespaces.entry(namespace.to_string()).or_default();
+        cached.generation += 1;
+        cached.root = None;
+    }
+}
+
+/// Mark the combined root dirty, within a write to doc_hashes.
+pub(super) fn mark_dirty(txn: &WriteTransaction, tables: &Tables) -> Result<()> {
+    txn.open_table(tables.doc_root())?.remove(())?;
+    Ok(())
+}
+
+impl Store {
+    /// `merkle::compute_root` over every document's hash: the same as
+    /// `GetCombinedRoot` with no ids, without reading doc_hashes unless a
+    /// write changed it since the last call.
+    pub fn combined_root(&self) -> Result<Vec<u8>> {
+        let generation = self.roots.generation(&self.namespace);
+        if let Some(root) = self.roots.get(&self.namespace) {
+            return Ok(root);
+        }
+        let stored = {
+            let txn = self.db.begin_read()?;
+            let table = txn.open_table(self.tables.doc_root())?;
+            table.get(())?.map(|v| v.value().to_vec())
+        };
+        let root = match stored {
+            Some(root) => root,
+            None => self.store_combined_root()?,
+        };
+        self.roots.insert(&self.namespace, root.clone(), generation);
+        Ok(root)
+    }
+
+    /// Compute the combined root and write it to doc_root.  Done in a
+    /// write transaction, so no write to doc_hashes can slip in between;
+    /// it is committed without syncing, as it can always be recomputed.
+    fn store_combined_root(&self) -> Result<Vec<u8>> {
+        let mut txn = self.db.begin_write()?;
+        txn.set_durability(redb::Durability::None);
+        let root = self.combined_root_in(&txn)?;
+        txn.commit()?;
+        Ok(root)
+    }
+
+    /// The combined root as of `txn`, computed and written to doc_root if
+    /// it is dirty.
+    pub(super) fn combined_root_in(&self, txn: &WriteTransaction) -> Result<Vec<u8>> {
+        let mut table = txn.open_table(self.tables.doc_root())?;
+        if let Some(root) = table.get(())? {
+            return Ok(root.value().to_vec());
+        }
+        let hashes = txn.open_table(self.tables.doc_hashes())?;
+        let mut pairs = Vec::new();
+        for entry in hashes.iter()? {
+            let (k, v) = entry?;
+            pairs.push((k.value().to_string(), v.value().to_vec()));
+        }
+        let root = merkle::compute_root(&pairs);
+        table.insert((), root.as_slice())?;
+        Ok(root)
+    }
+
+    /// Bumped whenever this namespace's combined root may have changed, so
+    /// a caller can tell whether a request wrote to its documents.
+    pub fn root_generation(&self) -> u64 {
+        self.roots.generation(&self.namespace)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stale_insert_is_dropped() {
+        let cache = RootCache::default();
+        let generation = cache.generation("ns");
+        cache.insert("ns", vec![1], generation);
+        assert_eq!(cache.get("ns"), Some(vec![1]));
+
+        let generation = cache.generation("ns");
+        cache.invalidate("ns");
+        assert_eq!(cache.get("ns"), None);
+        cache.insert("ns", vec![2], generation);
+        assert_eq!(cache.get("ns"), None);
+        assert_eq!(cache.get(""), None);
+    }
+}
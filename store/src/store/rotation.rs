@@ -0,0 +1,241 @@
+//! Encryption key rotation.
+//!
+//! `rotate_key` makes a new key current at once: values are sealed with it
+//! from then on, and the old key joins the retired keys in STORE_KEYS, all
+//! rewrapped under the new one.  A background pass then reseals every
+//! value still sealed with a retired key — blobs, spill files, CRDT states,
+//! history versions and siblings, a batch of rows per write transaction — and
+//! forgets the retired keys once nothing needs them.  Until then they stay
+//! readable, and an interrupted pass (a restart, an error) resumes when
+//! `RotateKey` is sent again with the new key.
+
+use super::encryption::{reseal, reseal_value, wrap, Key, Keys};
+use super::{codec, conflicts, history, spill, Store, KEY_ID, STORE_KEYS, STORE_META};
+use anyhow::{bail, Result};
+use redb::{ReadableTable, TableDefinition};
+use std::ops::Bound;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::thread;
+use tracing::{info, instrument, warn};
+
+/// Rows visited per write transaction, so writers aren't held up for long.
+const BATCH: usize = 256;
+
+impl Store {
+    /// Make `new` the database's key and reseal older values under it in the
+    /// background.  Rotating to the current key resumes an unfinished pass.
+    #[instrument(skip(self, new))]
+    pub fn rotate_key(&self, new: Key) -> Result<()> {
+        if self.keys.rotating.swap(true, Ordering::SeqCst) {
+            bail!("a key rotation is already running");
+        }
+        let retired = match self.switch_key(new) {
+            Ok(retired) => retired,
+            Err(e) => {
+                self.keys.rotating.store(false, Ordering::SeqCst);
+                return Err(e);
+            }
+        };
+        if !retired {
+            self.keys.rotating.store(false, Ordering::SeqCst);
+            return Ok(());
+        }
+
+        let store = self.clone();
+        thread::Builder::new()
+            .name("key-rotation".into())
+            .spawn(move || {
+                match store.reencrypt() {
+                    Ok(resealed) => info!(resealed, "key rotation complete"),
+                    Err(e) => warn!(
+                        error = %format!("{e:#}"),
+                        "key rotation stopped; send RotateKey again to resume"
+                    ),
+                }
+                store.keys.rotating.store(false, Ordering::SeqCst);
+            })
+            .expect("spawning key rotation thread");
+        Ok(())
+    }
+
+    /// Record `new` as the current key.  Returns whether any values may
+    /// still be sealed with a retired key.
+    fn switch_key(&self, new: Key) -> Result<bool> {
+        let mut state = self.keys.state();
+        let Some(current) = state.keys.current().cloned() else {
+            bail!("the database has no key to rotate; send ProvideKey first");
+        };
+        if current.id() != new.id() {
+            let mut retired = state.keys.retired().to_vec();
+            retired.retain(|key| key.id() != new.id());
+            retired.push(current.clone());
+
+            let txn = self.begin_write()?;
+            {
+                let mut table = txn.open_table(STORE_KEYS)?;
+                table.retain(|_, _| false)?;
+                for key in &retired {
+                    table.insert(key.id(), wrap(&new, key)?.as_slice())?;
+                }
+            }
+            txn.open_table(STORE_META)?.insert(KEY_ID, new.id())?;
+            txn.commit()?;
+            info!(
+                from = format!("{:016x}", current.id()),
+                to = format!("{:016x}", new.id()),
+                "encryption key rotated"
+            );
+            state.recorded = Some(new.id());
+            state.keys = Arc::new(Keys::new(new, retired));
+        }
+        Ok(!state.keys.retired().is_empty())
+    }
+
+    /// Reseal every value of every namespace sealed with a retired key,
+    /// then forget the retired keys.  Returns how many values were resealed.
+    fn reencrypt(&self) -> Result<u64> {
+        // As in `export`: make sure every namespace has all its tables.
+        let mut handles = vec![self.namespace("")?];
+        for namespace in self.namespaces()? {
+            handles.push(self.namespace(&namespace)?);
+        }
+
+        let keys = self.keys();
+        let mut resealed = 0;
+        for handle in &handles {
+            resealed += handle.reencrypt_namespace(&keys)?;
+        }
+
+        let txn = self.begin_write()?;
+        {
+            let mut table = txn.open_table(STORE_KEYS)?;
+            for key in keys.retired() {
+                table.remove(key.id())?;
+            }
+        }
+        txn.commit()?;
+        let mut state = self.keys.state();
+        if let Some(current) = state.keys.current().cloned() {
+            state.keys = Arc::new(Keys::new(current, Vec::new()));
+        }
+        Ok(resealed)
+    }
+
+    fn reencrypt_namespace(&self, keys: &Keys) -> Result<u64> {
+        let tables = self.tables.clone();
+        let mut spilled = 0;
+        let blobs = self.reseal_table(tables.blobs(), |hash, stored| {
+            if !codec::is_sealed_external(stored) {
+                return reseal_value(keys, hash, stored);
+            }
+            // The row stays as it is; the spill file is rewritten in place
+            // while this transaction keeps the blob from being removed.
+            let path = spill::sealed_path_for(&self.spill_dir, hash);
+            if let Some(sealed) = reseal(keys, hash, &spill::read(&path)?)? {
+                spill::replace(&path, &sealed)?;
+                spilled += 1;
+            }
+            Ok(None)
+        })?;
+        let data = self.reseal_table(tables.doc_data(), |id, stored| {
+            reseal_value(keys, id, stored)
+        })?;
+        let versions = self.reseal_table(tables.doc_history(), |key, value| {
+            let (id, _) = <(&str, u64) as redb::Value>::from_bytes(key);
+            history::rewrite_state(value, |state| reseal_value(keys, id.as_bytes(), state))
+        })?;
+        let siblings = self.reseal_table(tables.doc_siblings(), |key, value| {
+            let (id, _) = <(&str, &[u8]) as redb::Value>::from_bytes(key);
+            conflicts::rewrite_state(value, |state| reseal_value(keys, id.as_bytes(), state))
+        })?;
+        Ok(blobs + spilled + data + versions + siblings)
+    }
+
+    /// Replace each value of `table` for which `reseal` (given the key's
+    /// and value's bytes) returns a new one, `BATCH` rows per transaction.
+    fn reseal_table<K: redb::Key + 'static>(
+        &self,
+        table: TableDefinition<K, &'static [u8]>,
+        mut reseal: impl FnMut(&[u8], &[u8]) -> Result<Option<Vec<u8>>>,
+    ) -> Result<u64> {
+        let mut cursor: Option<Vec<u8>> = None;
+        let mut resealed = 0;
+        loop {
+            let txn = self.begin_write()?;
+            let mut rows = 0;
+            {
+                let mut table = txn.open_table(table)?;
+                let mut updates = Vec::new();
+                let start = match &cursor {
+                    Some(key) => Bound::Excluded(K::from_bytes(key)),
+                    None => Bound::Unbounded,
+                };
+                for entry in table.range::<K::SelfType<'_>>((start, Bound::Unbounded))? {
+                    let (key, value) = entry?;
+                    let key = K::as_bytes(&key.value()).as_ref().to_vec();
+                    if let Some(value) = reseal(&key, value.value())? {
+                        updates.push((key.clone(), value));
+                    }
+                    rows += 1;
+                    if rows == BATCH {
+                        cursor = Some(key);
+                        break;
+                    }
+                }
+                for (key, value) in &updates {
+                    table.insert(K::from_bytes(key), value.as_slice())?;
+                }
+                resealed += updates.len() as u64;
+            }
+            txn.commit()?;
+            if rows < BATCH {
+                return Ok(resealed);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::StoreOptions;
+    use redb::ReadableTableMetadata;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_rotate_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let (old, new) = (Key::from_bytes(&[1; 32]).unwrap(), Key::from_bytes(&[2; 32]).unwrap());
+        let options = |key: &Key| StoreOptions {
+            encryption_key: Some(key.clone()),
+            spill_threshold: Some(16),
+            history_depth: 2,
+            ..Default::default()
+        };
+        let store = Store::open(dir.path(), options(&old)).unwrap();
+        store.put_document("a", b"meta", b"state-1", None, false).unwrap();
+        store.put_document("a", b"meta", b"state-2", None, false).unwrap();
+        let spilled = store.put_blob(&[9; 1000], None).unwrap();
+
+        store.rotate_key(new.clone()).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while store.keys.rotating.load(Ordering::SeqCst) {
+            assert!(Instant::now() < deadline, "the rotation never finished");
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(store.keys().retired().is_empty());
+        let txn = store.db.begin_read().unwrap();
+        assert!(txn.open_table(STORE_KEYS).unwrap().is_empty().unwrap());
+        drop(txn);
+        store.put_document("b", b"meta", b"state-b", None, false).unwrap();
+        drop(store);
+
+        assert!(Store::open(dir.path(), options(&old)).is_err());
+        let store = Store::open(dir.path(), options(&new)).unwrap();
+        assert_eq!(store.get_document("a").unwrap().unwrap().crdt_state, b"state-2");
+        assert_eq!(store.get_document("b").unwrap().unwrap().crdt_state, b"state-b");
+        assert_eq!(store.get_blob(&spilled).unwrap().unwrap(), [9; 1000]);
+        assert_eq!(store.verify(true).unwrap().problems.count, 0);
+    }
+}
@@ -0,0 +1,357 @@
+//! Full-text search over designated metadata fields, with tantivy.
+//!
+//! `StoreOptions::search_fields` names top-level text fields of the meta,
+//! read as a CBOR map (see `cbor`).  Their text is indexed by tantivy in
+//! `search/` beside the database, one tantivy document per document, keyed
+//! by namespace and id.  redb stays the source of truth: every write to a
+//! document also records its id in the namespace's search_queue, in the
+//! same transaction, and `search` first brings the index up to date from
+//! the queues.  A drain runs in a write transaction that empties the
+//! queues only once tantivy has committed what was in them, so a crash in
+//! between just indexes those documents again.
+//!
+//! `search` ranks the documents holding any word of the query by BM25.
+//! The open rebuilds the index from every namespace's documents whenever
+//! the configured fields differ from those it was built with (recorded as
+//! its commit payload), e.g. because it is missing.  A store without
+//! search fields removes the index, so adding them later rebuilds it.
+//!
+//! tantivy comes with the `search` feature; without it, a store with
+//! search fields refuses to open.
+
+use super::{Store, Tables};
+use anyhow::{Context, Result};
+use redb::WriteTransaction;
+#[cfg(feature = "search")]
+use {
+    super::{cbor, filter::MetaValue},
+    redb::{ReadableTable, ReadableTableMetadata},
+    std::path::Path,
+    std::sync::{Arc, Mutex, MutexGuard},
+    tantivy::collector::TopDocs,
+    tantivy::directory::MmapDirectory,
+    tantivy::query::{BooleanQuery, Occur, Query, QueryParser, TermQuery},
+    tantivy::schema::{Field, IndexRecordOption, Schema, Value, STORED, STRING, TEXT},
+    tantivy::{doc, Index, IndexReader, IndexWriter, ReloadPolicy, TantivyDocument, Term},
+};
+
+/// Directory of the index, beside the database.
+pub(super) const SEARCH_DIR: &str = "search";
+
+/// Record that `id`'s search entry is stale, for the next drain.
+pub(super) fn queue_document(txn: &WriteTransaction, tables: &Tables, id: &str) -> Result<()> {
+    txn.open_table(tables.search_queue())?.insert(id, ())?;
+    Ok(())
+}
+
+/// The text of `fields` in `meta`, one field per line.  Meta that isn't a
+/// CBOR map simply has nothing to index.
+#[cfg(feature = "search")]
+fn searchable_text(meta: &[u8], fields: &[String]) -> String {
+    let mut text = String::new();
+    for (name, value) in cbor::top_level_fields(meta).unwrap_or_default() {
+        if let Some(MetaValue::Text(value)) = value {
+            if fields.contains(&name) {
+                text.push_str(&value);
+                text.push('\n');
+            }
+        }
+    }
+    text
+}
+
+/// Fingerprint of a field list, order and duplicates ignored.
+#[cfg(feature = "search")]
+fn fingerprint(fields: &[String]) -> String {
+    let mut sorted: Vec<&str> = fields.iter().map(String::as_str).collect();
+    sorted.sort();
+    sorted.dedup();
+    super::to_hex(&blake3::hash(sorted.join("\0").as_bytes()).as_bytes()[..8])
+}
+
+#[cfg(not(feature = "search"))]
+pub(super) enum SearchIndex {}
+
+#[cfg(not(feature = "search"))]
+impl SearchIndex {
+    fn search(&self, _namespace: &str, _query: &str, _limit: usize) -> Result<Vec<String>> {
+        match *self {}
+    }
+}
+
+/// Memory the writer buffers documents in before writing a segment.
+#[cfg(feature = "search")]
+const WRITER_BYTES: usize = 32 * 1024 * 1024;
+
+#[cfg(feature = "search")]
+pub(super) struct SearchIndex {
+    index: Index,
+    writer: Mutex<IndexWriter>,
+    reader: IndexReader,
+    /// `namespace\0id`, to replace a document's entry by.
+    key: Field,
+    namespace: Field,
+    id: Field,
+    text: Field,
+}
+
+#[cfg(feature = "search")]
+impl SearchIndex {
+    /// Open the index in `dir`, creating it if there is none.
+    pub(super) fn open(dir: &Path) -> Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        Self::new(Index::open_or_create(MmapDirectory::open(dir)?, schema())?)
+    }
+
+    pub(super) fn new(index: Index) -> Result<Self> {
+        let schema = index.schema();
+        Ok(Self {
+            writer: Mutex::new(index.writer_with_num_threads(1, WRITER_BYTES)?),
+            reader: index.reader_builder().reload_policy(ReloadPolicy::Manual).try_into()?,
+            key: schema.get_field("key")?,
+            namespace: schema.get_field("namespace")?,
+            id: schema.get_field("id")?,
+            text: schema.get_field("text")?,
+            index,
+        })
+    }
+
+    /// The fingerprint of the fields the index was last committed with.
+    pub(super) fn built_with(&self) -> Result<Option<String>> {
+        Ok(self.index.load_metas()?.payload)
+    }
+
+    pub(super) fn writer(&self) -> MutexGuard<'_, IndexWriter> {
+        self.writer.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Replace the entry of `id` in `namespace` with `text`, or drop it
+    /// if `text` is `None` or empty.
+    pub(super) fn replace(
+        &self,
+        writer: &IndexWriter,
+        namespace: &str,
+        id: &str,
+        text: Option<String>,
+    ) -> Result<()> {
+        let key = format!("{namespace}\0{id}");
+        writer.delete_term(Term::from_field_text(self.key, &key));
+        if let Some(text) = text.filter(|text| !text.trim().is_empty()) {
+            writer.add_document(doc!(
+                self.key => key,
+                self.namespace => namespace,
+                self.id => id,
+                self.text => text,
+            ))?;
+        }
+        Ok(())
+    }
+
+    /// Commit `writer`'s changes, recording `fingerprint`, and show
+    /// them to searches.
+    pub(super) fn commit(&self, writer: &mut IndexWriter, fingerprint: &str) -> Result<()> {
+        let mut prepared = writer.prepare_commit()?;
+        prepared.set_payload(fingerprint);
+        prepared.commit()?;
+        self.reader.reload()?;
+        Ok(())
+    }
+
+    /// Up to `limit` ids in `namespace` matching any word of `query`,
+    /// best match first.
+    pub(super) fn search(&self, namespace: &str, query: &str, limit: usize) -> Result<Vec<String>> {
+        if limit == 0 {
+            return Ok(Vec::new());
+        }
+        // Stray operators or quotes are taken as words, not errors.
+        let (words, _) = QueryParser::for_index(&self.index, vec![self.text]).parse_query_lenient(query);
+        let in_namespace = TermQuery::new(
+            Term::from_field_text(self.namespace, namespace),
+            IndexRecordOption::Basic,
+        );
+        let query = BooleanQuery::new(vec![
+            (Occur::Must, words),
+            (Occur::Must, Box::new(in_namespace) as Box<dyn Query>),
+        ]);
+        let searcher = self.reader.searcher();
+        let mut ids = Vec::new();
+        for (_, address) in searcher.search(&query, &TopDocs::with_limit(limit))? {
+            let doc: TantivyDocument = searcher.doc(address)?;
+            if let Some(id) = doc.get_first(self.id).and_then(|v| v.as_str()) {
+                ids.push(id.to_string());
+            }
+        }
+        Ok(ids)
+    }
+}
+
+#[cfg(feature = "search")]
+fn schema() -> Schema {
+    let mut schema = Schema::builder();
+    schema.add_text_field("key", STRING);
+    schema.add_text_field("namespace", STRING);
+    schema.add_text_field("id", STORED);
+    schema.add_text_field("text", TEXT);
+    schema.build()
+}
+
+impl Store {
+    /// Up to `limit` ids of documents matching any word of `query`, best
+    /// match first.
+    pub fn search(&self, query: &str, limit: usize) -> Result<Vec<String>> {
+        let Some(index) = &self.search else {
+            return Ok(Vec::new());
+        };
+        #[cfg(feature = "search")]
+        self.drain_search_queues(index)?;
+        index.search(&self.namespace, query, limit)
+    }
+
+    /// Open the search index if search fields are configured, rebuilding
+    /// it if they changed since it was built.
+    pub(super) fn open_search_index(&mut self) -> Result<()> {
+        let dir = self.dir.join(SEARCH_DIR);
+        let fields = &self.options.search_fields;
+        if fields.is_empty() {
+            if dir.exists() {
+                std::fs::remove_dir_all(&dir)
+                    .with_context(|| format!("removing the unused search index {}", dir.display()))?;
+            }
+            return Ok(());
+        }
+        #[cfg(not(feature = "search"))]
+        anyhow::bail!("--search-field needs a build with the `search` feature");
+        #[cfg(feature = "search")]
+        {
+            let index = SearchIndex::open(&dir)
+                .with_context(|| format!("opening the search index {}", dir.display()))?;
+            let wanted = fingerprint(fields);
+            if index.built_with()?.as_deref() != Some(wanted.as_str()) {
+                self.rebuild_search_index(&index, &wanted)?;
+            }
+            self.search = Some(Arc::new(index));
+            Ok(())
+        }
+    }
+
+    /// Every namespace's handle, the default one first.
+    #[cfg(feature = "search")]
+    fn all_namespaces(&self) -> Result<Vec<Store>> {
+        let mut handles = vec![self.namespace("")?];
+        for namespace in self.namespaces()? {
+            handles.push(self.namespace(&namespace)?);
+        }
+        Ok(handles)
+    }
+
+    /// Index every document of every namespace afresh.
+    #[cfg(feature = "search")]
+    fn rebuild_search_index(&self, index: &SearchIndex, fingerprint: &str) -> Result<()> {
+        let fields = &self.options.search_fields;
+        let handles = self.all_namespaces()?;
+        let mut writer = index.writer();
+        writer.delete_all_documents()?;
+        let txn = self.begin_write()?;
+        let mut indexed = 0u64;
+        for handle in &handles {
+            let tables = &handle.tables;
+            let docs = txn.open_table(tables.documents())?;
+            for entry in docs.iter()? {
+                let (id, meta) = entry?;
+                let text = searchable_text(meta.value(), fields);
+                index.replace(&writer, &handle.namespace, id.value(), Some(text))?;
+                indexed += 1;
+            }
+            txn.open_table(tables.search_queue())?.retain(|_, _| false)?;
+        }
+        index.commit(&mut writer, fingerprint)?;
+        txn.commit()?;
+        tracing::info!(documents = indexed, ?fields, "search index rebuilt");
+        Ok(())
+    }
+
+    /// Index the documents every namespace's search_queue holds.
+    #[cfg(feature = "search")]
+    fn drain_search_queues(&self, index: &SearchIndex) -> Result<()> {
+        let handles = self.all_namespaces()?;
+        {
+            let txn = self.db.begin_read()?;
+            let mut queued = 0;
+            for handle in &handles {
+                queued += txn.open_table(handle.tables.search_queue())?.len()?;
+            }
+            if queued == 0 {
+                return Ok(());
+            }
+        }
+        let fields = &self.options.search_fields;
+        let mut writer = index.writer();
+        let txn = self.begin_write()?;
+        for handle in &handles {
+            let tables = &handle.tables;
+            let docs = txn.open_table(tables.documents())?;
+            let mut queue = txn.open_table(tables.search_queue())?;
+            for entry in queue.iter()? {
+                let (id, _) = entry?;
+                let text = docs.get(id.value())?.map(|meta| searchable_text(meta.value(), fields));
+                index.replace(&writer, &handle.namespace, id.value(), text)?;
+            }
+            queue.retain(|_, _| false)?;
+        }
+        index.commit(&mut writer, &fingerprint(fields))?;
+        txn.commit()?;
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "search"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_searchable_text() {
+        // {"title": "Bank", "notes": "PIN 1234", "n": 1}
+        let meta = [
+            0xa3, 0x65, b't', b'i', b't', b'l', b'e', 0x64, b'B', b'a', b'n', b'k', 0x65, b'n',
+            b'o', b't', b'e', b's', 0x68, b'P', b'I', b'N', b' ', b'1', b'2', b'3', b'4', 0x61,
+            b'n', 0x01,
+        ];
+        let fields = ["title".to_string(), "n".to_string()];
+        assert_eq!(searchable_text(&meta, &fields), "Bank\n");
+        assert_eq!(searchable_text(b"not cbor", &fields), "");
+    }
+
+    #[test]
+    fn test_fingerprint_ignores_order() {
+        let a = fingerprint(&["title".to_string(), "notes".to_string()]);
+        let b = fingerprint(&["notes".to_string(), "title".to_string(), "notes".to_string()]);
+        assert_eq!(a, b);
+        assert_ne!(a, fingerprint(&["title".to_string()]));
+    }
+
+    #[test]
+    fn test_search_index() {
+        let index = SearchIndex::new(Index::create_in_ram(schema())).unwrap();
+        let mut writer = index.writer();
+        let notes = |text: &str| Some(text.to_string());
+        index.replace(&writer, "", "bank", notes("Bank PIN\nCrédit Agricole")).unwrap();
+        index.replace(&writer, "", "wifi", notes("Wifi password, bank building")).unwrap();
+        index.replace(&writer, "work", "bank", notes("Bank of work")).unwrap();
+        index.replace(&writer, "", "empty", notes("  ")).unwrap();
+        index.commit(&mut writer, "fields").unwrap();
+        assert_eq!(index.built_with().unwrap().as_deref(), Some("fields"));
+
+        assert_eq!(index.search("", "crédit", 10).unwrap(), ["bank"]);
+        let mut both = index.search("", "BANK", 10).unwrap();
+        both.sort();
+        assert_eq!(both, ["bank", "wifi"]);
+        assert_eq!(index.search("", "bank", 1).unwrap().len(), 1);
+        assert_eq!(index.search("work", "bank", 10).unwrap(), ["bank"]);
+        assert!(index.search("", "\"unclosed AND", 10).is_ok());
+        assert!(index.search("", "bank", 0).unwrap().is_empty());
+
+        index.replace(&writer, "", "bank", None).unwrap();
+        index.commit(&mut writer, "fields").unwrap();
+        assert_eq!(index.search("", "bank", 10).unwrap(), ["wifi"]);
+    }
+}
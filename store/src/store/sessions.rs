@@ -0,0 +1,141 @@
+//! Resumable sync sessions.
+//!
+//! `StartSync` records the ids a peer lacks, in id order, under a new
+//! session id.  Each `ContinueSync` acknowledges how many of them the peer
+//! has received and hands out the next batch, read as the documents are
+//! then.  Progress is committed with every request, so a peer that drops
+//! off resumes from its last acknowledgement, even across restarts of the
+//! port; repeating an acknowledgement repeats the batch that was lost.
+//! `FinishSync` drops the session, and the sweeper drops any left idle for
+//! `SESSION_IDLE_SECS`.
+//!
+//! Values are bincode of `Session`.
+
+use super::{unix_now, Store, NEXT_SYNC_SESSION, STORE_META};
+use anyhow::Result;
+use redb::ReadableTable;
+use serde::{Deserialize, Serialize};
+
+/// Sessions untouched for this long are dropped by `expire_sync_sessions`.
+pub const SESSION_IDLE_SECS: u64 = 24 * 60 * 60;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Session {
+    /// Ids to send, in id order.
+    pending: Vec<String>,
+    /// How many of `pending` the peer has acknowledged.
+    acked: u64,
+    /// How many of `pending` have been handed out.
+    sent: u64,
+    /// Unix seconds of the last request on the session.
+    touched: u64,
+}
+
+/// Where `ack_sync_session` left a session.
+#[derive(Debug)]
+pub enum SyncProgress {
+    /// No such session.
+    Unknown,
+    /// `ack` was below an earlier acknowledgement or beyond what was sent.
+    BadAck { acked: u64, sent: u64 },
+    /// The ids after the acknowledged ones, in order.
+    Pending(Vec<String>),
+}
+
+impl Store {
+    /// Open a session to send `pending` (sorted by the caller), returning
+    /// its id.
+    pub fn start_sync_session(&self, pending: Vec<String>) -> Result<u64> {
+        let session = Session {
+            pending,
+            acked: 0,
+            sent: 0,
+            touched: unix_now(),
+        };
+        let txn = self.begin_write()?;
+        let id = {
+            let mut meta = txn.open_table(STORE_META)?;
+            let id = meta.get(NEXT_SYNC_SESSION)?.map_or(1, |v| v.value());
+            meta.insert(NEXT_SYNC_SESSION, id + 1)?;
+            id
+        };
+        txn.open_table(self.tables.sync_sessions())?
+            .insert(id, bincode::serialize(&session)?.as_slice())?;
+        txn.commit()?;
+        Ok(id)
+    }
+
+    /// Record that the peer has the first `ack` ids of session `id`.
+    pub fn ack_sync_session(&self, id: u64, ack: u64) -> Result<SyncProgress> {
+        let txn = self.begin_write()?;
+        let pending = {
+            let mut sessions = txn.open_table(self.tables.sync_sessions())?;
+            let encoded = sessions.get(id)?.map(|v| v.value().to_vec());
+            let Some(encoded) = encoded else {
+                return Ok(SyncProgress::Unknown);
+            };
+            let mut session: Session = bincode::deserialize(&encoded)?;
+            if ack < session.acked || ack > session.sent {
+                return Ok(SyncProgress::BadAck {
+                    acked: session.acked,
+                    sent: session.sent,
+                });
+            }
+            session.acked = ack;
+            session.touched = unix_now();
+            sessions.insert(id, bincode::serialize(&session)?.as_slice())?;
+            session.pending.split_off(ack as usize)
+        };
+        txn.commit()?;
+        Ok(SyncProgress::Pending(pending))
+    }
+
+    /// Record that the first `sent` ids of session `id` have been handed
+    /// out.
+    pub fn sent_sync_session(&self, id: u64, sent: u64) -> Result<()> {
+        let txn = self.begin_write()?;
+        {
+            let mut sessions = txn.open_table(self.tables.sync_sessions())?;
+            let encoded = sessions.get(id)?.map(|v| v.value().to_vec());
+            if let Some(encoded) = encoded {
+                let mut session: Session = bincode::deserialize(&encoded)?;
+                session.sent = session.sent.max(sent);
+                sessions.insert(id, bincode::serialize(&session)?.as_slice())?;
+            }
+        }
+        txn.commit()?;
+        Ok(())
+    }
+
+    /// Drop session `id`.  Returns false if there was none.
+    pub fn finish_sync_session(&self, id: u64) -> Result<bool> {
+        let txn = self.begin_write()?;
+        let existed = txn.open_table(self.tables.sync_sessions())?.remove(id)?.is_some();
+        txn.commit()?;
+        Ok(existed)
+    }
+
+    /// Drop the sessions idle for `SESSION_IDLE_SECS` as of `now`.
+    /// Returns how many.
+    pub fn expire_sync_sessions(&self, now: u64) -> Result<usize> {
+        let txn = self.begin_write()?;
+        let mut sessions = txn.open_table(self.tables.sync_sessions())?;
+        let mut idle = Vec::new();
+        for entry in sessions.iter()? {
+            let (id, encoded) = entry?;
+            let session: Session = bincode::deserialize(encoded.value())?;
+            if session.touched + SESSION_IDLE_SECS <= now {
+                idle.push(id.value());
+            }
+        }
+        if idle.is_empty() {
+            return Ok(0);
+        }
+        for id in &idle {
+            sessions.remove(id)?;
+        }
+        drop(sessions);
+        txn.commit()?;
+        Ok(idle.len())
+    }
+}
@@ -0,0 +1,306 @@
+//! Signed combined roots, for tamper evidence when syncing through relays.
+//!
+//! A store given a signing key (`StoreOptions::signing_key`) signs its
+//! combined root (see `roots`) with Ed25519 (ed25519-dalek), together with
+//! the namespace and an epoch.  The epoch is per namespace and rises by one
+//! whenever a root other than the last one signed is signed; root_epoch
+//! holds the last (epoch, root).  So a relay can't pass off an older signed
+//! root as current, nor one namespace's as another's.
+//!
+//! A batch of changes is signed the same way, with a digest of the batch
+//! after the root (see `signed_changes`), so a relay can't swap the changes
+//! under a genuine root either.  `ApplyChanges` may carry such a signature.
+//! One that is there is always checked; with
+//! `StoreOptions::require_signed_roots` one must be, from a key in
+//! `StoreOptions::trusted_signers`.  signer_epochs keeps the newest epoch
+//! accepted from each key, and an older one is refused; it is recorded in
+//! the transaction that applies the batch (see `apply`).
+
+use super::{from_hex, to_hex, Store, Tables};
+use anyhow::{anyhow, Result};
+use ed25519_dalek::pkcs8::EncodePrivateKey;
+use ed25519_dalek::{Signature, Signer, VerifyingKey, PUBLIC_KEY_LENGTH, SECRET_KEY_LENGTH};
+use redb::{ReadableTable, WriteTransaction};
+use std::fmt;
+use zeroize::Zeroizing;
+
+/// Prefix of every signed root, so the signature can't be reused for
+/// anything else.
+const CONTEXT: &[u8] = b"ringforge-store signed root v1\0";
+
+/// Prefix of every signed batch of changes.
+const CHANGES_CONTEXT: &[u8] = b"ringforge-store signed changes v1\0";
+
+/// An Ed25519 secret key; `Debug` shows only its public key.
+#[derive(Clone)]
+pub struct SigningKey(ed25519_dalek::SigningKey);
+
+impl SigningKey {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let seed: [u8; SECRET_KEY_LENGTH] = bytes.try_into().map_err(|_| {
+            anyhow!("signing key must be {SECRET_KEY_LENGTH} bytes, not {}", bytes.len())
+        })?;
+        Ok(Self(ed25519_dalek::SigningKey::from_bytes(&seed)))
+    }
+
+    pub fn public(&self) -> [u8; PUBLIC_KEY_LENGTH] {
+        self.0.verifying_key().to_bytes()
+    }
+
+    /// The key as PKCS#8 DER, for TLS (see `quic`).
+    pub fn to_pkcs8_der(&self) -> Result<Zeroizing<Vec<u8>>> {
+        let document = self.0.to_pkcs8_der().map_err(|e| anyhow!("encoding signing key: {e}"))?;
+        Ok(Zeroizing::new(document.as_bytes().to_vec()))
+    }
+
+    /// Parse a key written as hex, e.g. in an environment variable.
+    pub fn from_hex(text: &str) -> Result<Self> {
+        let bytes = from_hex(text.trim()).map_err(|_| anyhow!("signing key is not hex"))?;
+        Self::from_bytes(&bytes)
+    }
+}
+
+impl fmt::Debug for SigningKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SigningKey")
+            .field("public", &to_hex(&self.public()))
+            .finish_non_exhaustive()
+    }
+}
+
+/// Parse a trusted signer's public key written as hex.
+pub fn parse_public_key(text: &str) -> Result<[u8; PUBLIC_KEY_LENGTH]> {
+    let bytes = from_hex(text.trim()).map_err(|_| anyhow!("public key is not hex"))?;
+    let public: [u8; PUBLIC_KEY_LENGTH] = bytes.as_slice().try_into().map_err(|_| {
+        anyhow!("public key must be {PUBLIC_KEY_LENGTH} bytes, not {}", bytes.len())
+    })?;
+    VerifyingKey::from_bytes(&public).map_err(|_| anyhow!("public key is not a curve point"))?;
+    Ok(public)
+}
+
+/// A combined root signed by its store.
+#[derive(Debug, Clone)]
+pub struct SignedRoot {
+    pub root: Vec<u8>,
+    pub epoch: u64,
+    pub public_key: Vec<u8>,
+    pub signature: Vec<u8>,
+    /// Digest of the batch of changes signed with the root, if any.
+    pub changes: Option<Vec<u8>>,
+}
+
+/// Why `check_signed_changes` or `record_epoch` refused a signed root.
+#[derive(Debug)]
+pub struct RootRejected(pub String);
+
+impl fmt::Display for RootRejected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "signed root rejected: {}", self.0)
+    }
+}
+
+impl std::error::Error for RootRejected {}
+
+/// What is signed: the namespace, epoch and root, after `CONTEXT`; or,
+/// for a batch, the same followed by its digest, after `CHANGES_CONTEXT`.
+fn message(namespace: &str, epoch: u64, root: &[u8], changes: Option<&[u8]>) -> Vec<u8> {
+    let mut message = match changes {
+        Some(_) => CHANGES_CONTEXT.to_vec(),
+        None => CONTEXT.to_vec(),
+    };
+    message.extend_from_slice(&(namespace.len() as u64).to_le_bytes());
+    message.extend_from_slice(namespace.as_bytes());
+    message.extend_from_slice(&epoch.to_le_bytes());
+    message.extend_from_slice(root);
+    message.extend_from_slice(changes.unwrap_or_default());
+    message
+}
+
+/// Whether `signed` is a valid signature over `namespace`'s root.  Strict
+/// verification refuses non-canonical and small-order encodings, so a
+/// signature can't be altered into another valid one.
+fn valid_signature(namespace: &str, signed: &SignedRoot) -> bool {
+    let (Ok(public), Ok(signature)) = (
+        <[u8; PUBLIC_KEY_LENGTH]>::try_from(signed.public_key.as_slice()),
+        Signature::from_slice(&signed.signature),
+    ) else {
+        return false;
+    };
+    let Ok(public) = VerifyingKey::from_bytes(&public) else {
+        return false;
+    };
+    let message = message(namespace, signed.epoch, &signed.root, signed.changes.as_deref());
+    public.verify_strict(&message, &signature).is_ok()
+}
+
+impl Store {
+    /// The key the store signs with, if it has one.
+    pub fn signing_key(&self) -> Option<&SigningKey> {
+        self.options.signing_key.as_ref()
+    }
+
+    /// The combined root signed with the store's key, at the namespace's
+    /// current epoch; `None` without a signing key.
+    pub fn signed_root(&self) -> Result<Option<SignedRoot>> {
+        self.sign(None)
+    }
+
+    /// As `signed_root`, but signing `digest`, the digest of a batch of
+    /// changes, with the root.
+    pub fn signed_changes(&self, digest: &[u8]) -> Result<Option<SignedRoot>> {
+        self.sign(Some(digest))
+    }
+
+    fn sign(&self, changes: Option<&[u8]>) -> Result<Option<SignedRoot>> {
+        let Some(key) = &self.options.signing_key else {
+            return Ok(None);
+        };
+        let root = self.combined_root()?;
+        let txn = self.begin_write()?;
+        let epoch = {
+            let mut table = txn.open_table(self.tables.root_epoch())?;
+            let last = table.get(())?.map(|v| {
+                let (epoch, root) = v.value();
+                (epoch, root.to_vec())
+            });
+            match last {
+                Some((epoch, last_root)) if last_root == root => epoch,
+                last => {
+                    let epoch = last.map_or(1, |(epoch, _)| epoch + 1);
+                    table.insert((), (epoch, root.as_slice()))?;
+                    epoch
+                }
+            }
+        };
+        txn.commit()?;
+        let signature = key.0.sign(&message(self.namespace_name(), epoch, &root, changes));
+        Ok(Some(SignedRoot {
+            root,
+            epoch,
+            public_key: key.public().to_vec(),
+            signature: signature.to_vec(),
+            changes: changes.map(<[u8]>::to_vec),
+        }))
+    }
+
+    /// Check the signature an `ApplyChanges` batch carries: a valid
+    /// signature over this namespace and `digest`, the batch's digest, from
+    /// a trusted key if `require_signed_roots` is set.  Fails with
+    /// `RootRejected`.  The epoch is checked by `record_epoch`.
+    pub(super) fn check_signed_changes(
+        &self,
+        signed: Option<&SignedRoot>,
+        digest: &[u8],
+    ) -> Result<()> {
+        let Some(signed) = signed else {
+            if self.options.require_signed_roots {
+                return Err(RootRejected("changes must carry a signed root".into()).into());
+            }
+            return Ok(());
+        };
+        if signed.changes.as_deref() != Some(digest) {
+            return Err(RootRejected("the signature doesn't cover these changes".into()).into());
+        }
+        if !valid_signature(self.namespace_name(), signed) {
+            return Err(RootRejected("bad signature".into()).into());
+        }
+        let trusted = self
+            .options
+            .trusted_signers
+            .iter()
+            .any(|key| key.as_slice() == signed.public_key);
+        if self.options.require_signed_roots && !trusted {
+            let signer = to_hex(&signed.public_key);
+            return Err(RootRejected(format!("{signer} is not a trusted signer")).into());
+        }
+        Ok(())
+    }
+}
+
+/// Record `signed`'s epoch as the newest accepted from its key, failing
+/// with `RootRejected` if an older one.
+pub(super) fn record_epoch(txn: &WriteTransaction, tables: &Tables, signed: &SignedRoot) -> Result<()> {
+    let mut table = txn.open_table(tables.signer_epochs())?;
+    let last = table.get(signed.public_key.as_slice())?.map(|v| v.value());
+    if let Some(last) = last.filter(|&last| last > signed.epoch) {
+        let message = format!("epoch {} is older than {last}", signed.epoch);
+        return Err(RootRejected(message).into());
+    }
+    table.insert(signed.public_key.as_slice(), signed.epoch)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_signature_binds_namespace_and_epoch() {
+        let key = SigningKey::from_bytes(&[3; 32]).unwrap();
+        let root = vec![9; 32];
+        let signature = key.0.sign(&message("docs", 4, &root, None));
+        let signed = SignedRoot {
+            root,
+            epoch: 4,
+            public_key: key.public().to_vec(),
+            signature: signature.to_vec(),
+            changes: None,
+        };
+        assert!(valid_signature("docs", &signed));
+        assert!(!valid_signature("", &signed));
+        assert!(!valid_signature("docs", &SignedRoot { epoch: 5, ..signed.clone() }));
+        let short_key = SignedRoot { public_key: vec![1; 5], ..signed.clone() };
+        assert!(!valid_signature("docs", &short_key));
+        let mut tampered = signed.signature.clone();
+        tampered[5] ^= 1;
+        assert!(!valid_signature("docs", &SignedRoot { signature: tampered, ..signed }));
+    }
+
+    #[test]
+    fn test_signature_binds_changes() {
+        let key = SigningKey::from_bytes(&[3; 32]).unwrap();
+        let root = vec![9; 32];
+        let digest = vec![7; 32];
+        let signature = key.0.sign(&message("docs", 4, &root, Some(&digest)));
+        let signed = SignedRoot {
+            root,
+            epoch: 4,
+            public_key: key.public().to_vec(),
+            signature: signature.to_vec(),
+            changes: Some(digest),
+        };
+        assert!(valid_signature("docs", &signed));
+        assert!(!valid_signature("docs", &SignedRoot { changes: Some(vec![8; 32]), ..signed.clone() }));
+        // A root signature can't pass for a batch's, nor the reverse.
+        assert!(!valid_signature("docs", &SignedRoot { changes: None, ..signed }));
+    }
+
+    // RFC 8032 section 7.1, test 2.
+    #[test]
+    fn test_rfc8032_vector() {
+        let key = SigningKey::from_hex(
+            "4ccd089b28ff96da9db6c346ec114e0f5b8a319f35aba624da8cf6ed4fb8a6fb",
+        )
+        .unwrap();
+        assert_eq!(
+            to_hex(&key.public()),
+            "3d4017c3e843895a92b70aa74d1b7ebc9c982ccf2ec4968cc0cd55f12af4660c"
+        );
+        assert_eq!(
+            to_hex(&key.0.sign(&[0x72]).to_bytes()),
+            "92a009a9f0d4cab8720e820b5f642540a2b27b5416503f8fb3762223ebdb69da\
+             085ac1e43e15996e458f3613d0f11d8c387b2eaeb4302aeeb00d291612bb0c00"
+        );
+    }
+
+    #[test]
+    fn test_parse_keys() {
+        assert!(SigningKey::from_hex(&"ab".repeat(32)).is_ok());
+        assert!(SigningKey::from_hex("abcd").is_err());
+        assert!(SigningKey::from_hex(&"zz".repeat(32)).is_err());
+        let key = SigningKey::from_bytes(&[3; 32]).unwrap();
+        assert!(!format!("{key:?}").contains(&to_hex(&[3; 32])));
+        assert_eq!(parse_public_key(&to_hex(&key.public())).unwrap(), key.public());
+        assert!(parse_public_key("00").is_err());
+    }
+}
@@ -0,0 +1,224 @@
+//! Read-only access to a served data directory from a second process.
+//!
+//! redb locks its file for the one process that opens it, readers
+//! included, so nothing can attach to a database while a port serves it.
+//! Instead the serving port publishes snapshots (`publish_snapshot`, every
+//! `--snapshot-interval-secs`): a `backup` into a new directory under
+//! `<data-dir>/snapshots/`, named in `snapshots/CURRENT` once it is
+//! complete.  With `StoreOptions::read_only`, `Store::open` opens the
+//! current snapshot instead of the database.  It copies the snapshot to a
+//! private directory, because redb's lock would let only one reader use
+//! the file, and opens the copy with every write refused.  The copy is
+//! deleted once the last handle on it is dropped.
+//!
+//! `snapshots/LOCK` keeps the publisher from deleting a snapshot that is
+//! being copied: readers hold it shared while they copy, and superseded
+//! snapshots are only removed while the publisher holds it exclusively.
+//! If a reader is copying, they are left for the next publish.
+
+use super::backup::BackupReport;
+use super::{CreateMode, Store, StoreOptions};
+use anyhow::{bail, Context, Result};
+use std::fs::{self, File, TryLockError};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::{debug, info, instrument, warn};
+
+pub(super) const SNAPSHOT_DIR: &str = "snapshots";
+
+/// Names the current snapshot's directory.
+const CURRENT: &str = "CURRENT";
+
+const LOCK: &str = "LOCK";
+
+/// Private copies made by this process, for naming the next one.
+static COPIES: AtomicU64 = AtomicU64::new(0);
+
+/// The private copy a read-only store was opened from, deleted on drop.
+pub(super) struct PrivateCopy {
+    dir: PathBuf,
+}
+
+impl Drop for PrivateCopy {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_dir_all(&self.dir) {
+            warn!(dir = %self.dir.display(), error = %e, "removing a snapshot copy failed");
+        }
+    }
+}
+
+impl Store {
+    /// Write a snapshot of the database for read-only openers and make it
+    /// the current one, then remove the snapshots it supersedes unless a
+    /// reader is copying one.
+    #[instrument(skip(self))]
+    pub fn publish_snapshot(&self) -> Result<BackupReport> {
+        let root = self.dir.join(SNAPSHOT_DIR);
+        let name = format!("{:024}", SystemTime::now().duration_since(UNIX_EPOCH)?.as_nanos());
+        let report = self.backup(&root.join(&name))?;
+        let partial = root.join("CURRENT.partial");
+        fs::write(&partial, &name).with_context(|| format!("writing {}", partial.display()))?;
+        fs::rename(&partial, root.join(CURRENT))
+            .with_context(|| format!("renaming {} into place", partial.display()))?;
+
+        let lock = lock_file(&root)?;
+        match lock.try_lock() {
+            Ok(()) => remove_superseded(&root, &name)?,
+            Err(TryLockError::WouldBlock) => debug!("a reader is copying a snapshot; keeping it"),
+            Err(TryLockError::Error(e)) => {
+                return Err(e).with_context(|| format!("locking {}", root.join(LOCK).display()))
+            }
+        }
+        info!(snapshot = name, file_bytes = report.file_bytes, "snapshot published");
+        Ok(report)
+    }
+
+    /// Whether this store is a read-only snapshot, see `snapshot`.
+    pub fn is_read_only(&self) -> bool {
+        self.db.snapshot.get().is_some()
+    }
+}
+
+/// Open a private copy of the snapshot published in `dir`.
+pub(super) fn open(dir: &Path, mut options: StoreOptions) -> Result<Store> {
+    let root = dir.join(SNAPSHOT_DIR);
+    let current = root.join(CURRENT);
+    if !current.exists() {
+        bail!(
+            "no snapshot has been published in {}; serve {} with --snapshot-interval-secs",
+            root.display(),
+            dir.display()
+        );
+    }
+    let copy = PrivateCopy {
+        dir: std::env::temp_dir().join(format!(
+            "keyring-store-snapshot-{}-{}",
+            std::process::id(),
+            COPIES.fetch_add(1, Ordering::Relaxed)
+        )),
+    };
+    {
+        let lock = lock_file(&root)?;
+        lock.lock_shared()
+            .with_context(|| format!("locking {}", root.join(LOCK).display()))?;
+        let name = fs::read_to_string(&current)
+            .with_context(|| format!("reading {}", current.display()))?;
+        copy_dir(&root.join(name.trim()), &copy.dir)?;
+        debug!(snapshot = name.trim(), copy = %copy.dir.display(), "snapshot copied");
+    }
+
+    // Reads of a snapshot are neither counted nor kept.
+    options.read_only = false;
+    options.track_access = false;
+    options.create = CreateMode::Never;
+    let store = Store::open(&copy.dir, options)?;
+    // Handles on namespaces are made on first use, which needs a write.
+    for namespace in store.namespaces()? {
+        store.namespace(&namespace)?;
+    }
+    if store.db.snapshot.set(copy).is_err() {
+        unreachable!("a database just opened has no snapshot yet");
+    }
+    Ok(store)
+}
+
+fn lock_file(root: &Path) -> Result<File> {
+    let path = root.join(LOCK);
+    File::options()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&path)
+        .with_context(|| format!("opening {}", path.display()))
+}
+
+/// Remove every snapshot in `root` but `current`, including any whose
+/// publish was interrupted.
+fn remove_superseded(root: &Path, current: &str) -> Result<()> {
+    for entry in fs::read_dir(root).with_context(|| format!("listing {}", root.display()))? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() && entry.file_name() != current {
+            fs::remove_dir_all(entry.path())
+                .with_context(|| format!("removing {}", entry.path().display()))?;
+            debug!(snapshot = %entry.file_name().to_string_lossy(), "superseded snapshot removed");
+        }
+    }
+    Ok(())
+}
+
+fn copy_dir(src: &Path, dst: &Path) -> Result<()> {
+    fs::create_dir_all(dst).with_context(|| format!("creating {}", dst.display()))?;
+    for entry in fs::read_dir(src).with_context(|| format!("listing {}", src.display()))? {
+        let entry = entry?;
+        let to = dst.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir(&entry.path(), &to)?;
+        } else {
+            fs::copy(entry.path(), &to)
+                .with_context(|| format!("copying {} to {}", entry.path().display(), to.display()))?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_only_snapshot() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_path_buf();
+        let primary = Store::open(&dir, StoreOptions::default()).unwrap();
+        assert!(Store::open(&dir, StoreOptions {
+            read_only: true,
+            ..Default::default()
+        })
+        .is_err());
+
+        primary.put_document("a", b"meta-a", b"state-a", None, false).unwrap();
+        let other = primary.namespace("other").unwrap();
+        other.put_document("b", b"meta-b", b"state-b", None, false).unwrap();
+        primary.publish_snapshot().unwrap();
+        primary.put_document("a", b"meta-a2", b"state-a2", None, false).unwrap();
+
+        let options = StoreOptions {
+            read_only: true,
+            ..Default::default()
+        };
+        let reader = Store::open(&dir, options.clone()).unwrap();
+        assert!(reader.is_read_only() && !primary.is_read_only());
+        assert_eq!(reader.get_document("a").unwrap().unwrap().meta, b"meta-a");
+        let other = reader.namespace("other").unwrap();
+        assert_eq!(other.get_document("b").unwrap().unwrap().crdt_state, b"state-b");
+        let refused = reader.put_document("c", b"meta", b"state", None, false).unwrap_err();
+        assert!(refused.to_string().contains("read-only"), "{refused:#}");
+        assert!(reader.namespace("new").is_err());
+        reader.stats().unwrap();
+
+        // A reader holding the lock keeps the snapshot it may be copying.
+        let held = lock_file(&dir.join(SNAPSHOT_DIR)).unwrap();
+        held.lock_shared().unwrap();
+        primary.publish_snapshot().unwrap();
+        let snapshots = |dir: &Path| {
+            fs::read_dir(dir.join(SNAPSHOT_DIR))
+                .unwrap()
+                .filter(|e| e.as_ref().unwrap().file_type().unwrap().is_dir())
+                .count()
+        };
+        assert_eq!(snapshots(&dir), 2);
+        drop(held);
+        primary.publish_snapshot().unwrap();
+        assert_eq!(snapshots(&dir), 1);
+
+        let second = Store::open(&dir, options).unwrap();
+        assert_eq!(second.get_document("a").unwrap().unwrap().meta, b"meta-a2");
+        assert_eq!(reader.get_document("a").unwrap().unwrap().meta, b"meta-a");
+
+        let copy = reader.dir().to_path_buf();
+        assert!(copy.exists());
+        drop((reader, other));
+        assert!(!copy.exists());
+    }
+}
@@ -0,0 +1,89 @@
+//! Filesystem storage for blobs too large to keep inside redb.
+//!
+//! Spilled blobs live at `<data_dir>/blobs/ab/cd/<hex hash>` (`blobs@<ns>`
+//! for other namespaces), sharded by the first two hash bytes so no
+//! directory grows unboundedly.  redb keeps only an `External` codec header
+//! recording the original length.  Encrypted spill files (`SealedExternal`,
+//! see `encryption`) add a `.sealed` extension, so a blob spilled before the
+//! database had a key and again after it never share a file.
+
+use super::codec;
+use anyhow::{Context, Result};
+use std::fs;
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+pub const SPILL_DIR: &str = "blobs";
+
+/// Path of the spill file for `hash` under a namespace's spill directory.
+pub fn path_for(spill_dir: &Path, hash: &[u8]) -> PathBuf {
+    let hex = super::to_hex(hash);
+    spill_dir
+        .join(&hex[0..2])
+        .join(&hex[2..4])
+        .join(hex)
+}
+
+/// Path of an encrypted spill file.
+pub fn sealed_path_for(spill_dir: &Path, hash: &[u8]) -> PathBuf {
+    path_for(spill_dir, hash).with_extension("sealed")
+}
+
+/// Path of the spill file the blobs-table value `stored` refers to.
+pub fn path_of(spill_dir: &Path, hash: &[u8], stored: &[u8]) -> PathBuf {
+    if codec::is_sealed_external(stored) {
+        sealed_path_for(spill_dir, hash)
+    } else {
+        path_for(spill_dir, hash)
+    }
+}
+
+/// Write `data` to `path` atomically (temp file + fsync + rename).  Content
+/// addressing means an existing file already holds the same bytes.
+pub fn write(path: &Path, data: &[u8]) -> Result<()> {
+    if path.exists() {
+        return Ok(());
+    }
+    replace(path, data)
+}
+
+/// Write `data` to `path` atomically, over any existing file.
+pub fn replace(path: &Path, data: &[u8]) -> Result<()> {
+    let dir = path.parent().expect("spill paths are nested");
+    fs::create_dir_all(dir).with_context(|| format!("creating {}", dir.display()))?;
+
+    let tmp = path.with_extension("tmp");
+    let mut file = fs::File::create(&tmp).with_context(|| format!("creating {}", tmp.display()))?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    fs::rename(&tmp, path).with_context(|| format!("renaming into {}", path.display()))?;
+    Ok(())
+}
+
+/// Copy a spill file to `to`, atomically like `write`.
+pub fn copy(from: &Path, to: &Path) -> Result<()> {
+    if to.exists() {
+        return Ok(());
+    }
+    let dir = to.parent().expect("spill paths are nested");
+    fs::create_dir_all(dir).with_context(|| format!("creating {}", dir.display()))?;
+
+    let tmp = to.with_extension("tmp");
+    fs::copy(from, &tmp).with_context(|| format!("copying spilled blob {}", from.display()))?;
+    fs::File::open(&tmp)?.sync_all()?;
+    fs::rename(&tmp, to).with_context(|| format!("renaming into {}", to.display()))?;
+    Ok(())
+}
+
+pub fn read(path: &Path) -> Result<Vec<u8>> {
+    fs::read(path).with_context(|| format!("reading spilled blob {}", path.display()))
+}
+
+/// Remove a spill file; a file that is already gone is not an error.
+pub fn remove(path: &Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e).with_context(|| format!("removing {}", path.display())),
+    }
+}
@@ -0,0 +1,166 @@
+//! Storage statistics and compaction.
+
+use super::{codec, unix_now, Store, Tables, LAST_COMPACTION, STORE_META};
+use anyhow::{Context, Result};
+use redb::{ReadableTable, ReadableTableMetadata, TableHandle, WriteTransaction};
+use serde::Serialize;
+use tracing::{info, instrument};
+
+/// blob_puts keys.
+const PUTS: &str = "puts";
+const HITS: &str = "hits";
+const LOGICAL_BYTES: &str = "logical_bytes";
+const DEDUPLICATED_BYTES: &str = "deduplicated_bytes";
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct StoreStats {
+    /// Size of keyring.redb on disk.
+    pub file_bytes: u64,
+    pub page_size: u64,
+    /// Pages holding live data or metadata.
+    pub allocated_pages: u64,
+    /// Pages in the file not allocated to anything; `Compact` reclaims them.
+    pub free_pages: u64,
+    /// Bytes lost to partially filled pages.
+    pub fragmented_bytes: u64,
+    /// Key and value bytes across all tables.
+    pub stored_bytes: u64,
+    pub metadata_bytes: u64,
+    /// Every table in the file, in name order.
+    pub tables: Vec<TableUsage>,
+    /// Original size of the blobs in this namespace, spilled ones included.
+    pub blob_bytes: u64,
+    /// Unix seconds of the last successful compaction, if any.
+    pub last_compaction: Option<u64>,
+    /// redb's page cache for the file, `StoreOptions::db_cache_bytes`.
+    pub db_cache_bytes: u64,
+}
+
+/// What one table holds, to tell which table grows and how deep lookups go.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct TableUsage {
+    /// Namespace suffix included, e.g. `documents@team`.
+    pub name: String,
+    pub entries: u64,
+    /// Key and value bytes, after compression for blobs.
+    pub stored_bytes: u64,
+    /// Levels of the table's b-tree; each is a page read on a cold lookup.
+    pub tree_height: u32,
+}
+
+/// Blob puts in a namespace since they were first counted.
+#[derive(Debug, Clone, Default)]
+pub struct DedupStats {
+    pub puts: u64,
+    /// Puts of content that was already stored.
+    pub hits: u64,
+    /// Bytes passed to all puts.
+    pub logical_bytes: u64,
+    /// Bytes passed to the hits, which took no new space.
+    pub deduplicated_bytes: u64,
+}
+
+/// Count a put of `len` bytes in `txn`; `existed` if the content was
+/// already stored.
+pub(super) fn count_blob_put(
+    txn: &WriteTransaction,
+    tables: &Tables,
+    len: usize,
+    existed: bool,
+) -> Result<()> {
+    let mut counters = txn.open_table(tables.blob_puts())?;
+    let mut add = |key: &str, n: u64| -> Result<()> {
+        let total = counters.get(key)?.map_or(0, |v| v.value()).saturating_add(n);
+        counters.insert(key, total)?;
+        Ok(())
+    };
+    add(PUTS, 1)?;
+    add(LOGICAL_BYTES, len as u64)?;
+    if existed {
+        add(HITS, 1)?;
+        add(DEDUPLICATED_BYTES, len as u64)?;
+    }
+    Ok(())
+}
+
+impl Store {
+    pub fn dedup_stats(&self) -> Result<DedupStats> {
+        let txn = self.db.begin_read()?;
+        let counters = txn.open_table(self.tables.blob_puts())?;
+        let get = |key: &str| -> Result<u64> { Ok(counters.get(key)?.map_or(0, |v| v.value())) };
+        Ok(DedupStats {
+            puts: get(PUTS)?,
+            hits: get(HITS)?,
+            logical_bytes: get(LOGICAL_BYTES)?,
+            deduplicated_bytes: get(DEDUPLICATED_BYTES)?,
+        })
+    }
+
+    pub fn stats(&self) -> Result<StoreStats> {
+        let file_bytes = self.file_bytes()?;
+
+        // redb only reports page usage from a write transaction; nothing is
+        // written, so abort it.
+        let txn = self.db.begin_inspection()?;
+        let db_stats = txn.stats()?;
+        txn.abort()?;
+
+        let page_size = db_stats.page_size() as u64;
+        let mut stats = StoreStats {
+            file_bytes,
+            page_size,
+            allocated_pages: db_stats.allocated_pages(),
+            free_pages: (file_bytes / page_size).saturating_sub(db_stats.allocated_pages()),
+            fragmented_bytes: db_stats.fragmented_bytes(),
+            stored_bytes: db_stats.stored_bytes(),
+            metadata_bytes: db_stats.metadata_bytes(),
+            db_cache_bytes: self.options.db_cache_bytes as u64,
+            ..Default::default()
+        };
+
+        let txn = self.db.begin_read()?;
+        for handle in txn.list_tables()? {
+            let table = txn.open_untyped_table(handle.clone())?;
+            let table_stats = table.stats()?;
+            stats.tables.push(TableUsage {
+                name: handle.name().to_string(),
+                entries: table.len()?,
+                stored_bytes: table_stats.stored_bytes(),
+                tree_height: table_stats.tree_height(),
+            });
+        }
+        for entry in txn.open_table(self.tables.blobs())?.iter()? {
+            let (_, value) = entry?;
+            stats.blob_bytes += codec::original_len(value.value())?;
+        }
+        stats.last_compaction = txn
+            .open_table(STORE_META)?
+            .get(LAST_COMPACTION)?
+            .map(|v| v.value());
+        Ok(stats)
+    }
+
+    /// Rewrite the database file without its free pages.  Returns the file
+    /// size before and after.  Fails if a transaction is open elsewhere,
+    /// e.g. a sweep in progress; retrying later is safe.
+    #[instrument(skip(self))]
+    pub fn compact(&self) -> Result<(u64, u64)> {
+        let before = self.file_bytes()?;
+        self.db.compact().context("compacting database")?;
+
+        let txn = self.db.begin_write()?;
+        txn.open_table(STORE_META)?.insert(LAST_COMPACTION, unix_now())?;
+        txn.commit()?;
+
+        let after = self.file_bytes()?;
+        info!(before, after, "database compacted");
+        Ok((before, after))
+    }
+
+    fn file_bytes(&self) -> Result<u64> {
+        let path = self.dir.join(super::DB_FILE);
+        Ok(std::fs::metadata(&path)
+            .with_context(|| format!("reading size of {}", path.display()))?
+            .len())
+    }
+}
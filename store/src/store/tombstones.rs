@@ -0,0 +1,138 @@
+//! Tombstones for deleted documents.
+//!
+//! Deleting a document leaves a tombstone row and replaces its doc_hashes
+//! entry with a deletion hash, so the deletion shows up in Merkle roots and
+//! travels through GetChanges/ApplyChanges like any other change.  The
+//! deletion hash depends only on the deleted state, so peers deleting the
+//! same version converge on the same root.
+//!
+//! Values are `[32-byte deletion hash][32-byte deleted state hash or empty]`
+//! followed by `[8-byte LE unix seconds]`.
+
+use super::{ancestry, buckets, changelog, unix_now, ApplyBatch, Store, Tables};
+use anyhow::{bail, Result};
+use redb::{ReadableTable, WriteTransaction};
+
+const HASH_LEN: usize = 32;
+
+#[derive(Debug, Clone)]
+pub struct Tombstone {
+    /// Hash standing in for the document in roots and change streams.
+    pub hash: Vec<u8>,
+    /// State hash of the version that was deleted; empty if the deletion
+    /// arrived from a peer for a document this store never had.
+    pub deleted_state: Vec<u8>,
+    pub deleted_at: u64,
+}
+
+/// Deletion hash for a document whose current state hash is `state_hash`.
+pub fn deletion_hash(state_hash: &[u8]) -> Vec<u8> {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(b"tombstone\0");
+    hasher.update(state_hash);
+    hasher.finalize().as_bytes().to_vec()
+}
+
+/// Record that `id` was deleted at `deleted_at` (unix seconds), replacing
+/// its doc_hashes entry with `hash`.
+pub(super) fn write_tombstone(
+    txn: &WriteTransaction,
+    tables: &Tables,
+    id: &str,
+    hash: &[u8],
+    deleted_state: &[u8],
+    deleted_at: u64,
+) -> Result<()> {
+    if hash.len() != HASH_LEN || !matches!(deleted_state.len(), 0 | HASH_LEN) {
+        bail!("malformed tombstone for {id:?}");
+    }
+    let mut value = Vec::with_capacity(2 * HASH_LEN + 8);
+    value.extend_from_slice(hash);
+    value.extend_from_slice(deleted_state);
+    value.extend_from_slice(&deleted_at.to_le_bytes());
+
+    txn.open_table(tables.tombstones())?.insert(id, value.as_slice())?;
+    buckets::set_doc_hash(txn, tables, id, Some(hash))?;
+    ancestry::record(txn, tables, id, hash, Some(deleted_state).filter(|s| !s.is_empty()))?;
+    changelog::append(txn, tables, id, hash, true)?;
+    Ok(())
+}
+
+/// Forget the tombstone of a document that is being written again.
+pub(super) fn clear_tombstone(txn: &WriteTransaction, tables: &Tables, id: &str) -> Result<()> {
+    txn.open_table(tables.tombstones())?.remove(id)?;
+    Ok(())
+}
+
+pub(super) fn parse(value: &[u8]) -> Result<Tombstone> {
+    let state_len = match value.len().checked_sub(HASH_LEN + 8) {
+        Some(n @ (0 | HASH_LEN)) => n,
+        _ => bail!("truncated tombstone"),
+    };
+    let (hash, rest) = value.split_at(HASH_LEN);
+    let (deleted_state, deleted_at) = rest.split_at(state_len);
+    Ok(Tombstone {
+        hash: hash.to_vec(),
+        deleted_state: deleted_state.to_vec(),
+        deleted_at: u64::from_le_bytes(deleted_at.try_into().unwrap()),
+    })
+}
+
+impl Store {
+    pub fn get_tombstone(&self, id: &str) -> Result<Option<Tombstone>> {
+        let txn = self.db.begin_read()?;
+        let tombstones = txn.open_table(self.tables.tombstones())?;
+        tombstones.get(id)?.map(|v| parse(v.value())).transpose()
+    }
+}
+
+impl ApplyBatch<'_> {
+    /// `id`'s tombstone, as `Store::get_tombstone`.
+    pub fn tombstone(&self, id: &str) -> Result<Option<Tombstone>> {
+        let tombstones = self.txn.open_table(self.store.tables.tombstones())?;
+        let tombstone = tombstones.get(id)?.map(|v| parse(v.value())).transpose();
+        tombstone
+    }
+
+    /// Apply a deletion received from a peer: drop the local copy (if any)
+    /// and adopt the peer's deletion hash.
+    pub fn apply_tombstone(&mut self, id: &str, hash: &[u8]) -> Result<()> {
+        let store = self.store;
+        store.validate_id(id)?;
+        let deleted_state = store.remove_document(&self.txn, id)?.unwrap_or_default();
+        write_tombstone(&self.txn, &store.tables, id, hash, &deleted_state, unix_now())?;
+        self.touched.push(store.document_key(id));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::StoreOptions;
+
+    #[test]
+    fn test_tombstone() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Store::open(dir.path(), StoreOptions::default()).unwrap();
+        store.put_document("a", b"meta", b"state", None, false).unwrap();
+        let state_hash = store.get_doc_hash("a").unwrap().unwrap();
+        let root = store.combined_root().unwrap();
+
+        assert!(store.delete_document("a", false).unwrap());
+        assert!(store.get_document("a").unwrap().is_none());
+        let tombstone = store.get_tombstone("a").unwrap().unwrap();
+        assert_eq!(tombstone.hash, deletion_hash(&state_hash));
+        assert_eq!(tombstone.deleted_state, state_hash);
+        assert_eq!(store.get_doc_hash("a").unwrap(), Some(tombstone.hash));
+        assert_ne!(store.combined_root().unwrap(), root);
+        assert!(!store.delete_document("a", false).unwrap());
+
+        store.put_document("a", b"meta", b"state", None, false).unwrap();
+        assert!(store.get_tombstone("a").unwrap().is_none());
+        assert_eq!(store.get_doc_hash("a").unwrap(), Some(state_hash));
+        assert_eq!(store.combined_root().unwrap(), root);
+        assert_eq!(parse(&[0; 40]).unwrap().deleted_state, b"");
+        assert!(parse(&[0; 41]).is_err());
+    }
+}
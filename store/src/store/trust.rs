@@ -0,0 +1,197 @@
+//! Registered sync peers, and the check that sync writes come from one.
+//!
+//! PEERS holds the peers the database accepts `ApplyChanges` and
+//! `PushBlobs` from, by peer id.  While it is empty any caller may send
+//! them, as before peers could be registered.  Once a peer is added, every
+//! such request must name a registered peer allowed the namespace it
+//! writes to.  A peer registered with a public key must also send its
+//! changes with a root signed by that key, so the id alone isn't enough to
+//! impersonate it; `signing` checks the signature itself.  Each accepted
+//! request updates the peer's last_seen.
+//!
+//! Values are bincode of `Entry`.
+
+use super::{to_hex, unix_now, SignedRoot, Store, PEERS};
+use anyhow::Result;
+use redb::{ReadableTable, ReadableTableMetadata, TableError};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Entry {
+    public_key: Vec<u8>,
+    namespaces: Vec<String>,
+    last_seen: u64,
+}
+
+/// A registered peer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Peer {
+    pub peer_id: String,
+    /// Ed25519 key its signed roots must carry; empty if it signs none.
+    pub public_key: Vec<u8>,
+    /// Namespaces it may write to; empty allows every one.
+    pub namespaces: Vec<String>,
+    /// Unix seconds of its last accepted request; 0 if it sent none.
+    pub last_seen: u64,
+}
+
+impl Peer {
+    fn from_entry(peer_id: &str, entry: Entry) -> Self {
+        Self {
+            peer_id: peer_id.to_string(),
+            public_key: entry.public_key,
+            namespaces: entry.namespaces,
+            last_seen: entry.last_seen,
+        }
+    }
+
+    pub fn allows(&self, namespace: &str) -> bool {
+        self.namespaces.is_empty() || self.namespaces.iter().any(|n| n == namespace)
+    }
+}
+
+/// A sync request refused because of who sent it.
+#[derive(Debug)]
+pub struct PeerRejected(pub String);
+
+impl fmt::Display for PeerRejected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "peer rejected: {}", self.0)
+    }
+}
+
+impl std::error::Error for PeerRejected {}
+
+impl Store {
+    /// Register `peer_id`, or replace its key and namespaces if it is
+    /// registered; its last_seen is kept.
+    pub fn add_peer(&self, peer_id: &str, public_key: &[u8], namespaces: &[String]) -> Result<()> {
+        let txn = self.begin_write()?;
+        {
+            let mut table = txn.open_table(PEERS)?;
+            let last_seen = match table.get(peer_id)? {
+                Some(v) => bincode::deserialize::<Entry>(v.value())?.last_seen,
+                None => 0,
+            };
+            let entry = Entry {
+                public_key: public_key.to_vec(),
+                namespaces: namespaces.to_vec(),
+                last_seen,
+            };
+            table.insert(peer_id, bincode::serialize(&entry)?.as_slice())?;
+        }
+        txn.commit()?;
+        Ok(())
+    }
+
+    /// Unregister `peer_id`; false if it wasn't registered.
+    pub fn remove_peer(&self, peer_id: &str) -> Result<bool> {
+        let txn = self.begin_write()?;
+        let removed = txn.open_table(PEERS)?.remove(peer_id)?.is_some();
+        txn.commit()?;
+        Ok(removed)
+    }
+
+    /// Every registered peer, in id order.
+    pub fn peers(&self) -> Result<Vec<Peer>> {
+        let txn = self.db.begin_read()?;
+        let table = match txn.open_table(PEERS) {
+            Ok(table) => table,
+            Err(TableError::TableDoesNotExist(_)) => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut peers = Vec::new();
+        for entry in table.iter()? {
+            let (k, v) = entry?;
+            peers.push(Peer::from_entry(k.value(), bincode::deserialize(v.value())?));
+        }
+        Ok(peers)
+    }
+
+    /// Check that a `PushBlobs` (or, through `check_peer_changes`, an
+    /// `ApplyChanges`) naming `peer_id` may write to this namespace, and
+    /// record that the peer was seen.  Fails with `PeerRejected`.  `None`
+    /// while no peer is registered.
+    pub fn check_peer(&self, peer_id: Option<&str>) -> Result<Option<Peer>> {
+        {
+            let txn = self.db.begin_read()?;
+            match txn.open_table(PEERS) {
+                Ok(table) if !table.is_empty()? => {}
+                Ok(_) | Err(TableError::TableDoesNotExist(_)) => return Ok(None),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        let Some(peer_id) = peer_id else {
+            return Err(PeerRejected("sync writes must name a registered peer".into()).into());
+        };
+        let txn = self.begin_write()?;
+        let peer = {
+            let mut table = txn.open_table(PEERS)?;
+            let entry = table.get(peer_id)?.map(|v| bincode::deserialize::<Entry>(v.value()));
+            let Some(entry) = entry.transpose()? else {
+                return Err(PeerRejected(format!("unknown peer {peer_id:?}")).into());
+            };
+            let mut peer = Peer::from_entry(peer_id, entry);
+            let namespace = self.namespace_name();
+            if !peer.allows(namespace) {
+                let message = format!("{peer_id:?} may not write to namespace {namespace:?}");
+                return Err(PeerRejected(message).into());
+            }
+            peer.last_seen = unix_now();
+            let entry = Entry {
+                public_key: peer.public_key.clone(),
+                namespaces: peer.namespaces.clone(),
+                last_seen: peer.last_seen,
+            };
+            table.insert(peer_id, bincode::serialize(&entry)?.as_slice())?;
+            peer
+        };
+        txn.commit()?;
+        Ok(Some(peer))
+    }
+
+    /// `check_peer` for an `ApplyChanges` batch, which must also carry a
+    /// root signed by the peer's key if it has one.
+    pub fn check_peer_changes(
+        &self,
+        peer_id: Option<&str>,
+        signed_root: Option<&SignedRoot>,
+    ) -> Result<()> {
+        let Some(peer) = self.check_peer(peer_id)? else {
+            return Ok(());
+        };
+        if peer.public_key.is_empty() {
+            return Ok(());
+        }
+        match signed_root {
+            Some(signed) if signed.public_key == peer.public_key => Ok(()),
+            _ => {
+                let (id, key) = (&peer.peer_id, to_hex(&peer.public_key));
+                let message = format!("changes from {id:?} must carry a root signed by {key}");
+                Err(PeerRejected(message).into())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allowed_namespaces() {
+        let mut peer = Peer {
+            peer_id: "edge".into(),
+            public_key: Vec::new(),
+            namespaces: Vec::new(),
+            last_seen: 0,
+        };
+        assert!(peer.allows(""));
+        assert!(peer.allows("docs"));
+        peer.namespaces = vec!["docs".into()];
+        assert!(peer.allows("docs"));
+        assert!(!peer.allows(""));
+        assert!(!peer.allows("doc"));
+    }
+}
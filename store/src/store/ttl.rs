@@ -0,0 +1,128 @@
+//! Blob expiry.
+//!
+//! A blob stored with a TTL gets a row in blob_ttl (hash → expiry) and
+//! blob_expiry ((expiry, hash) → ()), the latter letting the sweeper find
+//! due blobs with a range scan.
+//!
+//! Because blobs are content-addressed, one row may serve several writers,
+//! so a TTL never makes content *less* durable than another writer asked:
+//!   * a put without a TTL makes the blob permanent;
+//!   * a put with a TTL on an existing permanent blob leaves it permanent;
+//!   * a put with a TTL on an expiring blob keeps the later expiry.
+
+use super::{unix_now, Store, Tables};
+use anyhow::Result;
+use redb::{ReadableTable, WriteTransaction};
+use tracing::info;
+
+/// Record the expiry for a blob just written in `txn`.
+pub(super) fn set_expiry(
+    txn: &WriteTransaction,
+    tables: &Tables,
+    hash: &[u8],
+    ttl_secs: Option<u64>,
+    existed: bool,
+) -> Result<()> {
+    let Some(ttl) = ttl_secs else {
+        return clear_expiry(txn, tables, hash);
+    };
+
+    let mut ttls = txn.open_table(tables.blob_ttl())?;
+    let previous = ttls.get(hash)?.map(|v| v.value());
+    let expires_at = unix_now().saturating_add(ttl);
+    let expires_at = match (existed, previous) {
+        // Already stored without a TTL: stays permanent.
+        (true, None) => return Ok(()),
+        (_, Some(prev)) if prev >= expires_at => return Ok(()),
+        _ => expires_at,
+    };
+
+    let mut index = txn.open_table(tables.blob_expiry())?;
+    if let Some(prev) = previous {
+        index.remove((prev, hash))?;
+    }
+    ttls.insert(hash, expires_at)?;
+    index.insert((expires_at, hash), ())?;
+    Ok(())
+}
+
+/// Make a blob permanent (or forget the expiry of a deleted blob).
+pub(super) fn clear_expiry(txn: &WriteTransaction, tables: &Tables, hash: &[u8]) -> Result<()> {
+    let mut ttls = txn.open_table(tables.blob_ttl())?;
+    if let Some(prev) = ttls.remove(hash)? {
+        let mut index = txn.open_table(tables.blob_expiry())?;
+        index.remove((prev.value(), hash))?;
+    }
+    Ok(())
+}
+
+impl Store {
+    /// How many blobs `sweep_expired(now)` would delete.
+    pub fn count_expired(&self, now: u64) -> Result<usize> {
+        let txn = self.db.begin_read()?;
+        let index = txn.open_table(self.tables.blob_expiry())?;
+        let end: (u64, &[u8]) = (now.saturating_add(1), &[]);
+        Ok(index.range::<(u64, &[u8])>(..end)?.count())
+    }
+
+    /// Delete every blob whose expiry is at or before `now`.  Returns how
+    /// many were removed.
+    pub fn sweep_expired(&self, now: u64) -> Result<usize> {
+        let txn = self.begin_write()?;
+        let due: Vec<Vec<u8>> = {
+            let index = txn.open_table(self.tables.blob_expiry())?;
+            let mut due = Vec::new();
+            let end: (u64, &[u8]) = (now.saturating_add(1), &[]);
+            for entry in index.range::<(u64, &[u8])>(..end)? {
+                let (key, _) = entry?;
+                due.push(key.value().1.to_vec());
+            }
+            due
+        };
+        if due.is_empty() {
+            txn.abort()?;
+            return Ok(0);
+        }
+
+        let removed = self.remove_blobs(&txn, &due)?;
+        txn.commit()?;
+        self.blobs_removed(removed)?;
+
+        info!(count = due.len(), "expired blobs swept");
+        Ok(due.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::StoreOptions;
+
+    #[test]
+    fn test_sweep_removes_due_blobs() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Store::open(dir.path(), StoreOptions::default()).unwrap();
+        let short = store.put_blob(b"short", Some(10)).unwrap();
+        let long = store.put_blob(b"long", Some(1000)).unwrap();
+        let now = unix_now();
+
+        assert_eq!(store.count_expired(now).unwrap(), 0);
+        assert_eq!(store.sweep_expired(now + 100).unwrap(), 1);
+        assert!(!store.has_blob(&short).unwrap());
+        assert!(store.has_blob(&long).unwrap());
+        assert_eq!(store.count_expired(now + 100).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_permanent_put_keeps_blob() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Store::open(dir.path(), StoreOptions::default()).unwrap();
+        let hash = store.put_blob(b"data", Some(10)).unwrap();
+        store.put_blob(b"data", None).unwrap();
+        // A TTL on a permanent blob leaves it permanent.
+        store.put_blob(b"data", Some(10)).unwrap();
+
+        assert_eq!(store.sweep_expired(unix_now() + 100).unwrap(), 0);
+        assert!(store.has_blob(&hash).unwrap());
+    }
+}
@@ -0,0 +1,515 @@
+//! Integrity verification.
+//!
+//! A shallow pass checks that the tables agree with each other: every
+//! document has its data and hash rows, every hash belongs to a document or
+//! tombstone, expiry rows pair up, attachments point at a document and a
+//! blob, spill files exist.  A deep pass also
+//! recomputes blake3 over every CRDT state and blob.
+
+use super::encryption::{open_state, Keys};
+use super::{codec, hashing, spill, to_hex, tombstones, unix_now, Store};
+use anyhow::Result;
+use redb::{ReadTransaction, ReadableTable, ReadableTableMetadata, TableDefinition, TableHandle};
+use std::collections::HashSet;
+use tracing::{info, instrument, warn};
+
+/// Problems kept in a report; the rest are only counted.
+const MAX_LISTED: usize = 1000;
+
+#[derive(Debug, Clone)]
+pub struct Problem {
+    /// Table holding the bad row, namespace suffix included.
+    pub table: String,
+    /// Document id, or hex blob hash.
+    pub key: String,
+    pub message: String,
+}
+
+/// Problems found (or, for `repair`, fixed): the first `MAX_LISTED`, and
+/// how many there were in all.
+#[derive(Debug, Clone, Default)]
+pub struct Problems {
+    pub listed: Vec<Problem>,
+    pub count: u64,
+}
+
+impl Problems {
+    pub(super) fn push<K, V>(
+        &mut self,
+        table: TableDefinition<'_, K, V>,
+        key: impl Into<String>,
+        message: impl Into<String>,
+    ) where
+        K: redb::Key + 'static,
+        V: redb::Value + 'static,
+    {
+        self.count += 1;
+        if self.listed.len() < MAX_LISTED {
+            self.listed.push(Problem {
+                table: table.name().to_string(),
+                key: key.into(),
+                message: message.into(),
+            });
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct VerifyReport {
+    pub documents: u64,
+    pub blobs: u64,
+    pub problems: Problems,
+}
+
+impl Store {
+    /// Check every namespace of the database against one snapshot.  Finding
+    /// problems is not an error; failing to read the tables is.
+    #[instrument(skip(self))]
+    pub fn verify(&self, deep: bool) -> Result<VerifyReport> {
+        // As in `export`: make sure every namespace has all its tables.
+        let mut handles = vec![self.namespace("")?];
+        for namespace in self.namespaces()? {
+            handles.push(self.namespace(&namespace)?);
+        }
+
+        let txn = self.db.begin_read()?;
+        let mut report = VerifyReport::default();
+        for handle in &handles {
+            handle.verify_documents(&txn, deep, &mut report)?;
+            handle.verify_blobs(&txn, deep, &mut report)?;
+            handle.verify_attachments(&txn, &mut report)?;
+        }
+
+        if report.problems.count > 0 {
+            warn!(problems = report.problems.count, deep, "verification found problems");
+        } else {
+            info!(
+                documents = report.documents,
+                blobs = report.blobs,
+                deep,
+                "verification passed"
+            );
+        }
+        Ok(report)
+    }
+
+    /// Deep-check up to `count` documents and `count` blobs in every
+    /// namespace, each run starting at a random position and wrapping
+    /// around, so that repeated samples cover the database a slice at a
+    /// time.  Only the sampled rows are checked, not how tables agree.
+    #[instrument(skip(self))]
+    pub fn verify_sample(&self, count: u64) -> Result<VerifyReport> {
+        let mut handles = vec![self.namespace("")?];
+        for namespace in self.namespaces()? {
+            handles.push(self.namespace(&namespace)?);
+        }
+
+        let seed = blake3::hash(&unix_now().to_le_bytes());
+        let txn = self.db.begin_read()?;
+        let mut report = VerifyReport::default();
+        for handle in &handles {
+            handle.sample_documents(&txn, count, seed.as_bytes(), &mut report)?;
+            handle.sample_blobs(&txn, count, seed.as_bytes(), &mut report)?;
+        }
+
+        if report.problems.count > 0 {
+            warn!(problems = report.problems.count, "sampled verification found problems");
+        } else {
+            info!(
+                documents = report.documents,
+                blobs = report.blobs,
+                "sampled verification passed"
+            );
+        }
+        Ok(report)
+    }
+
+    /// Recompute blake3 over the stored bytes of this namespace's blob
+    /// `hash`, or of every blob if `None`, and compare it with the key.  A
+    /// hash that isn't stored here is not counted in `blobs`.
+    #[instrument(skip(self, hash))]
+    pub fn verify_blob_contents(&self, hash: Option<&[u8]>) -> Result<VerifyReport> {
+        let txn = self.db.begin_read()?;
+        let blobs = txn.open_table(self.tables.blobs())?;
+        let mut report = VerifyReport::default();
+        match hash {
+            Some(hash) => {
+                if let Some(stored) = blobs.get(hash)? {
+                    report.blobs += 1;
+                    self.check_blob(hash, stored.value(), &mut report.problems);
+                }
+            }
+            None => {
+                for entry in blobs.iter()? {
+                    let (hash, stored) = entry?;
+                    report.blobs += 1;
+                    self.check_blob(hash.value(), stored.value(), &mut report.problems);
+                }
+            }
+        }
+        if report.problems.count > 0 {
+            warn!(problems = report.problems.count, "blob verification found problems");
+        }
+        Ok(report)
+    }
+
+    fn sample_documents(
+        &self,
+        txn: &ReadTransaction,
+        count: u64,
+        seed: &[u8; 32],
+        report: &mut VerifyReport,
+    ) -> Result<()> {
+        let tables = &self.tables;
+        let keys = self.keys();
+        let docs = txn.open_table(tables.documents())?;
+        let data = txn.open_table(tables.doc_data())?;
+        let hashes = txn.open_table(tables.doc_hashes())?;
+
+        // Document ids are not uniformly spread, so start at a random
+        // offset rather than a random key.
+        let len = docs.len()?;
+        if len == 0 {
+            return Ok(());
+        }
+        let start = u64::from_le_bytes(seed[..8].try_into().expect("8 bytes")) % len;
+        let take = count.min(len) as usize;
+        let ids = docs.iter()?.skip(start as usize).chain(docs.iter()?).take(take);
+        for entry in ids {
+            let (id, _) = entry?;
+            let id = id.value();
+            report.documents += 1;
+            match (data.get(id)?, hashes.get(id)?) {
+                (None, _) => report.problems.push(tables.doc_data(), id, "document has no data"),
+                (_, None) => report.problems.push(tables.doc_hashes(), id, "document has no hash"),
+                (Some(state), Some(hash)) => {
+                    self.check_state(&keys, id, state.value(), hash.value(), &mut report.problems)
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn sample_blobs(
+        &self,
+        txn: &ReadTransaction,
+        count: u64,
+        seed: &[u8; 32],
+        report: &mut VerifyReport,
+    ) -> Result<()> {
+        // Blob keys are hashes, so a random key is a uniform starting point.
+        let blobs = txn.open_table(self.tables.blobs())?;
+        let take = count.min(blobs.len()?) as usize;
+        let start = seed.as_slice();
+        for entry in blobs.range(start..)?.chain(blobs.range(..start)?).take(take) {
+            let (hash, stored) = entry?;
+            report.blobs += 1;
+            self.check_blob(hash.value(), stored.value(), &mut report.problems);
+        }
+        Ok(())
+    }
+
+    fn verify_documents(
+        &self,
+        txn: &ReadTransaction,
+        deep: bool,
+        report: &mut VerifyReport,
+    ) -> Result<()> {
+        let tables = &self.tables;
+        let keys = self.keys();
+        let docs = txn.open_table(tables.documents())?;
+        let data = txn.open_table(tables.doc_data())?;
+        let hashes = txn.open_table(tables.doc_hashes())?;
+        let tombstones = txn.open_table(tables.tombstones())?;
+
+        let mut live = HashSet::new();
+        for entry in docs.iter()? {
+            let (id, _) = entry?;
+            let id = id.value();
+            live.insert(id.to_string());
+            report.documents += 1;
+
+            let state = data.get(id)?;
+            let hash = hashes.get(id)?;
+            match (&state, &hash) {
+                (None, _) => report.problems.push(tables.doc_data(), id, "document has no data"),
+                (_, None) => report.problems.push(tables.doc_hashes(), id, "document has no hash"),
+                (Some(state), Some(hash)) if deep => {
+                    self.check_state(&keys, id, state.value(), hash.value(), &mut report.problems)
+                }
+                _ => {}
+            }
+            if tombstones.get(id)?.is_some() {
+                report.problems.push(tables.tombstones(), id, "live document has a tombstone");
+            }
+        }
+
+        for entry in data.iter()? {
+            let (id, _) = entry?;
+            if !live.contains(id.value()) {
+                report.problems.push(tables.doc_data(), id.value(), "data without a document");
+            }
+        }
+
+        let mut deleted = HashSet::new();
+        for entry in tombstones.iter()? {
+            let (id, value) = entry?;
+            let id = id.value();
+            deleted.insert(id.to_string());
+            let tombstone = match tombstones::parse(value.value()) {
+                Ok(tombstone) => tombstone,
+                Err(e) => {
+                    report.problems.push(tables.tombstones(), id, format!("{e:#}"));
+                    continue;
+                }
+            };
+            if !live.contains(id) {
+                let hash = hashes.get(id)?;
+                if hash.is_none_or(|h| h.value() != tombstone.hash.as_slice()) {
+                    report.problems.push(tables.doc_hashes(), id, "hash does not match tombstone");
+                }
+            }
+        }
+
+        for entry in hashes.iter()? {
+            let (id, _) = entry?;
+            let id = id.value();
+            if !live.contains(id) && !deleted.contains(id) {
+                report.problems.push(
+                    tables.doc_hashes(),
+                    id,
+                    "hash without a document or tombstone",
+                );
+            }
+        }
+
+        for entry in txn.open_table(tables.doc_index_keys())?.iter()? {
+            let (id, _) = entry?;
+            if !live.contains(id.value()) {
+                report.problems.push(
+                    tables.doc_index_keys(),
+                    id.value(),
+                    "index entries without a document",
+                );
+            }
+        }
+
+        for entry in txn.open_table(tables.doc_refs())?.iter()? {
+            let (id, _) = entry?;
+            if !live.contains(id.value()) {
+                report.problems.push(
+                    tables.doc_refs(),
+                    id.value(),
+                    "blob references without a document",
+                );
+            }
+        }
+
+        for entry in txn.open_table(tables.doc_times())?.iter()? {
+            let (id, _) = entry?;
+            if !live.contains(id.value()) {
+                report.problems.push(tables.doc_times(), id.value(), "timestamps without a document");
+            }
+        }
+
+        for entry in txn.open_table(tables.doc_access())?.iter()? {
+            let (id, _) = entry?;
+            if !live.contains(id.value()) {
+                report.problems.push(
+                    tables.doc_access(),
+                    id.value(),
+                    "read statistics without a document",
+                );
+            }
+        }
+        Ok(())
+    }
+
+    fn verify_blobs(
+        &self,
+        txn: &ReadTransaction,
+        deep: bool,
+        report: &mut VerifyReport,
+    ) -> Result<()> {
+        let tables = &self.tables;
+        let blobs = txn.open_table(tables.blobs())?;
+        for entry in blobs.iter()? {
+            let (hash, stored) = entry?;
+            let (hash, stored) = (hash.value(), stored.value());
+            report.blobs += 1;
+
+            if let Err(e) = codec::original_len(stored) {
+                report.problems.push(tables.blobs(), to_hex(hash), format!("{e:#}"));
+                continue;
+            }
+            if deep {
+                self.check_blob(hash, stored, &mut report.problems);
+            } else if codec::is_external(stored)
+                && !spill::path_of(&self.spill_dir, hash, stored).is_file()
+            {
+                report.problems.push(tables.blobs(), to_hex(hash), "spill file is missing");
+            }
+        }
+
+        for entry in txn.open_table(tables.blob_times())?.iter()? {
+            let (hash, _) = entry?;
+            if blobs.get(hash.value())?.is_none() {
+                report.problems.push(
+                    tables.blob_times(),
+                    to_hex(hash.value()),
+                    "timestamps for a missing blob",
+                );
+            }
+        }
+
+        let expiry = txn.open_table(tables.blob_expiry())?;
+        for entry in txn.open_table(tables.blob_ttl())?.iter()? {
+            let (hash, expires_at) = entry?;
+            let hash = hash.value();
+            if blobs.get(hash)?.is_none() {
+                report.problems.push(tables.blob_ttl(), to_hex(hash), "expiry for a missing blob");
+            }
+            if expiry.get((expires_at.value(), hash))?.is_none() {
+                report.problems.push(
+                    tables.blob_expiry(),
+                    to_hex(hash),
+                    "expiry is not in the sweep index",
+                );
+            }
+        }
+        let ttls = txn.open_table(tables.blob_ttl())?;
+        for entry in expiry.iter()? {
+            let (key, _) = entry?;
+            let (expires_at, hash) = key.value();
+            if ttls.get(hash)?.is_none_or(|t| t.value() != expires_at) {
+                report.problems.push(
+                    tables.blob_expiry(),
+                    to_hex(hash),
+                    "sweep index entry without a matching expiry",
+                );
+            }
+        }
+        Ok(())
+    }
+
+    fn verify_attachments(&self, txn: &ReadTransaction, report: &mut VerifyReport) -> Result<()> {
+        let tables = &self.tables;
+        let docs = txn.open_table(tables.documents())?;
+        let blobs = txn.open_table(tables.blobs())?;
+        let reverse = txn.open_table(tables.attached_blobs())?;
+        for entry in txn.open_table(tables.attachments())?.iter()? {
+            let (key, hash) = entry?;
+            let ((id, name), hash) = (key.value(), hash.value());
+            if docs.get(id)?.is_none() {
+                report.problems.push(
+                    tables.attachments(),
+                    id,
+                    format!("attachment {name:?} of a missing document"),
+                );
+            }
+            if blobs.get(hash)?.is_none() {
+                report.problems.push(
+                    tables.attachments(),
+                    id,
+                    format!("attachment {name:?} of missing blob {}", to_hex(hash)),
+                );
+            }
+            if reverse.get((hash, id, name))?.is_none() {
+                report.problems.push(
+                    tables.attached_blobs(),
+                    id,
+                    format!("attachment {name:?} is not in the blob index"),
+                );
+            }
+        }
+        let attachments = txn.open_table(tables.attachments())?;
+        for entry in reverse.iter()? {
+            let (key, _) = entry?;
+            let (hash, id, name) = key.value();
+            if attachments.get((id, name))?.is_none_or(|h| h.value() != hash) {
+                report.problems.push(
+                    tables.attached_blobs(),
+                    to_hex(hash),
+                    "blob index entry without a matching attachment",
+                );
+            }
+        }
+        Ok(())
+    }
+
+    fn check_state(
+        &self,
+        keys: &Keys,
+        id: &str,
+        state: &[u8],
+        hash: &[u8],
+        problems: &mut Problems,
+    ) {
+        match open_state(keys, id, state) {
+            Ok(state) if hashing::hash(&state).as_bytes() == hash => {}
+            Ok(_) => problems.push(self.tables.doc_hashes(), id, "hash does not match state"),
+            Err(e) => problems.push(self.tables.doc_data(), id, format!("{e:#}")),
+        }
+    }
+
+    fn check_blob(&self, hash: &[u8], stored: &[u8], problems: &mut Problems) {
+        match self.hash_stored_blob(hash, stored) {
+            Ok(actual) if actual.as_bytes() == hash => {}
+            Ok(_) => problems.push(self.tables.blobs(), to_hex(hash), "contents do not match hash"),
+            Err(e) => problems.push(self.tables.blobs(), to_hex(hash), format!("{e:#}")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::StoreOptions;
+
+    fn store(dir: &std::path::Path) -> Store {
+        let store = Store::open(dir, StoreOptions::default()).unwrap();
+        store.put_document("doc", b"meta", b"state", None, false).unwrap();
+        store.put_blob(b"blob", None).unwrap();
+        store
+    }
+
+    #[test]
+    fn test_clean_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = store(dir.path());
+        for deep in [false, true] {
+            let report = store.verify(deep).unwrap();
+            assert_eq!((report.documents, report.blobs, report.problems.count), (1, 1, 0));
+        }
+    }
+
+    #[test]
+    fn test_missing_data() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = store(dir.path());
+        let txn = store.begin_write().unwrap();
+        txn.open_table(store.tables.doc_data()).unwrap().remove("doc").unwrap();
+        txn.commit().unwrap();
+
+        let report = store.verify(false).unwrap();
+        assert_eq!(report.problems.count, 1);
+        assert_eq!(report.problems.listed[0].message, "document has no data");
+    }
+
+    #[test]
+    fn test_wrong_hash_needs_deep_pass() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = store(dir.path());
+        let txn = store.begin_write().unwrap();
+        let wrong = blake3::hash(b"other");
+        let mut hashes = txn.open_table(store.tables.doc_hashes()).unwrap();
+        hashes.insert("doc", wrong.as_bytes().as_slice()).unwrap();
+        drop(hashes);
+        txn.commit().unwrap();
+
+        assert_eq!(store.verify(false).unwrap().problems.count, 0);
+        let report = store.verify(true).unwrap();
+        assert_eq!(report.problems.count, 1);
+        assert_eq!(report.problems.listed[0].message, "hash does not match state");
+    }
+}
@@ -0,0 +1,51 @@
+//! Background thread that deletes expired blobs and idle sync sessions and
+//! flushes read statistics.
+
+use crate::store::{unix_now, Store};
+use crate::tenants::Tenants;
+use anyhow::Result;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+/// Granularity at which the sweeper notices shutdown.
+const TICK: Duration = Duration::from_millis(200);
+
+/// Run `Store::sweep_expired` and `Store::expire_sync_sessions` over every
+/// namespace of every open database, and `Store::flush_access` over each
+/// database, every `interval` until `shutdown` is set.
+pub fn spawn(tenants: Tenants, interval: Duration, shutdown: Arc<AtomicBool>) -> JoinHandle<()> {
+    thread::Builder::new()
+        .name("ttl-sweeper".into())
+        .spawn(move || {
+            info!(interval_secs = interval.as_secs(), "expiry sweeper started");
+            let mut next = Instant::now() + interval;
+            while !shutdown.load(Ordering::SeqCst) {
+                if Instant::now() < next {
+                    thread::sleep(TICK);
+                    continue;
+                }
+                for store in tenants.stores() {
+                    if let Err(e) = sweep_all(&store) {
+                        warn!(dir = %store.dir().display(), error = %e, "expiry sweep failed");
+                    }
+                }
+                next = Instant::now() + interval;
+            }
+        })
+        .expect("spawning sweeper thread")
+}
+
+fn sweep_all(store: &Store) -> Result<()> {
+    let now = unix_now();
+    store.sweep_expired(now)?;
+    store.expire_sync_sessions(now)?;
+    for namespace in store.namespaces()? {
+        let handle = store.namespace(&namespace)?;
+        handle.sweep_expired(now)?;
+        handle.expire_sync_sessions(now)?;
+    }
+    store.flush_access()
+}
@@ -0,0 +1,213 @@
+//! Minimal ustar reader and writer, enough for export archives.
+//!
+//! Only regular files are written, with fixed mode, owner and mtime so the
+//! same data always produces the same archive.  The reader returns regular
+//! files and skips every other entry type (directories, pax headers), so
+//! archives repacked by other tar tools still read back.
+
+use anyhow::{bail, Context, Result};
+use std::io::{Read, Write};
+
+const BLOCK: usize = 512;
+
+/// Largest file ustar's 11 octal digits can describe.
+const MAX_SIZE: u64 = 0o77777777777;
+
+pub struct Writer<W: Write> {
+    inner: W,
+}
+
+impl<W: Write> Writer<W> {
+    pub fn new(inner: W) -> Self {
+        Self { inner }
+    }
+
+    /// Append a regular file at `path`.
+    pub fn append(&mut self, path: &str, data: &[u8]) -> Result<()> {
+        self.inner.write_all(&header(path, data.len() as u64)?)?;
+        self.inner.write_all(data)?;
+        self.inner.write_all(&[0; BLOCK][..padding(data.len() as u64)])?;
+        Ok(())
+    }
+
+    /// Write the end-of-archive marker and return the underlying writer.
+    pub fn finish(mut self) -> Result<W> {
+        self.inner.write_all(&[0; 2 * BLOCK])?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+pub struct Reader<R: Read> {
+    inner: R,
+}
+
+impl<R: Read> Reader<R> {
+    pub fn new(inner: R) -> Self {
+        Self { inner }
+    }
+
+    /// The next regular file as `(path, contents)`, or `None` at the end of
+    /// the archive.
+    pub fn next_file(&mut self) -> Result<Option<(String, Vec<u8>)>> {
+        loop {
+            let mut block = [0u8; BLOCK];
+            match self.inner.read_exact(&mut block) {
+                Ok(()) => {}
+                // Some writers omit the end-of-archive marker.
+                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+                Err(e) => return Err(e.into()),
+            }
+            if block.iter().all(|&b| b == 0) {
+                return Ok(None);
+            }
+
+            let stored = parse_octal(&block[148..156]).context("tar header checksum")?;
+            if stored != checksum(&block) {
+                bail!("tar header checksum mismatch");
+            }
+            let size = parse_octal(&block[124..136]).context("tar entry size")?;
+            let path = entry_path(&block)?;
+            let mut data = vec![0; size as usize];
+            self.inner
+                .read_exact(&mut data)
+                .with_context(|| format!("reading {path} from archive"))?;
+            let mut pad = [0u8; BLOCK];
+            self.inner.read_exact(&mut pad[..padding(size)])?;
+
+            if matches!(block[156], b'0' | 0) {
+                return Ok(Some((path, data)));
+            }
+        }
+    }
+}
+
+fn header(path: &str, size: u64) -> Result<[u8; BLOCK]> {
+    if size > MAX_SIZE {
+        bail!("{path} is too large for a tar entry ({size} bytes)");
+    }
+    let (prefix, name) = split_path(path)?;
+    let mut block = [0u8; BLOCK];
+    block[..name.len()].copy_from_slice(name.as_bytes());
+    write_octal(&mut block[100..108], 0o644);
+    write_octal(&mut block[108..116], 0);
+    write_octal(&mut block[116..124], 0);
+    write_octal(&mut block[124..136], size);
+    write_octal(&mut block[136..148], 0);
+    block[156] = b'0';
+    block[257..263].copy_from_slice(b"ustar\0");
+    block[263..265].copy_from_slice(b"00");
+    block[345..345 + prefix.len()].copy_from_slice(prefix.as_bytes());
+
+    let sum = checksum(&block);
+    block[148..156].copy_from_slice(format!("{sum:06o}\0 ").as_bytes());
+    Ok(block)
+}
+
+/// Split `path` into ustar's prefix (≤155 bytes) and name (≤100 bytes)
+/// fields at a `/`.
+fn split_path(path: &str) -> Result<(&str, &str)> {
+    if path.len() <= 100 {
+        return Ok(("", path));
+    }
+    path.match_indices('/')
+        .map(|(i, _)| (&path[..i], &path[i + 1..]))
+        .find(|(prefix, name)| prefix.len() <= 155 && name.len() <= 100 && !name.is_empty())
+        .with_context(|| format!("path too long for a tar entry: {path}"))
+}
+
+fn entry_path(block: &[u8; BLOCK]) -> Result<String> {
+    let field = |bytes: &[u8]| {
+        let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+        String::from_utf8(bytes[..end].to_vec()).context("tar entry path is not utf-8")
+    };
+    let name = field(&block[..100])?;
+    let prefix = if &block[257..262] == b"ustar" {
+        field(&block[345..500])?
+    } else {
+        String::new()
+    };
+    Ok(if prefix.is_empty() {
+        name
+    } else {
+        format!("{prefix}/{name}")
+    })
+}
+
+/// Header checksum: the byte sum with the checksum field read as spaces.
+fn checksum(block: &[u8; BLOCK]) -> u64 {
+    let sum: u64 = block.iter().map(|&b| b as u64).sum();
+    let field: u64 = block[148..156].iter().map(|&b| b as u64).sum();
+    sum - field + 8 * b' ' as u64
+}
+
+/// Zero-padded octal followed by a NUL, filling `field`.
+fn write_octal(field: &mut [u8], value: u64) {
+    let digits = format!("{value:0width$o}", width = field.len() - 1);
+    field[..digits.len()].copy_from_slice(digits.as_bytes());
+}
+
+fn parse_octal(field: &[u8]) -> Result<u64> {
+    let text = std::str::from_utf8(field)?.trim_matches(|c: char| c == '\0' || c == ' ');
+    if text.is_empty() {
+        return Ok(0);
+    }
+    Ok(u64::from_str_radix(text, 8)?)
+}
+
+fn padding(size: u64) -> usize {
+    (BLOCK - size as usize % BLOCK) % BLOCK
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_layout() {
+        let long = format!("{}/{}", "d".repeat(120), "f".repeat(90));
+        let mut writer = Writer::new(Vec::new());
+        writer.append("manifest", b"format\t1\n").unwrap();
+        writer.append(&long, &[7; 1000]).unwrap();
+        let archive = writer.finish().unwrap();
+        // header + 1 data block, header + 2 data blocks, end marker
+        assert_eq!(archive.len(), 7 * BLOCK);
+        assert_eq!(&archive[..8], b"manifest");
+        assert_eq!(&archive[BLOCK..BLOCK + 9], b"format\t1\n");
+        assert_eq!(&archive[2 * BLOCK + 345..2 * BLOCK + 465], "d".repeat(120).as_bytes());
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let long = format!("{}/{}", "d".repeat(120), "f".repeat(90));
+        let mut writer = Writer::new(Vec::new());
+        writer.append("manifest", b"format\t1\n").unwrap();
+        writer.append("empty", b"").unwrap();
+        writer.append(&long, &[7; 1000]).unwrap();
+        let archive = writer.finish().unwrap();
+        assert_eq!(archive.len() % BLOCK, 0);
+
+        let mut reader = Reader::new(archive.as_slice());
+        assert_eq!(
+            reader.next_file().unwrap(),
+            Some(("manifest".to_string(), b"format\t1\n".to_vec()))
+        );
+        assert_eq!(reader.next_file().unwrap(), Some(("empty".to_string(), vec![])));
+        assert_eq!(reader.next_file().unwrap(), Some((long, vec![7; 1000])));
+        assert_eq!(reader.next_file().unwrap(), None);
+    }
+
+    #[test]
+    fn test_rejects_corrupt_header() {
+        let mut writer = Writer::new(Vec::new());
+        writer.append("a", b"x").unwrap();
+        let mut archive = writer.finish().unwrap();
+        archive[0] = b'b';
+        assert!(Reader::new(archive.as_slice()).next_file().is_err());
+    }
+
+    #[test]
+    fn test_rejects_unsplittable_path() {
+        assert!(header(&"x".repeat(101), 0).is_err());
+    }
+}
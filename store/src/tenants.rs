@@ -0,0 +1,123 @@
+//! Registry of open tenant databases.
+//!
+//! Each tenant is a separate redb file (plus spill directory) under
+//! `<data_dir>/tenants/<name>/`, so it can be exported, deleted or
+//! size-limited on its own.  Requests without a tenant use the root store.
+
+use crate::store::{CreateMode, Store, StoreOptions};
+use anyhow::{bail, Context, Result};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use tracing::{info, warn};
+
+const TENANTS_DIR: &str = "tenants";
+
+#[derive(Clone)]
+pub struct Tenants {
+    root: Store,
+    open: Arc<Mutex<HashMap<String, Store>>>,
+}
+
+impl Tenants {
+    pub fn new(root: Store) -> Self {
+        Self {
+            root,
+            open: Arc::default(),
+        }
+    }
+
+    /// The root store, used by requests that name no tenant.
+    pub fn root(&self) -> &Store {
+        &self.root
+    }
+
+    /// Store of an open tenant.
+    pub fn get(&self, name: &str) -> Option<Store> {
+        self.lock().get(name).cloned()
+    }
+
+    /// Open (creating if needed) the tenant's database.  Opening an already
+    /// open tenant is a no-op.
+    pub fn open(&self, name: &str) -> Result<()> {
+        validate_tenant(name)?;
+        let mut open = self.lock();
+        if open.contains_key(name) {
+            return Ok(());
+        }
+        // Creating tenants is what OpenTenant is for, whatever the root's
+        // create mode.
+        let options = StoreOptions {
+            create: CreateMode::IfMissing,
+            ..self.root.options().clone()
+        };
+        let store = Store::open(&self.dir_for(name), options)?;
+        open.insert(name.to_string(), store);
+        info!(tenant = name, "tenant opened");
+        Ok(())
+    }
+
+    /// Close a tenant's database, flushing its read statistics.  Returns
+    /// false if it was not open.
+    /// Requests already running against it finish first; the file is
+    /// released once the last of them drops its handle.
+    pub fn close(&self, name: &str) -> bool {
+        let Some(store) = self.lock().remove(name) else {
+            return false;
+        };
+        if let Err(e) = store.flush_access() {
+            warn!(tenant = name, error = %e, "flushing access statistics failed");
+        }
+        info!(tenant = name, "tenant closed");
+        true
+    }
+
+    /// The root store followed by every open tenant's.
+    pub fn stores(&self) -> Vec<Store> {
+        let mut stores = vec![self.root.clone()];
+        stores.extend(self.lock().values().cloned());
+        stores
+    }
+
+    fn dir_for(&self, name: &str) -> PathBuf {
+        self.root.dir().join(TENANTS_DIR).join(name)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<String, Store>> {
+        self.open.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Tenant names become directory names, so keep them to a safe alphabet.
+/// Data dirs of the tenants created under `data_dir`, whether open or not,
+/// sorted by name.
+pub fn tenant_dirs(data_dir: &Path) -> Result<Vec<PathBuf>> {
+    let root = data_dir.join(TENANTS_DIR);
+    let entries = match std::fs::read_dir(&root) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).with_context(|| format!("listing {}", root.display())),
+    };
+    let mut dirs = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        let name = entry.file_name();
+        if validate_tenant(&name.to_string_lossy()).is_ok() && entry.file_type()?.is_dir() {
+            dirs.push(entry.path());
+        }
+    }
+    dirs.sort();
+    Ok(dirs)
+}
+
+pub fn validate_tenant(name: &str) -> Result<()> {
+    let valid = !name.is_empty()
+        && name.len() <= 64
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-'));
+    if !valid {
+        bail!("invalid tenant {name:?}: use 1 to 64 of [A-Za-z0-9_-]");
+    }
+    Ok(())
+}
@@ -0,0 +1,269 @@
+//! Bandwidth budgets for sync streaming.
+//!
+//! Budgets are configured like rate limits, as `kind=bytes[/burst]` per
+//! second, e.g. `get_changes=262144/1048576`.  `get_changes` paces the
+//! chunks streamed by `GetChanges`, `GetChangesBloom` and `ContinueSync`,
+//! to the port and to peers alike; `push_blobs` paces the blobs stored by
+//! `PushBlobs`, including those the peer puller fetches.  A chunk waits
+//! until the budget has refilled enough to cover it, so one larger than
+//! the burst still goes through, only later.  Kinds without a budget are
+//! never delayed.
+//!
+//! The serving loops don't wait on a chunk themselves: `reserve` tells
+//! them when it may go, and they hold it in a `Deferred` queue until then,
+//! serving other requests meanwhile.  Only the peer puller, which paces
+//! nothing but its own fetches, waits with `consume`.
+
+use crate::ratelimit::RateLimit;
+use anyhow::{bail, Result};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The kinds a budget can be set for.
+pub const KINDS: [&str; 2] = ["get_changes", "push_blobs"];
+
+/// Parse a `--bandwidth-limit`.
+pub fn parse_limit(s: &str) -> Result<RateLimit> {
+    let limit: RateLimit = s.parse()?;
+    if !KINDS.contains(&limit.kind.as_str()) {
+        bail!("no bandwidth limit for {:?}; use one of {}", limit.kind, KINDS.join(", "));
+    }
+    Ok(limit)
+}
+
+/// A byte token bucket whose tokens may go negative: a chunk is let
+/// through at once and the debt is waited out.
+#[derive(Debug)]
+struct Budget {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last: Instant,
+    /// Bytes let through so far.
+    bytes: u64,
+    /// Time chunks have been held back.
+    waited: Duration,
+    /// When the chunks held back may go, earliest first.  Debt only grows
+    /// between refills, so each deadline is no earlier than the last.
+    deadlines: VecDeque<Instant>,
+}
+
+impl Budget {
+    fn new(rate: f64, burst: f64, now: Instant) -> Self {
+        Self {
+            rate,
+            burst,
+            tokens: burst,
+            last: now,
+            bytes: 0,
+            waited: Duration::ZERO,
+            deadlines: VecDeque::new(),
+        }
+    }
+
+    /// Chunks still held back at `now`.
+    fn waiting(&mut self, now: Instant) -> u32 {
+        while self.deadlines.front().is_some_and(|&deadline| deadline <= now) {
+            self.deadlines.pop_front();
+        }
+        self.deadlines.len() as u32
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.last = now;
+    }
+
+    /// Take `bytes`, returning how long to wait before sending them.
+    fn reserve(&mut self, bytes: usize, now: Instant) -> Duration {
+        self.refill(now);
+        self.tokens -= bytes as f64;
+        self.bytes += bytes as u64;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+}
+
+/// Where a budget stands, for `Stats`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ThrottleState {
+    pub kind: String,
+    pub bytes_per_sec: u64,
+    pub burst_bytes: u64,
+    /// Bytes that could go through now without waiting.
+    pub available_bytes: u64,
+    pub bytes: u64,
+    pub waited: Duration,
+    pub waiting: u32,
+}
+
+/// The bandwidth budgets, shared by the port, the peer listener and the
+/// peer puller.
+#[derive(Debug, Default)]
+pub struct Throttles {
+    budgets: Mutex<HashMap<String, Budget>>,
+}
+
+impl Throttles {
+    pub fn new(limits: &[RateLimit]) -> Self {
+        let now = Instant::now();
+        let budgets = limits
+            .iter()
+            .map(|l| (l.kind.clone(), Budget::new(l.rate, l.burst, now)))
+            .collect();
+        Self {
+            budgets: Mutex::new(budgets),
+        }
+    }
+
+    /// Take `bytes` of `kind` from its budget, returning when they may be
+    /// sent, or `None` if they may go at once.
+    pub fn reserve(&self, kind: &str, bytes: usize) -> Option<Instant> {
+        let now = Instant::now();
+        let mut budgets = self.budgets.lock().unwrap_or_else(|e| e.into_inner());
+        let budget = budgets.get_mut(kind)?;
+        let wait = budget.reserve(bytes, now);
+        if wait.is_zero() {
+            return None;
+        }
+        budget.waiting(now);
+        budget.waited += wait;
+        budget.deadlines.push_back(now + wait);
+        Some(now + wait)
+    }
+
+    /// Wait until `bytes` of `kind` fit the budget, for a thread that only
+    /// paces itself.
+    pub fn consume(&self, kind: &str, bytes: usize) {
+        if let Some(deadline) = self.reserve(kind, bytes) {
+            thread::sleep(deadline.saturating_duration_since(Instant::now()));
+        }
+    }
+
+    /// Every configured budget, by kind.
+    pub fn states(&self) -> Vec<ThrottleState> {
+        let now = Instant::now();
+        let mut budgets = self.budgets.lock().unwrap_or_else(|e| e.into_inner());
+        let mut states: Vec<ThrottleState> = budgets
+            .iter_mut()
+            .map(|(kind, budget)| {
+                budget.refill(now);
+                ThrottleState {
+                    kind: kind.clone(),
+                    bytes_per_sec: budget.rate as u64,
+                    burst_bytes: budget.burst as u64,
+                    available_bytes: budget.tokens.max(0.0) as u64,
+                    bytes: budget.bytes,
+                    waited: budget.waited,
+                    waiting: budget.waiting(now),
+                }
+            })
+            .collect();
+        states.sort_by(|a, b| a.kind.cmp(&b.kind));
+        states
+    }
+}
+
+/// Work held back until a deadline, such as chunks `reserve` put off.
+#[derive(Debug)]
+pub struct Deferred<T> {
+    items: Vec<(Instant, T)>,
+}
+
+impl<T> Default for Deferred<T> {
+    fn default() -> Self {
+        Self { items: Vec::new() }
+    }
+}
+
+impl<T> Deferred<T> {
+    pub fn push(&mut self, deadline: Instant, item: T) {
+        self.items.push((deadline, item));
+    }
+
+    /// The items whose deadline has passed, earliest first.
+    pub fn due(&mut self) -> Vec<T> {
+        let now = Instant::now();
+        let (mut due, held): (Vec<_>, Vec<_>) =
+            self.items.drain(..).partition(|(deadline, _)| *deadline <= now);
+        self.items = held;
+        due.sort_by_key(|(deadline, _)| *deadline);
+        due.into_iter().map(|(_, item)| item).collect()
+    }
+
+    /// The earliest deadline, if anything is held.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.items.iter().map(|(deadline, _)| *deadline).min()
+    }
+
+    /// How long to wait for input before the next deadline, at most `max`.
+    pub fn wait(&self, max: Duration) -> Duration {
+        self.next_deadline()
+            .map_or(max, |deadline| deadline.saturating_duration_since(Instant::now()).min(max))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_limit() {
+        let limit = parse_limit("get_changes=1000/4000").unwrap();
+        assert_eq!((limit.rate, limit.burst), (1000.0, 4000.0));
+        assert!(parse_limit("push_blobs=1000").is_ok());
+        assert!(parse_limit("apply_changes=1000").is_err());
+        assert!(parse_limit("get_changes=0").is_err());
+    }
+
+    #[test]
+    fn test_debt_is_waited_out() {
+        let start = Instant::now();
+        let mut budget = Budget::new(1000.0, 2000.0, start);
+        assert_eq!(budget.reserve(1500, start), Duration::ZERO);
+        assert_eq!(budget.reserve(1500, start), Duration::from_secs(1));
+        // The debt is paid off after a second; the next chunk waits its own.
+        let later = start + Duration::from_secs(1);
+        assert_eq!(budget.reserve(500, later), Duration::from_millis(500));
+        assert_eq!(budget.bytes, 3500);
+        // Idle time refills no further than the burst.
+        let idle = later + Duration::from_secs(60);
+        assert_eq!(budget.reserve(2000, idle), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_reserve_holds_chunks_back() {
+        let throttles = Throttles::new(&[parse_limit("get_changes=1000/1000").unwrap()]);
+        assert_eq!(throttles.reserve("get_changes", 1000), None);
+        let deadline = throttles.reserve("get_changes", 500).unwrap();
+        let later = throttles.reserve("get_changes", 500).unwrap();
+        assert!(later > deadline);
+        assert!(later - deadline >= Duration::from_millis(499));
+        assert_eq!(throttles.states()[0].waiting, 2);
+
+        let mut deferred = Deferred::default();
+        deferred.push(later, "second");
+        deferred.push(deadline, "first");
+        deferred.push(Instant::now(), "now");
+        assert_eq!(deferred.due(), ["now"]);
+        assert_eq!(deferred.next_deadline(), Some(deadline));
+        assert!(deferred.wait(Duration::from_secs(5)) <= Duration::from_millis(500));
+        assert_eq!(deferred.wait(Duration::ZERO), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_unthrottled_kinds_pass() {
+        let throttles = Throttles::new(&[parse_limit("push_blobs=1").unwrap()]);
+        throttles.consume("get_changes", 1 << 30);
+        let states = throttles.states();
+        assert_eq!(states.len(), 1);
+        assert_eq!(states[0].kind, "push_blobs");
+        assert_eq!(states[0].bytes, 0);
+    }
+}
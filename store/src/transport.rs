@@ -0,0 +1,286 @@
+//! Transports the port can be served over.
+//!
+//! `stdio` is the Elixir port: frames on stdin and stdout.  `uds` and
+//! `tcp` listen on a socket and serve many client connections at once in
+//! the same framing.
+//!
+//! A Unix socket is guarded by its file's permissions.  A TCP listener is
+//! not, so without an `AuthToken` it only binds loopback addresses.  With
+//! one, every TCP client must first send a frame holding the token, within
+//! `HANDSHAKE_TIMEOUT`, or it is disconnected unserved.
+
+use crate::frame::write_frame;
+use anyhow::{bail, Context, Result};
+use std::fmt;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// How long a TCP client has to present the auth token.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Longest auth token frame read, so an unauthenticated client can't make
+/// the port allocate much.
+const MAX_TOKEN_BYTES: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Transport {
+    #[default]
+    Stdio,
+    Uds,
+    Tcp,
+}
+
+impl FromStr for Transport {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "stdio" => Ok(Transport::Stdio),
+            "uds" => Ok(Transport::Uds),
+            "tcp" => Ok(Transport::Tcp),
+            other => bail!("unknown transport {other:?} (stdio, uds, tcp)"),
+        }
+    }
+}
+
+impl fmt::Display for Transport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Transport::Stdio => "stdio",
+            Transport::Uds => "uds",
+            Transport::Tcp => "tcp",
+        })
+    }
+}
+
+/// The secret TCP clients present before they are served.
+#[derive(Clone)]
+pub struct AuthToken(String);
+
+impl AuthToken {
+    pub fn new(token: String) -> Result<Self> {
+        if token.is_empty() || token.len() > MAX_TOKEN_BYTES {
+            bail!("an auth token must be 1 to {MAX_TOKEN_BYTES} bytes");
+        }
+        Ok(Self(token))
+    }
+
+    /// Whether `presented` is the token, compared in constant time.
+    fn matches(&self, presented: &[u8]) -> bool {
+        blake3::hash(presented) == blake3::hash(self.0.as_bytes())
+    }
+}
+
+impl fmt::Debug for AuthToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("AuthToken(..)")
+    }
+}
+
+/// A client connection, accepted by a `Listener` or made by `connect`.
+pub struct Connection {
+    pub reader: Box<dyn Read + Send>,
+    pub writer: Box<dyn Write + Send>,
+    /// The other end's address, for logs.
+    pub peer: String,
+    /// The token an accepted TCP client still has to present, and its
+    /// stream, for the handshake's timeout.
+    handshake: Option<(TcpStream, AuthToken)>,
+}
+
+impl Connection {
+    /// Read the auth token an accepted client must send first, if its
+    /// listener requires one, and check it.
+    pub fn authenticate(&mut self) -> Result<()> {
+        let Some((stream, token)) = self.handshake.take() else {
+            return Ok(());
+        };
+        stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+        let mut len = [0u8; 4];
+        self.reader
+            .read_exact(&mut len)
+            .context("reading the auth token")?;
+        let len = u32::from_be_bytes(len) as usize;
+        if len > MAX_TOKEN_BYTES {
+            bail!("the auth token frame is {len} bytes, more than {MAX_TOKEN_BYTES}");
+        }
+        let mut presented = vec![0u8; len];
+        self.reader
+            .read_exact(&mut presented)
+            .context("reading the auth token")?;
+        stream.set_read_timeout(None)?;
+        if !token.matches(&presented) {
+            bail!("wrong auth token");
+        }
+        Ok(())
+    }
+}
+
+/// A bound socket, polled without blocking so shutdown is noticed.
+#[derive(Debug)]
+pub enum Listener {
+    /// Clients must present the token, if there is one.
+    Tcp(TcpListener, Option<AuthToken>),
+    /// The socket file is removed when the listener is dropped.
+    Unix(UnixListener, PathBuf),
+}
+
+impl Listener {
+    /// Bind `addr`: `host:port` for `tcp`, a socket path for `uds`.  A
+    /// stale socket file left by a crashed port is replaced.  `tcp`
+    /// requires `token` of its clients, and binds only loopback addresses
+    /// without one.
+    pub fn bind(transport: Transport, addr: &str, token: Option<AuthToken>) -> Result<Self> {
+        let listener = match transport {
+            Transport::Stdio => bail!("stdio has nothing to listen on"),
+            Transport::Tcp => {
+                let listener =
+                    TcpListener::bind(addr).with_context(|| format!("listening on {addr}"))?;
+                if token.is_none() && !listener.local_addr()?.ip().is_loopback() {
+                    bail!(
+                        "{addr} is not a loopback address; set {} so TCP clients must \
+                         authenticate",
+                        crate::AUTH_TOKEN_ENV
+                    );
+                }
+                listener.set_nonblocking(true)?;
+                Listener::Tcp(listener, token)
+            }
+            Transport::Uds => {
+                let path = PathBuf::from(addr);
+                match std::fs::remove_file(&path) {
+                    Ok(()) => {}
+                    Err(e) if e.kind() == ErrorKind::NotFound => {}
+                    Err(e) => {
+                        return Err(e).with_context(|| format!("removing {}", path.display()))
+                    }
+                }
+                let listener = UnixListener::bind(&path)
+                    .with_context(|| format!("listening on {}", path.display()))?;
+                listener.set_nonblocking(true)?;
+                Listener::Unix(listener, path)
+            }
+        };
+        Ok(listener)
+    }
+
+    /// The next waiting client, if any.
+    pub fn accept(&self) -> io::Result<Option<Connection>> {
+        let accepted = match self {
+            Listener::Tcp(listener, token) => listener.accept().and_then(|(stream, peer)| {
+                stream.set_nonblocking(false)?;
+                let handshake = match token {
+                    Some(token) => Some((stream.try_clone()?, token.clone())),
+                    None => None,
+                };
+                Ok(Connection {
+                    reader: Box::new(stream.try_clone()?),
+                    writer: Box::new(stream),
+                    peer: peer.to_string(),
+                    handshake,
+                })
+            }),
+            Listener::Unix(listener, path) => listener.accept().and_then(|(stream, _)| {
+                stream.set_nonblocking(false)?;
+                Ok(Connection {
+                    reader: Box::new(stream.try_clone()?),
+                    writer: Box::new(stream),
+                    peer: path.display().to_string(),
+                    handshake: None,
+                })
+            }),
+        };
+        match accepted {
+            Ok(connection) => Ok(Some(connection)),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Connect to a served port: `addr` is a Unix socket path if it contains a
+/// `/`, else `host:port`.  Over TCP, `token` is presented first if given.
+pub fn connect(addr: &str, token: Option<&AuthToken>) -> Result<Connection> {
+    let connection = if addr.contains('/') {
+        let stream = UnixStream::connect(addr).with_context(|| format!("connecting to {addr}"))?;
+        Connection {
+            reader: Box::new(stream.try_clone()?),
+            writer: Box::new(stream),
+            peer: addr.to_string(),
+            handshake: None,
+        }
+    } else {
+        let mut stream =
+            TcpStream::connect(addr).with_context(|| format!("connecting to {addr}"))?;
+        if let Some(token) = token {
+            write_frame(&mut stream, token.0.as_bytes())?;
+        }
+        Connection {
+            reader: Box::new(stream.try_clone()?),
+            writer: Box::new(stream),
+            peer: addr.to_string(),
+            handshake: None,
+        }
+    };
+    Ok(connection)
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Listener::Unix(_, path) = self {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_transport_from_str() {
+        for transport in [Transport::Stdio, Transport::Uds, Transport::Tcp] {
+            assert_eq!(transport.to_string().parse::<Transport>().unwrap(), transport);
+        }
+        assert!("pipe".parse::<Transport>().is_err());
+    }
+
+    #[test]
+    fn test_tcp_needs_a_token_off_loopback() {
+        let refused = Listener::bind(Transport::Tcp, "0.0.0.0:0", None).unwrap_err();
+        assert!(refused.to_string().contains(crate::AUTH_TOKEN_ENV), "{refused:#}");
+        Listener::bind(Transport::Tcp, "127.0.0.1:0", None).unwrap();
+        let token = AuthToken::new("secret".into()).unwrap();
+        Listener::bind(Transport::Tcp, "0.0.0.0:0", Some(token)).unwrap();
+        assert!(AuthToken::new(String::new()).is_err());
+    }
+
+    #[test]
+    fn test_authenticate() {
+        let token = AuthToken::new("secret".into()).unwrap();
+        let listener = Listener::bind(Transport::Tcp, "127.0.0.1:0", Some(token.clone())).unwrap();
+        let Listener::Tcp(tcp, _) = &listener else {
+            unreachable!()
+        };
+        let addr = tcp.local_addr().unwrap().to_string();
+        let accept = || loop {
+            if let Some(connection) = listener.accept().unwrap() {
+                return connection;
+            }
+            std::thread::sleep(Duration::from_millis(5));
+        };
+
+        let _client = connect(&addr, Some(&token)).unwrap();
+        accept().authenticate().unwrap();
+        let wrong = AuthToken::new("guess".into()).unwrap();
+        let _client = connect(&addr, Some(&wrong)).unwrap();
+        assert!(accept().authenticate().is_err());
+        let _client = connect(&addr, None).unwrap();
+        drop(_client);
+        assert!(accept().authenticate().is_err());
+    }
+}
@@ -0,0 +1,175 @@
+//! Client transactions.
+//!
+//! `BeginTxn` opens a handle bound to its request's database, namespace and
+//! durability.  Writes sent as `TxnWrite { txn, request }` are staged in
+//! memory instead of being applied, so an open transaction holds no lock
+//! and other requests carry on meanwhile.  `Commit` applies the staged
+//! writes in order in one write transaction (`Store::write_batch`), all or
+//! nothing; `Abort` drops them.  Reads see only committed data, never
+//! staged writes, not even those of the reader's own transaction.
+//!
+//! Every request of a transaction must target the same database,
+//! namespace and durability as its `BeginTxn`, and come from the same
+//! client connection: to any other client the handle does not exist.
+
+use crate::server::ClientId;
+use crate::store::{Store, WriteOp};
+use anyhow::{bail, Result};
+use std::collections::HashMap;
+use std::sync::{Mutex, MutexGuard};
+
+/// Transactions open at once, across every database.
+const MAX_OPEN: usize = 64;
+
+/// Writes one transaction may stage.
+const MAX_OPS: usize = 10_000;
+
+struct Txn {
+    client: ClientId,
+    store: Store,
+    ops: Vec<WriteOp>,
+}
+
+#[derive(Default)]
+struct Open {
+    /// Handle of the next transaction; handles are never reused.
+    next: u64,
+    by_handle: HashMap<u64, Txn>,
+}
+
+#[derive(Default)]
+pub struct Transactions {
+    open: Mutex<Open>,
+}
+
+impl Transactions {
+    /// Open a transaction of `client` on `store`, returning its handle.
+    pub fn begin(&self, client: ClientId, store: &Store) -> Result<u64> {
+        let mut open = self.lock();
+        if open.by_handle.len() >= MAX_OPEN {
+            bail!("too many open transactions ({MAX_OPEN}); commit or abort some first");
+        }
+        open.next += 1;
+        let handle = open.next;
+        open.by_handle.insert(
+            handle,
+            Txn {
+                client,
+                store: store.clone(),
+                ops: Vec::new(),
+            },
+        );
+        Ok(handle)
+    }
+
+    /// Stage `op` in transaction `handle`.  Returns false if `client` has
+    /// no such transaction.
+    pub fn stage(&self, client: ClientId, handle: u64, store: &Store, op: WriteOp) -> Result<bool> {
+        let mut open = self.lock();
+        let Some(txn) = open.by_handle.get_mut(&handle).filter(|txn| txn.client == client) else {
+            return Ok(false);
+        };
+        check_target(txn, store)?;
+        if txn.ops.len() >= MAX_OPS {
+            bail!("transaction {handle} already has {MAX_OPS} writes");
+        }
+        txn.ops.push(op);
+        Ok(true)
+    }
+
+    /// Close transaction `handle`, returning its staged writes, or `None`
+    /// if `client` has no such transaction.
+    pub fn take(
+        &self,
+        client: ClientId,
+        handle: u64,
+        store: &Store,
+    ) -> Result<Option<Vec<WriteOp>>> {
+        let mut open = self.lock();
+        let Some(txn) = open.by_handle.get(&handle).filter(|txn| txn.client == client) else {
+            return Ok(None);
+        };
+        check_target(txn, store)?;
+        let txn = open.by_handle.remove(&handle).expect("checked above");
+        Ok(Some(txn.ops))
+    }
+
+    /// Drop every transaction on `store`'s database, e.g. a tenant being
+    /// closed, so they don't keep it open.
+    pub fn forget_database(&self, store: &Store) {
+        self.lock()
+            .by_handle
+            .retain(|_, txn| !txn.store.same_database(store));
+    }
+
+    /// Drop the transactions `client` left open when it disconnected.
+    pub fn forget_client(&self, client: ClientId) {
+        self.lock().by_handle.retain(|_, txn| txn.client != client);
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Open> {
+        self.open.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+fn check_target(txn: &Txn, store: &Store) -> Result<()> {
+    if !txn.store.same_target(store) {
+        bail!("request targets another database, namespace or durability than its transaction");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::StoreOptions;
+
+    fn put(id: &str) -> WriteOp {
+        WriteOp::PutDocument {
+            id: id.into(),
+            meta: b"meta".to_vec(),
+            crdt_state: b"state".to_vec(),
+            index: None,
+            create_only: false,
+        }
+    }
+
+    #[test]
+    fn test_transactions() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Store::open(dir.path(), StoreOptions::default()).unwrap();
+        let other = store.namespace("other").unwrap();
+        let txns = Transactions::default();
+
+        let handle = txns.begin(1, &store).unwrap();
+        assert!(txns.stage(1, handle, &store, put("a")).unwrap());
+        assert!(txns.stage(1, handle, &store, put("b")).unwrap());
+        assert!(txns.stage(1, handle, &other, put("c")).is_err());
+        // Staged writes are invisible, and another client can't touch them.
+        assert!(store.get_document("a").unwrap().is_none());
+        assert!(!txns.stage(2, handle, &store, put("d")).unwrap());
+        assert!(txns.take(2, handle, &store).unwrap().is_none());
+
+        let ops = txns.take(1, handle, &store).unwrap().unwrap();
+        assert_eq!(ops.len(), 2);
+        assert!(txns.take(1, handle, &store).unwrap().is_none());
+        store.write_batch(&ops).unwrap();
+        assert_eq!(store.list_documents("").unwrap(), ["a", "b"]);
+
+        // A disconnecting client's transactions go with it; handles are
+        // never reused.
+        let first = txns.begin(1, &store).unwrap();
+        let second = txns.begin(2, &store).unwrap();
+        assert!(first > handle && second > first);
+        txns.forget_client(1);
+        assert!(!txns.stage(1, first, &store, put("e")).unwrap());
+        assert!(txns.stage(2, second, &store, put("e")).unwrap());
+        txns.forget_database(&store);
+        assert!(txns.take(2, second, &store).unwrap().is_none());
+
+        for _ in 0..MAX_OPEN {
+            txns.begin(3, &other).unwrap();
+        }
+        assert!(txns.begin(3, &other).is_err());
+    }
+}
@@ -0,0 +1,170 @@
+//! Document watches.
+//!
+//! A `Watch` request registers interest in documents of one database and
+//! namespace, for the client connection that sent it.  After each request
+//! the port serves, the changes it committed are matched against the
+//! watches on its database and pushed as `DocumentChanged` frames carrying
+//! the watch's ref_id.  Events for a write follow its reply on the writer's
+//! own connection; other clients get theirs on their next turn of the
+//! serving loop.  A database records changes only while it has watches.
+
+use crate::protocol::{RefId, Response};
+use crate::server::ClientId;
+use crate::store::Store;
+use anyhow::{bail, Result};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Mutex, MutexGuard};
+
+/// Which document ids a watch covers.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Filter {
+    ids: HashSet<String>,
+    prefix: Option<String>,
+}
+
+impl Filter {
+    pub fn new(ids: Vec<String>, prefix: Option<String>) -> Result<Self> {
+        if ids.is_empty() && prefix.is_none() {
+            bail!("a watch needs ids or a prefix");
+        }
+        Ok(Self {
+            ids: ids.into_iter().collect(),
+            prefix,
+        })
+    }
+
+    fn matches(&self, id: &str) -> bool {
+        self.ids.contains(id) || self.prefix.as_deref().is_some_and(|p| id.starts_with(p))
+    }
+}
+
+struct Watch {
+    store: Store,
+    filter: Filter,
+    /// `seq` of the next event.
+    seq: u64,
+}
+
+/// Watches by the client that made them and the ref_id of their `Watch`
+/// request.
+#[derive(Default)]
+pub struct Watches {
+    by_ref: Mutex<HashMap<(ClientId, RefId), Watch>>,
+}
+
+impl Watches {
+    /// Register the watch of `client`'s `Watch` request `ref_id` on
+    /// `store`'s database and namespace.
+    pub fn add(
+        &self,
+        client: ClientId,
+        ref_id: RefId,
+        store: &Store,
+        filter: Filter,
+    ) -> Result<()> {
+        let mut watches = self.lock();
+        if watches.contains_key(&(client, ref_id)) {
+            bail!("ref_id {ref_id} already has a watch");
+        }
+        store.track_changes(true);
+        watches.insert(
+            (client, ref_id),
+            Watch {
+                store: store.clone(),
+                filter,
+                seq: 0,
+            },
+        );
+        Ok(())
+    }
+
+    /// Remove one of `client`'s watches.  Returns false if there was none.
+    pub fn remove(&self, client: ClientId, ref_id: RefId) -> bool {
+        let mut watches = self.lock();
+        let Some(watch) = watches.remove(&(client, ref_id)) else {
+            return false;
+        };
+        if !watches.values().any(|w| w.store.same_database(&watch.store)) {
+            watch.store.track_changes(false);
+        }
+        true
+    }
+
+    /// Drop the watches of a client that disconnected.
+    pub fn forget_client(&self, client: ClientId) {
+        let mut watches = self.lock();
+        let gone: Vec<Watch> = watches
+            .extract_if(|&(owner, _), _| owner == client)
+            .map(|(_, watch)| watch)
+            .collect();
+        for watch in gone {
+            if !watches.values().any(|w| w.store.same_database(&watch.store)) {
+                watch.store.track_changes(false);
+            }
+        }
+    }
+
+    /// Drop every watch on `store`'s database, e.g. a tenant being closed,
+    /// so they don't keep it open.
+    pub fn forget_database(&self, store: &Store) {
+        self.lock().retain(|_, w| !w.store.same_database(store));
+        store.track_changes(false);
+    }
+
+    /// Events for the changes committed to `store`'s database since the
+    /// last call, as (client, watch ref_id, `DocumentChanged`).
+    pub fn events(&self, store: &Store) -> Result<Vec<(ClientId, RefId, Response)>> {
+        let mut watches = self.lock();
+        if !watches.values().any(|w| w.store.same_database(store)) {
+            return Ok(Vec::new());
+        }
+        let mut events = Vec::new();
+        for change in store.take_changes()? {
+            for (&(client, ref_id), watch) in watches.iter_mut() {
+                if !watch.store.same_database(store)
+                    || watch.store.namespace_name() != change.namespace
+                    || !watch.filter.matches(&change.id)
+                {
+                    continue;
+                }
+                events.push((
+                    client,
+                    ref_id,
+                    Response::DocumentChanged {
+                        seq: watch.seq,
+                        id: change.id.clone(),
+                        hash: change.hash.clone(),
+                        deleted: change.deleted,
+                    },
+                ));
+                watch.seq += 1;
+            }
+        }
+        Ok(events)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<(ClientId, RefId), Watch>> {
+        self.by_ref.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filter() {
+        assert!(Filter::new(Vec::new(), None).is_err());
+
+        let ids = Filter::new(vec!["a".into(), "b".into()], None).unwrap();
+        assert!(ids.matches("a") && ids.matches("b"));
+        assert!(!ids.matches("ab"));
+
+        let prefix = Filter::new(vec!["x".into()], Some("doc/".into())).unwrap();
+        assert!(prefix.matches("doc/1") && prefix.matches("x"));
+        assert!(!prefix.matches("docs"));
+
+        let all = Filter::new(Vec::new(), Some(String::new())).unwrap();
+        assert!(all.matches("anything") && all.matches(""));
+    }
+}